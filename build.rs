@@ -0,0 +1,100 @@
+//! Embeds information about the build into the binary, see `cargo_fetcher::build_info`
+
+/// The dependencies whose versions are most useful when debugging user reports
+const KEY_DEPENDENCIES: &[&str] = &["gix", "reqwest", "rustls", "tame-index", "tokio", "zstd"];
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    rerun_on_new_commits();
+
+    // The git sha is unavailable when built from a crates.io package, which is
+    // fine as the version is enough to identify the source in that case
+    let sha = std::process::Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_owned())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=CF_GIT_SHA={sha}");
+
+    println!("cargo:rustc-env=CF_DEPENDENCIES={}", dependency_versions());
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = std::process::Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=CF_RUSTC_VERSION={rustc_version}");
+
+    println!(
+        "cargo:rustc-env=CF_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+}
+
+/// Reruns the build script when HEAD moves, either by checking out another
+/// branch or commit, or by committing to the current branch, which only
+/// changes the branch's ref, which may be loose or packed.
+///
+/// Files that don't exist are skipped, as cargo would otherwise rerun the build
+/// script on every build.
+fn rerun_on_new_commits() {
+    let mut watched = vec![".git/HEAD".to_owned(), ".git/packed-refs".to_owned()];
+
+    if let Some(branch) = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_owned()))
+    {
+        watched.push(format!(".git/{branch}"));
+    }
+
+    for path in watched {
+        if std::path::Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+}
+
+/// Gathers the locked versions of the key dependencies as a comma separated
+/// list of `<name>=<version>`.
+///
+/// The lockfile is scanned line by line rather than deserialized so that we
+/// don't need any build dependencies.
+fn dependency_versions() -> String {
+    let Ok(lock) = std::fs::read_to_string("Cargo.lock") else {
+        return String::new();
+    };
+
+    let mut versions = Vec::new();
+    let mut lines = lock.lines();
+
+    while let Some(line) = lines.next() {
+        let Some(name) = line
+            .strip_prefix("name = \"")
+            .and_then(|name| name.strip_suffix('"'))
+        else {
+            continue;
+        };
+
+        if !KEY_DEPENDENCIES.contains(&name) {
+            continue;
+        }
+
+        let Some(version) = lines
+            .next()
+            .and_then(|line| line.strip_prefix("version = \""))
+            .and_then(|version| version.strip_suffix('"'))
+        else {
+            continue;
+        };
+
+        versions.push(format!("{name}={version}"));
+    }
+
+    versions.join(",")
+}
@@ -1,10 +1,19 @@
 #[cfg(feature = "gcs")]
+pub mod artifact_registry;
+#[cfg(feature = "gcs")]
 pub mod gcs;
 
 #[cfg(feature = "s3")]
 pub mod s3;
 
+pub mod cas;
+pub mod encrypted;
 pub mod fs;
+pub mod multi;
+pub mod none;
 
 #[cfg(feature = "blob")]
 pub mod blob;
+
+#[cfg(feature = "sftp")]
+pub mod sftp;
@@ -0,0 +1,349 @@
+//! Stores objects as files in a [generic repository](https://cloud.google.com/artifact-registry/docs/generic)
+//! in Google Artifact Registry, authenticating with the same service account
+//! credentials as the `gcs` backend
+
+use crate::{
+    util::{self, send_request_with_retry},
+    CloudId, HttpClient, Path,
+};
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+const API: &str = "https://artifactregistry.googleapis.com";
+/// Artifact Registry doesn't have narrower scopes like GCS does
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Generic artifacts are always identified by a package, a version, and a
+/// file name, so every object is a file in the same version of the package
+const VERSION: &str = "objects";
+
+/// A file in the repository, only the fields we use
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct File {
+    name: String,
+    /// int64 fields are encoded as strings
+    #[serde(default)]
+    size_bytes: Option<String>,
+    #[serde(default)]
+    update_time: Option<String>,
+}
+
+impl File {
+    fn stat(&self) -> crate::ObjectStat {
+        crate::ObjectStat {
+            size: self
+                .size_bytes
+                .as_deref()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or_default(),
+            updated: self.update_time.as_deref().and_then(|updated| {
+                crate::Timestamp::parse(updated, &time::format_description::well_known::Rfc3339)
+                    .ok()
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListFilesResponse {
+    #[serde(default)]
+    files: Vec<File>,
+    next_page_token: Option<String>,
+}
+
+pub struct ArtifactRegistryBackend {
+    client: HttpClient,
+    /// `projects/<project>/locations/<location>/repositories/<repository>`
+    repository: String,
+    package: String,
+    retry: util::RetryPolicy,
+}
+
+impl ArtifactRegistryBackend {
+    pub async fn new(
+        loc: crate::ArtifactRegistryLocation<'_>,
+        credentials: &Path,
+        timeout: std::time::Duration,
+        retry: util::RetryPolicy,
+        http: &util::HttpConfig,
+    ) -> Result<Self> {
+        let token = super::gcs::acquire_gcp_token(credentials, &[SCOPE], http).await?;
+
+        use reqwest::header;
+
+        let hm = {
+            let mut hm = header::HeaderMap::new();
+            hm.insert(
+                header::AUTHORIZATION,
+                <tame_oauth::Token as std::convert::TryInto<header::HeaderValue>>::try_into(token)?,
+            );
+            hm
+        };
+
+        let client = http
+            .client_builder()
+            .default_headers(hm)
+            .timeout(timeout)
+            .build()?;
+
+        Ok(Self {
+            client,
+            repository: format!(
+                "projects/{}/locations/{}/repositories/{}",
+                loc.project, loc.location, loc.repository
+            ),
+            package: loc.package.to_owned(),
+            retry,
+        })
+    }
+
+    /// The id of the file for the object, of the form `<package>:<version>:<file name>`
+    #[inline]
+    fn file_id(&self, id: CloudId<'_>) -> String {
+        format!("{}:{VERSION}:{id}", self.package)
+    }
+
+    /// The url of the file in the API, eg. `v1` for the file's metadata, or
+    /// `download/v1` for its contents
+    #[inline]
+    fn file_url(&self, api: &str, id: CloudId<'_>) -> String {
+        format!("{API}/{api}/{}/files/{}", self.repository, self.file_id(id))
+    }
+
+    /// Gets the object id from the resource name of a file, returning `None`
+    /// if the file belongs to a different package or version
+    fn object_id(&self, name: &str) -> Option<String> {
+        // The separators in file ids may or may not be escaped
+        let name = name.replace("%3A", ":");
+        let prefix = format!("{}/files/{}:{VERSION}:", self.repository, self.package);
+        name.strip_prefix(&prefix).map(str::to_owned)
+    }
+
+    async fn get_file(&self, id: CloudId<'_>) -> Result<Option<File>> {
+        let req = self.client.get(self.file_url("v1", id)).build()?;
+        let response = send_request_with_retry(&self.client, req, &self.retry).await?;
+
+        if response.status() == http::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = response.error_for_status()?.bytes().await?;
+        let file = serde_json::from_slice(&body).context("failed to deserialize file")?;
+        Ok(Some(file))
+    }
+
+    /// Uploads the object as a new file via a multipart upload
+    ///
+    /// See <https://cloud.google.com/artifact-registry/docs/reference/rest/v1/media/upload>
+    async fn create(&self, source: &bytes::Bytes, id: CloudId<'_>) -> Result<reqwest::Response> {
+        use bytes::BufMut as _;
+
+        // The contents are very unlikely to contain their own digest, which
+        // makes it a safe boundary
+        let boundary = format!("cargo-fetcher-{}", util::sha256_hex(source));
+        let metadata = serde_json::json!({
+            "filename": id.to_string(),
+            "packageId": self.package,
+            "versionId": VERSION,
+        });
+
+        let mut body = bytes::BytesMut::with_capacity(source.len() + 512);
+        body.put_slice(
+            format!(
+                "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{metadata}\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.put_slice(source);
+        body.put_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let req = self
+            .client
+            .post(format!(
+                "{API}/upload/v1/{}/genericArtifacts:create?alt=json&uploadType=multipart",
+                self.repository
+            ))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                format!("multipart/related; boundary={boundary}"),
+            )
+            .body(body.freeze())
+            .build()?;
+
+        send_request_with_retry(&self.client, req, &self.retry).await
+    }
+}
+
+use std::fmt;
+
+impl fmt::Debug for ArtifactRegistryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("artifactregistry")
+            .field("repository", &self.repository)
+            .field("package", &self.package)
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for ArtifactRegistryBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<bytes::Bytes> {
+        let req = self
+            .client
+            .get(format!(
+                "{}:download?alt=media",
+                self.file_url("download/v1", id)
+            ))
+            .build()?;
+
+        let content = send_request_with_retry(&self.client, req, &self.retry)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(content)
+    }
+
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64> {
+        let req = self
+            .client
+            .get(format!(
+                "{}:download?alt=media",
+                self.file_url("download/v1", id)
+            ))
+            .build()?;
+
+        let response = send_request_with_retry(&self.client, req, &self.retry)
+            .await?
+            .error_for_status()?;
+
+        util::write_response_to(response, file).await
+    }
+
+    async fn upload(&self, source: bytes::Bytes, id: CloudId<'_>) -> Result<usize> {
+        let mut response = self.create(&source, id).await?;
+
+        // Files can't be overwritten, so objects that are replaced, eg. index
+        // snapshots, are deleted first
+        if response.status() == http::StatusCode::CONFLICT {
+            self.delete(id).await?;
+            response = self.create(&source, id).await?;
+        }
+
+        response
+            .error_for_status()
+            .with_context(|| format!("failed to upload {id}"))?;
+
+        Ok(source.len())
+    }
+
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut url = url::Url::parse(&format!("{API}/v1/{}/files", self.repository))?;
+            {
+                let mut query = url.query_pairs_mut();
+                query
+                    .append_pair(
+                        "filter",
+                        &format!(
+                            "owner=\"{}/packages/{}/versions/{VERSION}\"",
+                            self.repository, self.package
+                        ),
+                    )
+                    .append_pair("pageSize", "1000");
+                if let Some(page_token) = &page_token {
+                    query.append_pair("pageToken", page_token);
+                }
+            }
+
+            let req = self.client.get(url).build()?;
+            let body = send_request_with_retry(&self.client, req, &self.retry)
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            let list: ListFilesResponse =
+                serde_json::from_slice(&body).context("failed to deserialize file list")?;
+
+            objects.extend(
+                list.files
+                    .iter()
+                    .filter_map(|file| Some((self.object_id(&file.name)?, file.stat()))),
+            );
+
+            page_token = list.next_page_token.filter(|token| !token.is_empty());
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn updated(&self, id: CloudId<'_>) -> Result<Option<crate::Timestamp>> {
+        Ok(self
+            .get_file(id)
+            .await?
+            .and_then(|file| file.stat().updated))
+    }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let req = self.client.delete(self.file_url("v1", id)).build()?;
+        let response = send_request_with_retry(&self.client, req, &self.retry).await?;
+
+        if response.status() != http::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        Ok(self.get_file(id).await?.map(|file| file.stat()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_objects_to_files() {
+        let backend = ArtifactRegistryBackend {
+            client: HttpClient::new(),
+            repository: "projects/proj/locations/us-central1/repositories/repo".to_owned(),
+            package: "cargo-fetcher".to_owned(),
+            retry: util::RetryPolicy::default(),
+        };
+
+        let krate = crate::Krate::object("abcdef");
+        assert_eq!(
+            backend.file_url("download/v1", krate.cloud_id(false)),
+            "https://artifactregistry.googleapis.com/download/v1/projects/proj/locations/us-central1/repositories/repo/files/cargo-fetcher:objects:abcdef"
+        );
+
+        let prefix = "projects/proj/locations/us-central1/repositories/repo/files";
+        assert_eq!(
+            backend
+                .object_id(&format!("{prefix}/cargo-fetcher:objects:abcdef"))
+                .unwrap(),
+            "abcdef"
+        );
+        assert_eq!(
+            backend
+                .object_id(&format!(
+                    "{prefix}/cargo-fetcher%3Aobjects%3Afoo-1a2b3c4.sha256"
+                ))
+                .unwrap(),
+            "foo-1a2b3c4.sha256"
+        );
+        assert!(backend
+            .object_id(&format!("{prefix}/other:objects:abcdef"))
+            .is_none());
+    }
+}
@@ -8,30 +8,124 @@ use bytes::Bytes;
 mod vendor;
 use vendor as blob;
 
+/// Objects larger than this are uploaded as multiple blocks, so that a failure
+/// only requires the failed block to be retried rather than the entire object.
+/// The API version we use limits single uploads to 64MiB.
+const BLOCK_UPLOAD_THRESHOLD: usize = 32 * 1024 * 1024;
+/// The size of each block, the API version we use limits blocks to 4MiB
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The well known development account of the Azurite storage emulator
+/// <https://learn.microsoft.com/azure/storage/common/storage-use-azurite#well-known-storage-account-and-key>
+const EMULATOR_ACCOUNT: &str = "devstoreaccount1";
+const EMULATOR_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+const EMULATOR_ENDPOINT: &str = "http://127.0.0.1:10000/devstoreaccount1";
+
+/// The account, key, and blob endpoint to use
+#[derive(Debug, PartialEq, Eq)]
+struct Credentials {
+    account: String,
+    key: String,
+    endpoint: Option<String>,
+}
+
+impl Credentials {
+    fn emulator() -> Self {
+        Self {
+            account: EMULATOR_ACCOUNT.to_owned(),
+            key: EMULATOR_KEY.to_owned(),
+            endpoint: Some(EMULATOR_ENDPOINT.to_owned()),
+        }
+    }
+
+    /// Parses an Azure storage [connection string](https://learn.microsoft.com/azure/storage/common/storage-configure-connection-string),
+    /// eg. `DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=<key>;BlobEndpoint=http://azurite:10000/devstoreaccount1;`
+    fn from_connection_string(cs: &str) -> Result<Self> {
+        let mut protocol = None;
+        let mut account = None;
+        let mut key = None;
+        let mut endpoint = None;
+        let mut suffix = None;
+
+        for kv in cs.split(';').filter(|kv| !kv.trim().is_empty()) {
+            let (k, v) = kv
+                .split_once('=')
+                .with_context(|| format!("invalid connection string setting '{kv}'"))?;
+
+            match k.trim() {
+                "UseDevelopmentStorage" if v == "true" => return Ok(Self::emulator()),
+                "DefaultEndpointsProtocol" => protocol = Some(v),
+                "AccountName" => account = Some(v),
+                "AccountKey" => key = Some(v),
+                "BlobEndpoint" => endpoint = Some(v.to_owned()),
+                "EndpointSuffix" => suffix = Some(v),
+                // Eg. endpoints for the other storage services
+                _ => {}
+            }
+        }
+
+        let account = account.context("connection string doesn't contain an AccountName")?;
+        let key = key.context("connection string doesn't contain an AccountKey")?;
+
+        let endpoint = endpoint.or_else(|| {
+            suffix
+                .map(|suffix| format!("{}://{account}.blob.{suffix}", protocol.unwrap_or("https")))
+        });
+
+        Ok(Self {
+            account: account.to_owned(),
+            key: key.to_owned(),
+            endpoint,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct BlobBackend {
     prefix: String,
     instance: blob::Blob,
     client: HttpClient,
+    retry: util::RetryPolicy,
 }
 
 impl BlobBackend {
-    pub fn new(loc: crate::BlobLocation<'_>, timeout: std::time::Duration) -> Result<Self> {
-        let account =
-            std::env::var("STORAGE_ACCOUNT").context("Set env variable STORAGE_ACCOUNT first!")?;
-        let master_key = std::env::var("STORAGE_MASTER_KEY")
-            .context("Set env variable STORAGE_MASTER_KEY first!")?;
-
-        let instance = blob::Blob::new(&account, &master_key, loc.container, false);
-        let client = HttpClient::builder()
-            .use_rustls_tls()
-            .timeout(timeout)
-            .build()?;
+    pub fn new(
+        loc: crate::BlobLocation<'_>,
+        timeout: std::time::Duration,
+        retry: util::RetryPolicy,
+        http: &util::HttpConfig,
+    ) -> Result<Self> {
+        // A connection string takes precedence, so that eg. an emulator that
+        // isn't on the default port can be targeted
+        let creds = if let Ok(cs) = std::env::var("AZURE_STORAGE_CONNECTION_STRING") {
+            Credentials::from_connection_string(&cs)
+                .context("invalid AZURE_STORAGE_CONNECTION_STRING")?
+        } else if loc.emulator {
+            Credentials::emulator()
+        } else {
+            Credentials {
+                account: std::env::var("STORAGE_ACCOUNT")
+                    .context("Set env variable STORAGE_ACCOUNT first!")?,
+                key: std::env::var("STORAGE_MASTER_KEY")
+                    .context("Set env variable STORAGE_MASTER_KEY first!")?,
+                endpoint: None,
+            }
+        };
+
+        let instance = blob::Blob::new(
+            &creds.account,
+            &creds.key,
+            loc.container,
+            creds.endpoint.as_deref(),
+        );
+        let client = http.client_builder().timeout(timeout).build()?;
 
         Ok(Self {
             prefix: loc.prefix.to_owned(),
             instance,
             client,
+            retry,
         })
     }
 
@@ -39,15 +133,48 @@ impl BlobBackend {
     fn make_key(&self, id: CloudId<'_>) -> String {
         format!("{}{id}", self.prefix)
     }
-}
 
-const FMT: &[time::format_description::FormatItem<'_>] = time::macros::format_description!(
-    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
-);
+    /// Uploads the blob as a sequence of blocks, then commits them
+    async fn upload_blocks(&self, source: Bytes, key: &str) -> Result<()> {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+        let mut block_ids = Vec::with_capacity(source.len() / BLOCK_SIZE + 1);
+
+        for (i, offset) in (0..source.len()).step_by(BLOCK_SIZE).enumerate() {
+            // All block ids in a blob must be the same length
+            let block_id = STANDARD.encode(format!("{i:08}"));
+            let block = source.slice(offset..(offset + BLOCK_SIZE).min(source.len()));
+
+            let request = self
+                .instance
+                .put_block(key, &block_id, block, &utc_now_to_str())?;
+
+            send_request_with_retry(&self.client, request.try_into()?, &self.retry)
+                .await?
+                .error_for_status()
+                .with_context(|| format!("failed to upload block {i}"))?;
+
+            block_ids.push(block_id);
+        }
+
+        let request = self
+            .instance
+            .put_block_list(key, &block_ids, &utc_now_to_str())?;
+
+        send_request_with_retry(&self.client, request.try_into()?, &self.retry)
+            .await?
+            .error_for_status()
+            .context("failed to commit block list")?;
+
+        Ok(())
+    }
+}
 
 #[inline]
 fn utc_now_to_str() -> String {
-    time::OffsetDateTime::now_utc().format(&FMT).unwrap()
+    time::OffsetDateTime::now_utc()
+        .format(&util::HTTP_DATE)
+        .unwrap()
 }
 
 #[async_trait::async_trait]
@@ -55,34 +182,79 @@ impl crate::Backend for BlobBackend {
     async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
         let dl_req = self
             .instance
-            .download(&self.make_key(id), &utc_now_to_str())?;
+            .download(&self.make_key(id), &utc_now_to_str(), None)?;
 
-        let res = send_request_with_retry(&self.client, util::convert_request(dl_req))
+        let res = send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
             .await?
             .error_for_status()?;
 
         Ok(res.bytes().await?)
     }
 
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64> {
+        let dl_req = self
+            .instance
+            .download(&self.make_key(id), &utc_now_to_str(), None)?;
+
+        let res = send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
+            .await?
+            .error_for_status()?;
+
+        util::write_response_to(res, file).await
+    }
+
+    /// Uses a conditional request, so the object is only downloaded if it was
+    /// modified, without a separate request for when it was last modified
+    /// that the object could be replaced after
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: crate::Timestamp,
+        file: &mut std::fs::File,
+    ) -> Result<Option<u64>> {
+        let since_str = util::http_date(since)?;
+        let dl_req =
+            self.instance
+                .download(&self.make_key(id), &utc_now_to_str(), Some(&since_str))?;
+
+        let res = send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
+            .await?;
+
+        if util::is_not_modified(&res, since) {
+            return Ok(None);
+        }
+
+        util::write_response_to(res.error_for_status()?, file)
+            .await
+            .map(Some)
+    }
+
     async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
         let content_len = source.len() as u64;
+
+        if source.len() > BLOCK_UPLOAD_THRESHOLD {
+            self.upload_blocks(source, &self.make_key(id)).await?;
+            return Ok(content_len as usize);
+        }
+
         let insert_req = self
             .instance
             .insert(&self.make_key(id), source, &utc_now_to_str())?;
 
-        send_request_with_retry(&self.client, insert_req.try_into()?)
+        send_request_with_retry(&self.client, insert_req.try_into()?, &self.retry)
             .await?
             .error_for_status()?;
 
         Ok(content_len as usize)
     }
 
-    async fn list(&self) -> Result<Vec<String>> {
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
         let list_req = self.instance.list(&utc_now_to_str())?;
 
-        let response = send_request_with_retry(&self.client, util::convert_request(list_req))
-            .await?
-            .error_for_status()?;
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(list_req), &self.retry)
+                .await?
+                .error_for_status()?;
 
         let resp_body = response
             .text()
@@ -94,8 +266,21 @@ impl crate::Backend for BlobBackend {
             .blobs
             .blob
             .into_iter()
-            .map(|b| b.name)
-            .collect::<Vec<String>>();
+            .map(|b| {
+                let updated =
+                    crate::Timestamp::parse(&b.properties.last_modified, &util::HTTP_DATE)
+                        .ok()
+                        .map(|lm| lm.replace_offset(time::UtcOffset::UTC));
+
+                (
+                    b.name,
+                    crate::ObjectStat {
+                        size: b.properties.content_length as u64,
+                        updated,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
         Ok(a)
     }
 
@@ -104,17 +289,98 @@ impl crate::Backend for BlobBackend {
             .instance
             .properties(&self.make_key(id), &utc_now_to_str())?;
 
-        let response = send_request_with_retry(&self.client, util::convert_request(request))
-            .await?
-            .error_for_status()?;
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(request), &self.retry)
+                .await?
+                .error_for_status()?;
 
         let properties =
             blob::PropertiesResponse::try_from(util::convert_response(response).await?)?;
 
         // Ensure the offset is UTC, the azure datetime format is truly terrible
-        let last_modified = crate::Timestamp::parse(&properties.last_modified, &FMT)?
+        let last_modified = crate::Timestamp::parse(&properties.last_modified, &util::HTTP_DATE)?
             .replace_offset(time::UtcOffset::UTC);
 
         Ok(Some(last_modified))
     }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let request = self
+            .instance
+            .delete(&self.make_key(id), &utc_now_to_str())?;
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(request), &self.retry)
+                .await?;
+
+        if response.status() != http::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        let request = self
+            .instance
+            .properties(&self.make_key(id), &utc_now_to_str())?;
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(request), &self.retry)
+                .await?;
+
+        if response.status() == http::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let properties = blob::PropertiesResponse::try_from(
+            util::convert_response(response.error_for_status()?).await?,
+        )?;
+
+        let updated = crate::Timestamp::parse(&properties.last_modified, &util::HTTP_DATE)
+            .ok()
+            .map(|lm| lm.replace_offset(time::UtcOffset::UTC));
+
+        Ok(Some(crate::ObjectStat {
+            size: properties.content_length,
+            updated,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Credentials;
+
+    #[test]
+    fn parses_connection_strings() {
+        assert_eq!(
+            Credentials::from_connection_string("UseDevelopmentStorage=true").unwrap(),
+            Credentials::emulator()
+        );
+
+        assert_eq!(
+            Credentials::from_connection_string(
+                "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=a2V5;BlobEndpoint=http://azurite:10000/devstoreaccount1;QueueEndpoint=http://azurite:10001/devstoreaccount1;"
+            )
+            .unwrap(),
+            Credentials {
+                account: "devstoreaccount1".to_owned(),
+                key: "a2V5".to_owned(),
+                endpoint: Some("http://azurite:10000/devstoreaccount1".to_owned()),
+            }
+        );
+
+        assert_eq!(
+            Credentials::from_connection_string(
+                "DefaultEndpointsProtocol=https;AccountName=acct;AccountKey=a2V5==;EndpointSuffix=core.chinacloudapi.cn"
+            )
+            .unwrap()
+            .endpoint
+            .as_deref(),
+            Some("https://acct.blob.core.chinacloudapi.cn")
+        );
+
+        assert!(Credentials::from_connection_string("AccountName=acct").is_err());
+    }
 }
@@ -1,3 +1,5 @@
+mod block;
+mod delete;
 mod download;
 mod insert;
 mod list;
@@ -7,10 +9,10 @@ use anyhow::Error;
 use std::fmt;
 
 pub use list::parse_list_body;
-pub use list::EnumerationResults;
 
 pub struct PropertiesResponse {
     pub last_modified: String,
+    pub content_length: u64,
 }
 
 #[derive(Debug)]
@@ -19,29 +21,29 @@ pub struct Blob {
     key: String,
     container: String,
     version_value: String,
-    azurite: bool,
+    /// The url of the account's blob service, eg. `http://127.0.0.1:10000/devstoreaccount1`
+    /// for Azurite
+    endpoint: String,
 }
 
 impl Blob {
-    pub fn new(account: &str, key: &str, container: &str, azurite: bool) -> Self {
+    /// Creates a client for the container, the endpoint defaults to the
+    /// account's public Azure endpoint
+    pub fn new(account: &str, key: &str, container: &str, endpoint: Option<&str>) -> Self {
         Self {
             account: account.to_owned(),
             key: key.to_owned(),
             container: container.to_owned(),
             version_value: String::from("2015-02-21"),
-            azurite,
+            endpoint: endpoint.map_or_else(
+                || format!("https://{account}.blob.core.windows.net"),
+                |ep| ep.trim_end_matches('/').to_owned(),
+            ),
         }
     }
 
     fn container_uri(&self) -> String {
-        if self.azurite {
-            format!("http://127.0.0.1:10000/{}/{}", self.account, self.container)
-        } else {
-            format!(
-                "https://{}.blob.core.windows.net/{}",
-                self.account, self.container
-            )
-        }
+        format!("{}/{}", self.endpoint, self.container)
     }
 
     fn sign(
@@ -71,18 +73,24 @@ impl fmt::Display for Blob {
 }
 
 enum Actions {
-    Download,
+    /// Carries the `If-Modified-Since` of a conditional download, as it is
+    /// part of the signature
+    Download(Option<String>),
     Insert,
     Properties,
     List,
+    Delete,
+    PutBlock(String),
+    PutBlockList,
 }
 
 impl From<&Actions> for http::Method {
     fn from(action: &Actions) -> Self {
         match action {
-            Actions::Download | Actions::List => http::Method::GET,
-            Actions::Insert => http::Method::PUT,
+            Actions::Download(_) | Actions::List => http::Method::GET,
+            Actions::Insert | Actions::PutBlock(_) | Actions::PutBlockList => http::Method::PUT,
             Actions::Properties => http::Method::HEAD,
+            Actions::Delete => http::Method::DELETE,
         }
     }
 }
@@ -120,12 +128,18 @@ fn prepare_to_sign(
         let content_md5 = "";
         let content_type = "";
         let date = "";
-        let if_modified_since = "";
+        let if_modified_since = match action {
+            Actions::Download(Some(since)) => since.as_str(),
+            _ => "",
+        };
         let if_match = "";
         let if_none_match = "";
         let if_unmodified_since = "";
         let range = "";
-        let canonicalized_headers = if matches!(action, Actions::Properties) {
+        let canonicalized_headers = if matches!(
+            action,
+            Actions::Properties | Actions::Delete | Actions::PutBlock(_) | Actions::PutBlockList
+        ) {
             format!("x-ms-date:{time_str}\nx-ms-version:{version_value}")
         } else {
             format!("x-ms-blob-type:BlockBlob\nx-ms-date:{time_str}\nx-ms-version:{version_value}")
@@ -133,10 +147,13 @@ fn prepare_to_sign(
         // let canonicalized_headers =
         //     format!("x-ms-date:{}\nx-ms-version:{}", time_str, version_value);
         let verb = http::Method::from(action).to_string();
-        let canonicalized_resource = if matches!(action, Actions::List) {
-            format!("/{account}{path}\ncomp:list\nrestype:container")
-        } else {
-            format!("/{account}{path}")
+        let canonicalized_resource = match action {
+            Actions::List => format!("/{account}{path}\ncomp:list\nrestype:container"),
+            Actions::PutBlock(block_id) => {
+                format!("/{account}{path}\nblockid:{block_id}\ncomp:block")
+            }
+            Actions::PutBlockList => format!("/{account}{path}\ncomp:blocklist"),
+            _ => format!("/{account}{path}"),
         };
         format!(
             "{verb}\n{content_encoding}\n{content_language}\n{content_length}\n{content_md5}\n{content_type}\n{date}\n{if_modified_since}\n{if_match}\n{if_none_match}\n{if_unmodified_since}\n{range}\n{canonicalized_headers}\n{canonicalized_resource}"
@@ -0,0 +1,312 @@
+//! A content-addressed layout, in which objects are stored as blobs named by
+//! the SHA-256 of their contents, so that a single storage location can be
+//! shared as a deduplicated pool by many lockfiles.
+//!
+//! Registry crates are already identified by their checksum, which is the
+//! SHA-256 of the `.crate`, so they are stored as is. Every other object, eg.
+//! git tarballs, is recorded in a named manifest that maps its id to its blob,
+//! so each team or lockfile has its own manifest while sharing the blobs.
+
+use crate::{util, CloudId, Krate, ObjectStat, PathBuf, Storage, Timestamp};
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
+use tracing::debug;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    /// Maps the id of each object to the SHA-256 of its contents, which is the
+    /// id of the blob it is stored as
+    objects: BTreeMap<String, String>,
+}
+
+/// Whether the id is the SHA-256 of a blob
+#[inline]
+fn is_blob(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub struct CasBackend {
+    inner: Storage,
+    /// The id of the manifest object in the inner backend
+    manifest_id: String,
+    manifest: Mutex<Manifest>,
+    /// The entries added, or removed if `None`, since the manifest was read,
+    /// which are applied to the latest manifest when it is saved
+    changes: Mutex<BTreeMap<String, Option<String>>>,
+}
+
+impl CasBackend {
+    /// Opens the manifest with the specified name, which is created when
+    /// [`Self::save`] is first called if it doesn't exist
+    pub async fn open(inner: Storage, name: &str) -> Result<Self> {
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains(['/', '\\']),
+            "invalid manifest name '{name}'"
+        );
+
+        let manifest_id = format!("{name}.manifest");
+        let manifest = read_manifest(&inner, &manifest_id).await?;
+
+        Ok(Self {
+            inner,
+            manifest_id,
+            manifest: Mutex::new(manifest),
+            changes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Uploads the manifest if any objects were added to or removed from it.
+    ///
+    /// The manifest is read again first, and only the entries changed by this
+    /// backend are applied to it, so that another mirror that saved the same
+    /// manifest since it was opened doesn't lose its entries. The storage
+    /// locations don't all support conditional uploads, so two saves at the
+    /// exact same time can still race, in which case the last one wins.
+    pub async fn save(&self) -> Result<()> {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut latest = read_manifest(&self.inner, &self.manifest_id).await?;
+        for (id, blob) in changes {
+            match blob {
+                Some(blob) => latest.objects.insert(id, blob),
+                None => latest.objects.remove(&id),
+            };
+        }
+
+        let data = serde_json::to_vec(&latest)?;
+        let krate = Krate::object(&self.manifest_id);
+        self.inner
+            .upload(data.into(), krate.cloud_id(false))
+            .await
+            .with_context(|| format!("failed to upload manifest '{}'", self.manifest_id))?;
+
+        *self.manifest.lock().unwrap() = latest;
+        Ok(())
+    }
+
+    /// Resolves the blob an object is stored as, if it exists
+    fn resolve(&self, id: CloudId<'_>) -> Option<Krate> {
+        let id = id.to_string();
+        let manifest = self.manifest.lock().unwrap();
+
+        manifest
+            .objects
+            .get(&id)
+            .map(|blob| Krate::object(blob))
+            .or_else(|| is_blob(&id).then(|| Krate::object(&id)))
+    }
+}
+
+/// Reads the manifest, or an empty one if it doesn't exist yet
+async fn read_manifest(inner: &Storage, manifest_id: &str) -> Result<Manifest> {
+    let krate = Krate::object(manifest_id);
+
+    if inner.stat(krate.cloud_id(false)).await?.is_none() {
+        debug!("manifest '{manifest_id}' doesn't exist");
+        return Ok(Manifest::default());
+    }
+
+    let data = inner.fetch(krate.cloud_id(false)).await?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("failed to deserialize manifest '{manifest_id}'"))
+}
+
+impl std::fmt::Debug for CasBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for CasBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
+        let blob = self
+            .resolve(id)
+            .with_context(|| format!("'{id}' is not in manifest '{}'", self.manifest_id))?;
+        self.inner.fetch(blob.cloud_id(false)).await
+    }
+
+    async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
+        let len = source.len();
+        let hash = util::sha256_hex(&source);
+        let blob = Krate::object(&hash);
+
+        // Another lockfile may have already uploaded the same contents
+        if self.inner.stat(blob.cloud_id(false)).await?.is_none() {
+            self.inner.upload(source, blob.cloud_id(false)).await?;
+        } else {
+            debug!("'{id}' is already stored as '{hash}'");
+        }
+
+        let id = id.to_string();
+        if id != hash {
+            let mut manifest = self.manifest.lock().unwrap();
+            if manifest.objects.get(&id) != Some(&hash) {
+                manifest.objects.insert(id.clone(), hash.clone());
+                self.changes.lock().unwrap().insert(id, Some(hash));
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Lists every object in the manifest whose blob exists, as well as every
+    /// blob that isn't referenced by any manifest, ie. the registry crates,
+    /// which are shared by all of them
+    async fn list_stats(&self) -> Result<Vec<(String, ObjectStat)>> {
+        let stored = self.inner.list_stats().await?;
+
+        // Blobs for other objects are only reachable through a manifest, so
+        // those referenced by other manifests must not be listed as if they
+        // were registry crates
+        let mut referenced: HashSet<String> = self
+            .manifest
+            .lock()
+            .unwrap()
+            .objects
+            .values()
+            .cloned()
+            .collect();
+        for (id, _) in stored
+            .iter()
+            .filter(|(id, _)| id.ends_with(".manifest") && id != &self.manifest_id)
+        {
+            let data = self.inner.fetch(Krate::object(id).cloud_id(false)).await?;
+            let manifest: Manifest = serde_json::from_slice(&data)
+                .with_context(|| format!("failed to deserialize manifest '{id}'"))?;
+            referenced.extend(manifest.objects.into_values());
+        }
+
+        let blobs: HashMap<_, _> = stored.into_iter().filter(|(id, _)| is_blob(id)).collect();
+
+        let mut objects: Vec<_> = {
+            let manifest = self.manifest.lock().unwrap();
+            manifest
+                .objects
+                .iter()
+                .filter_map(|(id, blob)| Some((id.clone(), blobs.get(blob)?.clone())))
+                .collect()
+        };
+        objects.extend(blobs.into_iter().filter(|(id, _)| !referenced.contains(id)));
+        objects.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(objects)
+    }
+
+    async fn updated(&self, id: CloudId<'_>) -> Result<Option<Timestamp>> {
+        match self.resolve(id) {
+            Some(blob) => self.inner.updated(blob.cloud_id(false)).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Blobs may be referenced by other manifests, so only the manifest entry
+    /// is removed
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let id = id.to_string();
+        if self.manifest.lock().unwrap().objects.remove(&id).is_some() {
+            self.changes.lock().unwrap().insert(id, None);
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<ObjectStat>> {
+        match self.resolve(id) {
+            Some(blob) => self.inner.stat(blob.cloud_id(false)).await,
+            None => Ok(None),
+        }
+    }
+
+    fn local_path(&self, id: CloudId<'_>) -> Option<PathBuf> {
+        let blob = self.resolve(id)?;
+        self.inner.local_path(blob.cloud_id(false))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{backends::fs::FsBackend, Backend as _};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn dedupes_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::util::path(dir.path()).unwrap();
+        let inner: Storage = Arc::new(FsBackend::new(crate::FilesystemLocation { path }).unwrap());
+
+        let checkout = Bytes::from_static(b"checkout");
+        let registry_crate = Bytes::from_static(b"crate");
+        let chksum = util::sha256_hex(&registry_crate);
+
+        let team_a = CasBackend::open(inner.clone(), "team-a").await.unwrap();
+        let team_b = CasBackend::open(inner.clone(), "team-b").await.unwrap();
+
+        for (team, id) in [(&team_a, "git-a"), (&team_b, "git-b")] {
+            team.upload(checkout.clone(), Krate::object(id).cloud_id(false))
+                .await
+                .unwrap();
+            team.upload(
+                registry_crate.clone(),
+                Krate::object(&chksum).cloud_id(false),
+            )
+            .await
+            .unwrap();
+            team.save().await.unwrap();
+        }
+
+        // Each object is only stored once, along with each manifest
+        let mut stored = inner.list().await.unwrap();
+        stored.sort();
+        let mut expected = vec![
+            util::sha256_hex(&checkout),
+            chksum.clone(),
+            "team-a.manifest".to_owned(),
+            "team-b.manifest".to_owned(),
+        ];
+        expected.sort();
+        assert_eq!(stored, expected);
+
+        // Mirrors that save the same manifest keep each other's entries
+        let first = CasBackend::open(inner.clone(), "team-a").await.unwrap();
+        let second = CasBackend::open(inner.clone(), "team-a").await.unwrap();
+        for (mirror, id) in [(&first, "git-c"), (&second, "git-d")] {
+            mirror
+                .upload(checkout.clone(), Krate::object(id).cloud_id(false))
+                .await
+                .unwrap();
+        }
+        first.save().await.unwrap();
+        second
+            .delete(Krate::object("git-a").cloud_id(false))
+            .await
+            .unwrap();
+        second.save().await.unwrap();
+
+        let team_a = CasBackend::open(inner.clone(), "team-a").await.unwrap();
+        assert_eq!(
+            team_a.list().await.unwrap(),
+            vec![chksum.as_str(), "git-c", "git-d"]
+        );
+        assert_eq!(
+            team_a
+                .fetch(Krate::object("git-c").cloud_id(false))
+                .await
+                .unwrap(),
+            checkout
+        );
+        assert!(team_a
+            .fetch(Krate::object("git-b").cloud_id(false))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Client-side encryption of objects at rest, wrapping another backend.
+//!
+//! Each object is encrypted with AES-256-GCM, using a random nonce that is
+//! stored in a small header before the ciphertext. The id of the object is
+//! used as the associated data, so an object can't be swapped with another
+//! without decryption failing.
+//!
+//! ```text
+//! | magic (4) | nonce (12) | ciphertext | tag (16) |
+//! ```
+
+use crate::{CloudId, ObjectStat, Path, Storage, Timestamp};
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use ring::aead;
+
+/// Identifies an object as encrypted, and the version of the format
+const MAGIC: &[u8; 4] = b"CFE1";
+const HEADER_LEN: usize = MAGIC.len() + aead::NONCE_LEN;
+
+pub struct EncryptedBackend {
+    inner: Storage,
+    key: aead::LessSafeKey,
+    rng: ring::rand::SystemRandom,
+}
+
+impl EncryptedBackend {
+    pub fn new(inner: Storage, key: &[u8]) -> Result<Self> {
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, key)
+            .map_err(|_err| anyhow::anyhow!("encryption key must be 32 bytes"))?;
+
+        Ok(Self {
+            inner,
+            key: aead::LessSafeKey::new(key),
+            rng: ring::rand::SystemRandom::new(),
+        })
+    }
+
+    /// Reads the key from the file, which contains either the 32 raw bytes of
+    /// the key, or the key as 64 hex characters, eg. from `openssl rand -hex 32`
+    pub fn read_key(path: &Path) -> Result<Vec<u8>> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("unable to read key file '{path}'"))?;
+
+        let Some(hex) = std::str::from_utf8(&contents)
+            .ok()
+            .map(str::trim)
+            .filter(|hex| hex.len() == 64)
+        else {
+            return Ok(contents);
+        };
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .context("key file is not valid hex")
+    }
+
+    fn encrypt(&self, data: &[u8], id: CloudId<'_>) -> Result<Bytes> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        ring::rand::SecureRandom::fill(&self.rng, &mut nonce)
+            .map_err(|_err| anyhow::anyhow!("failed to generate nonce"))?;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + data.len() + aead::AES_256_GCM.tag_len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(data);
+
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(id.to_string().as_bytes()),
+                &mut out[HEADER_LEN..],
+            )
+            .map_err(|_err| anyhow::anyhow!("failed to encrypt '{id}'"))?;
+        out.extend_from_slice(tag.as_ref());
+
+        Ok(out.into())
+    }
+
+    fn decrypt(&self, data: &[u8], id: CloudId<'_>) -> Result<Bytes> {
+        anyhow::ensure!(
+            data.len() >= HEADER_LEN + aead::AES_256_GCM.tag_len() && data.starts_with(MAGIC),
+            "'{id}' is not encrypted"
+        );
+
+        let nonce = aead::Nonce::try_assume_unique_for_key(&data[MAGIC.len()..HEADER_LEN])
+            .map_err(|_err| anyhow::anyhow!("'{id}' has an invalid nonce"))?;
+
+        let mut buf = data[HEADER_LEN..].to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(id.to_string().as_bytes()), &mut buf)
+            .map_err(|_err| {
+                anyhow::anyhow!("failed to decrypt '{id}', the key is wrong or it was modified")
+            })?
+            .len();
+        buf.truncate(len);
+
+        Ok(buf.into())
+    }
+}
+
+impl std::fmt::Debug for EncryptedBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for EncryptedBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
+        let data = self.inner.fetch(id).await?;
+        self.decrypt(&data, id)
+    }
+
+    async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
+        let data = self.encrypt(&source, id)?;
+        self.inner.upload(data, id).await
+    }
+
+    async fn list_stats(&self) -> Result<Vec<(String, ObjectStat)>> {
+        self.inner.list_stats().await
+    }
+
+    async fn updated(&self, id: CloudId<'_>) -> Result<Option<Timestamp>> {
+        self.inner.updated(id).await
+    }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        self.inner.delete(id).await
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<ObjectStat>> {
+        self.inner.stat(id).await
+    }
+
+    // Objects can't be linked from the inner backend, as they are encrypted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{backends::fs::FsBackend, Backend as _, Krate};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn encrypts_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::util::path(dir.path()).unwrap();
+        let inner: Storage = Arc::new(FsBackend::new(crate::FilesystemLocation { path }).unwrap());
+
+        let backend = EncryptedBackend::new(inner.clone(), &[7; 32]).unwrap();
+
+        let krate = Krate::object("secret");
+        let plaintext = Bytes::from_static(b"proprietary checkout");
+        backend
+            .upload(plaintext.clone(), krate.cloud_id(false))
+            .await
+            .unwrap();
+
+        let stored = inner.fetch(krate.cloud_id(false)).await.unwrap();
+        assert!(stored.starts_with(MAGIC));
+        assert!(!stored
+            .windows(plaintext.len())
+            .any(|window| window == plaintext));
+
+        assert_eq!(
+            backend.fetch(krate.cloud_id(false)).await.unwrap(),
+            plaintext
+        );
+
+        // The wrong key fails
+        let wrong = EncryptedBackend::new(inner.clone(), &[8; 32]).unwrap();
+        assert!(wrong.fetch(krate.cloud_id(false)).await.is_err());
+
+        // An object moved to a different id fails
+        let other = Krate::object("other");
+        inner.upload(stored, other.cloud_id(false)).await.unwrap();
+        assert!(backend.fetch(other.cloud_id(false)).await.is_err());
+    }
+}
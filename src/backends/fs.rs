@@ -1,7 +1,10 @@
 use crate::{CloudId, PathBuf};
 use anyhow::Result;
 use bytes::Bytes;
-use std::fs;
+use std::{fs, io::Write as _};
+
+/// The prefix of in-progress uploads, which are ignored when listing
+const TMP_PREFIX: &str = ".cf-tmp";
 
 #[derive(Debug)]
 pub struct FsBackend {
@@ -9,13 +12,12 @@ pub struct FsBackend {
 }
 
 impl FsBackend {
+    /// Creates the backend for the directory, which is only created once an
+    /// object is uploaded, so that a mirror on read-only media, eg. a read-only
+    /// NFS mount, can still be synced from
     pub fn new(loc: crate::FilesystemLocation<'_>) -> Result<Self> {
         let crate::FilesystemLocation { path } = loc;
 
-        if !path.exists() {
-            fs::create_dir_all(path)?;
-        }
-
         Ok(Self {
             path: path.to_owned(),
         })
@@ -25,6 +27,22 @@ impl FsBackend {
     fn make_path(&self, id: CloudId<'_>) -> PathBuf {
         self.path.join(id.to_string())
     }
+
+    /// Converts errors due to the directory not being writable into a clear
+    /// error, rather than the raw I/O error of whichever write failed
+    fn write_error(&self, err: std::io::Error, what: &str) -> anyhow::Error {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::ReadOnlyFilesystem | ErrorKind::PermissionDenied => {
+                anyhow::anyhow!(
+                    "read-only backend: unable to {what} in '{}': {err}",
+                    self.path
+                )
+            }
+            _ => anyhow::Error::new(err).context(format!("failed to {what}")),
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -35,18 +53,78 @@ impl crate::Backend for FsBackend {
         Ok(buf.into())
     }
 
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut fs::File) -> Result<u64> {
+        let path = self.make_path(id);
+        let mut src = fs::File::open(path)?;
+        Ok(std::io::copy(&mut src, file)?)
+    }
+
+    /// The modification time is read from the opened file, so an object that
+    /// is replaced in the meantime can't be copied with a stale time
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: crate::Timestamp,
+        file: &mut fs::File,
+    ) -> Result<Option<u64>> {
+        let path = self.make_path(id);
+        let mut src = fs::File::open(path)?;
+
+        let modified: crate::Timestamp = src.metadata()?.modified()?.into();
+        if modified <= since {
+            return Ok(None);
+        }
+
+        Ok(Some(std::io::copy(&mut src, file)?))
+    }
+
     async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
         let path = self.make_path(id);
-        fs::write(path, &source)?;
+
+        fs::create_dir_all(&self.path)
+            .map_err(|err| self.write_error(err, "create the directory"))?;
+
+        // Write to a temporary file in the same directory and rename it, so
+        // that an interrupted upload never leaves a truncated object behind.
+        // This also means an object is replaced rather than modified in place,
+        // so it's safe for `sync` to hard link objects into the cargo home.
+        let mut tmp = tempfile::Builder::new()
+            .prefix(TMP_PREFIX)
+            .tempfile_in(&self.path)
+            .map_err(|err| self.write_error(err, "create temporary file"))?;
+        tmp.write_all(&source)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(&path)
+            .map_err(|err| self.write_error(err.error, &format!("persist {path}")))?;
+
         Ok(source.len())
     }
 
-    async fn list(&self) -> Result<Vec<String>> {
-        let entries = fs::read_dir(&self.path)?
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
+        // The directory isn't created until the first upload
+        let dir = match fs::read_dir(&self.path) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let entries = dir
             .filter_map(|entry| {
                 let entry = entry.ok()?;
-                entry.file_type().ok().filter(|ft| ft.is_file())?;
-                entry.file_name().into_string().ok()
+                let metadata = entry.metadata().ok().filter(|md| md.is_file())?;
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .ok()
+                    .filter(|name| !name.starts_with(TMP_PREFIX))?;
+
+                Some((
+                    name,
+                    crate::ObjectStat {
+                        size: metadata.len(),
+                        updated: metadata.modified().ok().map(Into::into),
+                    },
+                ))
             })
             .collect();
 
@@ -65,4 +143,87 @@ impl crate::Backend for FsBackend {
 
         Ok(Some(modified))
     }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let path = self.make_path(id);
+
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(self.write_error(err, &format!("delete {path}"))),
+        }
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        let path = self.make_path(id);
+
+        let metadata = match fs::metadata(&path) {
+            Ok(md) => md,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(crate::ObjectStat {
+            size: metadata.len(),
+            updated: metadata.modified().ok().map(Into::into),
+        }))
+    }
+
+    fn local_path(&self, id: CloudId<'_>) -> Option<PathBuf> {
+        Some(self.make_path(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Backend as _;
+
+    #[tokio::test]
+    async fn replaces_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::util::path(dir.path()).unwrap();
+        let backend = FsBackend::new(crate::FilesystemLocation { path }).unwrap();
+
+        let krate = crate::Krate::object("object");
+        let id = krate.cloud_id(false);
+
+        backend
+            .upload(Bytes::from_static(b"first"), id)
+            .await
+            .unwrap();
+        let linked = path.join("linked");
+        fs::hard_link(backend.local_path(id).unwrap(), &linked).unwrap();
+
+        backend
+            .upload(Bytes::from_static(b"second"), id)
+            .await
+            .unwrap();
+
+        // The object is replaced rather than being written in place, so links
+        // to the previous object are unaffected
+        assert_eq!(fs::read(path.join("object")).unwrap(), b"second");
+        assert_eq!(fs::read(&linked).unwrap(), b"first");
+
+        let mut listed = backend.list().await.unwrap();
+        listed.sort();
+        assert_eq!(listed, ["linked", "object"]);
+    }
+
+    #[tokio::test]
+    async fn creates_directory_on_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::util::path(dir.path()).unwrap().join("mirror");
+        let backend = FsBackend::new(crate::FilesystemLocation { path: &path }).unwrap();
+
+        assert!(!path.exists());
+        assert!(backend.list().await.unwrap().is_empty());
+
+        let krate = crate::Krate::object("object");
+        backend
+            .upload(Bytes::from_static(b"object"), krate.cloud_id(false))
+            .await
+            .unwrap();
+        assert_eq!(fs::read(path.join("object")).unwrap(), b"object");
+    }
 }
@@ -6,7 +6,20 @@ use anyhow::{Context as _, Result};
 use tame_gcs::{objects::Object, BucketName, ObjectName};
 use tracing::debug;
 
-async fn acquire_gcs_token(cred_path: &Path) -> Result<tame_oauth::Token> {
+/// Objects larger than this are uploaded via a resumable upload session in
+/// multiple chunks, so that a failure only requires the failed chunk to be
+/// retried rather than the entire object
+const RESUMABLE_THRESHOLD: usize = 128 * 1024 * 1024;
+/// The size of each chunk, GCS requires this to be a multiple of 256KiB
+const CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+/// Acquires an oauth2 token for the scopes with the service account, this is
+/// shared with the other Google Cloud backends
+pub(crate) async fn acquire_gcp_token<S: AsRef<str>>(
+    cred_path: &Path,
+    scopes: &[S],
+    http: &util::HttpConfig,
+) -> Result<tame_oauth::Token> {
     // If we're not completing whatever task in under an hour then we
     // have more problems than the token expiring
     use tame_oauth::gcp::{self, TokenProvider};
@@ -19,13 +32,13 @@ async fn acquire_gcs_token(cred_path: &Path) -> Result<tame_oauth::Token> {
             .context("failed to deserilize service account")?;
     let svc_account_access = gcp::ServiceAccountProvider::new(svc_account_info)?;
 
-    let token = match svc_account_access.get_token(&[tame_gcs::Scopes::ReadWrite])? {
+    let token = match svc_account_access.get_token(scopes)? {
         gcp::TokenOrRequest::Request {
             request,
             scope_hash,
             ..
         } => {
-            let client = HttpClient::new();
+            let client = http.client_builder().build()?;
             let res = client.execute(request.try_into().unwrap()).await?;
 
             let mut builder = http::Response::builder()
@@ -58,6 +71,7 @@ pub struct GcsBackend {
     bucket: BucketName<'static>,
     prefix: String,
     obj: Object,
+    retry: util::RetryPolicy,
 }
 
 impl GcsBackend {
@@ -65,10 +79,12 @@ impl GcsBackend {
         loc: crate::GcsLocation<'_>,
         credentials: &Path,
         timeout: std::time::Duration,
+        retry: util::RetryPolicy,
+        http: &util::HttpConfig,
     ) -> Result<Self> {
         let bucket = BucketName::try_from(loc.bucket.to_owned())?;
 
-        let token = acquire_gcs_token(credentials).await?;
+        let token = acquire_gcp_token(credentials, &[tame_gcs::Scopes::ReadWrite], http).await?;
 
         use reqwest::header;
 
@@ -81,9 +97,9 @@ impl GcsBackend {
             hm
         };
 
-        let client = HttpClient::builder()
+        let client = http
+            .client_builder()
             .default_headers(hm)
-            .use_rustls_tls()
             .timeout(timeout)
             .build()?;
 
@@ -92,6 +108,7 @@ impl GcsBackend {
             client,
             prefix: loc.prefix.to_owned(),
             obj: Object::default(),
+            retry,
         })
     }
 
@@ -99,6 +116,69 @@ impl GcsBackend {
     fn obj_name(&self, id: CloudId<'_>) -> Result<ObjectName<'static>> {
         Ok(ObjectName::try_from(format!("{}{id}", self.prefix))?)
     }
+
+    /// Uploads the object in chunks via a resumable upload session
+    ///
+    /// See <https://cloud.google.com/storage/docs/performing-resumable-uploads>
+    async fn upload_resumable(&self, source: bytes::Bytes, name: &ObjectName<'_>) -> Result<()> {
+        use reqwest::header;
+
+        let len = source.len();
+
+        let mut init_url = url::Url::parse(&format!(
+            "https://storage.googleapis.com/upload/storage/v1/b/{}/o",
+            AsRef::<str>::as_ref(&self.bucket)
+        ))?;
+        init_url
+            .query_pairs_mut()
+            .append_pair("uploadType", "resumable")
+            .append_pair("name", name.as_ref());
+
+        let init_req = self
+            .client
+            .post(init_url)
+            .header("x-upload-content-type", "application/x-tar")
+            .header("x-upload-content-length", len)
+            .header(header::CONTENT_LENGTH, 0)
+            .build()?;
+
+        let response = send_request_with_retry(&self.client, init_req, &self.retry)
+            .await?
+            .error_for_status()
+            .context("failed to initiate resumable upload")?;
+
+        let session = response
+            .headers()
+            .get(header::LOCATION)
+            .context("resumable upload response is missing the session uri")?
+            .to_str()?
+            .to_owned();
+
+        for offset in (0..len).step_by(CHUNK_SIZE) {
+            let end = (offset + CHUNK_SIZE).min(len);
+
+            let chunk_req = self
+                .client
+                .put(&session)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {offset}-{}/{len}", end - 1),
+                )
+                .body(source.slice(offset..end))
+                .build()?;
+
+            let response = send_request_with_retry(&self.client, chunk_req, &self.retry).await?;
+
+            // A 308 means the chunk was persisted, but the upload is not yet complete
+            if response.status() != http::StatusCode::PERMANENT_REDIRECT {
+                response
+                    .error_for_status()
+                    .with_context(|| format!("failed to upload chunk at offset {offset}"))?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 use std::fmt;
@@ -119,20 +199,69 @@ impl crate::Backend for GcsBackend {
             .obj
             .download(&(&self.bucket, &self.obj_name(id)?), None)?;
 
-        let content = send_request_with_retry(&self.client, util::convert_request(dl_req))
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?;
+        let content =
+            send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
 
         Ok(content)
     }
 
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64> {
+        let dl_req = self
+            .obj
+            .download(&(&self.bucket, &self.obj_name(id)?), None)?;
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
+                .await?
+                .error_for_status()?;
+
+        util::write_response_to(response, file).await
+    }
+
+    /// Uses a conditional request, so the object is only downloaded if it was
+    /// modified, without a separate request for when it was last modified
+    /// that the object could be replaced after
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: crate::Timestamp,
+        file: &mut std::fs::File,
+    ) -> Result<Option<u64>> {
+        let mut dl_req = self
+            .obj
+            .download(&(&self.bucket, &self.obj_name(id)?), None)?;
+        dl_req.headers_mut().insert(
+            http::header::IF_MODIFIED_SINCE,
+            http::HeaderValue::from_str(&util::http_date(since)?)?,
+        );
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(dl_req), &self.retry)
+                .await?;
+
+        if util::is_not_modified(&response, since) {
+            return Ok(None);
+        }
+
+        util::write_response_to(response.error_for_status()?, file)
+            .await
+            .map(Some)
+    }
+
     async fn upload(&self, source: bytes::Bytes, id: CloudId<'_>) -> Result<usize> {
         use tame_gcs::objects::InsertObjectOptional;
 
         let content_len = source.len() as u64;
 
+        if source.len() > RESUMABLE_THRESHOLD {
+            self.upload_resumable(source, &self.obj_name(id)?).await?;
+            return Ok(content_len as usize);
+        }
+
         let insert_req = self.obj.insert_simple(
             &(&self.bucket, &self.obj_name(id)?),
             source,
@@ -143,14 +272,14 @@ impl crate::Backend for GcsBackend {
             }),
         )?;
 
-        send_request_with_retry(&self.client, insert_req.try_into()?)
+        send_request_with_retry(&self.client, insert_req.try_into()?, &self.retry)
             .await?
             .error_for_status()?;
 
         Ok(content_len as usize)
     }
 
-    async fn list(&self) -> Result<Vec<String>> {
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
         use tame_gcs::objects::{ListOptional, ListResponse};
 
         // Get a list of all crates already present in gcs, the list
@@ -173,7 +302,8 @@ impl crate::Backend for GcsBackend {
             )?;
 
             let response = util::convert_response(
-                send_request_with_retry(&self.client, util::convert_request(ls_req)).await?,
+                send_request_with_retry(&self.client, util::convert_request(ls_req), &self.retry)
+                    .await?,
             )
             .await?;
             let list_response = ListResponse::try_from(response)?;
@@ -181,7 +311,15 @@ impl crate::Backend for GcsBackend {
             let name_block: Vec<_> = list_response
                 .objects
                 .into_iter()
-                .filter_map(|obj| obj.name)
+                .filter_map(|obj| {
+                    Some((
+                        obj.name?,
+                        crate::ObjectStat {
+                            size: obj.size.unwrap_or_default(),
+                            updated: obj.updated,
+                        },
+                    ))
+                })
                 .collect();
             names.push(name_block);
 
@@ -196,7 +334,7 @@ impl crate::Backend for GcsBackend {
 
         Ok(names
             .into_iter()
-            .flat_map(|v| v.into_iter().map(|p| p[len..].to_owned()))
+            .flat_map(|v| v.into_iter().map(|(p, stat)| (p[len..].to_owned(), stat)))
             .collect())
     }
 
@@ -215,11 +353,59 @@ impl crate::Backend for GcsBackend {
         )?;
 
         let response = util::convert_response(
-            send_request_with_retry(&self.client, util::convert_request(get_req)).await?,
+            send_request_with_retry(&self.client, util::convert_request(get_req), &self.retry)
+                .await?,
         )
         .await?;
         let get_response = GetObjectResponse::try_from(response)?;
 
         Ok(get_response.metadata.updated)
     }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let del_req = self
+            .obj
+            .delete(&(&self.bucket, &self.obj_name(id)?), None)?;
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(del_req), &self.retry)
+                .await?;
+
+        if response.status() != http::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        use tame_gcs::objects::{GetObjectOptional, GetObjectResponse};
+
+        let get_req = self.obj.get(
+            &(&self.bucket, &self.obj_name(id)?),
+            Some(GetObjectOptional {
+                standard_params: tame_gcs::common::StandardQueryParameters {
+                    fields: Some("size,updated"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        )?;
+
+        let response =
+            send_request_with_retry(&self.client, util::convert_request(get_req), &self.retry)
+                .await?;
+
+        if response.status() == http::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = util::convert_response(response.error_for_status()?).await?;
+        let metadata = GetObjectResponse::try_from(response)?.metadata;
+
+        Ok(Some(crate::ObjectStat {
+            size: metadata.size.unwrap_or_default(),
+            updated: metadata.updated,
+        }))
+    }
 }
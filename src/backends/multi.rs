@@ -0,0 +1,343 @@
+//! A backend composed of a primary storage location and one or more
+//! fallbacks, in order of preference.
+//!
+//! Objects are read from the first location that has them, so a fallback,
+//! eg. a bucket in another region, keeps `sync` working if the primary is
+//! unavailable or missing an object. Objects are uploaded to the primary, or
+//! to every location if replicating.
+
+use crate::{CloudId, ObjectStat, PathBuf, Storage, Timestamp};
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, warn};
+
+pub struct MultiBackend {
+    /// The backends and the urls they were created from, the first is the
+    /// primary
+    backends: Vec<(Storage, String)>,
+    /// Upload objects to every backend rather than just the primary
+    replicate: bool,
+}
+
+impl MultiBackend {
+    pub fn new(backends: Vec<(Storage, String)>, replicate: bool) -> Result<Self> {
+        anyhow::ensure!(!backends.is_empty(), "at least one backend is required");
+        Ok(Self {
+            backends,
+            replicate,
+        })
+    }
+
+    #[inline]
+    fn primary(&self) -> &Storage {
+        &self.backends[0].0
+    }
+
+    /// Tries each backend in order until one succeeds with a value, if they
+    /// all fail the error from the primary is returned
+    async fn first<'s, T, F>(
+        &'s self,
+        op: &'static str,
+        f: impl Fn(&'s Storage) -> F,
+    ) -> Result<Option<T>>
+    where
+        F: std::future::Future<Output = Result<Option<T>>>,
+    {
+        let mut primary_err = None;
+
+        for (i, (backend, url)) in self.backends.iter().enumerate() {
+            match f(backend).await {
+                Ok(Some(value)) => return Ok(Some(value)),
+                Ok(None) => {}
+                Err(err) => {
+                    debug!("{op} failed for '{url}': {err:#}");
+                    if i == 0 {
+                        primary_err = Some(err);
+                    }
+                }
+            }
+        }
+
+        match primary_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Discards anything a failed fetch wrote to the file before it is fetched
+/// from the next backend
+fn reset(file: &mut std::fs::File) -> Result<()> {
+    use std::io::Seek as _;
+
+    file.set_len(0).context("failed to truncate file")?;
+    file.rewind().context("failed to rewind file")?;
+    Ok(())
+}
+
+impl std::fmt::Debug for MultiBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.backends.iter().map(|(backend, _)| backend))
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for MultiBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
+        let mut errors = Vec::new();
+
+        for (backend, url) in &self.backends {
+            match backend.fetch(id).await {
+                Ok(data) => {
+                    if !errors.is_empty() {
+                        debug!("fetched '{id}' from fallback '{url}'");
+                    }
+                    return Ok(data);
+                }
+                Err(err) => errors.push(format!("'{url}': {err:#}")),
+            }
+        }
+
+        anyhow::bail!(
+            "failed to fetch '{id}' from any backend: {}",
+            errors.join(", ")
+        )
+    }
+
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64> {
+        let mut errors = Vec::new();
+
+        for (backend, url) in &self.backends {
+            match backend.fetch_to(id, file).await {
+                Ok(len) => {
+                    if !errors.is_empty() {
+                        debug!("fetched '{id}' from fallback '{url}'");
+                    }
+                    return Ok(len);
+                }
+                Err(err) => {
+                    errors.push(format!("'{url}': {err:#}"));
+                    reset(file)?;
+                }
+            }
+        }
+
+        anyhow::bail!(
+            "failed to fetch '{id}' from any backend: {}",
+            errors.join(", ")
+        )
+    }
+
+    /// The first backend that has the object decides if it is newer, so that
+    /// each backend's conditional request is used rather than the default
+    /// of checking when it was updated and then fetching it
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: Timestamp,
+        file: &mut std::fs::File,
+    ) -> Result<Option<u64>> {
+        let mut errors = Vec::new();
+
+        for (backend, url) in &self.backends {
+            match backend.fetch_if_newer(id, since, file).await {
+                Ok(len) => {
+                    if !errors.is_empty() {
+                        debug!("fetched '{id}' from fallback '{url}'");
+                    }
+                    return Ok(len);
+                }
+                Err(err) => {
+                    errors.push(format!("'{url}': {err:#}"));
+                    reset(file)?;
+                }
+            }
+        }
+
+        anyhow::bail!(
+            "failed to fetch '{id}' from any backend: {}",
+            errors.join(", ")
+        )
+    }
+
+    async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
+        if !self.replicate {
+            return self.primary().upload(source, id).await;
+        }
+
+        // Every backend is attempted even if one fails, so that a single
+        // unavailable location doesn't prevent the others being populated
+        let mut len = 0;
+        let mut errors = Vec::new();
+        for (i, (backend, url)) in self.backends.iter().enumerate() {
+            match backend.upload(source.clone(), id).await {
+                Ok(size) => {
+                    if i == 0 {
+                        len = size;
+                    }
+                }
+                Err(err) => errors.push(format!("'{url}': {err:#}")),
+            }
+        }
+
+        anyhow::ensure!(
+            errors.is_empty(),
+            "failed to upload '{id}' to {} of {} backends: {}",
+            errors.len(),
+            self.backends.len(),
+            errors.join(", ")
+        );
+
+        Ok(len)
+    }
+
+    /// If replicating, only the objects present in every backend are listed,
+    /// so that `mirror` uploads objects that are missing from any of them.
+    /// Otherwise the objects present in any backend are listed, as they can
+    /// all be fetched.
+    async fn list_stats(&self) -> Result<Vec<(String, ObjectStat)>> {
+        let mut objects: HashMap<_, _> = self
+            .primary()
+            .list_stats()
+            .await
+            .with_context(|| format!("failed to list '{}'", self.backends[0].1))?
+            .into_iter()
+            .collect();
+
+        for (backend, url) in &self.backends[1..] {
+            let listed = match backend.list_stats().await {
+                Ok(listed) => listed,
+                Err(err) if !self.replicate => {
+                    warn!("failed to list fallback '{url}': {err:#}");
+                    continue;
+                }
+                Err(err) => return Err(err.context(format!("failed to list '{url}'"))),
+            };
+
+            if self.replicate {
+                let present: HashSet<_> = listed.into_iter().map(|(id, _)| id).collect();
+                objects.retain(|id, _| present.contains(id));
+            } else {
+                for (id, stat) in listed {
+                    objects.entry(id).or_insert(stat);
+                }
+            }
+        }
+
+        let mut objects: Vec<_> = objects.into_iter().collect();
+        objects.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(objects)
+    }
+
+    async fn updated(&self, id: CloudId<'_>) -> Result<Option<Timestamp>> {
+        self.first("updated", |backend| backend.updated(id)).await
+    }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let mut errors = Vec::new();
+        for (backend, url) in &self.backends {
+            if let Err(err) = backend.delete(id).await {
+                errors.push(format!("'{url}': {err:#}"));
+            }
+        }
+
+        anyhow::ensure!(
+            errors.is_empty(),
+            "failed to delete '{id}': {}",
+            errors.join(", ")
+        );
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<ObjectStat>> {
+        self.first("stat", |backend| backend.stat(id)).await
+    }
+
+    fn local_path(&self, id: CloudId<'_>) -> Option<PathBuf> {
+        self.backends
+            .iter()
+            .find_map(|(backend, _)| backend.local_path(id).filter(|path| path.exists()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{backends::fs::FsBackend, Backend as _, Krate};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn falls_back_and_replicates() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let fallback_dir = tempfile::tempdir().unwrap();
+
+        let fs = |dir: &tempfile::TempDir| -> (Storage, String) {
+            let path = crate::util::path(dir.path()).unwrap().to_owned();
+            (
+                Arc::new(FsBackend::new(crate::FilesystemLocation { path: &path }).unwrap()),
+                path.to_string(),
+            )
+        };
+
+        let (primary, _) = fs(&primary_dir);
+        let (fallback, _) = fs(&fallback_dir);
+
+        let only_fallback = Krate::object("only-fallback");
+        fallback
+            .upload(
+                Bytes::from_static(b"fallback"),
+                only_fallback.cloud_id(false),
+            )
+            .await
+            .unwrap();
+
+        let multi = MultiBackend::new(vec![fs(&primary_dir), fs(&fallback_dir)], false).unwrap();
+        assert_eq!(
+            &multi.fetch(only_fallback.cloud_id(false)).await.unwrap()[..],
+            b"fallback"
+        );
+        assert_eq!(multi.list().await.unwrap(), vec!["only-fallback"]);
+
+        // Streaming downloads also fall back
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(
+            multi
+                .fetch_to(only_fallback.cloud_id(false), file.as_file_mut())
+                .await
+                .unwrap(),
+            8
+        );
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"fallback");
+
+        // Without replication only the primary is uploaded to
+        let uploaded = Krate::object("uploaded");
+        multi
+            .upload(Bytes::from_static(b"data"), uploaded.cloud_id(false))
+            .await
+            .unwrap();
+        assert!(primary
+            .stat(uploaded.cloud_id(false))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(fallback
+            .stat(uploaded.cloud_id(false))
+            .await
+            .unwrap()
+            .is_none());
+
+        // When replicating, objects missing from any backend aren't listed so
+        // that they are uploaded again, to every backend
+        let multi = MultiBackend::new(vec![fs(&primary_dir), fs(&fallback_dir)], true).unwrap();
+        assert!(multi.list().await.unwrap().is_empty());
+
+        multi
+            .upload(Bytes::from_static(b"data"), uploaded.cloud_id(false))
+            .await
+            .unwrap();
+        assert_eq!(multi.list().await.unwrap(), vec!["uploaded"]);
+    }
+}
@@ -0,0 +1,40 @@
+use crate::{CloudId, ObjectStat, Timestamp};
+use anyhow::Result;
+use bytes::Bytes;
+
+/// The storage "location" used with `--url none`, when crates are synced
+/// directly from their upstream sources. It behaves as an empty location that
+/// can't be written to.
+#[derive(Debug)]
+pub struct NoBackend;
+
+#[async_trait::async_trait]
+impl crate::Backend for NoBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
+        Err(crate::error::coded(
+            crate::error::Code::NotFound,
+            format!("unable to fetch '{id}', there is no storage location (--url none)"),
+        )
+        .into())
+    }
+
+    async fn upload(&self, _source: Bytes, id: CloudId<'_>) -> Result<usize> {
+        anyhow::bail!("unable to upload '{id}', there is no storage location (--url none)")
+    }
+
+    async fn list_stats(&self) -> Result<Vec<(String, ObjectStat)>> {
+        Ok(Vec::new())
+    }
+
+    async fn updated(&self, _id: CloudId<'_>) -> Result<Option<Timestamp>> {
+        Ok(None)
+    }
+
+    async fn delete(&self, _id: CloudId<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn stat(&self, _id: CloudId<'_>) -> Result<Option<ObjectStat>> {
+        Ok(None)
+    }
+}
@@ -1,43 +1,64 @@
-use crate::{util::send_request_with_retry, CloudId, HttpClient};
+use crate::{
+    util::{self, send_request_with_retry},
+    CloudId, HttpClient,
+};
 use anyhow::{Context as _, Result};
 use rusty_s3::{
-    actions::{CreateBucket, GetObject, ListObjectsV2, PutObject, S3Action},
+    actions::{
+        AbortMultipartUpload, CompleteMultipartUpload, CreateBucket, CreateMultipartUpload,
+        DeleteObject, GetObject, HeadObject, ListObjectsV2, PutObject, S3Action, UploadPart,
+    },
     credentials::Ec2SecurityCredentialsMetadataResponse,
     Bucket, Credentials,
 };
 use std::time::Duration;
 
 const ONE_HOUR: Duration = Duration::from_secs(3600);
+/// Objects larger than this are uploaded in multiple parts, so that a failure
+/// only requires the failed part to be retried rather than the entire object
+const MULTIPART_THRESHOLD: usize = 128 * 1024 * 1024;
+/// The size of each part, S3 requires every part except the last to be at
+/// least 5MiB, and allows at most 10000 parts
+const PART_SIZE: usize = 32 * 1024 * 1024;
 
 pub struct S3Backend {
     prefix: String,
     bucket: Bucket,
     credential: Credentials,
     client: HttpClient,
+    retry: util::RetryPolicy,
+    checksum_sha256: bool,
+    content_sha256: Option<crate::ContentSha256>,
 }
 
-impl S3Backend {
-    pub async fn new(loc: crate::S3Location<'_>, timeout: std::time::Duration) -> Result<Self> {
-        let endpoint = format!("https://s3.{}.{}", loc.region, loc.host)
-            .parse()
-            .context("failed to parse s3 endpoint")?;
+/// Headers that are signed as part of a presigned url, and so must be sent
+/// with the request as well
+type Headers = Vec<(&'static str, String)>;
 
+impl S3Backend {
+    pub async fn new(
+        loc: crate::S3Location<'_>,
+        timeout: std::time::Duration,
+        retry: util::RetryPolicy,
+        http: &util::HttpConfig,
+    ) -> Result<Self> {
         let bucket = Bucket::new(
-            endpoint,
-            rusty_s3::UrlStyle::VirtualHost,
+            loc.endpoint,
+            if loc.path_style {
+                rusty_s3::UrlStyle::Path
+            } else {
+                rusty_s3::UrlStyle::VirtualHost
+            },
             loc.bucket.to_owned(),
-            loc.region.to_owned(),
+            loc.region,
         )
         .context("failed to new Bucket")?;
 
-        let client = HttpClient::builder()
-            .use_rustls_tls()
-            .timeout(timeout)
-            .build()?;
+        let client = http.client_builder().timeout(timeout).build()?;
         let credential = if let Some(creds) = Credentials::from_env() {
             creds
         } else {
-            ec2_credentials(&client).await.context("Either set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or run from an ec2 instance with an assumed IAM role")?
+            ec2_credentials(&client).await.context(crate::error::coded(crate::error::Code::Auth, "Either set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or run from an ec2 instance with an assumed IAM role"))?
         };
 
         Ok(Self {
@@ -45,9 +66,47 @@ impl S3Backend {
             bucket,
             credential,
             client,
+            retry,
+            checksum_sha256: loc.checksum_sha256,
+            content_sha256: loc.content_sha256,
         })
     }
 
+    /// The headers sent with an upload of the payload. The checksum isn't sent
+    /// with the parts of multipart uploads, as it would then be required
+    /// when completing the upload as well.
+    fn upload_headers(&self, payload: &[u8], checksum: bool) -> Headers {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+        let mut headers = Headers::new();
+
+        if checksum && self.checksum_sha256 {
+            let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+            headers.push(("x-amz-checksum-sha256", STANDARD.encode(digest)));
+        }
+
+        match self.content_sha256 {
+            Some(crate::ContentSha256::Unsigned) => {
+                headers.push(("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_owned()));
+            }
+            Some(crate::ContentSha256::Payload) => {
+                headers.push(("x-amz-content-sha256", util::sha256_hex(payload)));
+            }
+            None => {}
+        }
+
+        headers
+    }
+
+    /// Signs the action, including the headers
+    fn sign<'a>(&self, mut action: impl S3Action<'a>, headers: &'a Headers) -> url::Url {
+        for (name, value) in headers {
+            action.headers_mut().insert(*name, value.as_str());
+        }
+
+        action.sign(ONE_HOUR)
+    }
+
     #[inline]
     fn make_key(&self, id: CloudId<'_>) -> String {
         format!("{}{id}", self.prefix)
@@ -70,16 +129,114 @@ impl S3Backend {
         signed_url: url::Url,
         body: Option<bytes::Bytes>,
     ) -> Result<reqwest::Response> {
-        let req = if let Some(body) = body {
-            self.client.put(signed_url).body(body).build()
+        self.send_request_with_headers(signed_url, body, &Headers::new())
+            .await
+    }
+
+    async fn send_request_with_headers(
+        &self,
+        signed_url: url::Url,
+        body: Option<bytes::Bytes>,
+        headers: &Headers,
+    ) -> Result<reqwest::Response> {
+        let mut req = if let Some(body) = body {
+            self.client.put(signed_url).body(body)
         } else {
-            self.client.get(signed_url).build()
+            self.client.get(signed_url)
+        };
+        for (name, value) in headers {
+            req = req.header(*name, value);
         }
-        .unwrap();
-        Ok(send_request_with_retry(&self.client, req)
+        let req = req.build().unwrap();
+        Ok(send_request_with_retry(&self.client, req, &self.retry)
             .await?
             .error_for_status()?)
     }
+
+    /// Uploads the object in parts via the multipart upload API, aborting the
+    /// upload if any part fails so that the parts don't linger in the bucket
+    async fn upload_multipart(&self, source: bytes::Bytes, obj: &str) -> Result<()> {
+        let action = CreateMultipartUpload::new(&self.bucket, Some(&self.credential), obj);
+        let signed_url = action.sign(ONE_HOUR);
+        let req = self.client.post(signed_url).build()?;
+        let text = send_request_with_retry(&self.client, req, &self.retry)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let multipart = CreateMultipartUpload::parse_response(&text)
+            .context("failed parsing create multipart upload response")?;
+        let upload_id = multipart.upload_id();
+
+        let upload = async {
+            let mut etags = Vec::with_capacity(source.len() / PART_SIZE + 1);
+
+            for (i, offset) in (0..source.len()).step_by(PART_SIZE).enumerate() {
+                let part = source.slice(offset..(offset + PART_SIZE).min(source.len()));
+                let part_number = u16::try_from(i + 1).context("too many parts")?;
+
+                let action = UploadPart::new(
+                    &self.bucket,
+                    Some(&self.credential),
+                    obj,
+                    part_number,
+                    upload_id,
+                );
+                let headers = self.upload_headers(&part, false);
+                let signed_url = self.sign(action, &headers);
+                let res = self
+                    .send_request_with_headers(signed_url, Some(part), &headers)
+                    .await
+                    .with_context(|| format!("failed to upload part {part_number}"))?;
+
+                let etag = res
+                    .headers()
+                    .get(http::header::ETAG)
+                    .context("upload part response is missing ETag")?
+                    .to_str()?
+                    .to_owned();
+                etags.push(etag);
+            }
+
+            let action = CompleteMultipartUpload::new(
+                &self.bucket,
+                Some(&self.credential),
+                obj,
+                upload_id,
+                etags.iter().map(String::as_str),
+            );
+            let signed_url = action.sign(ONE_HOUR);
+            let req = self.client.post(signed_url).body(action.body()).build()?;
+            send_request_with_retry(&self.client, req, &self.retry)
+                .await?
+                .error_for_status()
+                .context("failed to complete multipart upload")?;
+
+            anyhow::Ok(())
+        };
+
+        let res = upload.await;
+
+        if res.is_err() {
+            let action =
+                AbortMultipartUpload::new(&self.bucket, Some(&self.credential), obj, upload_id);
+            let signed_url = action.sign(ONE_HOUR);
+
+            let abort = async {
+                let req = self.client.delete(signed_url).build()?;
+                send_request_with_retry(&self.client, req, &self.retry)
+                    .await?
+                    .error_for_status()?;
+                anyhow::Ok(())
+            };
+
+            if let Err(err) = abort.await {
+                tracing::warn!("failed to abort multipart upload '{upload_id}': {err:#}");
+            }
+        }
+
+        res
+    }
 }
 
 #[async_trait::async_trait]
@@ -95,25 +252,94 @@ impl crate::Backend for S3Backend {
         Ok(self.send_request(signed_url, None).await?.bytes().await?)
     }
 
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64> {
+        let obj = self.make_key(id);
+        let mut action = GetObject::new(&self.bucket, Some(&self.credential), &obj);
+        action
+            .query_mut()
+            .insert("response-cache-control", "no-cache, no-store");
+        let signed_url = action.sign(ONE_HOUR);
+
+        let response = self.send_request(signed_url, None).await?;
+        util::write_response_to(response, file).await
+    }
+
+    /// Uses a conditional request, so the object is only downloaded if it was
+    /// modified, rather than first requesting when it was last modified
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: crate::Timestamp,
+        file: &mut std::fs::File,
+    ) -> Result<Option<u64>> {
+        let obj = self.make_key(id);
+        let mut action = GetObject::new(&self.bucket, Some(&self.credential), &obj);
+        action
+            .query_mut()
+            .insert("response-cache-control", "no-cache, no-store");
+        let signed_url = action.sign(ONE_HOUR);
+
+        let req = self
+            .client
+            .get(signed_url)
+            .header(http::header::IF_MODIFIED_SINCE, util::http_date(since)?)
+            .build()?;
+        let response = send_request_with_retry(&self.client, req, &self.retry).await?;
+
+        if util::is_not_modified(&response, since) {
+            return Ok(None);
+        }
+
+        util::write_response_to(response.error_for_status()?, file)
+            .await
+            .map(Some)
+    }
+
     async fn upload(&self, source: bytes::Bytes, id: CloudId<'_>) -> Result<usize> {
         let len = source.len();
         let obj = self.make_key(id);
+
+        if len > MULTIPART_THRESHOLD {
+            self.upload_multipart(source, &obj).await?;
+            return Ok(len);
+        }
+
         let action = PutObject::new(&self.bucket, Some(&self.credential), &obj);
-        let signed_url = action.sign(ONE_HOUR);
-        self.send_request(signed_url, Some(source))
+        let headers = self.upload_headers(&source, true);
+        let signed_url = self.sign(action, &headers);
+        self.send_request_with_headers(signed_url, Some(source), &headers)
             .await?
             .bytes()
             .await?;
         Ok(len)
     }
 
-    async fn list(&self) -> Result<Vec<String>> {
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
         let action = ListObjectsV2::new(&self.bucket, Some(&self.credential));
         let signed_url = action.sign(ONE_HOUR);
         let text = self.send_request(signed_url, None).await?.text().await?;
         let parsed =
             ListObjectsV2::parse_response(&text).context("failed parsing list response")?;
-        Ok(parsed.contents.into_iter().map(|obj| obj.key).collect())
+        Ok(parsed
+            .contents
+            .into_iter()
+            .map(|obj| {
+                let updated = crate::Timestamp::parse(
+                    &obj.last_modified,
+                    &time::format_description::well_known::Rfc3339,
+                )
+                .ok()
+                .map(|lm| lm.replace_offset(time::UtcOffset::UTC));
+
+                (
+                    obj.key,
+                    crate::ObjectStat {
+                        size: obj.size,
+                        updated,
+                    },
+                )
+            })
+            .collect())
     }
 
     async fn updated(&self, id: CloudId<'_>) -> Result<Option<crate::Timestamp>> {
@@ -139,6 +365,51 @@ impl crate::Backend for S3Backend {
 
         Ok(Some(last_modified))
     }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        let obj = self.make_key(id);
+        let action = DeleteObject::new(&self.bucket, Some(&self.credential), &obj);
+        let signed_url = action.sign(ONE_HOUR);
+
+        // S3 returns a 204 regardless of whether the object existed or not
+        let req = self.client.delete(signed_url).build()?;
+        send_request_with_retry(&self.client, req, &self.retry)
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        let obj = self.make_key(id);
+        let action = HeadObject::new(&self.bucket, Some(&self.credential), &obj);
+        let signed_url = action.sign(ONE_HOUR);
+
+        let req = self.client.head(signed_url).build()?;
+        let response = send_request_with_retry(&self.client, req, &self.retry).await?;
+
+        if response.status() == http::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status()?;
+        let headers = response.headers();
+
+        let size = headers
+            .get(http::header::CONTENT_LENGTH)
+            .context("HEAD response is missing Content-Length")?
+            .to_str()?
+            .parse()
+            .context("failed to parse Content-Length")?;
+
+        let updated = headers
+            .get(http::header::LAST_MODIFIED)
+            .and_then(|lm| lm.to_str().ok())
+            .and_then(|lm| crate::Timestamp::parse(lm, &util::HTTP_DATE).ok())
+            .map(|lm| lm.replace_offset(time::UtcOffset::UTC));
+
+        Ok(Some(crate::ObjectStat { size, updated }))
+    }
 }
 
 use std::fmt;
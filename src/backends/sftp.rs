@@ -0,0 +1,300 @@
+//! A backend that stores objects in a directory on a remote host over SFTP,
+//! eg. a bastion host that is the only way into an air-gapped network.
+//!
+//! Rather than implementing SSH ourselves, the system `sftp` client is driven
+//! in batch mode, so that keys from the SSH agent, `~/.ssh/config`, and known
+//! hosts are used exactly as they would be interactively. Only SFTP commands
+//! are used, so this works with accounts restricted to `internal-sftp`.
+
+use crate::{CloudId, PathBuf};
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use std::io::Write as _;
+
+/// Objects are uploaded with this suffix and then renamed, so that a partial
+/// upload is never visible under the object's id
+const TMP_SUFFIX: &str = ".cf-tmp";
+
+#[derive(Clone, Debug)]
+pub struct SftpBackend {
+    /// The `[user@]host` to connect to
+    destination: String,
+    port: Option<u16>,
+    /// The directory objects are stored in
+    path: String,
+}
+
+impl SftpBackend {
+    pub async fn new(loc: crate::SftpLocation<'_>) -> Result<Self> {
+        let destination = match loc.user {
+            Some(user) => format!("{user}@{}", loc.host),
+            None => loc.host.to_owned(),
+        };
+
+        let path = match loc.path.trim_end_matches('/') {
+            "" => loc.path,
+            path => path,
+        };
+
+        let backend = Self {
+            destination,
+            port: loc.port,
+            path: path.to_owned(),
+        };
+
+        // Fail early if we can't connect, rather than on every object
+        backend
+            .batch_async(format!("@ls -ln {}", quote(&backend.path)))
+            .await
+            .with_context(|| {
+                format!(
+                    "unable to list '{}' on '{}'",
+                    backend.path, backend.destination
+                )
+            })?;
+
+        Ok(backend)
+    }
+
+    #[inline]
+    fn make_path(&self, id: CloudId<'_>) -> String {
+        format!("{}/{id}", self.path)
+    }
+
+    /// Runs the commands with `sftp` in batch mode, returning its stdout
+    fn batch(&self, commands: &str) -> Result<String> {
+        let mut cmd = std::process::Command::new("sftp");
+        cmd.args(["-q", "-b", "-", "-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            cmd.arg("-P").arg(port.to_string());
+        }
+        cmd.arg(&self.destination)
+            // Timestamps in listings are formatted in the local time zone
+            .env("TZ", "UTC")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+
+        let mut child = cmd.spawn().context("failed to spawn sftp")?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(commands.as_bytes())
+            .context("failed to write sftp commands")?;
+
+        let output = child.wait_with_output().context("failed to wait on sftp")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "sftp failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+
+        String::from_utf8(output.stdout).context("sftp output is not utf-8")
+    }
+
+    /// Runs the commands on a blocking thread, as they wait on a child process
+    async fn batch_async(&self, commands: String) -> Result<String> {
+        let backend = self.clone();
+        crate::rt::spawn_blocking(move || backend.batch(&commands)).await
+    }
+}
+
+/// Quotes a path for use in an sftp batch command
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parses a line from `ls -ln`, in the format used by OpenSSH, returning the
+/// file name and its metadata if it's a regular file.
+///
+/// Times within the last 6 months are formatted as `Mon DD HH:MM`, and older
+/// ones as `Mon DD YYYY`, so the year of recent times is inferred from `now`.
+fn parse_ls_line(line: &str, now: crate::Timestamp) -> Option<(String, crate::ObjectStat)> {
+    let mut fields = line.split_whitespace();
+
+    let perms = fields.next()?;
+    if !perms.starts_with('-') {
+        return None;
+    }
+
+    let size = fields.nth(3)?.parse().ok()?;
+
+    let month = match fields.next()? {
+        "Jan" => time::Month::January,
+        "Feb" => time::Month::February,
+        "Mar" => time::Month::March,
+        "Apr" => time::Month::April,
+        "May" => time::Month::May,
+        "Jun" => time::Month::June,
+        "Jul" => time::Month::July,
+        "Aug" => time::Month::August,
+        "Sep" => time::Month::September,
+        "Oct" => time::Month::October,
+        "Nov" => time::Month::November,
+        "Dec" => time::Month::December,
+        _ => return None,
+    };
+    let day = fields.next()?.parse().ok()?;
+    let time_or_year = fields.next()?;
+
+    // The name is printed as given, which may include the directory
+    let name = fields.collect::<Vec<_>>().join(" ");
+    let name = name.rsplit('/').next()?.to_owned();
+    if name.is_empty() || name.ends_with(TMP_SUFFIX) {
+        return None;
+    }
+
+    let updated = if let Some((hour, minute)) = time_or_year.split_once(':') {
+        let time = time::Time::from_hms(hour.parse().ok()?, minute.parse().ok()?, 0).ok()?;
+        let at = |year| {
+            time::Date::from_calendar_date(year, month, day)
+                .ok()
+                .map(|date| date.with_time(time).assume_utc())
+        };
+
+        // A time in the future is from last year
+        at(now.year())
+            .filter(|ts| *ts <= now + time::Duration::DAY)
+            .or_else(|| at(now.year() - 1))
+    } else {
+        time::Date::from_calendar_date(time_or_year.parse().ok()?, month, day)
+            .ok()
+            .map(|date| date.midnight().assume_utc())
+    };
+
+    Some((name, crate::ObjectStat { size, updated }))
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for SftpBackend {
+    async fn fetch(&self, id: CloudId<'_>) -> Result<Bytes> {
+        let temp_dir = tempfile::tempdir()?;
+        let local = crate::util::path(temp_dir.path())?.join("object");
+
+        self.batch_async(format!(
+            "@get {} {}",
+            quote(&self.make_path(id)),
+            quote(local.as_str())
+        ))
+        .await?;
+
+        Ok(std::fs::read(&local)?.into())
+    }
+
+    async fn upload(&self, source: Bytes, id: CloudId<'_>) -> Result<usize> {
+        let mut local = tempfile::NamedTempFile::new()?;
+        local.write_all(&source)?;
+        local.flush()?;
+        let local_path: PathBuf = crate::util::path(local.path())?.to_owned();
+
+        let remote = self.make_path(id);
+        let tmp = format!("{remote}{TMP_SUFFIX}");
+
+        // OpenSSH uses the posix-rename extension, which replaces the object if
+        // it already exists
+        self.batch_async(format!(
+            "@put {} {}\n@rename {} {}\n",
+            quote(local_path.as_str()),
+            quote(&tmp),
+            quote(&tmp),
+            quote(&remote)
+        ))
+        .await?;
+
+        Ok(source.len())
+    }
+
+    async fn list_stats(&self) -> Result<Vec<(String, crate::ObjectStat)>> {
+        let listing = self
+            .batch_async(format!("@ls -ln {}", quote(&self.path)))
+            .await?;
+
+        let now = crate::Timestamp::now_utc();
+        Ok(listing
+            .lines()
+            .filter_map(|line| parse_ls_line(line, now))
+            .collect())
+    }
+
+    async fn updated(&self, id: CloudId<'_>) -> Result<Option<crate::Timestamp>> {
+        Ok(self.stat(id).await?.and_then(|stat| stat.updated))
+    }
+
+    async fn delete(&self, id: CloudId<'_>) -> Result<()> {
+        // Ignore errors, as a missing object is not an error
+        self.batch_async(format!("-@rm {}", quote(&self.make_path(id))))
+            .await?;
+        Ok(())
+    }
+
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<crate::ObjectStat>> {
+        // A missing object fails the command, which is ignored, resulting in
+        // an empty listing
+        let listing = self
+            .batch_async(format!("-@ls -ln {}", quote(&self.make_path(id))))
+            .await?;
+
+        let now = crate::Timestamp::now_utc();
+        Ok(listing
+            .lines()
+            .find_map(|line| parse_ls_line(line, now))
+            .map(|(_, stat)| stat))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_ls_lines() {
+        let now = time::macros::datetime!(2024-02-10 12:00 UTC);
+
+        let (name, stat) = parse_ls_line(
+            "-rw-r--r--    1 1000     1000        12345 Feb  9 08:30 /srv/mirror/abcdef",
+            now,
+        )
+        .unwrap();
+        assert_eq!(name, "abcdef");
+        assert_eq!(stat.size, 12345);
+        assert_eq!(
+            stat.updated,
+            Some(time::macros::datetime!(2024-02-09 08:30 UTC))
+        );
+
+        // Recent times after now are from the previous year
+        let (_, stat) = parse_ls_line(
+            "-rw-r--r--    1 1000     1000            1 Dec 24 18:00 krate-checkout",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            stat.updated,
+            Some(time::macros::datetime!(2023-12-24 18:00 UTC))
+        );
+
+        let (_, stat) = parse_ls_line(
+            "-rw-r--r--    1 1000     1000            1 Mar  3  2022 old",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            stat.updated,
+            Some(time::macros::datetime!(2022-03-03 00:00 UTC))
+        );
+
+        // Directories and partial uploads are ignored
+        assert!(parse_ls_line(
+            "drwxr-xr-x    2 1000     1000         4096 Feb  9 08:30 subdir",
+            now
+        )
+        .is_none());
+        assert!(parse_ls_line(
+            "-rw-r--r--    1 1000     1000        12345 Feb  9 08:30 abcdef.cf-tmp",
+            now
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,318 @@
+//! Measures how quickly synthetic objects can be uploaded to and downloaded
+//! from a storage location, so that options such as the number of concurrent
+//! requests and the compression can be tuned for an environment without
+//! running a real mirror
+
+use crate::{util, Krate, Storage};
+use anyhow::{Context as _, Error};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// The number and size of the synthetic objects, in the form
+/// `<count>x<size>`, eg. `500x50KB`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Synthetic {
+    pub count: usize,
+    pub size: u64,
+}
+
+impl std::str::FromStr for Synthetic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, size) = s
+            .split_once('x')
+            .context("synthetic objects must be in the form <count>x<size>, eg. 500x50KB")?;
+        let count = count.parse().context("invalid object count")?;
+        anyhow::ensure!(count > 0, "object count must be at least 1");
+
+        let split = size
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len());
+        let (num, unit) = size.split_at(split);
+        let num: u64 = num.parse().context("invalid object size")?;
+        let multiplier = match unit.to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" | "kib" => 1024,
+            "mb" | "mib" => 1024 * 1024,
+            "gb" | "gib" => 1024 * 1024 * 1024,
+            unknown => anyhow::bail!("unknown size unit '{unknown}'"),
+        };
+        let size = num
+            .checked_mul(multiplier)
+            .filter(|size| *size > 0)
+            .context("object size must be at least 1 byte")?;
+
+        Ok(Self { count, size })
+    }
+}
+
+/// Latency percentiles of the requests made in a phase
+#[derive(Serialize, Debug, Default)]
+pub struct Latency {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latency {
+    fn new(mut durations: Vec<Duration>) -> Self {
+        durations.sort();
+
+        let percentile = |p: usize| {
+            if durations.is_empty() {
+                return 0.0;
+            }
+
+            // Nearest rank
+            let rank = (p * durations.len()).div_ceil(100).max(1);
+            durations[rank - 1].as_secs_f64() * 1000.0
+        };
+
+        Self {
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: percentile(100),
+        }
+    }
+}
+
+/// The results of uploading or downloading every object
+#[derive(Serialize, Debug)]
+pub struct Phase {
+    pub objects: usize,
+    /// The total size of the objects as stored, ie. after compression
+    pub bytes: u64,
+    pub duration_secs: f64,
+    /// Bytes per second, over the whole phase
+    pub throughput: f64,
+    pub latency: Latency,
+}
+
+impl Phase {
+    fn new(bytes: u64, duration: Duration, latencies: Vec<Duration>) -> Self {
+        let duration_secs = duration.as_secs_f64();
+
+        Self {
+            objects: latencies.len(),
+            bytes,
+            duration_secs,
+            throughput: if duration_secs > 0.0 {
+                bytes as f64 / duration_secs
+            } else {
+                0.0
+            },
+            latency: Latency::new(latencies),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// The total size of the objects before compression
+    pub uncompressed_bytes: u64,
+    /// The time taken to compress every object, on a single thread
+    pub compress_secs: f64,
+    pub upload: Phase,
+    pub download: Phase,
+}
+
+/// Generates the contents of a synthetic object, which compresses roughly as
+/// well as source code, so that the compression being benchmarked has
+/// something to do
+fn generate(seed: u64, size: u64) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz_ (){};:\n";
+
+    // xorshift, as the data only needs to look random, not be random
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ALPHABET[(state % ALPHABET.len() as u64) as usize]
+        })
+        .collect()
+}
+
+/// Packs the object into a tarball with the compression, the same as a git
+/// or index tarball would be
+fn pack(data: &[u8], compression: util::Compression) -> Result<bytes::Bytes, Error> {
+    let temp_dir = tempfile::tempdir()?;
+    let temp_dir_path = util::path(temp_dir.path())?;
+    std::fs::write(temp_dir_path.join("object"), data)?;
+    util::pack_tar(temp_dir_path, compression)
+}
+
+/// Uploads the synthetic objects, then downloads them again, with at most as
+/// many requests in flight as the limit allows. The objects are removed once
+/// the benchmark is done, even if it fails.
+pub async fn run(
+    backend: &Storage,
+    synthetic: Synthetic,
+    limit: &util::RequestLimit,
+    compression: util::Compression,
+) -> Result<Report, Error> {
+    // Unique to the run, so that concurrent runs against the same location
+    // don't interfere with each other
+    let run = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let krates: Vec<_> = (0..synthetic.count)
+        .map(|i| Krate::object(&format!("cargo-fetcher-bench-{run}-{i}")))
+        .collect();
+
+    let start = Instant::now();
+    let objects = crate::rt::spawn_blocking(move || {
+        (0..synthetic.count)
+            .map(|i| pack(&generate(i as u64, synthetic.size), compression))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .context("failed to generate objects")?;
+    let compress_secs = start.elapsed().as_secs_f64();
+
+    let res = transfer(backend, &krates, objects, limit).await;
+
+    // Only the objects that exist are actually removed, so it doesn't matter
+    // how far the benchmark got
+    let mut tasks = crate::rt::JoinSet::new();
+    for krate in krates {
+        let backend = backend.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+            backend.delete(krate.cloud_id(false)).await
+        });
+    }
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(Err(err)) = res {
+            tracing::warn!("failed to remove benchmark object: {err:#}");
+        }
+    }
+
+    let (upload, download) = res?;
+
+    Ok(Report {
+        uncompressed_bytes: synthetic.count as u64 * synthetic.size,
+        compress_secs,
+        upload,
+        download,
+    })
+}
+
+async fn transfer(
+    backend: &Storage,
+    krates: &[Krate],
+    objects: Vec<bytes::Bytes>,
+    limit: &util::RequestLimit,
+) -> Result<(Phase, Phase), Error> {
+    let bytes: u64 = objects.iter().map(|obj| obj.len() as u64).sum();
+
+    let start = Instant::now();
+    let mut tasks = crate::rt::JoinSet::new();
+    for (krate, object) in krates.iter().cloned().zip(objects) {
+        let backend = backend.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+            let start = Instant::now();
+            backend.upload(object, krate.cloud_id(false)).await?;
+            Ok::<_, Error>(start.elapsed())
+        });
+    }
+
+    let mut latencies = Vec::with_capacity(krates.len());
+    while let Some(res) = tasks.join_next().await {
+        latencies.push(
+            res.context("upload task panicked")?
+                .context("failed to upload object")?,
+        );
+    }
+    let upload = Phase::new(bytes, start.elapsed(), latencies);
+
+    let start = Instant::now();
+    for krate in krates {
+        let krate = krate.clone();
+        let backend = backend.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+            let start = Instant::now();
+            backend.fetch(krate.cloud_id(false)).await?;
+            Ok::<_, Error>(start.elapsed())
+        });
+    }
+
+    let mut latencies = Vec::with_capacity(krates.len());
+    while let Some(res) = tasks.join_next().await {
+        latencies.push(
+            res.context("download task panicked")?
+                .context("failed to download object")?,
+        );
+    }
+    let download = Phase::new(bytes, start.elapsed(), latencies);
+
+    Ok((upload, download))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_synthetic_objects() {
+        assert_eq!(
+            "500x50KB".parse::<Synthetic>().unwrap(),
+            Synthetic {
+                count: 500,
+                size: 50 * 1024
+            }
+        );
+        assert_eq!(
+            "3x2mib".parse::<Synthetic>().unwrap(),
+            Synthetic {
+                count: 3,
+                size: 2 * 1024 * 1024
+            }
+        );
+        assert_eq!("10x100".parse::<Synthetic>().unwrap().size, 100);
+
+        assert!("500".parse::<Synthetic>().is_err());
+        assert!("0x50KB".parse::<Synthetic>().is_err());
+        assert!("10x0KB".parse::<Synthetic>().is_err());
+        assert!("10x5PB".parse::<Synthetic>().is_err());
+    }
+
+    #[tokio::test]
+    async fn benchmarks_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = util::path(dir.path()).unwrap();
+        let backend: Storage = std::sync::Arc::new(
+            crate::backends::fs::FsBackend::new(crate::FilesystemLocation { path }).unwrap(),
+        );
+
+        let report = run(
+            &backend,
+            Synthetic {
+                count: 20,
+                size: 4096,
+            },
+            &util::RequestLimit::new(4),
+            util::Compression::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.uncompressed_bytes, 20 * 4096);
+        assert_eq!(report.upload.objects, 20);
+        assert_eq!(report.download.objects, 20);
+        assert!(report.upload.latency.p50_ms <= report.upload.latency.max_ms);
+
+        // The objects are removed afterwards
+        assert!(crate::Backend::list(&*backend).await.unwrap().is_empty());
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{util, Krate, Path, PathBuf};
 use anyhow::Context as _;
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
     sync::Arc,
 };
 use tame_index::index::IndexConfig;
@@ -17,9 +18,143 @@ use url::Url;
 /// does happen
 pub const CRATES_IO_DL: &str = "https://static.crates.io/crates/{crate}/{crate}-{version}.crate";
 
+/// The version of the pseudo krate used to identify registry index snapshots
+pub const INDEX_SNAPSHOT_VERSION: &str = "2.0.0";
+/// The revision of the pseudo krate used to identify registry index snapshots,
+/// the short form of which is part of the object id in storage.
+///
+/// Changing this changes the identity of every index snapshot, so it should
+/// only be bumped when the snapshot format changes in an incompatible way, as
+/// existing snapshots will no longer be found by `sync`, and will be replaced
+/// on the next `mirror`.
+pub const INDEX_SNAPSHOT_REV: &str = "feedc0de00000000000000000000000000000000";
+
+/// The revision of the pseudo krate used to identify tarballs of crates
+/// patched with a local path, see [`PathPatch::krate`]
+pub const PATH_PATCH_REV: &str = "ba7c4ed000000000000000000000000000000000";
+
+/// Returns true if the object id in storage is a registry index snapshot,
+/// rather than a crate or git object
+#[inline]
+pub fn is_index_snapshot(id: &str) -> bool {
+    id.strip_suffix(&INDEX_SNAPSHOT_REV[..7])
+        .is_some_and(|rest| rest.ends_with('-'))
+}
+
+/// Canonicalizes a registry index url, so that the different ways the same
+/// index can be written, eg. with or without the `sparse+` or `registry+`
+/// prefix, or a trailing slash, compare equal
+pub fn canonical_index(index: &str) -> String {
+    let index = index
+        .strip_prefix("sparse+")
+        .or_else(|| index.strip_prefix("registry+"))
+        .unwrap_or(index);
+
+    tame_index::utils::canonicalize_url(index)
+        .unwrap_or_else(|_err| index.trim_end_matches('/').to_owned())
+}
+
 #[derive(Deserialize)]
 pub struct CargoConfig {
     pub registries: Option<HashMap<String, Registry>>,
+    pub source: Option<HashMap<String, SourceConfig>>,
+}
+
+/// A `[source.<name>]` entry used for source replacement
+///
+/// See <https://doc.rust-lang.org/cargo/reference/source-replacement.html>
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct SourceConfig {
+    /// The name of the source that replaces this one
+    pub replace_with: Option<String>,
+    /// The index url of a remote registry
+    pub registry: Option<String>,
+    /// A directory of vendored crates
+    pub directory: Option<String>,
+    /// A directory containing a local registry
+    pub local_registry: Option<String>,
+}
+
+/// An authentication token for a private registry
+///
+/// The token is redacted when debug printed so that it doesn't end up in logs
+#[derive(Deserialize, PartialEq, Eq, Clone)]
+#[serde(transparent)]
+pub struct Token(String);
+
+impl Token {
+    #[inline]
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    #[inline]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(<redacted>)")
+    }
+}
+
+/// The contents of `$CARGO_HOME/credentials.toml`
+///
+/// See <https://doc.rust-lang.org/cargo/reference/config.html#credentials>
+#[derive(Deserialize, Default)]
+struct Credentials {
+    registries: Option<HashMap<String, CredentialEntry>>,
+}
+
+#[derive(Deserialize)]
+struct CredentialEntry {
+    token: Option<Token>,
+}
+
+impl Credentials {
+    /// Reads the credentials file from the cargo home, cargo checks for
+    /// `credentials` before `credentials.toml`, same as with config files
+    fn read(cargo_home: &Path) -> Self {
+        use tracing::error;
+
+        for name in ["credentials", "credentials.toml"] {
+            let path = cargo_home.join(name);
+            let Ok(contents) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+
+            return match toml::from_str(&contents) {
+                Ok(creds) => creds,
+                Err(e) => {
+                    error!("failed to deserialize cargo credentials({path}): {e}");
+                    Self::default()
+                }
+            };
+        }
+
+        Self::default()
+    }
+
+    fn token(&mut self, name: &str) -> Option<Token> {
+        self.registries
+            .as_mut()?
+            .remove(name)
+            .and_then(|entry| entry.token)
+    }
+}
+
+/// A credential provider, either as a single string with space separated
+/// arguments, or an array of arguments
+///
+/// See <https://doc.rust-lang.org/cargo/reference/registry-authentication.html>
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+enum CredentialProvider {
+    Command(String),
+    Args(Vec<String>),
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
@@ -51,8 +186,31 @@ pub struct Registry {
     config: Option<IndexConfig>,
     #[serde(skip)]
     pub protocol: RegistryProtocol,
-    #[serde(skip)]
+    /// The name of the directory used for the registry in the various cargo
+    /// home directories, computed from the index url in the same way as cargo
+    /// unless overridden via `dir-name` in the registry's configuration
+    #[serde(default, rename = "dir-name", skip_serializing)]
     dir_name: String,
+    /// The index urls of the sources this registry replaces via `[source]`
+    /// replacement, crates in the lockfile from these sources are fetched from
+    /// this registry instead
+    #[serde(skip)]
+    pub replaces: Vec<Url>,
+    /// The token sent in the `Authorization` header when downloading crates
+    /// from the registry
+    #[serde(default, skip_serializing)]
+    pub token: Option<Token>,
+    /// The credential provider configured for the registry, only the builtin
+    /// `cargo:token` provider is supported
+    #[serde(default, rename = "credential-provider", skip_serializing)]
+    credential_provider: Option<CredentialProvider>,
+    /// The download url template of the registry, which is normally only
+    /// available in the `config.json` in the root of its index
+    #[serde(default, skip_serializing)]
+    dl: Option<String>,
+    /// The api url of the registry, see `dl`
+    #[serde(default, skip_serializing)]
+    api: Option<String>,
 }
 
 impl Registry {
@@ -94,6 +252,11 @@ impl Registry {
                 RegistryProtocol::Git
             },
             dir_name,
+            replaces: Vec::new(),
+            token: None,
+            credential_provider: None,
+            dl: None,
+            api: None,
         })
     }
 
@@ -113,6 +276,59 @@ impl Registry {
         }
     }
 
+    /// The directory of the index if this is a local sparse registry, ie. its
+    /// index url is `sparse+file://`, in which case index entries are read
+    /// directly from disk rather than requested over http
+    pub fn local_index(&self) -> Option<PathBuf> {
+        let url = match self.index.scheme() {
+            "sparse+file" => Url::parse(self.index.as_str().strip_prefix("sparse+")?).ok()?,
+            "file" if self.protocol == RegistryProtocol::Sparse => self.index.clone(),
+            _ => return None,
+        };
+
+        PathBuf::from_path_buf(url.to_file_path().ok()?).ok()
+    }
+
+    /// Creates the pseudo krate used to identify snapshots of this registry's
+    /// index in storage, we don't have to worry about clashing with actual
+    /// crates since the name always contains a `.` which is not an allowed
+    /// character in crate names
+    pub fn index_krate(&self) -> Krate {
+        let ident = self.short_name().to_owned();
+
+        Krate {
+            name: ident.clone(),
+            version: INDEX_SNAPSHOT_VERSION.to_owned(),
+            source: Source::Git(GitSource {
+                url: self.index.clone(),
+                ident,
+                rev: GitRev::parse(INDEX_SNAPSHOT_REV).unwrap(),
+                follow: None,
+                rev_spec: None,
+            }),
+        }
+    }
+
+    /// Creates the pseudo krate used to identify a snapshot of this registry's
+    /// index that only contains the entries for the crates in a particular set
+    /// of lockfiles, identified by the scope, rather than the snapshot shared
+    /// by everything mirrored to the storage location. Git indices can't be
+    /// scoped, as cargo requires the whole repository, so always use the
+    /// shared snapshot.
+    pub fn scoped_index_krate(&self, scope: Option<&str>) -> Krate {
+        let mut krate = self.index_krate();
+
+        if let (Some(scope), RegistryProtocol::Sparse) = (scope, self.protocol) {
+            let ident = format!("{}-{scope}", self.short_name());
+            if let Source::Git(gs) = &mut krate.source {
+                gs.ident = ident.clone();
+            }
+            krate.name = ident;
+        }
+
+        krate
+    }
+
     #[inline]
     pub fn short_name(&self) -> &str {
         &self.dir_name
@@ -132,6 +348,13 @@ impl Registry {
         cdir
     }
 
+    #[inline]
+    pub fn quarantine_dir(&self, root: &Path) -> PathBuf {
+        let mut qdir = root.join(crate::sync::QUARANTINE_DIR);
+        qdir.push(self.short_name());
+        qdir
+    }
+
     #[inline]
     pub fn sync_dirs(&self, root: &Path) -> (PathBuf, PathBuf) {
         (self.cache_dir(root), self.src_dir(root))
@@ -144,6 +367,54 @@ impl Registry {
             RegistryProtocol::Sparse => self.index.as_str() == tame_index::CRATES_IO_HTTP_INDEX,
         }
     }
+
+    /// Whether crates from the source with the [canonical](canonical_index)
+    /// index url are retrieved from this registry, either because it is the
+    /// registry's index, or one that it replaces
+    pub fn serves_index(&self, canonical: &str) -> bool {
+        canonical_index(self.index.as_str()) == canonical
+            || self
+                .replaces
+                .iter()
+                .any(|url| canonical_index(url.as_str()) == canonical)
+    }
+
+    /// Merges another registry with the same index into this one, filling in
+    /// anything this one is missing
+    fn merge(&mut self, other: Self) {
+        // An explicit `sparse+` prefix takes precedence, as without it cargo
+        // would use the git protocol
+        if other.protocol == RegistryProtocol::Sparse && self.protocol == RegistryProtocol::Git {
+            self.index = other.index;
+            self.protocol = other.protocol;
+            self.dir_name = other.dir_name;
+        }
+
+        if self.config.is_none() {
+            self.config = other.config;
+        }
+
+        if self.token.is_none() {
+            self.token = other.token;
+        }
+
+        for url in other.replaces {
+            if !self.replaces.contains(&url) {
+                self.replaces.push(url);
+            }
+        }
+    }
+
+    /// Whether crates from crates.io are retrieved from this registry, either
+    /// because it is crates.io, or because it replaces it
+    #[inline]
+    pub fn serves_crates_io(&self) -> bool {
+        self.is_crates_io()
+            || self
+                .replaces
+                .iter()
+                .any(|url| url.as_str() == tame_index::CRATES_IO_INDEX)
+    }
 }
 
 impl PartialEq for Registry {
@@ -263,14 +534,24 @@ pub struct GitSource {
     pub rev: GitRev,
     pub ident: String,
     pub follow: Option<GitFollow>,
+    /// The revision as specified in the manifest, ie. the `?rev=` query of
+    /// the lockfile source, which may be abbreviated or even a named ref
+    pub rev_spec: Option<String>,
 }
 
+/// The length of the short revision cargo uses as the identity for checkouts,
+/// which is git's default `core.abbrev`
+const SHORT_REV_LEN: usize = 7;
+
 #[derive(Clone, Debug)]
 pub struct GitRev {
-    /// The full git revision
+    /// The full git revision, or the zero padded revision if the lockfile only
+    /// contains an abbreviated revision, see [`Self::prefix`]
     pub id: gix::ObjectId,
+    /// The number of hex characters of the revision that are actually known
+    hex_len: usize,
     /// The short revision, this is used as the identity for checkouts
-    short: [u8; 7],
+    short: String,
 }
 
 impl Eq for GitRev {}
@@ -293,21 +574,54 @@ impl PartialOrd for GitRev {
 }
 
 impl GitRev {
+    /// Parses a full revision, or an abbreviated one as found in some older
+    /// lockfiles
     pub fn parse(s: &str) -> anyhow::Result<Self> {
-        let id = gix::ObjectId::from_hex(s.as_bytes()).context("failed to parse revision")?;
-        let mut short = [0u8; 7];
-        short.copy_from_slice(&s.as_bytes()[..7]);
+        let prefix = gix::hash::Prefix::from_hex(s)
+            .with_context(|| format!("failed to parse revision '{s}'"))?;
 
-        Ok(Self { id, short })
+        Ok(Self::from_prefix(prefix))
     }
 
+    fn from_prefix(prefix: gix::hash::Prefix) -> Self {
+        let id = prefix.as_oid().to_owned();
+        let hex_len = prefix.hex_len();
+
+        Self {
+            short: id.to_hex_with_len(hex_len.min(SHORT_REV_LEN)).to_string(),
+            id,
+            hex_len,
+        }
+    }
+
+    /// The abbreviated revision, if the full revision is not known and needs
+    /// to be resolved from the repository
+    #[inline]
+    pub fn prefix(&self) -> Option<gix::hash::Prefix> {
+        (self.hex_len < self.id.kind().len_in_hex())
+            .then(|| gix::hash::Prefix::new(&self.id, self.hex_len).ok())
+            .flatten()
+    }
+
+    /// The revision with the full id as resolved from the repository
+    pub fn resolved(&self, id: gix::ObjectId) -> Self {
+        Self::from_prefix(id.into())
+    }
+
+    /// The short revision used as the identity of checkouts.
+    ///
+    /// Like cargo, this is the first 7 characters of the revision, but if an
+    /// abbreviated revision is shorter than that, this is the abbreviated
+    /// revision until it is [`Self::resolved`]
     #[inline]
     pub fn short(&self) -> &str {
-        #[allow(unsafe_code)]
-        // SAFETY: these are only hex characters that we've already validated
-        unsafe {
-            std::str::from_utf8_unchecked(&self.short)
-        }
+        &self.short
+    }
+}
+
+impl fmt::Display for GitRev {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.id.to_hex_with_len(self.hex_len).fmt(f)
     }
 }
 
@@ -319,21 +633,26 @@ pub enum Source {
 
 impl Source {
     pub fn from_git_url(url: &Url) -> anyhow::Result<Self> {
-        let rev = url.fragment().context("url doesn't contain a revision")?;
-
-        // The revision fragment in the cargo.lock will always be the full
-        // sha-1, but we only use the short-id since that is how cargo calculates
-        // the local identity of a specific git checkout
-        let rev = GitRev::parse(rev)?;
-
         // There is guaranteed to be exactly one query parameter
         let (key, value) = url
             .query_pairs()
             .next()
             .context("url doesn't contain a query parameter")?;
 
+        // The revision fragment in the cargo.lock will almost always be the
+        // full sha-1, but some older lockfiles only contain the abbreviated
+        // revision, if even that, in which case it is resolved when cloning
+        let rev = match url.fragment() {
+            Some(rev) => rev,
+            None if key == "rev" => value.as_ref(),
+            None => anyhow::bail!("url doesn't contain a revision"),
+        };
+        let rev = GitRev::parse(rev)?;
+
+        let rev_spec = (key == "rev").then(|| value.to_string());
         let follow = match key.as_ref() {
-            // A rev specifier is duplicate info so we just ignore it
+            // A rev specifier is only needed to refer to the source as cargo
+            // does, the revision itself is always taken from the fragment
             "rev" => None,
             "branch" => Some(GitFollow::Branch(value.into())),
             "tag" => Some(GitFollow::Tag(value.into())),
@@ -360,6 +679,7 @@ impl Source {
             },
             ident: dir_name,
             rev,
+            rev_spec,
             follow,
         }))
     }
@@ -371,12 +691,23 @@ impl Source {
 /// them in reverse order, as the more local ones override the ones higher
 /// up in the hierarchy
 ///
+/// Registry tokens are resolved from, in order of precedence, the provided
+/// `explicit_tokens`, `CARGO_REGISTRIES_<NAME>_TOKEN` environment variables,
+/// `$CARGO_HOME/credentials.toml`, and the `token` of the registry in the
+/// config files
+///
+/// Registry download url templates are resolved from, in order of precedence,
+/// the provided `explicit_dls`, `CARGO_FETCHER_<NAME>_DL` environment
+/// variables, and the `dl` of the registry in the config files
+///
 /// See <https://doc.rust-lang.org/cargo/reference/config.html>
 pub fn read_cargo_config(
     mut cargo_home_path: PathBuf,
     dir: PathBuf,
+    explicit_tokens: &[(String, Token)],
+    explicit_dls: &[(String, String)],
 ) -> anyhow::Result<Vec<Registry>> {
-    use tracing::{error, info};
+    use tracing::{error, info, warn};
 
     let mut configs = Vec::new();
 
@@ -422,6 +753,7 @@ pub fn read_cargo_config(
     }
 
     let mut regs = HashMap::new();
+    let mut sources = HashMap::new();
 
     for config_path in configs.iter().rev() {
         let config: CargoConfig = {
@@ -450,13 +782,43 @@ pub fn read_cargo_config(
                 }
             }
         }
+
+        if let Some(source) = config.source {
+            for (name, value) in source {
+                info!("found source '{name}' in {config_path}");
+                if sources.insert(name, value).is_some() {
+                    info!("source overriden");
+                }
+            }
+        }
+    }
+
+    // Forks of cargo may use a different scheme for naming registry directories,
+    // but the name is joined onto several cargo home paths, so it must be a
+    // single path component
+    for (name, registry) in &regs {
+        let dir_name = &registry.dir_name;
+        if dir_name.is_empty() {
+            continue;
+        }
+
+        anyhow::ensure!(
+            !dir_name.contains(['/', '\\']) && dir_name != "." && dir_name != "..",
+            "registry '{name}' has an invalid dir-name '{dir_name}'"
+        );
+        info!("registry '{name}' uses the directory name '{dir_name}'");
     }
 
     // The sparse protocol is now the default as of 1.70, so we need to take that
     // into account, as well as if the default has been overriden by config or env
     // https://doc.rust-lang.org/cargo/reference/config.html#registriescrates-ioprotocol
     if let Some(crates_io) = regs.get_mut("crates-io") {
+        let dir_name = std::mem::take(&mut crates_io.dir_name);
         *crates_io = Registry::crates_io(crates_io.protocol);
+
+        if !dir_name.is_empty() {
+            crates_io.dir_name = dir_name;
+        }
     } else {
         let protocol = if let Ok(protocol) = std::env::var("CARGO_REGISTRIES_CRATES_IO_PROTOCOL") {
             protocol
@@ -469,24 +831,95 @@ pub fn read_cargo_config(
         regs.insert("crates-io".to_owned(), Registry::crates_io(protocol));
     }
 
+    apply_source_replacements(&mut regs, &sources)?;
+
+    let mut credentials = Credentials::read(&cargo_home_path);
+
     // Unfortunately, cargo uses the config.json file located in the indexes
     // root to determine the "dl" property of the registry, and isn't a property
     // that can be set in .cargo/config, but we really don't want to have to
     // fetch the index/cache this property before we can download any crates in
     // the lockfile that are referenced from the lockfile, so instead we try and
-    // see if the user has set an environment variable of the form
-    // CARGO_FETCHER_<UPPER_NAME>_DL and use that instead, otherwise we fallback
-    // to the default that cargo uses, <index>/<crate_name>/<crate_version>/download
-    Ok(regs
+    // see if the user has specified it with --registry-dl, an environment
+    // variable of the form CARGO_FETCHER_<UPPER_NAME>_DL, or `dl` in the
+    // registry's config, and use that instead, otherwise we fallback to the
+    // default that cargo uses, <index>/<crate_name>/<crate_version>/download
+    let registries = regs
         .into_iter()
         .map(|(name, mut registry)| {
-            if registry.config.is_none() {
-                if let Ok(dl) = std::env::var(format!("CARGO_FETCHER_{}_DL", name.to_uppercase())) {
+            let explicit = explicit_dls
+                .iter()
+                .find(|(dname, _)| dname == &name)
+                .map(|(_, dl)| dl.clone());
+
+            // An explicit template also overrides the builtin one for crates.io,
+            // eg. to download crates via a redirector
+            if explicit.is_some() || registry.config.is_none() {
+                let env = std::env::var(format!("CARGO_FETCHER_{}_DL", name.to_uppercase())).ok();
+
+                if let Some(dl) = explicit.or(env).or_else(|| registry.dl.take()) {
                     info!("Found DL location for registry '{name}'");
-                    registry.config = Some(IndexConfig { dl, api: None });
+                    registry.config = Some(IndexConfig {
+                        dl,
+                        api: registry.api.take(),
+                    });
                 }
             }
 
+            // Unlike remote registries, the config.json of a local one can be
+            // read without fetching anything, so use it the same as cargo does
+            if registry.config.is_none() {
+                if let Some(index) = registry.local_index() {
+                    let config_path = index.join("config.json");
+                    match std::fs::read(&config_path)
+                        .with_context(|| format!("failed to read '{config_path}'"))
+                        .and_then(|config| {
+                            serde_json::from_slice(&config)
+                                .with_context(|| format!("failed to deserialize '{config_path}'"))
+                        }) {
+                        Ok(config) => registry.config = Some(config),
+                        Err(err) => warn!("unable to read config for registry '{name}': {err:#}"),
+                    }
+                }
+            }
+
+            // crates.io doesn't require authentication for downloads, and we
+            // really don't want to send a publish token to its CDN
+            if !registry.is_crates_io() {
+                match &registry.credential_provider {
+                    None => {}
+                    Some(CredentialProvider::Command(provider)) if provider == "cargo:token" => {}
+                    Some(provider) => {
+                        warn!("registry '{name}' uses unsupported credential provider {provider:?}, only 'cargo:token' is supported");
+                    }
+                }
+
+                let explicit = explicit_tokens
+                    .iter()
+                    .find(|(tname, _)| tname == &name)
+                    .map(|(_, token)| token.clone());
+                let env = std::env::var(format!(
+                    "CARGO_REGISTRIES_{}_TOKEN",
+                    name.to_uppercase().replace('-', "_")
+                ))
+                .ok()
+                .map(Token::new);
+
+                if let Some(token) = explicit.or(env).or_else(|| credentials.token(&name)) {
+                    info!("Found token for registry '{name}'");
+                    registry.token = Some(token);
+                }
+            }
+
+            // cargo requires sparse index urls to end with a slash, as paths
+            // are joined onto them, so add it rather than failing to download
+            if registry.index.scheme().starts_with("sparse+")
+                && !registry.index.path().ends_with('/')
+            {
+                let path = format!("{}/", registry.index.path());
+                registry.index.set_path(&path);
+            }
+
             if registry.dir_name.is_empty() {
                 let tame_index::utils::UrlDir { dir_name, .. } =
                     tame_index::utils::url_to_local_dir(registry.index.as_str()).unwrap();
@@ -502,31 +935,489 @@ pub fn read_cargo_config(
                 }
             }
 
-            registry
+            (name, registry)
         })
-        .collect())
+        .collect();
+
+    Ok(dedupe_registries(registries))
+}
+
+/// The same index can be configured under several names, or written
+/// differently in different config files, eg. with or without the `sparse+`
+/// prefix, but crates in a lockfile can only be attributed to one registry,
+/// so registries with the same canonical index are merged
+fn dedupe_registries(mut registries: Vec<(String, Registry)>) -> Vec<Registry> {
+    use tracing::info;
+
+    // Registries are read into a map, so sort to get the same result every time
+    registries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut deduped: Vec<(String, String, Registry)> = Vec::with_capacity(registries.len());
+    for (name, registry) in registries {
+        let canonical = canonical_index(registry.index.as_str());
+
+        if let Some((existing_name, _, existing)) = deduped
+            .iter_mut()
+            .find(|(_, existing, _)| *existing == canonical)
+        {
+            info!("registry '{name}' has the same index as '{existing_name}', merging them");
+            existing.merge(registry);
+        } else {
+            deduped.push((name, canonical, registry));
+        }
+    }
+
+    deduped
+        .into_iter()
+        .map(|(_, _, registry)| registry)
+        .collect()
 }
 
+/// Replaces registries with the source that replaces them, so that crates in
+/// the lockfile from a replaced source are fetched from, and synced to the
+/// directories of, the replacement
+fn apply_source_replacements(
+    regs: &mut HashMap<String, Registry>,
+    sources: &HashMap<String, SourceConfig>,
+) -> anyhow::Result<()> {
+    use tracing::{info, warn};
+
+    for (name, source) in sources {
+        let Some(replace_with) = &source.replace_with else {
+            continue;
+        };
+
+        let Some(original) = regs.remove(name) else {
+            warn!("source '{name}' is replaced, but is not a known registry");
+            continue;
+        };
+
+        // Follow the chain of replacements to the source that is actually used
+        let mut target = replace_with;
+        let mut hops = 0;
+        while let Some(next) = sources.get(target).and_then(|s| s.replace_with.as_ref()) {
+            hops += 1;
+            anyhow::ensure!(
+                hops <= sources.len(),
+                "source replacement for '{name}' is cyclic"
+            );
+            target = next;
+        }
+
+        let target_source = sources.get(target);
+
+        let mut replacement = if let Some(reg) = regs.remove(target) {
+            reg
+        } else if let Some(index) = target_source.and_then(|s| s.registry.as_ref()) {
+            Registry::new(index, None)
+                .with_context(|| format!("source '{target}' has an invalid registry url"))?
+        } else if let Some(dir) =
+            target_source.and_then(|s| s.directory.as_ref().or(s.local_registry.as_ref()))
+        {
+            warn!("source '{name}' is replaced by the local directory '{dir}', its crates will not be fetched");
+            continue;
+        } else {
+            anyhow::bail!("source '{name}' is replaced with unknown source '{target}'");
+        };
+
+        info!("source '{name}' is replaced by '{target}'");
+
+        // The lockfile always refers to crates.io by its git index url, even
+        // if the sparse protocol is being used
+        replacement.replaces.push(if original.is_crates_io() {
+            Url::parse(tame_index::CRATES_IO_INDEX).unwrap()
+        } else {
+            original.index
+        });
+        replacement.replaces.extend(original.replaces);
+
+        regs.insert(target.clone(), replacement);
+    }
+
+    Ok(())
+}
+
+/// The subset of a `Cargo.toml` manifest we care about
+#[derive(Deserialize)]
+struct Manifest {
+    /// `[patch.<source>]` tables, keyed by the source being patched
+    patch: Option<HashMap<String, HashMap<String, PatchDep>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatchDep {
+    /// A plain version requirement, which can't be a path
+    Version(#[allow(dead_code)] String),
+    Detailed {
+        path: Option<String>,
+        /// The actual name of the package if the patch is renamed
+        package: Option<String>,
+    },
+}
+
+/// A crate in a lockfile that is patched via `[patch]` with a local path
+///
+/// Patches that point to git repositories are already present in the lockfile
+/// with a git source, and are mirrored and synced like any other git source,
+/// but path patches have no source, so there is nothing for cargo-fetcher to
+/// fetch unless the path is packed into a tarball during mirror
+#[derive(Clone, Debug)]
+pub struct PathPatch {
+    pub name: String,
+    pub version: String,
+    /// The path to the crate, resolved against the directory of the manifest
+    /// that patches it
+    pub path: PathBuf,
+}
+
+impl PathPatch {
+    /// Creates the pseudo krate used to identify the patched crate in storage,
+    /// as path crates don't have a checksum. The object under this id holds
+    /// the SHA-256 of the latest tarball, see [`Self::tarball_krate`]
+    pub fn krate(&self) -> Krate {
+        self.pseudo_krate(format!("{}-{}", self.name, self.version))
+    }
+
+    /// Creates the pseudo krate used to identify a tarball of the patched
+    /// crate, keyed by its SHA-256, so that different contents with the same
+    /// name and version never overwrite each other
+    pub fn tarball_krate(&self, hash: &str) -> Krate {
+        let hash = &hash[..hash.len().min(16)];
+        self.pseudo_krate(format!("{}-{}-{hash}", self.name, self.version))
+    }
+
+    fn pseudo_krate(&self, ident: String) -> Krate {
+        Krate {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            source: Source::Git(GitSource {
+                url: Url::parse("file:///").unwrap(),
+                ident,
+                rev: GitRev::parse(PATH_PATCH_REV).unwrap(),
+                follow: None,
+                rev_spec: None,
+            }),
+        }
+    }
+}
+
+/// Reads the `[patch]` tables of the `Cargo.toml` adjacent to each lockfile,
+/// returning the crates in the lockfiles that are patched with a local path
+pub fn read_path_patches(lock_paths: &[PathBuf]) -> anyhow::Result<Vec<PathPatch>> {
+    use tracing::{debug, info};
+
+    let mut patches = Vec::new();
+
+    for lock_path in lock_paths {
+        let manifest_path = lock_path.with_file_name("Cargo.toml");
+        let Ok(contents) = std::fs::read_to_string(&manifest_path) else {
+            debug!("unable to read '{manifest_path}', skipping [patch] detection");
+            continue;
+        };
+
+        let manifest: Manifest = toml::from_str(&contents)
+            .with_context(|| format!("failed to deserialize '{manifest_path}'"))?;
+
+        let manifest_dir = manifest_path.parent().unwrap_or(Path::new(""));
+
+        let paths: HashMap<_, _> = manifest
+            .patch
+            .into_iter()
+            .flat_map(|patch| patch.into_values())
+            .flat_map(|deps| deps.into_iter())
+            .filter_map(|(name, dep)| match dep {
+                PatchDep::Detailed {
+                    path: Some(path),
+                    package,
+                } => Some((package.unwrap_or(name), manifest_dir.join(path))),
+                _ => None,
+            })
+            .collect();
+
+        if paths.is_empty() {
+            continue;
+        }
+
+        let lock: LockContents = toml::from_str(&std::fs::read_to_string(lock_path)?)?;
+
+        for pkg in lock.package {
+            if pkg.source.is_some() {
+                continue;
+            }
+
+            let Some(path) = paths.get(&pkg.name) else {
+                continue;
+            };
+
+            info!(
+                "'{}:{}' is patched with the local path '{path}'",
+                pkg.name, pkg.version
+            );
+
+            patches.push(PathPatch {
+                name: pkg.name,
+                version: pkg.version,
+                path: path.clone(),
+            });
+        }
+    }
+
+    patches.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    patches.dedup_by(|a, b| a.name == b.name && a.version == b.version);
+
+    Ok(patches)
+}
+
+/// The packages in the resolved dependency graph of a workspace, as reported
+/// by `cargo metadata`
+pub struct Metadata {
+    /// The lockfile of the workspace, which the checksums of registry crates
+    /// are read from, as `cargo metadata` doesn't include them
+    pub lock_file: PathBuf,
+    /// The name and version of every package with a non-path source
+    packages: BTreeSet<(String, String)>,
+}
+
+impl Metadata {
+    /// Whether the crate is part of the resolved dependency graph
+    #[inline]
+    pub fn contains(&self, krate: &Krate) -> bool {
+        self.packages
+            .contains(&(krate.name.clone(), krate.version.clone()))
+    }
+}
+
+/// Runs `cargo metadata --locked` for the manifest, so that cargo resolves the
+/// dependency graph rather than us parsing the lockfile directly. If any
+/// target triples are specified, crates that are only depended on for other
+/// targets are left out.
+pub fn read_metadata(manifest_path: &Path, targets: &[String]) -> anyhow::Result<Metadata> {
+    #[derive(Deserialize)]
+    struct MetadataPackage {
+        name: String,
+        version: String,
+        source: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct CargoMetadata {
+        packages: Vec<MetadataPackage>,
+        workspace_root: PathBuf,
+    }
+
+    let mut subcommand = vec!["metadata", "--locked", "--format-version", "1"];
+    subcommand.extend(
+        targets
+            .iter()
+            .flat_map(|target| ["--filter-platform", target.as_str()]),
+    );
+    let output = run_cargo(&subcommand, manifest_path)?;
+
+    let metadata: CargoMetadata =
+        serde_json::from_slice(&output).context("failed to deserialize cargo metadata")?;
+
+    Ok(Metadata {
+        lock_file: metadata.workspace_root.join("Cargo.lock"),
+        packages: metadata
+            .packages
+            .into_iter()
+            .filter(|pkg| pkg.source.is_some())
+            .map(|pkg| (pkg.name, pkg.version))
+            .collect(),
+    })
+}
+
+/// Runs a cargo subcommand for the manifest, returning its stdout
+fn run_cargo(subcommand: &[&str], manifest_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_err| "cargo".to_owned());
+    let output = std::process::Command::new(&cargo)
+        .args(subcommand)
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .stderr(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .output()
+        .with_context(|| format!("failed to spawn '{cargo}'"))?;
+
+    if !output.status.success() {
+        let error = String::from_utf8(output.stderr)
+            .unwrap_or_else(|_err| "cargo error output is non-utf8".to_owned());
+        anyhow::bail!(
+            "failed to run cargo {} for '{manifest_path}':\n{error}",
+            subcommand[0]
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// Runs `cargo generate-lockfile` for the manifest, for repositories that
+/// don't commit their lockfile, returning the path of the lockfile, which is
+/// at the root of the workspace the manifest belongs to
+pub fn generate_lock_file(manifest_path: &Path) -> anyhow::Result<PathBuf> {
+    run_cargo(&["generate-lockfile"], manifest_path)?;
+
+    let workspace_manifest = run_cargo(
+        &["locate-project", "--workspace", "--message-format", "plain"],
+        manifest_path,
+    )?;
+    let workspace_manifest =
+        String::from_utf8(workspace_manifest).context("workspace manifest path is not utf-8")?;
+
+    Ok(PathBuf::from(workspace_manifest.trim()).with_file_name("Cargo.lock"))
+}
+
+/// Directories that are never searched for lockfiles, as they contain build
+/// output or vendored sources rather than crates of the workspace
+const IGNORED_DIRS: &[&str] = &["target", "vendor", "node_modules"];
+
+/// Recursively discovers every `Cargo.lock` under the root directory, skipping
+/// hidden, build output, and vendored directories. The lockfiles are sorted by
+/// depth, so that the lockfile closest to the root is first.
+pub fn discover_lock_files(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut lock_files = Vec::new();
+
+    let walker = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            if entry.depth() == 0 || !entry.file_type().is_dir() {
+                return true;
+            }
+
+            let name = entry.file_name().to_string_lossy();
+            !name.starts_with('.')
+                && !IGNORED_DIRS.contains(&name.as_ref())
+                // Vendored crates are also marked by their checksum file
+                && !entry.path().join(".cargo-checksum.json").exists()
+        });
+
+    for entry in walker {
+        let entry = entry.with_context(|| format!("failed to search '{root}' for lockfiles"))?;
+        if entry.file_type().is_file() && entry.file_name() == "Cargo.lock" {
+            lock_files.push(util::path(entry.path())?.to_owned());
+        }
+    }
+
+    lock_files.sort_by(|a, b| {
+        (a.components().count(), a.as_str()).cmp(&(b.components().count(), b.as_str()))
+    });
+
+    Ok(lock_files)
+}
+
+fn read_lock_file(lock_path: &Path) -> anyhow::Result<Vec<Package>> {
+    let toml_contents = std::fs::read_to_string(lock_path)?;
+    let lock: LockContents = toml::from_str(&toml_contents)?;
+    Ok(lock.package)
+}
+
+/// Reads the crates from every lockfile. If a lockfile fails to parse, the
+/// rest are still parsed so that every failure is reported, but reading only
+/// succeeds if `keep_going` is set and at least one lockfile was parsed.
 pub fn read_lock_files(
     lock_paths: Vec<PathBuf>,
     registries: Vec<Registry>,
+    keep_going: bool,
 ) -> anyhow::Result<(Vec<Krate>, Vec<Arc<Registry>>)> {
-    use tracing::{error, info, trace, warn};
+    read_lock_files_with_origins(lock_paths, registries, keep_going)
+        .map(|(krates, registries, _origins)| (krates, registries))
+}
+
+/// The lockfiles each crate was read from, so that crates can still be
+/// attributed to their lockfiles once the lockfiles are merged, eg. when
+/// per-target lockfiles mostly overlap
+#[derive(Clone, Debug, Default)]
+pub struct Origins {
+    lock_files: Vec<PathBuf>,
+    krates: BTreeMap<(String, String, Source), Vec<usize>>,
+}
+
+impl Origins {
+    /// The lockfiles the crate was read from, in the order they were specified
+    pub fn lock_files<'o>(&'o self, krate: &Krate) -> impl Iterator<Item = &'o Path> + 'o {
+        self.krates
+            .get(&(
+                krate.name.clone(),
+                krate.version.clone(),
+                krate.source.clone(),
+            ))
+            .into_iter()
+            .flatten()
+            .map(|i| self.lock_files[*i].as_path())
+    }
+}
+
+/// The same as [`read_lock_files`], but also returns the lockfiles each crate
+/// was read from
+pub fn read_lock_files_with_origins(
+    lock_paths: Vec<PathBuf>,
+    registries: Vec<Registry>,
+    keep_going: bool,
+) -> anyhow::Result<(Vec<Krate>, Vec<Arc<Registry>>, Origins)> {
+    use tracing::{debug, error, info, trace, warn};
+
+    let mut origins = Origins {
+        lock_files: lock_paths.clone(),
+        krates: BTreeMap::new(),
+    };
 
     let packages = {
-        let all_packages = lock_paths
+        let all_packages: Vec<_> = lock_paths
             .into_par_iter()
-            .map(|lock_path| -> anyhow::Result<Vec<Package>> {
-                let toml_contents = std::fs::read_to_string(lock_path)?;
-                let lock: LockContents = toml::from_str(&toml_contents)?;
-                Ok(lock.package)
+            .enumerate()
+            .map(|(i, lock_path)| {
+                let res = read_lock_file(&lock_path).with_context(|| {
+                    crate::error::coded(
+                        crate::error::Code::Lockfile,
+                        format!("failed to parse lockfile '{lock_path}'"),
+                    )
+                });
+                (i, lock_path, res)
             })
-            .collect::<anyhow::Result<Vec<_>>>()?;
+            .collect();
+
+        let multiple = all_packages.len() > 1;
+        // Each package is mapped to the indices of the lockfiles it is in
+        let mut packages = BTreeMap::<Package, Vec<usize>>::new();
+        let mut failed = Vec::new();
+        let mut status = String::new();
+
+        for (i, lock_path, res) in all_packages {
+            use std::fmt::Write as _;
+
+            match res {
+                Ok(lp) => {
+                    let _ = writeln!(status, "  ok     {:>6} {lock_path}", lp.len());
+                    for pkg in lp {
+                        packages.entry(pkg).or_default().push(i);
+                    }
+                }
+                Err(err) => {
+                    let _ = writeln!(status, "  failed {:>6} {lock_path}", "-");
+                    error!("{err:#}");
+                    failed.push(err);
+                }
+            }
+        }
 
-        let mut packages = BTreeSet::<Package>::new();
+        if multiple {
+            info!("lockfiles:\n  status packages path\n{}", status.trim_end());
+        }
 
-        for lp in all_packages {
-            packages.extend(lp);
+        if let Some(err) = failed.pop() {
+            if !keep_going || packages.is_empty() {
+                return Err(if failed.is_empty() {
+                    err
+                } else {
+                    err.context(format!("{} lockfiles failed to parse", failed.len() + 1))
+                });
+            }
+
+            warn!(
+                "{} lockfiles failed to parse, continuing with the rest",
+                failed.len() + 1
+            );
         }
 
         packages
@@ -536,8 +1427,11 @@ pub fn read_lock_files(
 
     let registries: Vec<_> = registries.into_iter().map(Arc::new).collect();
     let mut regs_to_sync = vec![0u32; registries.len()];
+    let crates_io = canonical_index(tame_index::CRATES_IO_INDEX);
+    // A misconfigured registry can otherwise emit a warning for every crate
+    let repeated = util::RepeatedWarnings::default();
 
-    for pkg in packages {
+    for (pkg, lock_files) in packages {
         let Some(source) = &pkg.source else {
             trace!("skipping 'path' source {}-{}", pkg.name, pkg.version);
             continue;
@@ -547,26 +1441,32 @@ pub fn read_lock_files(
             .strip_prefix("registry+")
             .or_else(|| source.strip_prefix("sparse+"))
         {
+            let canonical = canonical_index(source);
+
             // This will most likely be an extremely short list, so we just do a
-            // linear search
+            // linear search. The lockfile always refers to crates.io by its git
+            // index url, even if the sparse protocol is being used
             let Some((ind, registry)) = registries.iter().enumerate().find(|(_, reg)| {
-                source.ends_with(tame_index::CRATES_IO_INDEX) && reg.is_crates_io()
-                    || source.ends_with(reg.index.as_str())
+                canonical == crates_io && reg.is_crates_io() || reg.serves_index(&canonical)
             }) else {
-                warn!(
-                    "skipping '{}:{}': unknown registry index '{reg_src}' encountered",
-                    pkg.name, pkg.version
-                );
+                let message = format!("skipping crates from unknown registry index '{reg_src}'");
+                if repeated.first(&message) {
+                    warn!("{message}, eg. '{}:{}'", pkg.name, pkg.version);
+                } else {
+                    debug!("skipping '{}:{}'", pkg.name, pkg.version);
+                }
                 continue;
             };
 
             regs_to_sync[ind] += 1;
 
             let Some(chksum) = pkg.checksum else {
-                warn!(
-                    "skipping '{}:{}': unable to retrieve package checksum",
-                    pkg.name, pkg.version,
-                );
+                let message = "skipping crates without a package checksum";
+                if repeated.first(message) {
+                    warn!("{message}, eg. '{}:{}'", pkg.name, pkg.version);
+                } else {
+                    debug!("skipping '{}:{}'", pkg.name, pkg.version);
+                }
                 continue;
             };
 
@@ -606,9 +1506,19 @@ pub fn read_lock_files(
             }
         };
 
+        origins.krates.insert(
+            (
+                krate.name.clone(),
+                krate.version.clone(),
+                krate.source.clone(),
+            ),
+            lock_files,
+        );
         krates.push(krate);
     }
 
+    repeated.summarize();
+
     Ok((
         krates,
         registries
@@ -623,6 +1533,7 @@ pub fn read_lock_files(
                 }
             })
             .collect(),
+        origins,
     ))
 }
 
@@ -637,6 +1548,7 @@ mod test {
         let (krates, regs) = read_lock_files(
             vec!["tests/multi_one.lock".into(), "tests/multi_two.lock".into()],
             vec![Registry::crates_io(RegistryProtocol::Sparse)],
+            false,
         )
         .unwrap();
 
@@ -697,11 +1609,540 @@ mod test {
 
         assert_eq!(krates.len(), expected.len());
 
-        for (actual, expected) in krates.into_iter().zip(expected.into_iter()) {
+        for (actual, expected) in krates.into_iter().zip(expected) {
             assert_eq!(
                 (actual.name, actual.version),
                 (expected.name, expected.version)
             );
         }
     }
+
+    /// Ensures that crates from a replaced source are attributed to the
+    /// registry that replaces it
+    #[test]
+    fn replaces_sources() {
+        let mut regs = HashMap::new();
+        regs.insert(
+            "crates-io".to_owned(),
+            Registry::crates_io(RegistryProtocol::Sparse),
+        );
+
+        let source = |replace_with: Option<&str>, registry: Option<&str>| SourceConfig {
+            replace_with: replace_with.map(String::from),
+            registry: registry.map(String::from),
+            directory: None,
+            local_registry: None,
+        };
+
+        let mut sources = HashMap::new();
+        sources.insert("crates-io".to_owned(), source(Some("intermediate"), None));
+        sources.insert("intermediate".to_owned(), source(Some("mirror"), None));
+        sources.insert(
+            "mirror".to_owned(),
+            source(None, Some("sparse+https://mirror.example.com/index/")),
+        );
+
+        apply_source_replacements(&mut regs, &sources).unwrap();
+
+        assert_eq!(regs.len(), 1);
+        let mirror = &regs["mirror"];
+        assert!(!mirror.is_crates_io());
+        assert_eq!(mirror.protocol, RegistryProtocol::Sparse);
+        assert_eq!(mirror.replaces[0].as_str(), tame_index::CRATES_IO_INDEX);
+
+        let (krates, regs) = read_lock_files(
+            vec!["tests/v3.lock".into()],
+            regs.into_values().collect(),
+            false,
+        )
+        .unwrap();
+
+        assert!(!krates.is_empty());
+        for krate in krates {
+            if let Source::Registry(rs) = &krate.source {
+                assert_eq!(rs.registry.index, regs[0].index);
+            }
+        }
+
+        // Cyclic replacements are an error rather than an infinite loop
+        let mut regs = HashMap::new();
+        regs.insert(
+            "crates-io".to_owned(),
+            Registry::crates_io(RegistryProtocol::Sparse),
+        );
+        let mut sources = HashMap::new();
+        sources.insert("crates-io".to_owned(), source(Some("a"), None));
+        sources.insert("a".to_owned(), source(Some("b"), None));
+        sources.insert("b".to_owned(), source(Some("a"), None));
+
+        assert!(apply_source_replacements(&mut regs, &sources).is_err());
+    }
+
+    /// Ensures registries whose urls are a prefix or suffix of another's are
+    /// only matched exactly
+    #[test]
+    fn matches_lookalike_registries() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = util::path(dir.path()).unwrap().join("Cargo.lock");
+
+        let package = |name: &str, source: &str| {
+            format!(
+                "[[package]]\nname = \"{name}\"\nversion = \"1.0.0\"\nsource = \"{source}\"\nchecksum = \"{}\"\n\n",
+                util::sha256_hex(name.as_bytes())
+            )
+        };
+
+        let mut lock = "version = 3\n\n".to_owned();
+        lock.push_str(&package(
+            "public",
+            "sparse+https://registry.example.com/index/",
+        ));
+        lock.push_str(&package(
+            "internal",
+            "registry+https://registry.example.com/index-internal",
+        ));
+        lock.push_str(&package(
+            "proxied",
+            "registry+https://proxy.example.com/https://registry.example.com/index-internal",
+        ));
+        std::fs::write(&lock_path, lock).unwrap();
+
+        let (krates, regs) = read_lock_files(
+            vec![lock_path],
+            vec![
+                Registry::new("sparse+https://registry.example.com/index", None).unwrap(),
+                Registry::new("https://registry.example.com/index-internal/", None).unwrap(),
+            ],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(regs.len(), 2);
+        assert_eq!(krates.len(), 2, "the proxied registry is unknown");
+
+        for krate in krates {
+            let Source::Registry(rs) = &krate.source else {
+                panic!("expected a registry source");
+            };
+
+            let expected = match krate.name.as_str() {
+                "public" => "sparse+https://registry.example.com/index",
+                "internal" => "https://registry.example.com/index-internal/",
+                other => panic!("unexpected crate '{other}'"),
+            };
+            assert_eq!(rs.registry.index.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn tracks_lockfile_origins() {
+        let (one, two) = ("tests/multi_one.lock", "tests/multi_two.lock");
+        let (krates, _, origins) = read_lock_files_with_origins(
+            vec![one.into(), two.into()],
+            vec![Registry::crates_io(RegistryProtocol::Sparse)],
+            false,
+        )
+        .unwrap();
+
+        let lock_files = |name: &str, version: &str| {
+            let krate = krates
+                .iter()
+                .find(|k| k.name == name && k.version == version)
+                .unwrap();
+            origins
+                .lock_files(krate)
+                .map(|p| p.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lock_files("autometrics-macros", "0.4.1"), [one, two]);
+        assert_eq!(lock_files("axum", "0.6.17"), [one]);
+        assert_eq!(lock_files("axum", "0.6.18"), [two]);
+    }
+
+    #[test]
+    fn keeps_going_past_bad_lockfiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let bad = util::path(dir.path()).unwrap().join("bad.lock");
+        std::fs::write(&bad, "[[package]\nname = ").unwrap();
+
+        let lock_paths = vec!["tests/multi_one.lock".into(), bad.clone()];
+        let registries = || vec![Registry::crates_io(RegistryProtocol::Sparse)];
+
+        let err = read_lock_files(lock_paths.clone(), registries(), false).unwrap_err();
+        assert!(format!("{err:#}").contains(bad.as_str()));
+
+        let (krates, _) = read_lock_files(lock_paths, registries(), true).unwrap();
+        assert!(!krates.is_empty());
+
+        // There's nothing to keep going with if every lockfile is bad
+        assert!(read_lock_files(vec![bad], registries(), true).is_err());
+    }
+
+    #[test]
+    fn discovers_lockfiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = util::path(dir.path()).unwrap();
+
+        for lock in [
+            "Cargo.lock",
+            "tools/xtask/Cargo.lock",
+            "crates/a/Cargo.lock",
+            "target/package/a-0.1.0/Cargo.lock",
+            "vendor/b/Cargo.lock",
+            "third-party/c/Cargo.lock",
+            ".git/Cargo.lock",
+        ] {
+            let path = root.join(lock);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::write(root.join("third-party/c/.cargo-checksum.json"), "{}").unwrap();
+
+        let found: Vec<_> = discover_lock_files(root)
+            .unwrap()
+            .into_iter()
+            .map(|lf| lf.strip_prefix(root).unwrap().to_string())
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                "Cargo.lock",
+                "crates/a/Cargo.lock",
+                "tools/xtask/Cargo.lock"
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_credentials() {
+        let mut creds: Credentials = toml::from_str(
+            r#"
+[registry]
+token = "crates-io-publish-token"
+
+[registries.private]
+token = "secret"
+
+[registries.no-token]
+credential-provider = "cargo:token"
+"#,
+        )
+        .unwrap();
+
+        let token = creds.token("private").unwrap();
+        assert_eq!(token.expose(), "secret");
+        assert_eq!(format!("{token:?}"), "Token(<redacted>)");
+
+        assert!(creds.token("no-token").is_none());
+        assert!(creds.token("crates-io").is_none());
+    }
+
+    #[test]
+    fn overrides_dir_name() {
+        let config: CargoConfig = toml::from_str(
+            r#"
+[registries.forked]
+index = "sparse+https://registry.example.com/index/"
+dir-name = "registry.example.com-custom"
+"#,
+        )
+        .unwrap();
+
+        let registry = &config.registries.unwrap()["forked"];
+        assert_eq!(registry.short_name(), "registry.example.com-custom");
+        assert_eq!(
+            registry.cache_dir(Path::new("/cargo")),
+            "/cargo/registry/cache/registry.example.com-custom"
+        );
+    }
+
+    /// Ensures the same index written differently in different configs is
+    /// deduplicated, and crates in the lockfile are attributed to it
+    #[test]
+    fn canonicalizes_indices() {
+        assert_eq!(
+            canonical_index("sparse+https://registry.example.com/index/"),
+            canonical_index("https://registry.example.com/index")
+        );
+        assert_eq!(
+            canonical_index("registry+https://github.com/rust-lang/crates.io-index"),
+            canonical_index("https://github.com/rust-lang/crates.io-index.git")
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let root = util::path(root.path()).unwrap();
+
+        let write_config = |dir: &Path, name: &str, index: &str| {
+            std::fs::create_dir_all(dir).unwrap();
+            std::fs::write(
+                dir.join("config.toml"),
+                format!("[registries.{name}]\nindex = \"{index}\"\n"),
+            )
+            .unwrap();
+        };
+
+        let home = root.join("home");
+        let project = root.join("project");
+        write_config(&home, "private", "https://registry.example.com/index");
+        write_config(
+            &project.join(".cargo"),
+            "renamed",
+            "sparse+https://registry.example.com/index",
+        );
+
+        let regs = read_cargo_config(home, project, &[], &[]).unwrap();
+        let private: Vec<_> = regs.iter().filter(|reg| !reg.is_crates_io()).collect();
+        assert_eq!(private.len(), 1);
+        assert_eq!(private[0].protocol, RegistryProtocol::Sparse);
+        assert_eq!(
+            private[0].index.as_str(),
+            "https://registry.example.com/index/"
+        );
+        assert!(private[0].serves_index(&canonical_index(
+            "sparse+https://registry.example.com/index/"
+        )));
+    }
+
+    #[test]
+    fn overrides_download_urls() {
+        let root = tempfile::tempdir().unwrap();
+        let root = util::path(root.path()).unwrap();
+
+        let home = root.join("home");
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(
+            home.join("config.toml"),
+            "[registries.private]\nindex = \"https://registry.example.com/index\"\ndl = \"https://dl.example.com/{crate}/{version}\"\n",
+        )
+        .unwrap();
+
+        let regs = read_cargo_config(
+            home,
+            root.to_owned(),
+            &[],
+            &[(
+                "crates-io".to_owned(),
+                "https://redirect.example.com/{crate}-{version}.crate".to_owned(),
+            )],
+        )
+        .unwrap();
+
+        let download_url = |registry: &Registry| {
+            registry.download_url(&Krate {
+                name: "foo".to_owned(),
+                version: "1.0.0".to_owned(),
+                source: Source::Registry(RegistrySource {
+                    registry: std::sync::Arc::new(registry.clone()),
+                    chksum: String::new(),
+                }),
+            })
+        };
+
+        let private = regs.iter().find(|reg| !reg.is_crates_io()).unwrap();
+        assert_eq!(download_url(private), "https://dl.example.com/foo/1.0.0");
+        let crates_io = regs.iter().find(|reg| reg.is_crates_io()).unwrap();
+        assert_eq!(
+            download_url(crates_io),
+            "https://redirect.example.com/foo-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn reads_local_registries() {
+        let root = tempfile::tempdir().unwrap();
+        let root = util::path(root.path()).unwrap();
+
+        let index = root.join("registry");
+        std::fs::create_dir_all(&index).unwrap();
+        std::fs::write(
+            index.join("config.json"),
+            format!("{{\"dl\":\"file://{root}/crates/{{crate}}-{{version}}.crate\"}}"),
+        )
+        .unwrap();
+
+        let home = root.join("home");
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(
+            home.join("config.toml"),
+            format!("[registries.local]\nindex = \"sparse+file://{index}\"\n"),
+        )
+        .unwrap();
+
+        let regs = read_cargo_config(home, root.to_owned(), &[], &[]).unwrap();
+        let local = regs.iter().find(|reg| !reg.is_crates_io()).unwrap();
+
+        assert_eq!(local.protocol, RegistryProtocol::Sparse);
+        assert_eq!(local.local_index().unwrap(), index);
+        assert_eq!(
+            local.download_url(&Krate {
+                name: "foo".to_owned(),
+                version: "1.0.0".to_owned(),
+                source: Source::Registry(RegistrySource {
+                    registry: std::sync::Arc::new(local.clone()),
+                    chksum: String::new(),
+                }),
+            }),
+            format!("file://{root}/crates/foo-1.0.0.crate")
+        );
+
+        let crates_io = regs.iter().find(|reg| reg.is_crates_io()).unwrap();
+        assert!(crates_io.local_index().is_none());
+    }
+
+    #[tokio::test]
+    async fn snapshots_local_registries() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+
+        let index = root.join("registry");
+        std::fs::create_dir_all(index.join("3/f")).unwrap();
+        std::fs::write(index.join("config.json"), r#"{"dl":"file:///crates"}"#).unwrap();
+        std::fs::write(
+            index.join("3/f/foo"),
+            format!(
+                r#"{{"name":"foo","vers":"1.0.0","deps":[],"cksum":"{}","features":{{}},"yanked":false}}"#,
+                util::sha256_hex(b"foo")
+            ),
+        )
+        .unwrap();
+        let registry = Registry::new(format!("sparse+file://{index}"), None).unwrap();
+
+        let snapshot = crate::fetch::registry(
+            &crate::HttpClient::new(),
+            &registry,
+            vec!["foo".to_owned()],
+            &util::RequestLimit::default(),
+            &util::RetryPolicy::default(),
+            &util::HttpConfig::default(),
+            crate::fetch::SnapshotOptions {
+                compression: util::Compression::None,
+                sparse_cache: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let out = root.join("out");
+        util::unpack_tar(snapshot, util::Encoding::Detect, &out).unwrap();
+
+        let entry = std::fs::read_to_string(out.join(".cache/3/f/foo")).unwrap();
+        assert!(entry.contains(r#""vers":"1.0.0""#));
+        assert!(out.join("config.json").exists());
+    }
+
+    #[test]
+    fn reads_path_patches() {
+        let td = tempfile::tempdir().unwrap();
+        let root = crate::util::path(td.path()).unwrap();
+
+        std::fs::write(
+            root.join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["app"]
+
+[patch.crates-io]
+local = { path = "../local" }
+renamed = { path = "vendor/renamed", package = "actual" }
+remote = { git = "https://github.com/EmbarkStudios/remote" }
+"#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            root.join("Cargo.lock"),
+            r#"
+version = 3
+
+[[package]]
+name = "actual"
+version = "0.2.0"
+
+[[package]]
+name = "app"
+version = "0.1.0"
+
+[[package]]
+name = "local"
+version = "1.0.0"
+
+[[package]]
+name = "remote"
+version = "0.3.0"
+source = "git+https://github.com/EmbarkStudios/remote#1b85e8ab1c8e8c44e8f7a1a0d6e6f0b0e2f4a6c8"
+"#,
+        )
+        .unwrap();
+
+        let patches = read_path_patches(&[root.join("Cargo.lock")]).unwrap();
+
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].name, "actual");
+        assert_eq!(patches[0].path, root.join("vendor/renamed"));
+        assert_eq!(patches[1].name, "local");
+        assert_eq!(patches[1].version, "1.0.0");
+        assert_eq!(patches[1].path, root.join("../local"));
+
+        assert_eq!(
+            patches[1].krate().cloud_id(false).to_string(),
+            "local-1.0.0-ba7c4ed"
+        );
+    }
+
+    #[test]
+    fn recognizes_index_snapshots() {
+        let registry = Registry::crates_io(RegistryProtocol::Sparse);
+        let id = registry.index_krate().cloud_id(false).to_string();
+
+        assert!(is_index_snapshot(&id));
+        assert!(!is_index_snapshot(
+            "a2bb4b1d6b0b2e1e3e3ce4a1f5e2b5dfb8b0e5a8b5d2f47e5cd4f8a8a8c3e7d1"
+        ));
+        assert!(!is_index_snapshot("feedc0d"));
+
+        let scoped = registry
+            .scoped_index_krate(Some("0123456789abcdef"))
+            .cloud_id(false)
+            .to_string();
+        assert_ne!(scoped, id);
+        assert!(is_index_snapshot(&scoped));
+
+        // Git indices are always shared
+        let git = Registry::crates_io(RegistryProtocol::Git);
+        assert_eq!(
+            git.scoped_index_krate(Some("0123456789abcdef"))
+                .cloud_id(false)
+                .to_string(),
+            git.index_krate().cloud_id(false).to_string()
+        );
+    }
+
+    #[test]
+    fn parses_abbreviated_revs() {
+        let full = GitRev::parse("71abe4d0a8f0b5fa1e7b8c2c6c0b3f1ad2c40e99").unwrap();
+        assert!(full.prefix().is_none());
+        assert_eq!(full.short(), "71abe4d");
+
+        let Source::Git(gs) = Source::from_git_url(
+            &Url::parse("https://github.com/EmbarkStudios/cpal?rev=71ab").unwrap(),
+        )
+        .unwrap() else {
+            panic!("expected a git source");
+        };
+
+        let prefix = gs.rev.prefix().unwrap();
+        assert_eq!(prefix.to_string(), "71ab");
+        assert_eq!(gs.rev.to_string(), "71ab");
+        assert_eq!(gs.rev.short(), "71ab");
+
+        // Once resolved, the short id is the same as cargo would use
+        let resolved = gs.rev.resolved(full.id);
+        assert!(resolved.prefix().is_none());
+        assert_eq!(resolved.short(), "71abe4d");
+
+        assert!(GitRev::parse("71a").is_err());
+        assert!(GitRev::parse("not-hex").is_err());
+    }
 }
@@ -0,0 +1,66 @@
+use anyhow::{Context as _, Error};
+use cf::{bench, util::human_size, Storage};
+use std::io::Write;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The number and size of the synthetic objects, in the form
+    /// `<count>x<size>`, where the size is in bytes or has a `KB`, `MB`, or
+    /// `GB` suffix, eg. `500x50KB`
+    #[clap(long, default_value = "100x1MB")]
+    synthetic: bench::Synthetic,
+    /// The compression applied to each object before it is uploaded, in the
+    /// same format as `mirror --compression`
+    #[clap(long, default_value = "zstd:9")]
+    compression: cf::util::Compression,
+    /// The format the results are printed in
+    #[clap(long, value_enum, default_value = "table")]
+    format: super::list::Format,
+}
+
+pub(crate) async fn cmd(
+    backend: Storage,
+    limit: cf::util::RequestLimit,
+    args: Args,
+) -> Result<(), Error> {
+    let report = bench::run(&backend, args.synthetic, &limit, args.compression)
+        .await
+        .context("failed to run benchmark")?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    match args.format {
+        super::list::Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &report)?;
+            writeln!(out)?;
+        }
+        super::list::Format::Table => {
+            writeln!(
+                out,
+                "{} objects, {} compressed to {} in {:.2}s",
+                args.synthetic.count,
+                human_size(report.uncompressed_bytes),
+                human_size(report.upload.bytes),
+                report.compress_secs,
+            )?;
+            writeln!(
+                out,
+                "\nPHASE     THROUGHPUT      P50      P90      P99      MAX"
+            )?;
+            for (name, phase) in [("upload", &report.upload), ("download", &report.download)] {
+                writeln!(
+                    out,
+                    "{name:8}  {:>8}/s  {:>5.0}ms  {:>5.0}ms  {:>5.0}ms  {:>5.0}ms",
+                    human_size(phase.throughput as u64),
+                    phase.latency.p50_ms,
+                    phase.latency.p90_ms,
+                    phase.latency.p99_ms,
+                    phase.latency.max_ms,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
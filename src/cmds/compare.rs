@@ -0,0 +1,81 @@
+use anyhow::{Context as _, Error};
+use cf::{compare, util::human_size, Storage};
+use std::io::Write;
+use url::Url;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// A url to the first storage location
+    #[clap(long = "a")]
+    pub(crate) a: Url,
+    /// A url to the second storage location
+    #[clap(long = "b")]
+    pub(crate) b: Url,
+    /// Also compares the checksums of objects that have a `.sha256` sidecar in
+    /// both locations, which requires fetching each sidecar
+    #[clap(long)]
+    checksums: bool,
+    /// The format the differences are printed in
+    #[clap(long, value_enum, default_value = "table")]
+    format: super::list::Format,
+}
+
+pub(crate) async fn cmd(a: Storage, b: Storage, args: Args) -> Result<(), Error> {
+    let comparison = compare::backends(&a, &b, args.checksums)
+        .await
+        .context("failed to compare storage locations")?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    match args.format {
+        super::list::Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &comparison)?;
+            writeln!(out)?;
+        }
+        super::list::Format::Table => {
+            let width = comparison
+                .skew
+                .iter()
+                .map(|skew| skew.difference.as_str().len())
+                .max()
+                .unwrap_or_default()
+                .max("DIFFERENCE".len());
+
+            if !comparison.skew.is_empty() {
+                writeln!(out, "{:width$}  {:>10}  {:>10}  ID", "DIFFERENCE", "A", "B")?;
+            }
+
+            let size = |size: Option<u64>| size.map_or_else(|| "-".to_owned(), human_size);
+            for skew in &comparison.skew {
+                writeln!(
+                    out,
+                    "{:width$}  {:>10}  {:>10}  {}",
+                    skew.difference.as_str(),
+                    size(skew.a_size),
+                    size(skew.b_size),
+                    skew.id,
+                )?;
+            }
+
+            writeln!(
+                out,
+                "{} objects in a, {} objects in b, {} matching, {} differences",
+                comparison.a_objects,
+                comparison.b_objects,
+                comparison.matching,
+                comparison.skew.len(),
+            )?;
+        }
+    }
+
+    // Like diff, differences are a failure so that this can be used to gate
+    // eg. the cutover in a migration
+    anyhow::ensure!(
+        comparison.skew.is_empty(),
+        "found {} differences between the storage locations",
+        comparison.skew.len()
+    );
+
+    Ok(())
+}
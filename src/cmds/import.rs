@@ -0,0 +1,63 @@
+use anyhow::{Context as _, Error};
+use cf::{import, Ctx};
+use tracing::info;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The cargo home to import crates and git repositories from, defaults to
+    /// the cargo home that `sync` would use
+    #[clap(long)]
+    cargo_home: Option<cf::PathBuf>,
+    /// The compression used for git tarballs, the same as `mirror --compression`
+    #[clap(long, env = "CARGO_FETCHER_COMPRESSION", default_value = "zstd:9")]
+    compression: cf::util::Compression,
+    /// The number of worker threads used to compress each zstd tarball, the
+    /// same as `mirror --pack-threads`
+    #[clap(long, env = "CARGO_FETCHER_PACK_THREADS", default_value = "0")]
+    pack_threads: u32,
+    /// Enables zstd long distance matching, the same as `mirror --zstd-long`
+    #[clap(
+        long,
+        env = "CARGO_FETCHER_ZSTD_LONG",
+        num_args = 0..=1,
+        default_missing_value = "27"
+    )]
+    zstd_long: Option<u32>,
+    /// A gitignore style pattern of paths to leave out of git checkout
+    /// tarballs, the same as `mirror --checkout-exclude`
+    #[clap(
+        long = "checkout-exclude",
+        env = "CARGO_FETCHER_CHECKOUT_EXCLUDE",
+        value_delimiter = ','
+    )]
+    checkout_exclusions: Vec<String>,
+    /// Uploads a `<id>.sha256` sidecar alongside each object, the same as
+    /// `mirror --checksums`
+    #[clap(long)]
+    checksums: bool,
+}
+
+pub(crate) async fn cmd(mut ctx: Ctx, args: Args) -> Result<(), Error> {
+    ctx.compression = args.compression.with_threads(args.pack_threads);
+    if let Some(window_log) = args.zstd_long {
+        ctx.compression = ctx.compression.with_long_window(window_log)?;
+    }
+    ctx.checkout_exclusions = cf::util::PathExclusions::new(&args.checkout_exclusions)?;
+    ctx.checksums = args.checksums;
+
+    let cargo_home = args.cargo_home.unwrap_or_else(|| ctx.root_dir.clone());
+
+    let summary = import::cargo_home(&ctx, &cargo_home)
+        .await
+        .with_context(|| format!("failed to import {cargo_home}"))?;
+
+    info!(
+        bytes = summary.total_bytes,
+        uploaded = summary.uploaded,
+        present = summary.present,
+        failed = summary.failed,
+        "imported {cargo_home}"
+    );
+
+    Ok(())
+}
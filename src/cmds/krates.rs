@@ -0,0 +1,76 @@
+use anyhow::Error;
+use cf::{
+    cargo::{Origins, PathPatch},
+    provenance, Krate,
+};
+use std::io::Write;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Prints the crates as JSON rather than a table
+    #[clap(long)]
+    json: bool,
+}
+
+pub(crate) fn cmd(
+    krates: &[Krate],
+    path_patches: &[PathPatch],
+    origins: &Origins,
+    args: Args,
+) -> Result<(), Error> {
+    let entries = provenance::entries(krates, path_patches, origins);
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    if args.json {
+        serde_json::to_writer_pretty(&mut out, &entries)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    let lock_files: Vec<_> = entries.iter().map(|e| e.lock_files.join(",")).collect();
+    let rows: Vec<_> = entries
+        .iter()
+        .zip(&lock_files)
+        .map(|(entry, lock_files)| {
+            [
+                entry.name.as_str(),
+                entry.version.as_str(),
+                entry.kind.as_str(),
+                entry.source.as_str(),
+                entry.key.as_str(),
+                entry.checkout_key.as_deref().unwrap_or("-"),
+                lock_files.as_str(),
+            ]
+        })
+        .collect();
+
+    let header = [
+        "NAME",
+        "VERSION",
+        "KIND",
+        "SOURCE",
+        "KEY",
+        "CHECKOUT KEY",
+        "LOCKFILES",
+    ];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, col) in widths.iter_mut().zip(row) {
+            *width = (*width).max(col.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        for (i, (col, width)) in row.iter().zip(widths).enumerate() {
+            if i + 1 == row.len() {
+                writeln!(out, "{col}")?;
+            } else {
+                write!(out, "{col:width$}  ")?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use anyhow::{Context as _, Error};
+use cf::{list, Ctx};
+use std::io::Write;
+
+#[derive(clap::ValueEnum, Copy, Clone)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The format objects are printed in
+    #[clap(long, value_enum, default_value = "table")]
+    format: Format,
+}
+
+pub(crate) async fn cmd(ctx: Ctx, args: Args) -> Result<(), Error> {
+    let entries = list::objects(&ctx)
+        .await
+        .context("failed to list objects")?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    match args.format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &entries)?;
+            writeln!(out)?;
+        }
+        Format::Table => {
+            let rows: Vec<_> = entries
+                .iter()
+                .map(|entry| {
+                    [
+                        entry.kind.as_str().to_owned(),
+                        entry.name.clone().unwrap_or_default(),
+                        entry
+                            .version
+                            .as_deref()
+                            .or(entry.rev.as_deref())
+                            .unwrap_or_default()
+                            .to_owned(),
+                        cf::util::human_size(entry.size),
+                        entry.updated.clone().unwrap_or_default(),
+                        entry.id.clone(),
+                    ]
+                })
+                .collect();
+
+            let header = ["KIND", "NAME", "VERSION", "SIZE", "UPDATED", "ID"];
+            let mut widths = header.map(str::len);
+            for row in &rows {
+                for (width, col) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(col.len());
+                }
+            }
+
+            let mut write_row = |row: &[&str]| -> std::io::Result<()> {
+                for (i, (col, width)) in row.iter().zip(widths).enumerate() {
+                    if i + 1 == row.len() {
+                        writeln!(out, "{col}")?;
+                    } else {
+                        write!(out, "{col:width$}  ")?;
+                    }
+                }
+                Ok(())
+            };
+
+            write_row(&header)?;
+            for row in &rows {
+                write_row(&row.iter().map(String::as_str).collect::<Vec<_>>())?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
-use anyhow::Error;
+use anyhow::{Context as _, Error};
 use cf::{mirror, Ctx};
+use std::time::Duration;
 use tracing::{error, info};
 
 #[derive(clap::Parser)]
@@ -7,30 +8,233 @@ pub struct Args {
     #[clap(
         short,
         default_value = "1d",
+        value_parser = cf::util::parse_duration,
         long_help = "The duration for which the index will not be replaced after its most recent update.
 
-Times may be specified with no suffix (default seconds), or one of:
+Times may be specified with no suffix (default seconds), or one or more of:
 * (s)econds
 * (m)inutes
 * (h)ours
 * (d)ays
 
+eg. 1h30m
+
 "
     )]
-    max_stale: crate::Dur,
+    max_stale: Duration,
+    /// Uploads only the files in registry index snapshots that changed since
+    /// the previous snapshot, as a delta on top of the last full snapshot,
+    /// until there are this many deltas and a full snapshot is uploaded again.
+    /// By default, full snapshots are always uploaded.
+    #[clap(long, env = "CARGO_FETCHER_INDEX_DELTAS", default_value = "0")]
+    index_deltas: usize,
+    /// Fetches each new revision of a git dependency into the db of the
+    /// previously mirrored revision of the same repository, and uploads only
+    /// the files that changed, as a delta on top of the last full db, until
+    /// there are this many deltas and a full db is uploaded again. By default,
+    /// full dbs are always uploaded.
+    #[clap(long, env = "CARGO_FETCHER_GIT_DELTAS", default_value = "0")]
+    git_deltas: usize,
+    /// If the locked revision of a git dependency that follows a branch or
+    /// tag no longer exists in the remote, eg. due to a force push, mirror the
+    /// current head of the branch or tag instead of failing
+    #[clap(long)]
+    git_follow_fallback: bool,
+    /// The maximum time that cloning a single git source, including its
+    /// submodules, may take before it is interrupted and fails, so that an
+    /// unresponsive git server can't stall the mirror. Uses the same format
+    /// as --max-stale.
+    #[clap(long, env = "CARGO_FETCHER_GIT_TIMEOUT", value_parser = cf::util::parse_duration)]
+    git_timeout: Option<Duration>,
+    /// Clone the full history and all tags of git dependencies. By default
+    /// only the locked revision is fetched, falling back to a full clone if
+    /// the remote doesn't allow fetching a single revision.
+    #[clap(long, env = "CARGO_FETCHER_FULL_GIT_HISTORY")]
+    full_git_history: bool,
+    /// The private key used to authenticate with git dependencies fetched
+    /// over ssh, eg. `git+ssh://git@github.com/org/private`, rather than the
+    /// keys offered by the ssh agent. Credential helpers from the git
+    /// configuration are always consulted for https remotes.
+    #[clap(long, env = "CARGO_FETCHER_GIT_SSH_KEY")]
+    git_ssh_key: Option<cf::PathBuf>,
+    /// The compression used for git and registry index tarballs, one of
+    /// `zstd[:<level>]` (default level 9), `gzip[:<level>]` (default level 6),
+    /// or `none`. `sync` detects the compression automatically.
+    #[clap(long, env = "CARGO_FETCHER_COMPRESSION", default_value = "zstd:9")]
+    compression: cf::util::Compression,
+    /// The number of worker threads used to compress each zstd tarball, which
+    /// speeds up packing large git checkouts. By default tarballs are
+    /// compressed on a single thread.
+    #[clap(long, env = "CARGO_FETCHER_PACK_THREADS", default_value = "0")]
+    pack_threads: u32,
+    /// Enables zstd long distance matching, which compresses large git
+    /// tarballs, eg. of monorepos, significantly better at the cost of memory.
+    /// Optionally takes the base 2 log of the window size, from 10 to 27,
+    /// defaulting to 27 (128MiB), the largest that all versions of `sync` can
+    /// decode.
+    #[clap(
+        long,
+        env = "CARGO_FETCHER_ZSTD_LONG",
+        num_args = 0..=1,
+        default_missing_value = "27"
+    )]
+    zstd_long: Option<u32>,
+    /// A gitignore style pattern of paths to leave out of git checkout
+    /// tarballs, eg. `*.png` or `testdata/`, to avoid storing large files that
+    /// aren't needed to build. The db tarball is never affected. May be
+    /// specified multiple times.
+    #[clap(
+        long = "checkout-exclude",
+        env = "CARGO_FETCHER_CHECKOUT_EXCLUDE",
+        value_delimiter = ','
+    )]
+    checkout_exclusions: Vec<String>,
+    /// A directory in which sparse registry index entries are kept between
+    /// runs. Entries that are already present are revalidated with conditional
+    /// requests rather than downloaded in full each time the index is mirrored.
+    #[clap(long, env = "CARGO_FETCHER_SPARSE_INDEX_CACHE")]
+    sparse_index_cache: Option<cf::PathBuf>,
+    /// Uploads a `<id>.sha256` sidecar alongside each object, in the format
+    /// used by `sha256sum`, so the contents of the storage location can be
+    /// verified with standard tooling
+    #[clap(long)]
+    checksums: bool,
+    /// Also mirrors newer versions of the crates in the lockfile(s), the
+    /// newest semver compatible version and the newest version overall, so
+    /// that dependency bumps find them already present. Run `mirror` on a
+    /// schedule to keep the storage location warm as new versions are published.
+    #[clap(long)]
+    prewarm: bool,
+    /// Also mirrors the dependency closure of a crate from crates.io, in the
+    /// form `<name>[@<version>]`, eg. `foo@1.2.3`, which doesn't need to be in
+    /// any lockfile. May be specified multiple times.
+    #[clap(long = "crate")]
+    pub(crate) crates: Vec<cf::resolve::CrateSpec>,
+    /// A directory in which uploads that fail are queued, and retried at the
+    /// start of the next run, for agents with intermittent connectivity
+    #[clap(long, env = "CARGO_FETCHER_OUTBOX")]
+    outbox: Option<cf::PathBuf>,
+    /// If multiple storage locations are specified with --url, uploads every
+    /// object to all of them rather than just the primary, and uploads objects
+    /// that are missing from any of them
+    #[clap(long)]
+    pub(crate) replicate: bool,
+    /// Records the hash of each lockfile in the storage location after the
+    /// crates are mirrored, so that `sync` can warn if the lockfile it is
+    /// syncing doesn't match
+    #[clap(long, env = "CARGO_FETCHER_RECORD_LOCKFILES")]
+    record_lockfiles: bool,
+    /// The name lockfiles are recorded under with `--record-lockfiles`, eg.
+    /// the name of the repository, so that repositories sharing a storage
+    /// location don't replace each other's lockfiles
+    #[clap(
+        long,
+        requires = "record_lockfiles",
+        env = "CARGO_FETCHER_LOCKFILE_NAME"
+    )]
+    lockfile_name: Option<String>,
+    /// Verifies that the registry crates already in the storage location match
+    /// the checksum in the lockfile, and mirrors the ones that don't again, eg.
+    /// crates that were yanked and then republished with different contents.
+    /// The `.sha256` sidecar is used if present, otherwise the crate is
+    /// downloaded and hashed.
+    #[clap(long, env = "CARGO_FETCHER_REFRESH_MISMATCHED")]
+    refresh_mismatched: bool,
+    /// A command run with the shell once the mirror completes, with the
+    /// summary of the mirror, in the same format as `--output-summary`, on
+    /// stdin. May be specified multiple times, in which case the hooks are run
+    /// in order, and the mirror fails if any of them fail.
+    #[clap(long = "post-mirror-hook")]
+    pub(crate) post_mirror_hooks: Vec<String>,
 }
 
-pub(crate) async fn cmd(ctx: Ctx, include_index: bool, args: Args) -> Result<(), Error> {
+pub(crate) async fn cmd(mut ctx: Ctx, include_index: bool, args: Args) -> Result<(), Error> {
+    ctx.git_follow_fallback = args.git_follow_fallback;
+    ctx.git_timeout = args.git_timeout;
+    ctx.git_full_history = args.full_git_history;
+    ctx.git_ssh_key = args.git_ssh_key;
+    ctx.git_deltas = args.git_deltas;
+    ctx.refresh_mismatched = args.refresh_mismatched;
+    ctx.compression = args.compression.with_threads(args.pack_threads);
+    if let Some(window_log) = args.zstd_long {
+        ctx.compression = ctx.compression.with_long_window(window_log)?;
+    }
+    ctx.checkout_exclusions = cf::util::PathExclusions::new(&args.checkout_exclusions)?;
+    ctx.sparse_index_cache = args.sparse_index_cache;
+    ctx.checksums = args.checksums;
+
+    if let Some(outbox) = args.outbox {
+        let outbox = cf::outbox::Outbox::new(outbox)?;
+
+        let drained = outbox
+            .drain(&ctx.backend)
+            .await
+            .context("failed to drain outbox")?;
+        if drained.uploaded > 0 || drained.failed > 0 {
+            info!(
+                uploaded = drained.uploaded,
+                failed = drained.failed,
+                "drained outbox"
+            );
+        }
+
+        ctx.outbox = Some(outbox);
+    }
+    if !args.crates.is_empty() {
+        let registry = ctx
+            .registries
+            .iter()
+            .find(|reg| reg.serves_crates_io())
+            .cloned()
+            .context("unable to find the registry for crates.io")?;
+
+        let closure = cf::resolve::closure(&ctx, &registry, &args.crates)
+            .await
+            .context("failed to resolve dependency closure")?;
+        info!(
+            "resolved {} crates in the dependency closure of {}",
+            closure.len(),
+            args.crates
+                .iter()
+                .map(|spec| spec.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        ctx.krates.extend(closure);
+    }
+
     let regs = ctx.registry_sets();
 
+    // The index snapshot only needs the names of the crates, which are the
+    // same for the pre-warmed versions, so they are only added to the crates
+    // that are mirrored
+    if args.prewarm {
+        match mirror::prewarm(&ctx).await {
+            Ok(prewarm) => {
+                info!("pre-warming {} newer crate versions", prewarm.len());
+                ctx.krates.extend(prewarm);
+            }
+            Err(e) => error!("failed to find newer crate versions: {:#}", e),
+        }
+    }
+
     async_scoped::TokioScope::scope_and_block(|s| {
         if include_index {
             s.spawn(async {
-                mirror::registry_indices(&ctx, args.max_stale.0, regs).await;
+                mirror::registry_indices(&ctx, args.max_stale, args.index_deltas, regs).await;
                 info!("finished uploading registry indices");
             });
         }
 
+        if !ctx.path_patches.is_empty() {
+            s.spawn(async {
+                match mirror::path_patches(&ctx).await {
+                    Ok(_) => info!("finished uploading path patches"),
+                    Err(e) => error!("failed to mirror path patches: {:#}", e),
+                }
+            });
+        }
+
         s.spawn(async {
             match mirror::crates(&ctx).await {
                 Ok(_) => info!("finished uploading crates"),
@@ -39,5 +243,21 @@ pub(crate) async fn cmd(ctx: Ctx, include_index: bool, args: Args) -> Result<(),
         });
     });
 
+    // Only recorded once the crates are present, so that syncing a lockfile
+    // that matches never finds any missing
+    if args.record_lockfiles {
+        cf::drift::record(&ctx.backend, &ctx.lock_files, args.lockfile_name.as_deref())
+            .await
+            .context("failed to record lockfiles")?;
+    }
+
+    if !args.post_mirror_hooks.is_empty() {
+        let report = ctx
+            .report
+            .as_deref()
+            .context("a summary is required to run hooks")?;
+        cf::hook::run("post-mirror", &args.post_mirror_hooks, &report.render()?)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,33 @@
+use anyhow::Error;
+use cf::{provenance, Ctx};
+use std::io::Write;
+
+#[derive(clap::ValueEnum, Copy, Clone)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The format the report is printed in
+    #[clap(long, value_enum, default_value = "csv")]
+    format: Format,
+}
+
+pub(crate) async fn cmd(ctx: Ctx, args: Args) -> Result<(), Error> {
+    let entries = provenance::crates(&ctx);
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    match args.format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &entries)?;
+            writeln!(out)?;
+        }
+        Format::Csv => provenance::write_csv(&entries, &mut out)?,
+    }
+
+    Ok(())
+}
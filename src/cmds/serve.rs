@@ -0,0 +1,31 @@
+use anyhow::Error;
+use cf::{serve, Ctx};
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The address to listen on
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    addr: std::net::SocketAddr,
+    /// The url clients reach the server at, used for the crate download urls.
+    /// By default it is inferred from the `Host` header of each request
+    #[clap(long)]
+    public_url: Option<url::Url>,
+    /// Reloads the index snapshots at this interval, so that crates mirrored
+    /// after the server started are served. Uses the same format as --timeout.
+    #[clap(long, value_parser = cf::util::parse_duration)]
+    refresh: Option<std::time::Duration>,
+}
+
+pub(crate) async fn cmd(ctx: Ctx, args: Args) -> Result<(), Error> {
+    let url = args
+        .public_url
+        .as_ref()
+        .map_or_else(|| format!("http://{}", args.addr), |url| url.to_string());
+
+    println!(
+        "To use the mirror, add this to your .cargo/config.toml:\n\n{}",
+        serve::source_config(&ctx.registries, &url)
+    );
+
+    serve::registries(ctx, args.addr, args.public_url, args.refresh).await
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context as _, Error};
+use cf::{stats, util::human_size, Ctx};
+use std::io::Write;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The format the report is printed in
+    #[clap(long, value_enum, default_value = "table")]
+    format: super::list::Format,
+}
+
+pub(crate) async fn cmd(ctx: Ctx, args: Args) -> Result<(), Error> {
+    let stats = stats::coverage(&ctx)
+        .await
+        .context("failed to gather stats")?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    match args.format {
+        super::list::Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &stats)?;
+            writeln!(out)?;
+        }
+        super::list::Format::Table => {
+            let width = stats
+                .sources
+                .keys()
+                .map(String::len)
+                .max()
+                .unwrap_or_default()
+                .max("total".len());
+
+            writeln!(out, "{:width$}  MIRRORED        SIZE", "SOURCE")?;
+            for (source, ss) in stats
+                .sources
+                .iter()
+                .chain(std::iter::once((&"total".to_owned(), &stats.total)))
+            {
+                writeln!(
+                    out,
+                    "{source:width$}  {:>8}  {:>10}",
+                    format!("{}/{}", ss.mirrored, ss.crates),
+                    human_size(ss.bytes),
+                )?;
+            }
+
+            if !stats.missing.is_empty() {
+                writeln!(out, "\nmissing:")?;
+                for missing in &stats.missing {
+                    writeln!(
+                        out,
+                        "  {}-{} ({})",
+                        missing.name, missing.version, missing.source
+                    )?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
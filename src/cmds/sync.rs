@@ -1,28 +1,336 @@
-use anyhow::Error;
+use anyhow::{Context as _, Error};
 use cf::{sync, Ctx};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 #[derive(clap::Parser)]
-pub struct Args {}
+pub struct Args {
+    /// Reports the number of crates downloaded and unpacked, and the estimated
+    /// time remaining. Progress is drawn on a single line if stderr is a
+    /// terminal, otherwise it is logged periodically.
+    #[clap(long)]
+    progress: bool,
+    /// Exits with a non-zero code if any crate fails to sync, the same as
+    /// `--max-failures 0`
+    #[clap(long, conflicts_with = "max_failures")]
+    strict: bool,
+    /// Exits with a non-zero code if more than this many crates fail to sync,
+    /// by default failures are logged but the sync still succeeds
+    #[clap(long)]
+    max_failures: Option<u32>,
+    /// Doesn't write the `.cache` entries for the crates from sparse
+    /// registries when the index isn't synced, eg. if the registries can't be
+    /// reached from where the sync is run
+    #[clap(long)]
+    skip_index_entries: bool,
+    /// Writes the `.cache` entries for the crates from sparse registries from
+    /// the index snapshot, if it is seekable, and only requests the entries it
+    /// doesn't contain from the registries. The whole snapshot is downloaded,
+    /// but only the parts that contain the entries are decompressed.
+    #[clap(long, env = "CARGO_FETCHER_INDEX_ENTRIES_FROM_SNAPSHOT")]
+    index_entries_from_snapshot: bool,
+    /// Verifies git tarballs, path patches, and index snapshots against the
+    /// `.sha256` sidecar uploaded by `mirror --checksums` before unpacking
+    /// them, failing if the sidecar is missing. Registry crates are always
+    /// verified against the checksum in the lockfile. Not applied with `--image`.
+    #[clap(long, env = "CARGO_FETCHER_VERIFY_CHECKSUMS")]
+    verify_checksums: bool,
+    /// Git dbs are always checked to contain the locked revision once they
+    /// are unpacked, and downloaded again if they don't. This also checks
+    /// that every tree and blob of the revision is present, which is slower
+    /// for large repositories.
+    #[clap(long, env = "CARGO_FETCHER_CHECK_GIT_CONNECTIVITY")]
+    check_git_connectivity: bool,
+    /// Gives files in git checkouts the time of their commit as their mtime,
+    /// so that checkouts of the same revision are identical on every machine.
+    /// By default they are given the time they were unpacked, the same as
+    /// cargo's own checkouts. Files in registry crates always keep the mtime
+    /// from the crate tarball, as with cargo.
+    #[clap(long, env = "CARGO_FETCHER_PRESERVE_MTIME")]
+    preserve_mtime: bool,
+    /// Only syncs a deterministic subset of the crates, in the form
+    /// `<index>/<count>`, eg. `2/8`, so that syncing can be split between
+    /// several machines that share the same root directory. Registry indices
+    /// and path patches are only synced by the first shard.
+    #[clap(long, env = "CARGO_FETCHER_SHARD")]
+    shard: Option<sync::Shard>,
+    /// Also writes the cache key, a digest of the synced crates and the
+    /// layout they are written in that is printed once the sync succeeds, to
+    /// this file, so that external caching layers can key off of it. If any
+    /// crate failed to sync, eg. within `--max-failures`, the key is neither
+    /// printed nor written, and an existing file is removed.
+    #[clap(long, env = "CARGO_FETCHER_CACHE_KEY_FILE")]
+    cache_key_file: Option<cf::PathBuf>,
+    /// A command run with the shell once the sync succeeds, with the summary
+    /// of the sync, in the same format as `--output-summary`, on stdin, eg. to
+    /// fix up ownership of the synced files. May be specified multiple times,
+    /// in which case the hooks are run in order, and the sync fails if any of
+    /// them fail.
+    #[clap(long = "post-sync-hook")]
+    pub(crate) post_sync_hooks: Vec<String>,
+    /// The cargo home to sync into, rather than the one cargo would use for the
+    /// lockfile, eg. `/usr/local/cargo` when building a container image whose
+    /// builds run with a different `CARGO_HOME` than the sync does. Registry
+    /// configuration and credentials are also read from it.
+    #[clap(long, env = "CARGO_FETCHER_CARGO_HOME")]
+    pub(crate) cargo_home: Option<cf::PathBuf>,
+    /// Writes a shell snippet exporting `CARGO_HOME` as the cargo home that
+    /// was synced to this file once the sync succeeds, so that later build
+    /// steps can source it rather than relying on the environment matching
+    #[clap(long, env = "CARGO_FETCHER_CARGO_HOME_ENV")]
+    cargo_home_env: Option<cf::PathBuf>,
+    /// The maximum time to wait for cargo's package cache lock in the cargo
+    /// home, if it is held by another sync or by cargo. By default the sync
+    /// waits until the lock is released. Uses the same format as --timeout.
+    #[clap(long, env = "CARGO_FETCHER_LOCK_WAIT", value_parser = cf::util::parse_duration)]
+    lock_wait: Option<std::time::Duration>,
+    /// Writes the cargo home layout into a squashfs or erofs image at this
+    /// path, instead of loose files in the cargo home. Requires `mksquashfs`
+    /// (squashfs-tools 4.6+) or `mkfs.erofs` (erofs-utils 1.7+).
+    #[cfg(feature = "image")]
+    #[clap(long)]
+    image: Option<cf::PathBuf>,
+    /// The format of the image, one of `squashfs` or `erofs`, by default it
+    /// is inferred from the extension of the image path
+    #[cfg(feature = "image")]
+    #[clap(long, requires = "image")]
+    image_format: Option<cf::image::Format>,
+}
+
+/// Fails if more crates failed to sync than the maximum, if there is one
+fn check_failures(failed: Option<&[cf::Krate]>, max_failures: Option<u32>) -> Result<(), Error> {
+    let Some(max_failures) = max_failures else {
+        return Ok(());
+    };
+
+    // The whole sync failing, eg. due to not being able to create the
+    // cache directories, is the same as every crate failing
+    let Some(failed) = failed else {
+        anyhow::bail!("failed to sync crates");
+    };
+
+    if failed.len() > max_failures as usize {
+        let failed: Vec<_> = failed.iter().map(|krate| krate.to_string()).collect();
+        anyhow::bail!(
+            "{} crates failed to sync, more than the maximum of {max_failures}: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints the cache key, and writes it to the file, if one was specified.
+///
+/// The key identifies a complete cargo home, so if any crate failed to sync it
+/// isn't emitted, and a key file from a previous sync is removed, so that
+/// a cache saved under it is never missing crates.
+fn emit_cache_key(cache_key: &str, path: Option<&cf::Path>, complete: bool) -> Result<(), Error> {
+    if !complete {
+        warn!("not emitting the cache key, as not every crate was synced");
+
+        if let Some(path) = path {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to remove stale cache key '{path}'"))
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    info!(cache_key, "computed cache key");
+    println!("{cache_key}");
+
+    if let Some(path) = path {
+        std::fs::write(path, format!("{cache_key}\n"))
+            .with_context(|| format!("failed to write cache key to '{path}'"))?;
+    }
+
+    Ok(())
+}
+
+/// Writes a shell snippet that exports the cargo home
+fn write_cargo_home_env(cargo_home: &cf::Path, path: &cf::Path) -> Result<(), Error> {
+    // Single quoted so that the path is used verbatim, with any single
+    // quotes in it closing the quote, being escaped, and reopening it
+    let quoted = cargo_home.as_str().replace('\'', r"'\''");
+    std::fs::write(path, format!("export CARGO_HOME='{quoted}'\n"))
+        .with_context(|| format!("failed to write CARGO_HOME to '{path}'"))
+}
+
+/// Handles Ctrl-C for the rest of the sync. The first interrupt cancels the
+/// crate downloads that are still outstanding, and lets the crates that are
+/// being written to disk finish. A second interrupt, or any interrupt once the
+/// sync is already cancelled, removes the partially written crates and exits
+/// immediately.
+fn handle_interrupts(root_dir: cf::PathBuf, cancel: sync::Cancel) {
+    tokio::spawn(async move {
+        loop {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+
+            if cancel.is_cancelled() {
+                sync::cleanup_partial(&root_dir, &cancel.in_progress());
+                std::process::exit(130);
+            }
+
+            warn!("interrupted, waiting for the crates being written to disk to finish, interrupt again to exit immediately");
+            cancel.cancel();
+        }
+    });
+}
+
+/// Emits the cache key and runs the post-sync hooks once the sync succeeded
+fn finish(ctx: &Ctx, cache_key: &str, args: &Args, complete: bool) -> Result<(), Error> {
+    emit_cache_key(cache_key, args.cache_key_file.as_deref(), complete)?;
+
+    if let Some(path) = &args.cargo_home_env {
+        write_cargo_home_env(&ctx.root_dir, path)?;
+    }
+
+    if !args.post_sync_hooks.is_empty() {
+        let report = ctx
+            .report
+            .as_deref()
+            .context("a summary is required to run hooks")?;
+        cf::hook::run("post-sync", &args.post_sync_hooks, &report.render()?)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn cmd(mut ctx: Ctx, mut include_index: bool, args: Args) -> Result<(), Error> {
+    ctx.progress = args.progress;
+    ctx.verify_checksums = args.verify_checksums;
+    ctx.check_git_connectivity = args.check_git_connectivity;
+    ctx.preserve_mtime = args.preserve_mtime;
+    ctx.index_entries_from_snapshot = args.index_entries_from_snapshot;
+
+    let max_failures = if args.strict {
+        Some(0)
+    } else {
+        args.max_failures
+    };
+
+    // Everything that isn't specific to a crate is synced by the first shard
+    let mut skip_index_entries = args.skip_index_entries;
+    if let Some(shard) = args.shard {
+        let total = ctx.krates.len();
+        ctx.krates.retain(|krate| shard.contains(krate));
+        info!(
+            "shard {shard} includes {} of {total} crates",
+            ctx.krates.len()
+        );
+
+        if !shard.is_first() {
+            include_index = false;
+            skip_index_entries = true;
+            ctx.path_patches.clear();
+        }
+    }
+
+    // Without a storage location there are no index snapshots or path patch
+    // tarballs, though the index entries of the crates are still fetched from
+    // their registries
+    if ctx.upstream_only {
+        if include_index {
+            warn!(
+                "ignoring --include-index, there are no index snapshots without a storage location"
+            );
+            include_index = false;
+        }
+        ctx.path_patches.clear();
+    }
+
+    if let Err(err) = cf::drift::check(&ctx.backend, &ctx.lock_files).await {
+        warn!("unable to check lockfiles against the storage location: {err:#}");
+    }
+
+    let cache_key = sync::cache_key(&ctx.krates);
+
+    #[cfg(feature = "image")]
+    if let Some(image) = &args.image {
+        anyhow::ensure!(
+            !ctx.upstream_only,
+            "--image requires a storage location, it can't be used with --url none"
+        );
+        let format = cf::image::Format::resolve(args.image_format, image)?;
+
+        let summary = cf::image::sync(&ctx, include_index, format, image)
+            .await
+            .with_context(|| format!("failed to write image '{image}'"))?;
+        info!(
+            bytes = summary.total_bytes,
+            succeeded = summary.good,
+            failed = summary.bad,
+            "wrote image"
+        );
+
+        check_failures(Some(&summary.failed), max_failures)?;
+        return finish(&ctx, &cache_key, &args, summary.failed.is_empty());
+    }
 
-pub(crate) async fn cmd(ctx: Ctx, include_index: bool, _args: Args) -> Result<(), Error> {
     ctx.prep_sync_dirs()?;
 
+    if let Some(fs) = cf::util::network_filesystem(&ctx.root_dir) {
+        warn!(
+            "'{}' is on a network filesystem ({fs}), which makes syncing very slow as every unpacked file is a round trip to the server. {}",
+            ctx.root_dir,
+            if cfg!(feature = "image") {
+                "Consider syncing to a CARGO_HOME on a local disk, or writing an image with --image, which is a single file"
+            } else {
+                "Consider syncing to a CARGO_HOME on a local disk"
+            }
+        );
+    }
+
+    // Held until every crate has been written, the same as cargo does
+    let _lock = sync::lock_cargo_home(&ctx.root_dir, args.lock_wait).await?;
+
     let root = ctx.root_dir.clone();
-    let backend = ctx.backend.clone();
+    let backend = ctx.index_backend().clone();
     let registries = ctx.registries.clone();
+    let verify = ctx.verify_checksums;
+    let scope = ctx.index_scope.as_deref();
+    let mut failed = None;
+    let mut interrupted = false;
+
+    // Installed for the rest of the process, as tokio never restores the
+    // default handler once it is replaced
+    let cancel = sync::Cancel::new();
+    handle_interrupts(ctx.root_dir.clone(), cancel.clone());
 
     async_scoped::TokioScope::scope_and_block(|s| {
         if include_index {
             s.spawn(async {
                 info!("syncing registries index");
-                sync::registry_indices(root, backend, registries).await;
+                sync::registry_indices(root, backend, registries, verify, scope).await;
                 info!("synced registries index");
             });
+        } else if !skip_index_entries {
+            s.spawn(async {
+                sync::sparse_index_entries(&ctx).await;
+                info!("wrote sparse index entries");
+            });
+        }
+
+        if !ctx.path_patches.is_empty() {
+            s.spawn(async {
+                match sync::path_patches(&ctx).await {
+                    Ok(restored) => info!(restored, "synced path patches"),
+                    Err(e) => error!(err = ?e, "failed to sync path patches"),
+                }
+            });
         }
 
         s.spawn(async {
-            match sync::crates(&ctx).await {
+            match sync::crates(&ctx, &cancel).await {
                 Ok(summary) => {
                     info!(
                         bytes = summary.total_bytes,
@@ -30,11 +338,22 @@ pub(crate) async fn cmd(ctx: Ctx, include_index: bool, _args: Args) -> Result<()
                         failed = summary.bad,
                         "synced crates"
                     );
+                    failed = Some(summary.failed);
+                    interrupted = summary.interrupted;
                 }
                 Err(e) => error!(err = ?e, "failed to sync crates"),
             }
         });
     });
 
-    Ok(())
+    // Once the crates are synced there is nothing left to wait for, so an
+    // interrupt, eg. while the hooks run, exits immediately
+    cancel.cancel();
+
+    // The next sync picks up where this one left off
+    anyhow::ensure!(!interrupted, "sync was interrupted");
+
+    check_failures(failed.as_deref(), max_failures)?;
+    let complete = failed.as_ref().is_some_and(Vec::is_empty);
+    finish(&ctx, &cache_key, &args, complete)
 }
@@ -0,0 +1,49 @@
+use anyhow::{Context as _, Error};
+use cf::{vendor, Ctx};
+use tracing::info;
+
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The directory the crates are written to, in the same layout as
+    /// `cargo vendor --versioned-dirs`
+    #[clap(long, default_value = "vendor")]
+    out: cf::PathBuf,
+    /// Exits with a non-zero code if any crate fails to be vendored
+    #[clap(long)]
+    strict: bool,
+}
+
+pub(crate) async fn cmd(ctx: Ctx, args: Args) -> Result<(), Error> {
+    let summary = vendor::crates(&ctx, &args.out)
+        .await
+        .context("failed to vendor crates")?;
+
+    info!(
+        bytes = summary.total_bytes,
+        succeeded = summary.vendored,
+        failed = summary.failed.len(),
+        "vendored crates"
+    );
+
+    if args.strict && !summary.failed.is_empty() {
+        let failed: Vec<_> = summary
+            .failed
+            .iter()
+            .map(|krate| krate.to_string())
+            .collect();
+        anyhow::bail!(
+            "{} crates failed to be vendored: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+
+    // Like cargo vendor, print the configuration needed to use the vendored
+    // sources, so it can be redirected to a config file
+    println!(
+        "To use the vendored sources, add this to your .cargo/config.toml:\n\n{}",
+        vendor::source_config(&ctx, &args.out)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Compares the contents of two storage locations, eg. to validate that
+//! replication between them is working, or that a migration is complete
+
+use crate::{Krate, ObjectStat, Storage};
+use anyhow::{Context as _, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Difference {
+    /// The object is only present in `b`
+    MissingInA,
+    /// The object is only present in `a`
+    MissingInB,
+    /// The object is present in both, but with different sizes
+    SizeMismatch,
+    /// The object is present in both with the same size, but the checksums
+    /// in the `.sha256` sidecars differ
+    ChecksumMismatch,
+}
+
+impl Difference {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MissingInA => "missing-in-a",
+            Self::MissingInB => "missing-in-b",
+            Self::SizeMismatch => "size-mismatch",
+            Self::ChecksumMismatch => "checksum-mismatch",
+        }
+    }
+}
+
+/// An object that differs between the two storage locations
+#[derive(Serialize, Debug)]
+pub struct Skew {
+    pub id: String,
+    pub difference: Difference,
+    /// The size of the object in `a`, if present
+    pub a_size: Option<u64>,
+    /// The size of the object in `b`, if present
+    pub b_size: Option<u64>,
+}
+
+/// The result of comparing two storage locations
+#[derive(Serialize, Debug)]
+pub struct Comparison {
+    /// The number of objects in `a`
+    pub a_objects: usize,
+    /// The number of objects in `b`
+    pub b_objects: usize,
+    /// The number of objects that are the same in both
+    pub matching: usize,
+    pub skew: Vec<Skew>,
+}
+
+/// Retrieves the checksum from an object's `.sha256` sidecar
+async fn sidecar_checksum(backend: &Storage, id: &str) -> Result<String, Error> {
+    let krate = Krate::object(id);
+    let sidecar = backend
+        .fetch(krate.cloud_id(false).checksum())
+        .await
+        .with_context(|| format!("failed to fetch checksum for '{id}'"))?;
+
+    String::from_utf8(sidecar.to_vec())
+        .ok()
+        .and_then(|sc| sc.split_whitespace().next().map(str::to_owned))
+        .with_context(|| format!("checksum for '{id}' is invalid"))
+}
+
+/// Diffs the listings of two storage locations.
+///
+/// Objects are compared by their key and size. If `checksums` is set, objects
+/// that have a `.sha256` sidecar in both locations also have their checksums
+/// compared, which requires fetching each sidecar.
+pub async fn backends(a: &Storage, b: &Storage, checksums: bool) -> Result<Comparison, Error> {
+    let (a_objects, b_objects) = crate::rt::try_join!(
+        async { a.list_stats().await.context("failed to list a") },
+        async { b.list_stats().await.context("failed to list b") },
+    )?;
+
+    let a_objects: BTreeMap<_, _> = a_objects.into_iter().collect();
+    let b_objects: BTreeMap<_, _> = b_objects.into_iter().collect();
+
+    let mut comparison = Comparison {
+        a_objects: a_objects.len(),
+        b_objects: b_objects.len(),
+        matching: 0,
+        skew: Vec::new(),
+    };
+
+    let size = |stat: Option<&ObjectStat>| stat.map(|stat| stat.size);
+
+    let ids: std::collections::BTreeSet<_> = a_objects.keys().chain(b_objects.keys()).collect();
+    for id in ids {
+        let (a_stat, b_stat) = (a_objects.get(id), b_objects.get(id));
+
+        let difference = match (a_stat, b_stat) {
+            (None, _) => Some(Difference::MissingInA),
+            (_, None) => Some(Difference::MissingInB),
+            (Some(a_stat), Some(b_stat)) if a_stat.size != b_stat.size => {
+                Some(Difference::SizeMismatch)
+            }
+            _ => {
+                let sidecar = format!("{id}.sha256");
+                if checksums && a_objects.contains_key(&sidecar) && b_objects.contains_key(&sidecar)
+                {
+                    let (a_sum, b_sum) =
+                        crate::rt::try_join!(sidecar_checksum(a, id), sidecar_checksum(b, id))?;
+                    (a_sum != b_sum).then_some(Difference::ChecksumMismatch)
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(difference) = difference {
+            comparison.skew.push(Skew {
+                id: id.clone(),
+                difference,
+                a_size: size(a_stat),
+                b_size: size(b_stat),
+            });
+        } else {
+            comparison.matching += 1;
+        }
+    }
+
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backends::fs::FsBackend;
+
+    fn backend(dir: &crate::Path) -> Storage {
+        std::sync::Arc::new(FsBackend::new(crate::FilesystemLocation { path: dir }).unwrap())
+    }
+
+    #[tokio::test]
+    async fn finds_skew() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a_path = crate::util::path(a_dir.path()).unwrap();
+        let b_path = crate::util::path(b_dir.path()).unwrap();
+
+        for (path, files) in [
+            (
+                a_path,
+                &[
+                    ("same", "same"),
+                    ("only-a", "a"),
+                    ("sized", "short"),
+                    ("summed", "same"),
+                    ("summed.sha256", "aaaa  summed\n"),
+                ][..],
+            ),
+            (
+                b_path,
+                &[
+                    ("same", "same"),
+                    ("only-b", "b"),
+                    ("sized", "longer"),
+                    ("summed", "same"),
+                    ("summed.sha256", "bbbb  summed\n"),
+                ][..],
+            ),
+        ] {
+            for (name, contents) in files {
+                std::fs::write(path.join(name), contents).unwrap();
+            }
+        }
+
+        let comparison = backends(&backend(a_path), &backend(b_path), true)
+            .await
+            .unwrap();
+
+        assert_eq!(comparison.a_objects, 5);
+        assert_eq!(comparison.b_objects, 5);
+        // same, summed.sha256
+        assert_eq!(comparison.matching, 2);
+
+        let skew: Vec<_> = comparison
+            .skew
+            .iter()
+            .map(|skew| (skew.id.as_str(), skew.difference))
+            .collect();
+        assert_eq!(
+            skew,
+            [
+                ("only-a", Difference::MissingInB),
+                ("only-b", Difference::MissingInA),
+                ("sized", Difference::SizeMismatch),
+                ("summed", Difference::ChecksumMismatch),
+            ]
+        );
+    }
+}
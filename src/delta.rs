@@ -0,0 +1,408 @@
+//! Incremental updates of registry index snapshots and git dbs.
+//!
+//! Rather than uploading the entire snapshot every time it is stale, a full
+//! base snapshot is only uploaded periodically, and in between, the files that
+//! were added or changed since the previous update are uploaded as a small
+//! delta tarball, which are applied on top of the base in order. A state object
+//! records the hash of every file in the snapshot, so that `mirror` can compute
+//! the next delta without downloading the base.
+//!
+//! Git dbs are stored per revision, so a db uploaded as a delta is replaced by
+//! a [`Pointer`] to the base and the deltas that make up that revision, and the
+//! state is stored per repository rather than per snapshot.
+
+use crate::{util, Krate, Path, Storage};
+use anyhow::{Context as _, Error};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Read};
+use tracing::{debug, warn};
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// The SHA-256 of the base snapshot the deltas apply to
+    pub(crate) base: String,
+    /// The SHA-256 of every file in the snapshot, as of the last delta
+    pub(crate) files: BTreeMap<String, String>,
+    /// The files removed by each delta, in the order they are applied
+    pub(crate) deltas: Vec<Vec<String>>,
+    /// The id of the base snapshot, for git dbs, whose state is stored per
+    /// repository rather than per snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) base_id: Option<String>,
+}
+
+impl State {
+    /// The pointer to the snapshot as of the last delta, for git dbs
+    pub(crate) fn pointer(&self) -> Option<Pointer> {
+        Some(Pointer {
+            base: self.base_id.clone()?,
+            base_hash: self.base.clone(),
+            deltas: self.deltas.clone(),
+        })
+    }
+}
+
+/// Stored in place of a git db that was uploaded as a delta
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Pointer {
+    /// The id of the full db the deltas apply to
+    pub(crate) base: String,
+    /// The SHA-256 of the full db
+    pub(crate) base_hash: String,
+    /// The files removed by each delta, in the order they are applied
+    pub(crate) deltas: Vec<Vec<String>>,
+}
+
+impl Pointer {
+    /// Parses the object as a pointer, if it is one. Tarballs, compressed or
+    /// not, never start with `{`.
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        if data.first() != Some(&b'{') {
+            return None;
+        }
+
+        serde_json::from_slice(data).ok()
+    }
+
+    /// Fetches the base and its deltas and unpacks them to `dir`
+    pub(crate) async fn unpack(&self, backend: &Storage, dir: &Path) -> Result<(), Error> {
+        let base = backend
+            .fetch(Krate::object(&self.base).cloud_id(false))
+            .await
+            .with_context(|| format!("failed to fetch base '{}'", self.base))?;
+        anyhow::ensure!(
+            util::sha256_hex(&base) == self.base_hash,
+            "base '{}' was replaced since the deltas were uploaded",
+            self.base
+        );
+
+        let deltas = fetch_deltas(backend, &self.base, self.deltas.clone()).await?;
+
+        let dir = dir.to_owned();
+        crate::rt::spawn_blocking(move || {
+            util::unpack_tar(base, util::Encoding::Detect, &dir)?;
+            for (i, delta) in deltas.iter().enumerate() {
+                apply(delta, &dir).with_context(|| format!("failed to apply delta {}", i + 1))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Fetches the base and its deltas and packs them into a single tarball
+    pub(crate) async fn resolve(&self, backend: &Storage) -> Result<Bytes, Error> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_dir_path = util::path(temp_dir.path())?;
+        self.unpack(backend, temp_dir_path).await?;
+
+        let temp_dir_path = temp_dir_path.to_owned();
+        // The tarball is unpacked immediately, so there's no point compressing it
+        crate::rt::spawn_blocking(move || util::pack_tar(&temp_dir_path, util::Compression::None))
+            .await
+    }
+}
+
+/// Fetches a git db, resolving it if it was uploaded as a delta
+pub(crate) async fn fetch_git_db(backend: &Storage, krate: &Krate) -> Result<Bytes, Error> {
+    let db = backend.fetch(krate.cloud_id(false)).await?;
+
+    match Pointer::parse(&db) {
+        Some(pointer) => pointer
+            .resolve(backend)
+            .await
+            .context("failed to resolve git db delta"),
+        None => Ok(db),
+    }
+}
+
+/// A delta to apply on top of a snapshot
+pub(crate) struct Delta {
+    /// The tarball of files added or changed by the delta
+    pub(crate) data: Bytes,
+    /// The files removed by the delta
+    pub(crate) removed: Vec<String>,
+}
+
+#[inline]
+pub(crate) fn state_id(base: &str) -> String {
+    format!("{base}.delta-state")
+}
+
+/// The id of the delta, deltas are numbered from 1
+#[inline]
+pub(crate) fn delta_id(base: &str, n: usize) -> String {
+    format!("{base}.delta-{n}")
+}
+
+/// Gets the id of the snapshot that a state or delta object belongs to
+pub(crate) fn base_id(id: &str) -> Option<&str> {
+    let (base, suffix) = id.rsplit_once(".delta-")?;
+    (suffix == "state" || suffix.parse::<usize>().is_ok()).then_some(base)
+}
+
+/// Hashes every file in the snapshot, and if the hashes of a `previous`
+/// version of the snapshot are provided, packs the files that were added or
+/// changed since then into a delta, which is `None` if nothing changed
+pub(crate) fn diff(
+    snapshot: &[u8],
+    previous: Option<&BTreeMap<String, String>>,
+    compression: util::Compression,
+) -> Result<(BTreeMap<String, String>, Option<Delta>), Error> {
+    let temp_dir = tempfile::tempdir()?;
+    let temp_dir_path = util::path(temp_dir.path())?;
+
+    let mut archive = util::read_tar(snapshot, util::Encoding::Detect)?;
+    let mut files = BTreeMap::new();
+    let mut changed = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = {
+            let path = entry.path()?;
+            let path = path.to_string_lossy();
+            path.strip_prefix("./").unwrap_or(&path).to_owned()
+        };
+
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        let hash = util::sha256_hex(&data);
+
+        if let Some(previous) = previous {
+            if previous.get(&path) != Some(&hash) {
+                let changed_path = temp_dir_path.join(&path);
+                if let Some(parent) = changed_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&changed_path, &data)
+                    .with_context(|| format!("failed to write '{changed_path}'"))?;
+                changed += 1;
+            }
+        }
+
+        files.insert(path, hash);
+    }
+
+    let Some(previous) = previous else {
+        return Ok((files, None));
+    };
+
+    let removed: Vec<_> = previous
+        .keys()
+        .filter(|path| !files.contains_key(*path))
+        .cloned()
+        .collect();
+
+    if changed == 0 && removed.is_empty() {
+        return Ok((files, None));
+    }
+
+    debug!(changed, removed = removed.len(), "computed delta");
+
+    let data = util::pack_tar(temp_dir_path, compression)?;
+    Ok((files, Some(Delta { data, removed })))
+}
+
+/// Reads the delta state for the snapshot, if deltas have been uploaded for it
+pub(crate) async fn read_state(backend: &Storage, base: &str) -> Result<Option<State>, Error> {
+    let krate = Krate::object(&state_id(base));
+
+    if backend.stat(krate.cloud_id(false)).await?.is_none() {
+        return Ok(None);
+    }
+
+    let data = backend.fetch(krate.cloud_id(false)).await?;
+    let state = serde_json::from_slice(&data)
+        .with_context(|| format!("failed to deserialize delta state for '{base}'"))?;
+    Ok(Some(state))
+}
+
+/// Fetches the deltas to apply on top of the base snapshot with the specified
+/// hash, in order. If deltas aren't used for the snapshot, or the state is for
+/// a different base, eg. because the base was replaced since, there are none.
+pub(crate) async fn fetch(
+    backend: &Storage,
+    base: &str,
+    base_hash: &str,
+) -> Result<Vec<Delta>, Error> {
+    let Some(state) = read_state(backend, base).await? else {
+        return Ok(Vec::new());
+    };
+
+    if state.base != base_hash {
+        warn!("delta state for '{base}' is for a different base snapshot, ignoring deltas");
+        return Ok(Vec::new());
+    }
+
+    fetch_deltas(backend, base, state.deltas).await
+}
+
+/// Fetches the deltas of the base snapshot, given the files removed by each
+async fn fetch_deltas(
+    backend: &Storage,
+    base: &str,
+    removed: Vec<Vec<String>>,
+) -> Result<Vec<Delta>, Error> {
+    let mut deltas = Vec::with_capacity(removed.len());
+    for (i, removed) in removed.into_iter().enumerate() {
+        let id = delta_id(base, i + 1);
+        let data = backend
+            .fetch(Krate::object(&id).cloud_id(false))
+            .await
+            .with_context(|| format!("failed to fetch delta '{id}'"))?;
+
+        deltas.push(Delta { data, removed });
+    }
+
+    Ok(deltas)
+}
+
+/// Applies the delta on top of a snapshot that has been unpacked to `dir`
+pub(crate) fn apply(delta: &Delta, dir: &Path) -> Result<(), Error> {
+    util::unpack_tar(delta.data.clone(), util::Encoding::Detect, dir)?;
+
+    for removed in &delta.removed {
+        // Paths come from the state object, so don't trust them blindly
+        anyhow::ensure!(
+            Path::new(removed)
+                .components()
+                .all(|comp| matches!(comp, camino::Utf8Component::Normal(_))),
+            "delta removes '{removed}' outside of the snapshot"
+        );
+
+        let path = dir.join(removed);
+
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(Error::from(err).context(format!("failed to remove '{path}'")));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches the snapshot for the registry index, with any deltas applied
+#[cfg(any(feature = "image", feature = "serve"))]
+pub(crate) async fn fetch_snapshot(backend: &Storage, krate: &Krate) -> Result<Bytes, Error> {
+    let base = backend.fetch(krate.cloud_id(false)).await?;
+    let deltas = fetch(
+        backend,
+        &krate.cloud_id(false).to_string(),
+        &util::sha256_hex(&base),
+    )
+    .await?;
+
+    if deltas.is_empty() {
+        return Ok(base);
+    }
+
+    crate::rt::spawn_blocking(move || {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_dir_path = util::path(temp_dir.path())?;
+
+        util::unpack_tar(base, util::Encoding::Detect, temp_dir_path)?;
+        for delta in &deltas {
+            apply(delta, temp_dir_path)?;
+        }
+
+        // The snapshot is read immediately, so there's no point compressing it
+        util::pack_tar(temp_dir_path, util::Compression::None)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diffs_and_applies() {
+        let write = |root: &Path, files: &[(&str, &str)]| {
+            for (path, contents) in files {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+        };
+
+        let base_dir = tempfile::tempdir().unwrap();
+        let base_path = util::path(base_dir.path()).unwrap();
+        write(
+            base_path,
+            &[
+                ("config.json", "{}"),
+                (".cache/3/s/syn", "v1"),
+                (".cache/se/rd/serde", "v1"),
+            ],
+        );
+        let base = util::pack_tar(base_path, util::Compression::default()).unwrap();
+        let (files, delta) = diff(&base, None, util::Compression::default()).unwrap();
+        assert_eq!(files.len(), 3);
+        assert!(delta.is_none());
+
+        let (unchanged, delta) = diff(&base, Some(&files), util::Compression::default()).unwrap();
+        assert_eq!(unchanged, files);
+        assert!(delta.is_none());
+
+        std::fs::remove_file(base_path.join(".cache/se/rd/serde")).unwrap();
+        write(
+            base_path,
+            &[(".cache/3/s/syn", "v2"), (".cache/2/cc", "v1")],
+        );
+        let next = util::pack_tar(base_path, util::Compression::default()).unwrap();
+        let (_, delta) = diff(&next, Some(&files), util::Compression::default()).unwrap();
+        let delta = delta.unwrap();
+        assert_eq!(delta.removed, [".cache/se/rd/serde"]);
+
+        // Applying the delta to the base results in the next snapshot
+        let out_dir = tempfile::tempdir().unwrap();
+        let out_path = util::path(out_dir.path()).unwrap();
+        util::unpack_tar(base, util::Encoding::Detect, out_path).unwrap();
+        apply(&delta, out_path).unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(out_path.join(path)).unwrap();
+        assert_eq!(read(".cache/3/s/syn"), "v2");
+        assert_eq!(read(".cache/2/cc"), "v1");
+        assert_eq!(read("config.json"), "{}");
+        assert!(!out_path.join(".cache/se/rd/serde").exists());
+
+        assert_eq!(
+            base_id(&delta_id("index-feedc0d", 3)),
+            Some("index-feedc0d")
+        );
+        assert_eq!(base_id(&state_id("index-feedc0d")), Some("index-feedc0d"));
+        assert_eq!(base_id("index-feedc0d"), None);
+    }
+
+    #[test]
+    fn parses_pointers() {
+        let mut state = State {
+            base: "feedc0d".to_owned(),
+            files: BTreeMap::new(),
+            deltas: vec![Vec::new(), vec!["packed-refs".to_owned()]],
+            base_id: None,
+        };
+        // Only git db states point to their base
+        assert!(state.pointer().is_none());
+
+        state.base_id = Some("cpal-a7ffa0a7bdf9aa3e-b8ed1bc".to_owned());
+        let pointer = serde_json::to_vec(&state.pointer().unwrap()).unwrap();
+        let pointer = Pointer::parse(&pointer).unwrap();
+        assert_eq!(pointer.base, "cpal-a7ffa0a7bdf9aa3e-b8ed1bc");
+        assert_eq!(pointer.base_hash, "feedc0d");
+        assert_eq!(pointer.deltas, state.deltas);
+
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+        std::fs::write(root.join("HEAD"), "ref: refs/heads/main").unwrap();
+        for compression in [util::Compression::None, util::Compression::default()] {
+            let db = util::pack_tar(root, compression).unwrap();
+            assert!(Pointer::parse(&db).is_none());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Records the lockfiles that a storage location was mirrored for, so that
+//! `sync` can warn when the local lockfile doesn't match any of them, eg.
+//! because the branch being built changed its dependencies since the storage
+//! location was last mirrored
+
+use crate::{util, Krate, Path, PathBuf, Storage};
+use anyhow::{Context as _, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{debug, warn};
+
+/// The id of the object the lockfile hashes are stored as
+pub const STATE_ID: &str = "lockfiles.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// The SHA-256 of each lockfile, by its [`key`]
+    lockfiles: BTreeMap<String, String>,
+}
+
+/// The key a lockfile is recorded under, which is its path relative to the
+/// root of the git repository it is in, so that it is the same regardless of
+/// where the repository is checked out, prefixed with the name, if one is
+/// specified, so that repositories sharing a storage location don't replace
+/// each other's lockfiles
+fn key(lock_file: &Path, name: Option<&str>) -> String {
+    let relative = lock_file
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join(".git").exists())
+        .and_then(|root| lock_file.strip_prefix(root).ok())
+        .unwrap_or(lock_file);
+
+    match name {
+        Some(name) => format!("{name}/{relative}"),
+        None => relative.to_string(),
+    }
+}
+
+/// Hashes the contents of each lockfile, by its [`key`]
+fn hash(lock_files: &[PathBuf], name: Option<&str>) -> Result<BTreeMap<String, String>, Error> {
+    lock_files
+        .iter()
+        .map(|lf| {
+            let contents =
+                std::fs::read(lf).with_context(|| format!("failed to read lockfile '{lf}'"))?;
+            Ok((key(lf, name), util::sha256_hex(&contents)))
+        })
+        .collect()
+}
+
+/// Computes the scope of registry index snapshots scoped to the lockfiles,
+/// which only depends on their contents, not their paths or order
+pub fn scope(lock_files: &[PathBuf]) -> Result<String, Error> {
+    let mut hashes: Vec<_> = hash(lock_files, None)?.into_values().collect();
+    hashes.sort();
+    hashes.dedup();
+
+    let mut scope = util::sha256_hex(hashes.join("\n").as_bytes());
+    scope.truncate(16);
+    Ok(scope)
+}
+
+async fn read_state(backend: &Storage) -> Result<Option<State>, Error> {
+    let krate = Krate::object(STATE_ID);
+
+    if backend.stat(krate.cloud_id(false)).await?.is_none() {
+        return Ok(None);
+    }
+
+    let data = backend.fetch(krate.cloud_id(false)).await?;
+    let state = serde_json::from_slice(&data).context("failed to deserialize lockfile state")?;
+    Ok(Some(state))
+}
+
+/// Records the hashes of the lockfiles, replacing those previously recorded
+/// under the same key, the path of the lockfile relative to its git repository
+/// and the name, if one is specified, so that several repositories can share a
+/// storage location.
+///
+/// The state is read again immediately before it is uploaded, but the storage
+/// locations don't all support conditional uploads, so two mirrors recording
+/// at the exact same time can still race, in which case the last one wins and
+/// the other's lockfiles are recorded by its next mirror.
+pub async fn record(
+    backend: &Storage,
+    lock_files: &[PathBuf],
+    name: Option<&str>,
+) -> Result<(), Error> {
+    let hashes = hash(lock_files, name)?;
+
+    let mut state = read_state(backend).await?.unwrap_or_default();
+    state.lockfiles.extend(hashes);
+
+    let data = serde_json::to_vec(&state)?;
+    backend
+        .upload(data.into(), Krate::object(STATE_ID).cloud_id(false))
+        .await
+        .context("failed to upload lockfile state")?;
+
+    Ok(())
+}
+
+/// Checks that each lockfile matches one of the lockfiles the storage location
+/// was mirrored for, returning the ones that don't. Nothing is checked if the
+/// lockfiles were never recorded.
+pub async fn check(backend: &Storage, lock_files: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let Some(state) = read_state(backend).await? else {
+        debug!("lockfiles were not recorded when mirroring");
+        return Ok(Vec::new());
+    };
+
+    let mut drifted = Vec::new();
+    for lf in lock_files {
+        let contents =
+            std::fs::read(lf).with_context(|| format!("failed to read lockfile '{lf}'"))?;
+        let hash = util::sha256_hex(&contents);
+
+        if !state.lockfiles.values().any(|recorded| *recorded == hash) {
+            drifted.push(lf.clone());
+        }
+    }
+
+    for lf in &drifted {
+        warn!("lockfile '{lf}' doesn't match any lockfile the storage location was mirrored for, crates may be missing");
+    }
+
+    Ok(drifted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backends::fs::FsBackend;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn detects_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = util::path(dir.path()).unwrap();
+        let backend: Storage = Arc::new(
+            FsBackend::new(crate::FilesystemLocation {
+                path: &root.join("bucket"),
+            })
+            .unwrap(),
+        );
+
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        let lock_file = root.join("Cargo.lock");
+        std::fs::write(&lock_file, "version = 3").unwrap();
+        let lock_files = [lock_file.clone()];
+
+        // Nothing to compare against
+        assert!(check(&backend, &lock_files).await.unwrap().is_empty());
+
+        record(&backend, &lock_files, None).await.unwrap();
+        assert!(check(&backend, &lock_files).await.unwrap().is_empty());
+
+        // The same lockfile in another checkout replaces the recorded hash
+        // rather than adding another entry
+        let other = root.join("other");
+        std::fs::create_dir_all(other.join(".git")).unwrap();
+        std::fs::write(other.join("Cargo.lock"), "version = 2").unwrap();
+        record(&backend, &lock_files, Some("repo")).await.unwrap();
+        record(&backend, &[other.join("Cargo.lock")], Some("repo"))
+            .await
+            .unwrap();
+        let state = read_state(&backend).await.unwrap().unwrap();
+        assert_eq!(
+            state.lockfiles.keys().collect::<Vec<_>>(),
+            ["Cargo.lock", "repo/Cargo.lock"]
+        );
+
+        std::fs::write(&lock_file, "version = 4").unwrap();
+        assert_eq!(check(&backend, &lock_files).await.unwrap(), lock_files);
+    }
+}
@@ -0,0 +1,189 @@
+//! Stable, machine readable codes for the kinds of failures that can occur,
+//! so that automation can react to a failure without matching on error
+//! messages, which can change between releases.
+//!
+//! The codes, and the process exit codes they map to, are part of the public
+//! API. A code is never reused for a different kind of failure, new codes are
+//! only ever appended.
+
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(into = "&'static str")]
+pub enum Code {
+    /// `CF-0001` Any failure that isn't covered by a more specific code
+    Other,
+    /// `CF-0002` Credentials were missing, or were rejected by the storage
+    /// location or registry
+    Auth,
+    /// `CF-0003` An object, crate, or file that was expected to exist doesn't
+    NotFound,
+    /// `CF-0004` A crate or tarball failed checksum validation, or couldn't be
+    /// decompressed or unpacked
+    Corrupt,
+    /// `CF-0005` A lockfile couldn't be read or parsed
+    Lockfile,
+    /// `CF-0006` A request timed out, or a connection couldn't be established
+    Network,
+    /// `CF-0007` A crate that is already in the storage location doesn't match
+    /// the checksum in the lockfile, eg. because it was yanked and then
+    /// republished with different contents
+    ChecksumConflict,
+}
+
+impl Code {
+    pub const ALL: [Self; 7] = [
+        Self::Other,
+        Self::Auth,
+        Self::NotFound,
+        Self::Corrupt,
+        Self::Lockfile,
+        Self::Network,
+        Self::ChecksumConflict,
+    ];
+
+    #[inline]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Other => "CF-0001",
+            Self::Auth => "CF-0002",
+            Self::NotFound => "CF-0003",
+            Self::Corrupt => "CF-0004",
+            Self::Lockfile => "CF-0005",
+            Self::Network => "CF-0006",
+            Self::ChecksumConflict => "CF-0007",
+        }
+    }
+
+    /// The exit code the process exits with when a command fails with this
+    /// code. `2` is not used as that is what usage errors exit with.
+    #[inline]
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::Auth => 10,
+            Self::NotFound => 11,
+            Self::Corrupt => 12,
+            Self::Lockfile => 13,
+            Self::Network => 14,
+            Self::ChecksumConflict => 15,
+        }
+    }
+
+    /// Classifies an error by the first code attached to it with [`coded`],
+    /// falling back to the underlying HTTP or I/O errors in its chain
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(coded) = err.downcast_ref::<Coded>() {
+            return coded.code;
+        }
+
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                match err.status().map(|status| status.as_u16()) {
+                    Some(401 | 403) => return Self::Auth,
+                    Some(404) => return Self::NotFound,
+                    _ => {}
+                }
+
+                if err.is_timeout() || err.is_connect() {
+                    return Self::Network;
+                }
+            } else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                use std::io::ErrorKind;
+
+                match err.kind() {
+                    ErrorKind::NotFound => return Self::NotFound,
+                    ErrorKind::TimedOut | ErrorKind::ConnectionRefused => return Self::Network,
+                    _ => {}
+                }
+            }
+        }
+
+        Self::Other
+    }
+}
+
+impl From<Code> for &'static str {
+    #[inline]
+    fn from(code: Code) -> Self {
+        code.as_str()
+    }
+}
+
+impl fmt::Display for Code {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Context that attaches a [`Code`] to an error, for failures that can't be
+/// classified from the underlying error alone. Only the message is displayed.
+#[derive(Debug)]
+pub struct Coded {
+    code: Code,
+    message: String,
+}
+
+impl fmt::Display for Coded {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Coded {}
+
+/// Creates context that attaches the code to an error, eg.
+/// `.with_context(|| coded(Code::Lockfile, "failed to parse lockfile"))`
+#[inline]
+pub fn coded(code: Code, message: impl Into<String>) -> Coded {
+    Coded {
+        code,
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context as _;
+
+    #[test]
+    fn classifies_errors() {
+        let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::InvalidData))
+            .context(coded(Code::Corrupt, "failed to unpack"))
+            .context("failed to sync 'krate'")
+            .unwrap_err();
+        assert_eq!(Code::of(&err), Code::Corrupt);
+        assert_eq!(
+            format!("{err:#}"),
+            "failed to sync 'krate': failed to unpack: invalid data"
+        );
+
+        let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .context("failed to read")
+            .unwrap_err();
+        assert_eq!(Code::of(&err), Code::NotFound);
+
+        // The outermost code wins
+        let err = Err::<(), _>(anyhow::anyhow!("checksum mismatch"))
+            .context(coded(Code::Corrupt, "invalid crate"))
+            .context(coded(Code::ChecksumConflict, "conflicting crate"))
+            .unwrap_err();
+        assert_eq!(Code::of(&err), Code::ChecksumConflict);
+
+        assert_eq!(Code::of(&anyhow::anyhow!("oh no")), Code::Other);
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, a) in Code::ALL.iter().enumerate() {
+            for b in &Code::ALL[i + 1..] {
+                assert_ne!(a.as_str(), b.as_str());
+                assert_ne!(a.exit_code(), b.exit_code());
+            }
+        }
+    }
+}
@@ -17,54 +17,82 @@ impl KratePackage {
     }
 }
 
-#[tracing::instrument(level = "debug")]
+#[tracing::instrument(level = "debug", skip_all, fields(krate = %krate))]
 pub(crate) async fn from_registry(
     client: &crate::HttpClient,
     krate: &Krate,
+    retry: &util::RetryPolicy,
+    git_options: &crate::git::CloneOptions,
+    compression: util::Compression,
+    checkout_exclusions: &util::PathExclusions,
 ) -> anyhow::Result<KratePackage> {
     match &krate.source {
         Source::Git(gs) => {
             let gs = gs.clone();
-            tokio::task::spawn_blocking(move || crate::git::clone(&gs).map(KratePackage::Git))
-                .await
-                .unwrap()
+            let options = git_options.clone();
+            let exclusions = checkout_exclusions.clone();
+            crate::rt::spawn_blocking(move || {
+                crate::git::clone(&gs, &options, compression, &exclusions).map(KratePackage::Git)
+            })
+            .await
         }
         Source::Registry(rs) => {
             let url = rs.registry.download_url(krate);
 
+            // Crates from local registries are usually on disk as well
+            if let Some(path) = local_path(&url) {
+                let content = crate::rt::spawn_blocking(move || {
+                    std::fs::read(&path).with_context(|| format!("failed to read '{path}'"))
+                })
+                .await?;
+                util::validate_checksum(&content, &rs.chksum)?;
+
+                return Ok(KratePackage::Registry(content.into()));
+            }
+
             // Depending on how many crates we are mirroring, we can be sending
             // hundreds of concurrent requests to crates.io...and hit
             // https://github.com/seanmonstar/reqwest/issues/1748
-            let res = loop {
-                let res = client.get(&url).send().await;
+            let mut req = client.get(&url);
 
-                match res {
-                    Err(err) if err.is_connect() || err.is_timeout() || err.is_request() => {
-                        continue
-                    }
-                    Err(err) => return Err(err.into()),
-                    Ok(res) => break res,
-                }
-            };
+            if let Some(token) = &rs.registry.token {
+                req = req.header(reqwest::header::AUTHORIZATION, token.expose());
+            }
 
-            let response = res.error_for_status()?;
-            let res = util::convert_response(response).await?;
-            let content = res.into_body();
+            let res = util::send_request_with_retry(client, req.build()?, retry).await?;
 
-            util::validate_checksum(&content, &rs.chksum)?;
+            let content = util::read_validated_response(res.error_for_status()?, &rs.chksum)
+                .await
+                .with_context(|| format!("failed to download '{url}'"))?;
 
             Ok(KratePackage::Registry(content))
         }
     }
 }
 
-#[tracing::instrument(level = "debug", skip(krates))]
+/// How the snapshot of a registry index is built
+pub struct SnapshotOptions<'a> {
+    /// The compression of the snapshot tarball
+    pub compression: util::Compression,
+    /// A directory in which sparse index entries are persisted between runs,
+    /// see [`crate::Ctx::sparse_index_cache`]
+    pub sparse_cache: Option<&'a crate::Path>,
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(registry = %registry.index))]
 pub async fn registry(
     client: &crate::HttpClient,
     registry: &crate::cargo::Registry,
     krates: Vec<String>,
+    limit: &util::RequestLimit,
+    retry: &util::RetryPolicy,
+    http: &util::HttpConfig,
+    options: SnapshotOptions<'_>,
 ) -> anyhow::Result<Bytes> {
-    use tame_index::index;
+    let SnapshotOptions {
+        compression,
+        sparse_cache,
+    } = options;
 
     // We don't bother to support older versions of cargo that don't support
     // bare checkouts of registry indexes, as that has been in since early 2017
@@ -73,38 +101,94 @@ pub async fn registry(
     let temp_dir = tempfile::tempdir()?;
     let temp_dir_path = util::path(temp_dir.path())?;
 
-    let index_url = registry.index.as_str().to_owned();
+    let auth_client = authenticated_client(registry, http)?;
+    let client = auth_client.as_ref().unwrap_or(client);
 
-    let write_cache = tracing::span!(tracing::Level::DEBUG, "write-cache-entries");
+    // If there is a persistent cache for sparse entries, they are written there
+    // instead, so that the etag/last-modified stored in each entry from the
+    // previous run is used to make a conditional request, and only the entries
+    // for the crates we were asked for are then copied into the snapshot
+    let cache_root = sparse_cache
+        .filter(|_| registry.protocol == crate::cargo::RegistryProtocol::Sparse)
+        .map(|dir| dir.join(registry.short_name()));
 
-    let location = index::IndexLocation {
-        // note this is a bit of a misnomer, it could be the crates.io registry
-        url: index::IndexUrl::NonCratesIo(index_url.clone().into()),
-        root: index::IndexPath::Exact(temp_dir_path.to_owned()),
-    };
+    if let Some(cache_root) = &cache_root {
+        std::fs::create_dir_all(cache_root)
+            .with_context(|| format!("unable to create sparse index cache '{cache_root}'"))?;
+    }
+
+    write_cache_entries(
+        client,
+        registry,
+        cache_root.as_deref().unwrap_or(temp_dir_path),
+        temp_dir_path,
+        &krates,
+        limit,
+        retry,
+    )
+    .await?;
+
+    if let Some(cache_root) = &cache_root {
+        copy_cache_entries(cache_root, &krates, temp_dir_path)
+            .context("failed to copy cached index entries")?;
+    }
+
+    // Snapshots compressed with zstd are seekable, so that sync can read
+    // individual entries without decompressing the whole snapshot, unless
+    // long distance matching is enabled, as its window spans many frames
+    match compression {
+        util::Compression::Zstd {
+            level,
+            threads,
+            window_log: None,
+        } => crate::seekable::pack_tar(temp_dir_path, level, threads),
+        _ => util::pack_tar(temp_dir_path, compression),
+    }
+}
+
+/// Writes `.cache` entries for all of the specified crates to the index at
+/// `index_root`, and for sparse registries, the registry's `config.json` to
+/// `config_dir`.
+///
+/// Cargo will write these entries itself if they don't exist the first time it
+/// tries to access the crate's metadata in the case of git, but this noticeably
+/// increases initial fetch times. (see src/cargo/sources/registry/index.rs)
+///
+/// For sparse indices, the cache entries are the _only_ local state, and if
+/// not present means every missing crate needs to be fetched, without the
+/// possibility of the local cache entry being up to date according to the
+/// etag/modified time of the remote
+pub(crate) async fn write_cache_entries(
+    client: &crate::HttpClient,
+    registry: &crate::cargo::Registry,
+    index_root: &crate::Path,
+    config_dir: &crate::Path,
+    krates: &[String],
+    limit: &util::RequestLimit,
+    retry: &util::RetryPolicy,
+) -> anyhow::Result<()> {
+    use tame_index::index;
 
-    // Writes .cache entries in the registry's directory for all of the specified
-    // crates.
-    //
-    // Cargo will write these entries itself if they don't exist the first time it
-    // tries to access the crate's metadata in the case of git, but this noticeably
-    // increases initial fetch times. (see src/cargo/sources/registry/index.rs)
-    //
-    // For sparse indices, the cache entries are the _only_ local state, and if
-    // not present means every missing crate needs to be fetched, without the
-    // possibility of the local cache entry being up to date according to the
-    // etag/modified time of the remote
     match registry.protocol {
         crate::cargo::RegistryProtocol::Git => {
-            tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let location = index::IndexLocation {
+                // note this is a bit of a misnomer, it could be the crates.io registry
+                url: index::IndexUrl::NonCratesIo(registry.index.to_string().into()),
+                root: index::IndexPath::Exact(index_root.to_owned()),
+            };
+
+            let _permit = limit.acquire().await;
+
+            let write_cache = tracing::span!(tracing::Level::DEBUG, "write-cache-entries");
+            let krates = krates.to_vec();
+            crate::rt::spawn_blocking(move || -> anyhow::Result<()> {
                 let rgi = {
                     let span = tracing::debug_span!("fetch");
                     let _fs = span.enter();
 
-                    tame_index::index::RemoteGitIndex::new(
-                        tame_index::index::GitIndex::new(location)
-                            .context("unable to open git index")?,
-                        &tame_index::index::FileLock::unlocked(),
+                    index::RemoteGitIndex::new(
+                        index::GitIndex::new(location).context("unable to open git index")?,
+                        &index::FileLock::unlocked(),
                     )
                     .context("failed to fetch")?
                 };
@@ -115,7 +199,7 @@ pub async fn registry(
                     // worth it for a few hundred crates (probably), but see
                     // https://github.com/frewsxcv/rust-crates-index/blob/a9b60653efb72d9e6be98c4f8fe56194475cbd3f/src/git/mod.rs#L316-L360
                     // for a way this could be done in the future
-                    let unlocked = &tame_index::index::FileLock::unlocked();
+                    let unlocked = &index::FileLock::unlocked();
                     for name in krates {
                         let Ok(name) = name.as_str().try_into() else {
                             warn!("crate name '{name}' is invalid");
@@ -132,7 +216,292 @@ pub async fn registry(
                 Ok(())
             })
             .await
-            .unwrap()?;
+        }
+        crate::cargo::RegistryProtocol::Sparse => {
+            write_sparse_entries(
+                client, registry, index_root, config_dir, krates, limit, retry,
+            )
+            .await
+        }
+    }
+}
+
+/// Writes the `.cache` entries for the crates from a sparse registry to the
+/// index at `index_root`, as well as the registry's `config.json` to `config_dir`.
+///
+/// Failures for individual crates are only logged, as the index will be
+/// healed by cargo.
+async fn write_sparse_entries(
+    client: &crate::HttpClient,
+    registry: &crate::cargo::Registry,
+    index_root: &crate::Path,
+    config_dir: &crate::Path,
+    krates: &[String],
+    limit: &util::RequestLimit,
+    retry: &util::RetryPolicy,
+) -> anyhow::Result<()> {
+    use tame_index::index;
+
+    // tame-index only understands `sparse+http(s)` urls, so local registries
+    // are written directly to the cache rather than through a `SparseIndex`
+    if let Some(local) = registry.local_index() {
+        let cache = index::IndexCache::at_path(index_root.to_owned());
+        let krates = krates.to_vec();
+        let config_dir = config_dir.to_owned();
+
+        crate::rt::spawn_blocking(move || {
+            write_local_entries(&cache, &local, &krates, &config_dir);
+        })
+        .await;
+        return Ok(());
+    }
+
+    let location = index::IndexLocation {
+        url: index::IndexUrl::NonCratesIo(registry.index.as_str().into()),
+        root: index::IndexPath::Exact(index_root.to_owned()),
+    };
+
+    let index_url = registry.index.as_str();
+    let index =
+        index::AsyncRemoteSparseIndex::new(index::SparseIndex::new(location)?, client.clone());
+
+    #[allow(unsafe_code)]
+    // SAFETY: we don't forget the future :p
+    unsafe {
+        crate::rt::Scope::scope_and_collect(|s| {
+            s.spawn(async {
+                // The index requests all of the crates in a batch concurrently,
+                // so split them into batches no larger than the request limit
+                for chunk in krates.chunks(limit.max()) {
+                    let _permits = limit.acquire_many(chunk.len()).await;
+
+                    // We don't particularly care if an individual crate fails here
+                    // since the index will be healed by cargo, but still good to
+                    // know if something was amiss
+                    for (name, res) in index
+                        .krates(
+                            chunk.iter().cloned().collect(),
+                            true,
+                            None,
+                            &tame_index::index::FileLock::unlocked(),
+                        )
+                        .await
+                    {
+                        match res {
+                            Ok(Some(_)) => {}
+                            Ok(None) => {
+                                warn!("index entry for '{name}' was not found");
+                            }
+                            Err(err) => {
+                                warn!("unable to write .cache entry for '{name}': {err:#}");
+                            }
+                        }
+                    }
+                }
+            });
+
+            s.spawn(async {
+                let write_config = async {
+                    let url = format!("{}config.json", index_url.split_once('+').unwrap().1);
+
+                    let _permit = limit.acquire().await;
+                    let res =
+                        util::send_request_with_retry(client, client.get(&url).build()?, retry)
+                            .await
+                            .context("failed to send request for config.json")?
+                            .error_for_status()?;
+
+                    let config_body = res
+                        .bytes()
+                        .await
+                        .context("failed to read config.json response body")?;
+
+                    std::fs::write(config_dir.join("config.json"), &config_body)
+                        .context("failed to write config.json")
+                };
+
+                if let Err(err) = write_config.await {
+                    warn!("unable to write config.json: {err:#}");
+                }
+            });
+        })
+        .await;
+    }
+
+    Ok(())
+}
+
+/// The revision cargo stores in `.cache` entries when the index doesn't send an
+/// etag or last-modified header, which is always the case for `file://` urls
+const LOCAL_REVISION: &str = "Unknown";
+
+/// Gets the path of a `file://` url, eg. the download url of a crate from a
+/// local registry
+fn local_path(url: &str) -> Option<crate::PathBuf> {
+    let url = url::Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "file")?;
+    crate::PathBuf::from_path_buf(url.to_file_path().ok()?).ok()
+}
+
+/// Reads the index entry for the crate from the directory of a local sparse
+/// registry, which has the same layout as the paths requested from a remote one
+fn local_index_entry(
+    local: &crate::Path,
+    name: &str,
+) -> anyhow::Result<Option<tame_index::IndexKrate>> {
+    let kname: tame_index::KrateName<'_> = name.try_into()?;
+    let path = local.join(kname.relative_path(Some('/')));
+
+    match std::fs::read(&path) {
+        Ok(entry) => Ok(Some(
+            tame_index::IndexKrate::from_slice(&entry)
+                .with_context(|| format!("failed to parse '{path}'"))?,
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read '{path}'")),
+    }
+}
+
+/// The equivalent of [`write_sparse_entries`] for a local sparse registry,
+/// reading the entries and `config.json` from disk
+fn write_local_entries(
+    cache: &tame_index::index::IndexCache,
+    local: &crate::Path,
+    krates: &[String],
+    config_dir: &crate::Path,
+) {
+    let unlocked = &tame_index::index::FileLock::unlocked();
+    for name in krates {
+        match local_index_entry(local, name) {
+            Ok(Some(ik)) => {
+                if let Err(err) = cache.write_to_cache(&ik, LOCAL_REVISION, unlocked) {
+                    warn!("unable to write .cache entry for '{name}': {err:#}");
+                }
+            }
+            Ok(None) => warn!("index entry for '{name}' was not found"),
+            Err(err) => warn!("unable to read index entry for '{name}': {err:#}"),
+        }
+    }
+
+    if let Err(err) = std::fs::copy(local.join("config.json"), config_dir.join("config.json")) {
+        warn!("unable to write config.json: {err:#}");
+    }
+}
+
+/// Private sparse registries require the token to be sent with every request,
+/// including config.json, the same as cargo's `cargo:token` credential provider
+/// does, so we build a client that sends it by default
+pub(crate) fn authenticated_client(
+    registry: &crate::cargo::Registry,
+    http: &util::HttpConfig,
+) -> anyhow::Result<Option<crate::HttpClient>> {
+    let Some(token) = registry
+        .token
+        .as_ref()
+        .filter(|_| registry.protocol == crate::cargo::RegistryProtocol::Sparse)
+    else {
+        return Ok(None);
+    };
+
+    let mut auth = reqwest::header::HeaderValue::from_str(token.expose())
+        .context("registry token is not a valid header value")?;
+    auth.set_sensitive(true);
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::AUTHORIZATION, auth);
+
+    let client = http
+        .upstream_client_builder()
+        .default_headers(headers)
+        .build()
+        .context("failed to build authenticated client")?;
+    Ok(Some(client))
+}
+
+/// Retrieves the index entries for the specified crates, without writing
+/// anything that persists beyond the call other than sparse cache entries
+#[tracing::instrument(level = "debug", skip_all, fields(registry = %registry.index))]
+pub(crate) async fn index_entries(
+    client: &crate::HttpClient,
+    registry: &crate::cargo::Registry,
+    krates: std::collections::BTreeSet<String>,
+    limit: &util::RequestLimit,
+    http: &util::HttpConfig,
+    sparse_cache: Option<&crate::Path>,
+) -> anyhow::Result<std::collections::BTreeMap<String, tame_index::IndexKrate>> {
+    use tame_index::index;
+
+    if let Some(local) = registry.local_index() {
+        return crate::rt::spawn_blocking(move || {
+            let mut entries = std::collections::BTreeMap::new();
+            for name in krates {
+                match local_index_entry(&local, &name) {
+                    Ok(Some(ik)) => {
+                        entries.insert(name, ik);
+                    }
+                    Ok(None) => warn!("index entry for '{name}' was not found"),
+                    Err(err) => warn!("unable to read index entry for '{name}': {err:#}"),
+                }
+            }
+
+            Ok(entries)
+        })
+        .await;
+    }
+
+    let temp_dir = tempfile::tempdir()?;
+    let temp_dir_path = util::path(temp_dir.path())?;
+
+    let auth_client = authenticated_client(registry, http)?;
+    let client = auth_client.as_ref().unwrap_or(client);
+
+    // Reusing the persistent cache means entries that haven't changed since
+    // the last run are revalidated rather than downloaded again
+    let root = sparse_cache
+        .filter(|_| registry.protocol == crate::cargo::RegistryProtocol::Sparse)
+        .map_or_else(
+            || temp_dir_path.to_owned(),
+            |dir| dir.join(registry.short_name()),
+        );
+    std::fs::create_dir_all(&root).with_context(|| format!("unable to create '{root}'"))?;
+
+    let location = index::IndexLocation {
+        url: index::IndexUrl::NonCratesIo(registry.index.as_str().to_owned().into()),
+        root: index::IndexPath::Exact(root),
+    };
+
+    let entries = match registry.protocol {
+        crate::cargo::RegistryProtocol::Git => {
+            let _permit = limit.acquire().await;
+
+            crate::rt::spawn_blocking(move || -> anyhow::Result<_> {
+                let unlocked = &index::FileLock::unlocked();
+                let rgi = index::RemoteGitIndex::new(
+                    index::GitIndex::new(location).context("unable to open git index")?,
+                    unlocked,
+                )
+                .context("failed to fetch")?;
+
+                let mut entries = std::collections::BTreeMap::new();
+                for name in krates {
+                    let Ok(kname) = name.as_str().try_into() else {
+                        warn!("crate name '{name}' is invalid");
+                        continue;
+                    };
+
+                    match rgi.krate(kname, false, unlocked) {
+                        Ok(Some(ik)) => {
+                            entries.insert(name, ik);
+                        }
+                        Ok(None) => warn!("index entry for '{name}' was not found"),
+                        Err(err) => warn!("unable to read index entry for '{name}': {err:#}"),
+                    }
+                }
+
+                Ok(entries)
+            })
+            .await?
         }
         crate::cargo::RegistryProtocol::Sparse => {
             let index = index::AsyncRemoteSparseIndex::new(
@@ -140,75 +509,124 @@ pub async fn registry(
                 client.clone(),
             );
 
-            #[allow(unsafe_code)]
-            // SAFETY: we don't forget the future :p
-            unsafe {
-                async_scoped::TokioScope::scope_and_collect(|s| {
-                    s.spawn(async {
-                        // We don't particularly care if an individual crate fails here
-                        // since the index will be healed by cargo, but still good to
-                        // know if something was amiss
-                        for (name, res) in index
-                            .krates(
-                                krates.into_iter().collect(),
-                                true,
-                                None,
-                                &tame_index::index::FileLock::unlocked(),
-                            )
-                            .await
-                        {
-                            match res {
-                                Ok(Some(_)) => {}
-                                Ok(None) => {
-                                    warn!("index entry for '{name}' was not found");
-                                }
-                                Err(err) => {
-                                    warn!("unable to write .cache entry for '{name}': {err:#}");
-                                }
-                            }
-                        }
-                    });
-
-                    s.spawn(async {
-                        let write_config = async {
-                            let url =
-                                format!("{}config.json", index_url.split_once('+').unwrap().1);
-
-                            let res = loop {
-                                let res = client.get(&url).send().await;
-
-                                match res {
-                                    Err(err)
-                                        if err.is_connect()
-                                            || err.is_timeout()
-                                            || err.is_request() =>
-                                    {
-                                        continue
-                                    }
-                                    Err(err) => Err(err)
-                                        .context("failed to send request for config.json")?,
-                                    Ok(res) => break res,
-                                }
-                            };
-
-                            let config_body = res
-                                .bytes()
-                                .await
-                                .context("failed to read config.json response body")?;
-
-                            std::fs::write(temp_dir.path().join("config.json"), &config_body)
-                                .context("failed to write config.json")
-                        };
+            let mut entries = std::collections::BTreeMap::new();
+            let krates: Vec<_> = krates.into_iter().collect();
+            for chunk in krates.chunks(limit.max()) {
+                let _permits = limit.acquire_many(chunk.len()).await;
 
-                        if let Err(err) = write_config.await {
-                            warn!("unable to write config.json: {err:#}");
+                for (name, res) in index
+                    .krates(
+                        chunk.iter().cloned().collect(),
+                        sparse_cache.is_some(),
+                        None,
+                        &index::FileLock::unlocked(),
+                    )
+                    .await
+                {
+                    match res {
+                        Ok(Some(ik)) => {
+                            entries.insert(name, ik);
                         }
-                    });
-                })
-                .await;
+                        Ok(None) => warn!("index entry for '{name}' was not found"),
+                        Err(err) => warn!("unable to read index entry for '{name}': {err:#}"),
+                    }
+                }
             }
+
+            entries
         }
     };
 
-    util::pack_tar(temp_dir_path)
+    Ok(entries)
+}
+
+/// Copies the `.cache` entries for the specified crates from a persistent
+/// sparse index cache into the directory that is packed into the snapshot.
+///
+/// The cache may contain entries for crates that are no longer used by any
+/// lockfile, so we only copy the ones that were requested.
+fn copy_cache_entries(
+    cache_root: &crate::Path,
+    krates: &[String],
+    dst: &crate::Path,
+) -> anyhow::Result<()> {
+    // Cache entries are stored with lowercased names, regardless of the casing
+    // used in the lockfile
+    let names: std::collections::BTreeSet<_> = krates.iter().map(|k| k.to_lowercase()).collect();
+
+    for entry in walkdir::WalkDir::new(cache_root.join(".cache"))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| names.contains(name))
+        {
+            continue;
+        }
+
+        let rel_path = util::path(entry.path())?.strip_prefix(cache_root)?;
+        let dst_path = dst.join(rel_path);
+
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("unable to create '{parent}'"))?;
+        }
+
+        std::fs::copy(entry.path(), &dst_path)
+            .with_context(|| format!("unable to copy '{rel_path}'"))?;
+    }
+
+    Ok(())
+}
+
+/// Packs a crate patched with a local path into a tarball.
+///
+/// Build output and version control directories are excluded, everything else
+/// is included as is, since unlike `cargo package` we don't know which files
+/// the crate actually needs.
+pub(crate) fn path_patch(
+    patch: &crate::cargo::PathPatch,
+    compression: util::Compression,
+) -> anyhow::Result<Bytes> {
+    const EXCLUDED: &[&str] = &["target", ".git"];
+
+    anyhow::ensure!(
+        patch.path.join("Cargo.toml").exists(),
+        "'{}' does not contain a Cargo.toml",
+        patch.path
+    );
+
+    let temp_dir = tempfile::tempdir()?;
+    let temp_dir_path = util::path(temp_dir.path())?;
+
+    for entry in walkdir::WalkDir::new(&patch.path)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1
+                || !entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| EXCLUDED.contains(&name))
+        })
+    {
+        let entry = entry?;
+        let rel_path = util::path(entry.path())?.strip_prefix(&patch.path)?;
+        let dst_path = temp_dir_path.join(rel_path);
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&dst_path)
+                .with_context(|| format!("unable to create '{dst_path}'"))?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &dst_path)
+                .with_context(|| format!("unable to copy '{rel_path}'"))?;
+        }
+    }
+
+    util::pack_tar(temp_dir_path, compression)
 }
@@ -6,50 +6,365 @@ pub struct GitPackage {
     pub db: bytes::Bytes,
     /// The tarball of the checked out repository, including all submodules
     pub checkout: Option<bytes::Bytes>,
+    /// The db was fetched into the base clone from the options, so it only
+    /// differs from it by the objects that were fetched
+    pub from_base: bool,
+}
+
+/// How git sources are cloned
+#[derive(Clone, Debug, Default)]
+pub struct CloneOptions {
+    /// If the revision no longer exists in the remote, eg. due to a force
+    /// push, the current head of the branch or tag the source follows is used
+    /// instead
+    pub follow_fallback: bool,
+    /// Fetching the repository and its submodules is interrupted if it takes
+    /// longer than this in total
+    pub timeout: Option<std::time::Duration>,
+    /// Fetches the full history and all tags of the repository, rather than
+    /// only the locked revision
+    pub full_history: bool,
+    /// The private key used to authenticate with ssh remotes, rather than the
+    /// keys offered by the ssh agent or the ssh configuration
+    pub ssh_key: Option<PathBuf>,
+    /// The proxy that https remotes are fetched through, rather than the one,
+    /// if any, from the git configuration or the environment. SOCKS5 proxies
+    /// are supported the same as git's `http.proxy`, ssh remotes are never
+    /// fetched through the proxy.
+    pub proxy: Option<url::Url>,
+    /// A PEM bundle of root certificates trusted for https remotes, in
+    /// addition to the system ones
+    pub ca_certificates: Option<PathBuf>,
+    /// A bare clone of the repository, eg. of a previously mirrored revision,
+    /// that the revision is fetched into, so that only the objects it doesn't
+    /// already have are fetched, as a new pack alongside the existing ones
+    pub base: Option<PathBuf>,
 }
 
 const DIR: gix::remote::Direction = gix::remote::Direction::Fetch;
 use gix::progress::Discard;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The options used to open or create the repositories that are fetched into.
+/// The configuration of the git installation is loaded so that its credential
+/// helpers are consulted for private remotes, while the ssh key, proxy, and CA
+/// certificates, if specified, take precedence over that configuration
+fn open_options(options: &CloneOptions) -> gix::open::Options {
+    let mut overrides = Vec::new();
+    if let Some(key) = &options.ssh_key {
+        overrides.push(format!(
+            "core.sshCommand=ssh -i '{key}' -o IdentitiesOnly=yes"
+        ));
+    }
+    if let Some(proxy) = &options.proxy {
+        overrides.push(format!("http.proxy={proxy}"));
+    }
+    if let Some(ca) = &options.ca_certificates {
+        overrides.push(format!("http.sslCAInfo={ca}"));
+    }
+
+    gix::open::Options::default()
+        .permissions(gix::open::Permissions {
+            config: gix::open::permissions::Config {
+                git_binary: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .config_overrides(overrides)
+}
+
+/// Runs the git operations for a single repository, setting the interrupt flag
+/// passed to them if they haven't completed before the timeout, so that an
+/// unresponsive server can't hold a worker thread forever
+fn with_deadline<T>(
+    timeout: Option<std::time::Duration>,
+    op: impl FnOnce(&AtomicBool) -> Result<T>,
+) -> Result<T> {
+    let interrupt = AtomicBool::new(false);
+
+    let Some(timeout) = timeout else {
+        return op(&interrupt);
+    };
+
+    let res = std::thread::scope(|s| {
+        let (done, wait) = crossbeam_channel::bounded::<()>(0);
+        let interrupt = &interrupt;
+
+        s.spawn(move || {
+            // The sender is dropped when the operation completes, so this only
+            // times out if the operation is still in progress
+            if let Err(crossbeam_channel::RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+                interrupt.store(true, Ordering::Relaxed);
+            }
+        });
+
+        let res = op(interrupt);
+        drop(done);
+        res
+    });
+
+    res.map_err(|err| {
+        if interrupt.load(Ordering::Relaxed) {
+            err.context(format!("git operation timed out after {timeout:?}"))
+        } else {
+            err
+        }
+    })
+}
 
 /// Clones the git source and all of its submodules
 ///
 /// The bare git clone acts as the source for `$CARGO_HOME/git/db/*`
 /// The checkout and submodules clones act as the source for `$CARGO_HOME/git/checkouts/*`
-#[tracing::instrument(level = "debug")]
-pub fn clone(src: &crate::cargo::GitSource) -> Result<GitPackage> {
+///
+/// Unless the full history is requested, only the locked revision is fetched,
+/// falling back to a full clone if the remote doesn't allow fetching it
+/// directly, or the revision is abbreviated
+///
+/// Paths matching the `exclusions` are left out of the checkout tarball, but
+/// never the db, which remains the complete source of the checkout
+#[tracing::instrument(level = "debug", skip_all, fields(url = %src.url, rev = %src.rev))]
+pub fn clone(
+    src: &crate::cargo::GitSource,
+    options: &CloneOptions,
+    compression: util::Compression,
+    exclusions: &util::PathExclusions,
+) -> Result<GitPackage> {
+    with_deadline(options.timeout, |interrupt| {
+        clone_with_interrupt(src, options, compression, exclusions, interrupt)
+    })
+}
+
+/// Fetches the repository into a bare clone, either with its full history and
+/// all tags, or only the specified revision without any history
+fn fetch(
+    url: &str,
+    path: &std::path::Path,
+    rev: Option<gix::ObjectId>,
+    options: &CloneOptions,
+    interrupt: &AtomicBool,
+) -> Result<gix::Repository> {
+    let span = tracing::debug_span!("fetch", shallow = rev.is_some());
+    let _fs = span.enter();
+
+    let prepare = gix::clone::PrepareFetch::new(
+        url,
+        path,
+        gix::create::Kind::Bare,
+        Default::default(),
+        open_options(options),
+    )
+    .context("failed to prepare clone")?
+    .with_remote_name("origin")?;
+
+    let mut prepare = match rev {
+        Some(rev) => prepare
+            .with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(
+                std::num::NonZeroU32::MIN,
+            ))
+            .configure_remote(move |remote| {
+                let refspec = format!("+{rev}:refs/remotes/origin/HEAD");
+                Ok(remote
+                    .with_fetch_tags(gix::remote::fetch::Tags::None)
+                    .with_refspecs([refspec.as_str()], DIR)?)
+            }),
+        None => prepare.configure_remote(|remote| {
+            Ok(remote
+                .with_fetch_tags(gix::remote::fetch::Tags::All)
+                .with_refspecs(["+HEAD:refs/remotes/origin/HEAD"], DIR)?)
+        }),
+    };
+
+    let (repo, _out) = prepare
+        .fetch_only(&mut Discard, interrupt)
+        .context("failed to fetch")?;
+    Ok(repo)
+}
+
+/// Fetches the revision, or the full history and all tags, into an existing
+/// bare clone, so that only the objects it doesn't have are transferred
+fn fetch_into(
+    url: &str,
+    path: &std::path::Path,
+    rev: Option<gix::ObjectId>,
+    options: &CloneOptions,
+    interrupt: &AtomicBool,
+) -> Result<gix::Repository> {
+    let span = tracing::debug_span!("fetch_into", shallow = rev.is_some());
+    let _fs = span.enter();
+
+    let repo = gix::open_opts(path, open_options(options)).context("failed to open base clone")?;
+
+    {
+        let (refspec, tags) = match rev {
+            Some(rev) => (
+                format!("+{rev}:refs/remotes/origin/HEAD"),
+                gix::remote::fetch::Tags::None,
+            ),
+            None => (
+                "+HEAD:refs/remotes/origin/HEAD".to_owned(),
+                gix::remote::fetch::Tags::All,
+            ),
+        };
+
+        let remote = repo
+            .remote_at(url)
+            .context("invalid remote url")?
+            .with_fetch_tags(tags)
+            .with_refspecs([refspec.as_str()], DIR)
+            .context("invalid refspec")?;
+
+        let mut prepare = remote
+            .connect(DIR)
+            .context("failed to connect to remote")?
+            .prepare_fetch(&mut Discard, Default::default())
+            .context("failed to prepare fetch")?;
+
+        // Keep a shallow base shallow, rather than fetching the history of
+        // the revision that it doesn't have
+        if rev.is_some() && repo.is_shallow() {
+            prepare = prepare.with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(
+                std::num::NonZeroU32::MIN,
+            ));
+        }
+
+        prepare
+            .receive(&mut Discard, interrupt)
+            .context("failed to fetch")?;
+    }
+
+    Ok(repo)
+}
+
+fn clone_with_interrupt(
+    src: &crate::cargo::GitSource,
+    options: &CloneOptions,
+    compression: util::Compression,
+    exclusions: &util::PathExclusions,
+    interrupt: &AtomicBool,
+) -> Result<GitPackage> {
     // Create a temporary directory to fetch the repo into
-    let temp_dir = tempfile::tempdir()?;
+    let mut temp_dir = tempfile::tempdir()?;
     // Create another temporary directory where we *may* checkout submodules into
     let submodule_dir = tempfile::tempdir()?;
 
-    let (repo, _out) = {
-        let span = tracing::debug_span!("fetch");
-        let _fs = span.enter();
-        gix::prepare_clone_bare(src.url.as_str(), temp_dir.path())
-            .context("failed to prepare clone")?
-            .with_remote_name("origin")?
-            .configure_remote(|remote| {
-                Ok(remote
-                    .with_fetch_tags(gix::remote::fetch::Tags::All)
-                    .with_refspecs(["+HEAD:refs/remotes/origin/HEAD"], DIR)?)
-            })
-            .fetch_only(&mut Discard, &Default::default())
-            .context("failed to fetch")?
+    // Abbreviated revisions can only be resolved with the full history
+    let shallow_rev = (!options.full_history && src.rev.prefix().is_none()).then_some(src.rev.id);
+
+    let base = match &options.base {
+        Some(base) => match fetch_into(
+            src.url.as_str(),
+            base.as_std_path(),
+            shallow_rev,
+            options,
+            interrupt,
+        )
+        .and_then(|repo| {
+            resolve(&repo, &src.rev)?;
+            Ok(repo)
+        }) {
+            Ok(repo) => Some(repo),
+            Err(err) if interrupt.load(Ordering::Relaxed) => return Err(err),
+            Err(err) => {
+                tracing::debug!(
+                    "unable to fetch into the base clone, falling back to a new clone: {err:#}"
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let from_base = base.is_some();
+
+    let shallow = if base.is_some() || shallow_rev.is_none() {
+        None
+    } else {
+        match fetch(
+            src.url.as_str(),
+            temp_dir.path(),
+            Some(src.rev.id),
+            options,
+            interrupt,
+        )
+        .and_then(|repo| {
+            repo.find_object(src.rev.id)
+                .context("rev is missing from the shallow clone")?;
+            Ok(repo)
+        }) {
+            Ok(repo) => Some(repo),
+            // Don't bother with a full clone if we've already run out of time
+            Err(err) if interrupt.load(Ordering::Relaxed) => return Err(err),
+            Err(err) => {
+                tracing::debug!(
+                    "unable to fetch rev shallowly, falling back to a full clone: {err:#}"
+                );
+                temp_dir = tempfile::tempdir()?;
+                None
+            }
+        }
+    };
+
+    let repo = match base.or(shallow) {
+        Some(repo) => repo,
+        None => fetch(src.url.as_str(), temp_dir.path(), None, options, interrupt)?,
     };
 
     // Ensure that the repo actually contains the revision we need
-    repo.find_object(src.rev.id).with_context(|| {
-        format!(
-            "'{}' doesn't contain rev '{}'",
-            src.url,
-            src.rev.id.to_hex()
-        )
-    })?;
+    let fetch_rev = match resolve(&repo, &src.rev) {
+        Ok(id) => id,
+        Err(err) => {
+            let err = err.context(format!("'{}' doesn't contain rev '{}'", src.url, src.rev));
 
-    let fetch_rev = src.rev.id;
-    let temp_db_path = util::path(temp_dir.path())?;
+            let Some(follow) = src.follow.as_ref().filter(|_| options.follow_fallback) else {
+                return Err(err);
+            };
+
+            let (kind, name, ref_name) = match follow {
+                crate::cargo::GitFollow::Branch(b) => {
+                    ("branch", b, format!("refs/remotes/origin/{b}"))
+                }
+                crate::cargo::GitFollow::Tag(t) => ("tag", t, format!("refs/tags/{t}")),
+            };
+
+            let head = match repo.find_reference(ref_name.as_str()) {
+                Ok(mut reference) => reference
+                    .peel_to_id_in_place()
+                    .with_context(|| format!("failed to resolve {kind} '{name}'"))?
+                    .detach(),
+                Err(ref_err) => {
+                    return Err(
+                        err.context(format!("unable to fall back to {kind} '{name}': {ref_err}"))
+                    );
+                }
+            };
+
+            tracing::warn!(
+                "'{}' doesn't contain rev '{}', falling back to the current head of {kind} '{name}' ({head}), the lockfile is out of date with the remote",
+                src.url,
+                src.rev,
+            );
+
+            head
+        }
+    };
+    let temp_db_path = match options.base.as_deref().filter(|_| from_base) {
+        Some(base) => base,
+        None => util::path(temp_dir.path())?,
+    };
     let sub_dir_path = util::path(submodule_dir.path())?;
 
+    // Files in the checkout are given the time of the commit rather than when
+    // they happened to be checked out, so that the tarball for a revision is
+    // always the same, and `sync --preserve-mtime` gives the same mtimes on
+    // every machine
+    let commit_time = repo
+        .find_object(fetch_rev)
+        .ok()
+        .and_then(|obj| obj.try_into_commit().ok())
+        .and_then(|commit| commit.time().ok())
+        .map(|time| time.seconds.max(0) as u64);
+
     let (checkout, db) = rayon::join(
         || -> anyhow::Result<_> {
             let span = tracing::info_span!("cloning submodules", %src.url);
@@ -59,15 +374,18 @@ pub fn clone(src: &crate::cargo::GitSource) -> Result<GitPackage> {
                 temp_db_path.to_owned(),
                 sub_dir_path.to_owned(),
                 fetch_rev,
+                options,
+                interrupt,
             )?;
 
-            util::pack_tar(sub_dir_path)
+            util::pack_tar_excluding(sub_dir_path, compression, exclusions, commit_time)
         },
-        || -> anyhow::Result<_> { util::pack_tar(temp_db_path) },
+        || -> anyhow::Result<_> { util::pack_tar(temp_db_path, compression) },
     );
 
     Ok(crate::git::GitPackage {
         db: db?,
+        from_base,
         checkout: match checkout {
             Ok(co) => Some(co),
             Err(err) => {
@@ -78,6 +396,70 @@ pub fn clone(src: &crate::cargo::GitSource) -> Result<GitPackage> {
     })
 }
 
+/// Resolves the full id of the revision, which may only be abbreviated in
+/// older lockfiles
+pub(crate) fn resolve(repo: &gix::Repository, rev: &crate::cargo::GitRev) -> Result<gix::ObjectId> {
+    let Some(prefix) = rev.prefix() else {
+        repo.find_object(rev.id)?;
+        return Ok(rev.id);
+    };
+
+    match repo
+        .objects
+        .lookup_prefix(prefix, None)
+        .context("failed to look up abbreviated rev")?
+    {
+        Some(Ok(id)) => {
+            tracing::debug!("resolved abbreviated rev '{prefix}' to '{id}'");
+            Ok(id)
+        }
+        Some(Err(())) => anyhow::bail!("abbreviated rev '{prefix}' is ambiguous"),
+        None => anyhow::bail!("abbreviated rev '{prefix}' was not found"),
+    }
+}
+
+/// Validates that the db actually contains the revision, eg. that it isn't a
+/// stale db uploaded before the revision was fetched, or truncated. If
+/// `connectivity` is set, also checks that every tree and blob reachable from
+/// the revision's tree is present, so that it can be checked out.
+pub(crate) fn validate(
+    repo: &gix::Repository,
+    rev: gix::ObjectId,
+    connectivity: bool,
+) -> Result<()> {
+    let commit = repo
+        .find_object(rev)
+        .with_context(|| format!("rev '{rev}' is missing"))?
+        .try_into_commit()
+        .with_context(|| format!("rev '{rev}' is not a commit"))?;
+
+    if !connectivity {
+        return Ok(());
+    }
+
+    let tree = commit
+        .tree()
+        .with_context(|| format!("the tree of rev '{rev}' is missing"))?;
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    tree.traverse()
+        .breadthfirst(&mut recorder)
+        .with_context(|| format!("the tree of rev '{rev}' is incomplete"))?;
+
+    // Submodules are commits in other repositories, so aren't checked
+    if let Some(missing) = recorder
+        .records
+        .iter()
+        .find(|entry| entry.mode.is_blob_or_symlink() && !repo.has_object(entry.oid))
+    {
+        anyhow::bail!(
+            "'{}' is missing from the tree of rev '{rev}'",
+            missing.filepath
+        );
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument(level = "debug")]
 pub(crate) fn checkout(
     src: PathBuf,
@@ -275,9 +657,20 @@ fn reset(repo: &mut gix::Repository, rev: gix::ObjectId) -> Result<()> {
     Ok(())
 }
 
-#[tracing::instrument(level = "debug")]
-pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::ObjectId) -> Result<()> {
-    fn update_submodules(repo: &mut gix::Repository, rev: gix::ObjectId) -> Result<()> {
+#[tracing::instrument(level = "debug", skip(options, interrupt))]
+pub(crate) fn prepare_submodules(
+    src: PathBuf,
+    target: PathBuf,
+    rev: gix::ObjectId,
+    options: &CloneOptions,
+    interrupt: &AtomicBool,
+) -> Result<()> {
+    fn update_submodules(
+        repo: &mut gix::Repository,
+        rev: gix::ObjectId,
+        options: &CloneOptions,
+        interrupt: &AtomicBool,
+    ) -> Result<()> {
         // We only get here if checkout succeeds, so we're guaranteed to have a working dir
         let work_dir = repo.work_dir().unwrap().to_owned();
 
@@ -383,7 +776,10 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
         let mut res = Vec::new();
         submodules
             .into_par_iter()
-            .map(|subm| update_submodule(&work_dir, subm).context("failed to update submodule"))
+            .map(|subm| {
+                update_submodule(&work_dir, subm, options, interrupt)
+                    .context("failed to update submodule")
+            })
             .collect_into_vec(&mut res);
 
         res.into_iter().collect::<Result<()>>()?;
@@ -391,7 +787,12 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
-    fn update_submodule(parent: &std::path::Path, subm: Submodule) -> Result<()> {
+    fn update_submodule(
+        parent: &std::path::Path,
+        subm: Submodule,
+        options: &CloneOptions,
+        interrupt: &AtomicBool,
+    ) -> Result<()> {
         // A submodule which is listed in .gitmodules but not actually
         // checked out will not have a head id, so we should ignore it.
         let Some(head) = subm.head_id else {
@@ -402,19 +803,10 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
         let submodule_path = parent.join(subm.path());
 
         let open_or_init_repo = || -> Result<_> {
-            let open_with_complete_config =
-                gix::open::Options::default().permissions(gix::open::Permissions {
-                    config: gix::open::permissions::Config {
-                        // Be sure to get all configuration, some of which is only known by the git binary.
-                        // That way we are sure to see all the systems credential helpers
-                        git_binary: true,
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                });
-
-            let repo = if let Ok(repo) = gix::open_opts(&submodule_path, open_with_complete_config)
-            {
+            // Be sure to get all configuration, some of which is only known by
+            // the git binary, both when opening and creating the repository.
+            // That way we are sure to see all the systems credential helpers
+            let repo = if let Ok(repo) = gix::open_opts(&submodule_path, open_options(options)) {
                 repo
             } else {
                 // Blow away the submodules directory in case it exists but is
@@ -422,7 +814,14 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
                 // is an error the init or subsequent clone _might_ fail but also
                 // might not!
                 let _ = remove_dir_all::remove_dir_all(&submodule_path);
-                gix::init(&submodule_path).context("failed to init submodule")?
+                gix::ThreadSafeRepository::init_opts(
+                    &submodule_path,
+                    gix::create::Kind::WithWorktree,
+                    Default::default(),
+                    open_options(options),
+                )
+                .context("failed to init submodule")?
+                .to_thread_local()
             };
 
             Ok(repo)
@@ -435,9 +834,9 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
         if repo
             .head_commit()
             .ok()
-            .map_or(false, |commit| commit.id == head)
+            .is_some_and(|commit| commit.id == head)
         {
-            return update_submodules(&mut repo, head);
+            return update_submodules(&mut repo, head, options, interrupt);
         }
 
         // We perform fetches and update the reflog, and gix forces us to set a
@@ -486,16 +885,16 @@ pub(crate) fn prepare_submodules(src: PathBuf, target: PathBuf, rev: gix::Object
             .context("failed to connect to remote")?
             .prepare_fetch(&mut Discard, Default::default())
             .context("failed to prepare fetch")?
-            .receive(&mut Discard, &Default::default())
+            .receive(&mut Discard, interrupt)
             .context("failed to fetch submodule")?;
 
         tame_index::utils::git::write_fetch_head(&repo, &outcome, &remote)
             .context("failed to write FETCH_HEAD")?;
 
         reset(&mut repo, head)?;
-        update_submodules(&mut repo, head)
+        update_submodules(&mut repo, head, options, interrupt)
     }
 
     let mut repo = checkout(src, target, rev)?;
-    update_submodules(&mut repo, rev)
+    update_submodules(&mut repo, rev, options, interrupt)
 }
@@ -0,0 +1,80 @@
+//! Commands run after a successful `sync` or `mirror`, so that small site
+//! specific steps, eg. fixing up ownership or warming a compiler cache, don't
+//! require a wrapper script
+
+use anyhow::{Context as _, Error};
+use std::io::Write as _;
+use tracing::info;
+
+/// Runs each hook in order with the platform's shell, writing the summary of
+/// the run, in the same format as `--output-summary`, to its stdin. The kind of
+/// hook, eg. `post-sync`, is available in the `CARGO_FETCHER_HOOK` environment
+/// variable. Fails on the first hook that doesn't exit successfully.
+pub fn run(kind: &str, hooks: &[String], summary: &str) -> Result<(), Error> {
+    for hook in hooks {
+        info!("running {kind} hook '{hook}'");
+
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = std::process::Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = std::process::Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+
+        let mut child = cmd
+            .arg(hook)
+            .env("CARGO_FETCHER_HOOK", kind)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn {kind} hook '{hook}'"))?;
+
+        // Hooks aren't required to read the summary
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(err) = stdin.write_all(summary.as_bytes()) {
+                anyhow::ensure!(
+                    err.kind() == std::io::ErrorKind::BrokenPipe,
+                    "failed to write summary to {kind} hook '{hook}': {err}"
+                );
+            }
+        }
+
+        let status = child
+            .wait()
+            .with_context(|| format!("failed to wait for {kind} hook '{hook}'"))?;
+        anyhow::ensure!(
+            status.success(),
+            "{kind} hook '{hook}' failed with {status}"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = crate::util::path(dir.path()).unwrap().join("summary.json");
+
+        run(
+            "post-sync",
+            &[
+                format!("cat > '{out}'"),
+                "test \"$CARGO_FETCHER_HOOK\" = post-sync".to_owned(),
+            ],
+            "{\"failed\":0}",
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "{\"failed\":0}");
+
+        // Hooks that don't read stdin, and those that fail
+        run("post-sync", &["true".to_owned()], "{}").unwrap();
+        assert!(run("post-sync", &["exit 3".to_owned()], "{}").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! Builds the cargo home layout that `sync` would produce directly into a
+//! read-only filesystem image, rather than writing loose files to the host.
+//!
+//! The layout is streamed as a tarball to the external tool that creates the
+//! image, `mksquashfs` (squashfs-tools 4.6+) or `mkfs.erofs` (erofs-utils
+//! 1.7+), so the only file written to the host is the image itself.
+
+use crate::{sync, util, Ctx, Krate, Path, Source};
+use anyhow::{Context as _, Error};
+use std::io::{self, Read};
+use tracing::{debug, error, info};
+
+/// The maximum number of objects that are downloaded, or waiting to be written
+/// to the image, at once. The request limit is unlimited by default, so it
+/// can't be used to bound the objects held in memory.
+const QUEUE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Squashfs,
+    Erofs,
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "squashfs" => Ok(Self::Squashfs),
+            "erofs" => Ok(Self::Erofs),
+            unknown => anyhow::bail!("unknown image format '{unknown}'"),
+        }
+    }
+}
+
+impl Format {
+    /// Uses the explicitly specified format, or infers it from the extension
+    /// of the image path
+    pub fn resolve(format: Option<Self>, path: &Path) -> Result<Self, Error> {
+        format.or_else(|| Self::from_path(path)).with_context(|| {
+            format!("unable to infer the image format of '{path}' from its extension")
+        })
+    }
+
+    /// Infers the format from the extension of the image path
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()? {
+            "squashfs" | "sqfs" => Some(Self::Squashfs),
+            "erofs" => Some(Self::Erofs),
+            _ => None,
+        }
+    }
+
+    /// The command that reads a tarball from stdin and writes the image
+    fn command(self, out: &Path) -> std::process::Command {
+        match self {
+            Self::Squashfs => {
+                let mut cmd = std::process::Command::new("mksquashfs");
+                cmd.arg("-").arg(out).args(["-tar", "-noappend", "-quiet"]);
+                cmd
+            }
+            Self::Erofs => {
+                let mut cmd = std::process::Command::new("mkfs.erofs");
+                cmd.arg("--tar=f").arg(out);
+                cmd
+            }
+        }
+    }
+}
+
+/// Writes entries into the image, as a tarball streamed to the image tool
+pub struct ImageWriter {
+    builder: tar::Builder<std::process::ChildStdin>,
+    child: std::process::Child,
+    format: Format,
+}
+
+impl ImageWriter {
+    pub fn new(format: Format, out: &Path) -> Result<Self, Error> {
+        let mut child = format
+            .command(out)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn the {format:?} image tool"))?;
+
+        let mut builder = tar::Builder::new(child.stdin.take().unwrap());
+        builder.follow_symlinks(false);
+        // Don't leak the ownership and times of temporary checkouts into the image
+        builder.mode(tar::HeaderMode::Deterministic);
+
+        Ok(Self {
+            builder,
+            child,
+            format,
+        })
+    }
+
+    /// Appends a single file
+    fn append_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        self.builder.append_data(&mut header, path, data)
+    }
+
+    /// Appends every entry in the tarball, with their paths rooted at `prefix`
+    fn append_tar<R: Read>(
+        &mut self,
+        prefix: &str,
+        reader: R,
+        encoding: util::Encoding,
+    ) -> Result<(), Error> {
+        let mut archive = util::read_tar(reader, encoding)?;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = format!("{prefix}/{}", entry.path()?.display());
+            let mut header = entry.header().clone();
+
+            match header.entry_type() {
+                // Hard links are relative to the root of the archive
+                tar::EntryType::Link => {
+                    let target = entry.link_name()?.context("hard link without a target")?;
+                    let target = format!("{prefix}/{}", target.display());
+                    self.builder.append_link(&mut header, &path, target)?;
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry.link_name()?.context("symlink without a target")?;
+                    self.builder.append_link(&mut header, &path, &target)?;
+                }
+                _ => {
+                    self.builder.append_data(&mut header, &path, &mut entry)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the image to be written
+    pub fn finish(self) -> Result<(), Error> {
+        let Self {
+            builder,
+            mut child,
+            format,
+        } = self;
+
+        // Closes stdin so the tool knows the tarball is complete
+        drop(builder.into_inner()?);
+
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+
+        let status = child.wait()?;
+        anyhow::ensure!(
+            status.success(),
+            "the {format:?} image tool failed ({status}): {}",
+            stderr.trim()
+        );
+
+        Ok(())
+    }
+}
+
+/// A downloaded object ready to be appended to the image
+enum Pkg {
+    Index {
+        dir: String,
+        data: bytes::Bytes,
+    },
+    Krate {
+        krate: Box<Krate>,
+        data: bytes::Bytes,
+        checkout: Option<bytes::Bytes>,
+    },
+}
+
+/// Appends a registry crate, both its tarball in `registry/cache` and the
+/// unpacked source in `registry/src`
+fn append_registry_crate(
+    image: &mut ImageWriter,
+    krate: &Krate,
+    data: &bytes::Bytes,
+    chksum: &str,
+    registry_dir: &str,
+) -> Result<(), Error> {
+    util::validate_mirrored_checksum(data, chksum)?;
+    let krate_dir = format!("{}-{}", krate.name, krate.version);
+    util::validate_crate_tarball(data, &krate_dir)?;
+
+    image.append_file(
+        &format!("{}/{registry_dir}/{}", sync::CACHE_DIR, krate.local_id()),
+        data,
+    )?;
+
+    let src_dir = format!("{}/{registry_dir}", sync::SRC_DIR);
+    image.append_tar(&src_dir, &data[..], util::Encoding::Gzip)?;
+    image.append_file(&format!("{src_dir}/{krate_dir}/.cargo-ok"), b"{\"v\":1}")?;
+
+    Ok(())
+}
+
+/// Appends a git source, both the bare clone in `git/db` and the checkout in
+/// `git/checkouts`
+fn append_git(
+    image: &mut ImageWriter,
+    krate: &Krate,
+    db: &bytes::Bytes,
+    checkout: Option<&bytes::Bytes>,
+    rev: &crate::cargo::GitRev,
+) -> Result<(), Error> {
+    let db_dir = format!("{}/{}", sync::GIT_DB_DIR, krate.local_id());
+    image.append_tar(&db_dir, &db[..], util::Encoding::Detect)?;
+
+    let co_dir = |short: &str| format!("{}/{}/{short}", sync::GIT_CO_DIR, krate.local_id());
+
+    let co_dir = if let Some(checkout) = checkout {
+        let co_dir = co_dir(rev.short());
+        image.append_tar(&co_dir, &checkout[..], util::Encoding::Detect)?;
+        co_dir
+    } else {
+        // Without a checkout tarball the checkout has to be done on disk, but
+        // this is only a single repository rather than the whole cargo home
+        let temp_dir = tempfile::tempdir()?;
+        let temp_path = util::path(temp_dir.path())?;
+        let db_path = temp_path.join("db");
+        util::unpack_tar(db.clone(), util::Encoding::Detect, &db_path)?;
+
+        let repo = gix::open(db_path.as_std_path()).context("failed to open git db")?;
+        let rev = rev.resolved(crate::git::resolve(&repo, rev)?);
+        let co_path = temp_path.join("checkout");
+        crate::git::checkout(db_path, co_path.clone(), rev.id)?;
+
+        let co_dir = co_dir(rev.short());
+        image.builder.append_dir_all(&co_dir, &co_path)?;
+        co_dir
+    };
+
+    image.append_file(&format!("{co_dir}/.cargo-ok"), b"")?;
+
+    Ok(())
+}
+
+/// Downloads every crate, and optionally the registry indices, and writes
+/// them into the image, in the same layout as `sync` writes to the cargo home
+pub async fn sync(
+    ctx: &Ctx,
+    include_index: bool,
+    format: Format,
+    out: &Path,
+) -> Result<sync::Summary, Error> {
+    let mut krates = ctx.krates.clone();
+    krates.sort();
+    krates.dedup();
+
+    // Crates from the same git repository and revision, eg. the members of a
+    // workspace, share a db and checkout, which can only be written once
+    let mut git_sources = std::collections::BTreeSet::new();
+    krates.retain(|krate| match &krate.source {
+        Source::Git(gs) => {
+            let first = git_sources.insert((krate.local_id().to_string(), gs.rev.clone()));
+            if !first {
+                debug!("{krate} shares its git source with another crate");
+            }
+            first
+        }
+        Source::Registry(_) => true,
+    });
+
+    info!("writing {} crates to {out}...", krates.len());
+
+    let mut image = ImageWriter::new(format, out)?;
+
+    // Entries are appended to the single tarball stream in the order they are
+    // downloaded, on a blocking thread since the image tool applies back
+    // pressure. Downloads keep their queue permit until they are queued, so
+    // at most twice the queue size of objects is held in memory.
+    let (tx, mut rx) = crate::rt::mpsc::channel::<Pkg>(QUEUE);
+    let queue = std::sync::Arc::new(crate::rt::Semaphore::new(QUEUE));
+
+    let mut tasks = crate::rt::JoinSet::new();
+
+    if include_index {
+        for registry in &ctx.registries {
+            let backend = ctx.index_backend().clone();
+            let limit = ctx.request_limit.clone();
+            let index_krate = registry.scoped_index_krate(ctx.index_scope.as_deref());
+            let dir = registry.short_name().to_owned();
+            let tx = tx.clone();
+            let queue = queue.clone();
+
+            tasks.spawn(async move {
+                let _queued = queue.acquire_owned().await;
+                let _permit = limit.acquire().await;
+                let res = match crate::delta::fetch_snapshot(&backend, &index_krate).await {
+                    Ok(data) => {
+                        let _ = tx.send(Pkg::Index { dir, data }).await;
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                };
+                // The index isn't a crate, so isn't counted as a failure
+                (None, res)
+            });
+        }
+    }
+
+    for krate in krates {
+        let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+        let tx = tx.clone();
+        let queue = queue.clone();
+
+        tasks.spawn(async move {
+            let _queued = queue.acquire_owned().await;
+            let _permit = limit.acquire().await;
+
+            let data = match &krate.source {
+                Source::Git(_) => crate::delta::fetch_git_db(&backend, &krate).await,
+                Source::Registry(_) => backend.fetch(krate.cloud_id(false)).await,
+            };
+            let data = match data {
+                Ok(data) => data,
+                Err(err) => return (Some(krate), Err(err)),
+            };
+
+            let checkout = if matches!(krate.source, Source::Git(_)) {
+                backend.fetch(krate.cloud_id(true)).await.ok()
+            } else {
+                None
+            };
+
+            let pkg = Pkg::Krate {
+                krate: Box::new(krate.clone()),
+                data,
+                checkout,
+            };
+            let _ = tx.send(pkg).await;
+            (Some(krate), Ok(()))
+        });
+    }
+    drop(tx);
+
+    let mut summary = sync::Summary {
+        total_bytes: 0,
+        bad: 0,
+        good: 0,
+        failed: Vec::new(),
+        interrupted: false,
+    };
+
+    let writer = std::thread::spawn(move || {
+        let mut results = Vec::new();
+
+        while let Some(pkg) = rx.blocking_recv() {
+            let (krate, res) = match pkg {
+                Pkg::Index { dir, data } => {
+                    if let Err(err) = image.append_tar(
+                        &format!("{}/{dir}", sync::INDEX_DIR),
+                        &data[..],
+                        util::Encoding::Detect,
+                    ) {
+                        error!("failed to write index '{dir}': {err:#}");
+                    }
+                    continue;
+                }
+                Pkg::Krate {
+                    krate,
+                    data,
+                    checkout,
+                } => {
+                    let len = data.len() + checkout.as_ref().map_or(0, |co| co.len());
+                    let res = match &krate.source {
+                        Source::Registry(rs) => append_registry_crate(
+                            &mut image,
+                            &krate,
+                            &data,
+                            &rs.chksum,
+                            rs.registry.short_name(),
+                        ),
+                        Source::Git(gs) => {
+                            append_git(&mut image, &krate, &data, checkout.as_ref(), &gs.rev)
+                        }
+                    };
+                    (*krate, res.map(|()| len))
+                }
+            };
+
+            results.push((krate, res));
+        }
+
+        (image.finish(), results)
+    });
+
+    while let Some(res) = tasks.join_next().await {
+        let Ok((krate, res)) = res else {
+            continue;
+        };
+
+        match (krate, res) {
+            (_, Ok(())) => {}
+            (Some(krate), Err(err)) => {
+                error!(krate = %krate, "failed to download: {err:#}");
+                summary.bad += 1;
+                summary.failed.push(krate);
+            }
+            (None, Err(err)) => error!("failed to download index: {err:#}"),
+        }
+    }
+
+    let (finished, results) = writer.join().unwrap();
+
+    for (krate, res) in results {
+        match res {
+            Ok(len) => {
+                debug!(bytes = len, "wrote {krate}");
+                summary.good += 1;
+                summary.total_bytes += len;
+            }
+            Err(err) => {
+                error!(krate = %krate, "failed to write to image: {err:#}");
+                summary.bad += 1;
+                summary.failed.push(krate);
+            }
+        }
+    }
+
+    finished.context("failed to create image")?;
+
+    Ok(summary)
+}
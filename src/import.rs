@@ -0,0 +1,284 @@
+//! Seeds the storage location from the crates and git repositories already
+//! present in a local `$CARGO_HOME`, eg. a developer machine, rather than
+//! downloading all of them again from their original sources
+
+use crate::{mirror, sync, util, Ctx, Krate, Path, PathBuf};
+use anyhow::{Context as _, Error};
+use std::{collections::HashSet, sync::Arc};
+use tracing::{debug, error, info, warn};
+
+pub struct Summary {
+    pub total_bytes: usize,
+    /// The number of objects uploaded to the storage location
+    pub uploaded: usize,
+    /// The number of objects that were already present
+    pub present: usize,
+    pub failed: usize,
+}
+
+/// An object in the cargo home that can be imported
+#[derive(Debug)]
+enum Candidate {
+    /// A crate tarball in `registry/cache`
+    Crate(PathBuf),
+    /// A bare clone in `git/db`, and one of its checkouts in `git/checkouts`
+    Git {
+        db: PathBuf,
+        checkout: PathBuf,
+        /// The id of the db object, which is the same as the mirror uses
+        id: String,
+    },
+}
+
+/// Iterates the subdirectories of the directory, ignoring any that can't be
+/// read, as cargo homes often accumulate junk
+fn subdirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            entry
+                .file_type()
+                .ok()
+                .filter(|ft| ft.is_dir())
+                .and_then(|_| util::path(&entry.path()).ok().map(Path::to_owned))
+        })
+}
+
+/// Gathers the crate tarballs and git checkouts in the cargo home
+fn candidates(cargo_home: &Path) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+
+    for registry_dir in subdirs(&cargo_home.join(sync::CACHE_DIR)) {
+        for entry in std::fs::read_dir(&registry_dir).into_iter().flatten() {
+            let Ok(path) = entry
+                .map_err(Error::from)
+                .and_then(|entry| util::path(&entry.path()).map(Path::to_owned))
+            else {
+                continue;
+            };
+
+            if path.extension() == Some("crate") {
+                candidates.push(Candidate::Crate(path));
+            }
+        }
+    }
+
+    // Only revisions that have been checked out are known, as the db itself
+    // contains every revision fetched from the remote
+    let db_dir = cargo_home.join(sync::GIT_DB_DIR);
+    for ident_dir in subdirs(&cargo_home.join(sync::GIT_CO_DIR)) {
+        let ident = ident_dir.file_name().unwrap_or_default().to_owned();
+        let db = db_dir.join(&ident);
+        if !db.exists() {
+            continue;
+        }
+
+        for checkout in subdirs(&ident_dir) {
+            if !checkout.join(".cargo-ok").exists() {
+                continue;
+            }
+
+            let short = checkout.file_name().unwrap_or_default();
+            candidates.push(Candidate::Git {
+                id: format!("{ident}-{short}"),
+                db: db.clone(),
+                checkout,
+            });
+        }
+    }
+
+    candidates
+}
+
+/// Packs the candidate into the objects uploaded for it, in the same format
+/// as `mirror` uses
+fn pack(
+    candidate: &Candidate,
+    compression: util::Compression,
+    exclusions: &util::PathExclusions,
+) -> Result<Vec<(String, bytes::Bytes)>, Error> {
+    match candidate {
+        Candidate::Crate(path) => {
+            let data = std::fs::read(path).with_context(|| format!("unable to read {path}"))?;
+            let krate_dir = path.file_stem().unwrap_or_default();
+
+            // Don't seed the mirror with a crate that was only partially written
+            util::validate_crate_tarball(&data, krate_dir)?;
+
+            Ok(vec![(util::sha256_hex(&data), data.into())])
+        }
+        Candidate::Git { db, checkout, id } => {
+            let (db, checkout) = rayon::join(
+                || util::pack_tar(db, compression),
+                || util::pack_tar_excluding(checkout, compression, exclusions, None),
+            );
+
+            Ok(vec![
+                (id.clone(), db.context("failed to pack git db")?),
+                (
+                    format!("{id}-checkout"),
+                    checkout.context("failed to pack git checkout")?,
+                ),
+            ])
+        }
+    }
+}
+
+/// The outcome of importing a single object
+enum Outcome {
+    Present,
+    Uploaded(usize),
+    Failed,
+}
+
+/// Uploads every crate and git repository in the cargo home that isn't
+/// already present in the storage location
+pub async fn cargo_home(ctx: &Ctx, cargo_home: &Path) -> Result<Summary, Error> {
+    let existing: Arc<HashSet<_>> = Arc::new(
+        ctx.backend
+            .list()
+            .await
+            .context("failed to list storage location")?
+            .into_iter()
+            .collect(),
+    );
+
+    let candidates = candidates(cargo_home);
+    info!(
+        "found {} crates and git checkouts in {cargo_home}",
+        candidates.len()
+    );
+
+    let mut summary = Summary {
+        total_bytes: 0,
+        uploaded: 0,
+        present: 0,
+        failed: 0,
+    };
+
+    let mut tasks = crate::rt::JoinSet::new();
+    for candidate in candidates {
+        // Registry crates are identified by their checksum, so they need to be
+        // read before we know if they are present, but packing a git repo is
+        // expensive so it is skipped if it's already present
+        if let Candidate::Git { id, .. } = &candidate {
+            if existing.contains(id) {
+                summary.present += 1;
+                continue;
+            }
+        }
+
+        let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+        let existing = existing.clone();
+        let compression = ctx.compression;
+        let exclusions = ctx.checkout_exclusions.clone();
+        let checksums = ctx.checksums;
+
+        // Created outside of the task so that it's a child of the current span
+        let span = tracing::info_span!("import", ?candidate);
+
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+
+            let _is = span.enter();
+
+            let objects =
+                match crate::rt::spawn_blocking(move || pack(&candidate, compression, &exclusions))
+                    .await
+                {
+                    Ok(objects) => objects,
+                    Err(err) => {
+                        warn!("skipping: {err:#}");
+                        return vec![Outcome::Failed];
+                    }
+                };
+
+            let mut outcomes = Vec::with_capacity(objects.len());
+            for (id, data) in objects {
+                if existing.contains(&id) {
+                    outcomes.push(Outcome::Present);
+                    continue;
+                }
+
+                match mirror::upload(&backend, data, &Krate::object(&id), false, checksums, None)
+                    .await
+                {
+                    Ok(len) => {
+                        debug!(bytes = len, "uploaded '{id}'");
+                        outcomes.push(Outcome::Uploaded(len));
+                    }
+                    Err(err) => {
+                        error!("failed to upload '{id}': {err:#}");
+                        outcomes.push(Outcome::Failed);
+                    }
+                }
+            }
+
+            outcomes
+        });
+    }
+
+    while let Some(outcomes) = tasks.join_next().await {
+        let Ok(outcomes) = outcomes else {
+            summary.failed += 1;
+            continue;
+        };
+
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Present => summary.present += 1,
+                Outcome::Uploaded(len) => {
+                    summary.uploaded += 1;
+                    summary.total_bytes += len;
+                }
+                Outcome::Failed => summary.failed += 1,
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_candidates() {
+        let td = tempfile::tempdir().unwrap();
+        let home = util::path(td.path()).unwrap();
+
+        let cache = home
+            .join(sync::CACHE_DIR)
+            .join("index.crates.io-6f17d22bba15001f");
+        std::fs::create_dir_all(&cache).unwrap();
+        std::fs::write(cache.join("foo-1.0.0.crate"), b"").unwrap();
+        std::fs::write(cache.join("not-a-crate.txt"), b"").unwrap();
+
+        let ident = "repo-0123456789abcdef";
+        std::fs::create_dir_all(home.join(sync::GIT_DB_DIR).join(ident)).unwrap();
+        let checkouts = home.join(sync::GIT_CO_DIR).join(ident);
+        std::fs::create_dir_all(checkouts.join("abcdef0")).unwrap();
+        std::fs::write(checkouts.join("abcdef0/.cargo-ok"), b"").unwrap();
+        // An incomplete checkout is ignored
+        std::fs::create_dir_all(checkouts.join("1234567")).unwrap();
+        // A checkout without a db is ignored
+        std::fs::create_dir_all(
+            home.join(sync::GIT_CO_DIR)
+                .join("other-0123456789abcdef/abcdef0"),
+        )
+        .unwrap();
+
+        let candidates = candidates(home);
+        assert_eq!(candidates.len(), 2);
+        assert!(
+            matches!(&candidates[0], Candidate::Crate(path) if path.file_name() == Some("foo-1.0.0.crate"))
+        );
+        assert!(
+            matches!(&candidates[1], Candidate::Git { id, .. } if id == "repo-0123456789abcdef-abcdef0")
+        );
+    }
+}
@@ -4,12 +4,34 @@ use std::{fmt, sync::Arc};
 pub use url::Url;
 
 pub mod backends;
+pub mod bench;
 pub mod cargo;
+pub mod compare;
+pub(crate) mod delta;
+pub mod drift;
+pub mod error;
 mod fetch;
 pub(crate) mod git;
+pub mod hook;
+#[cfg(feature = "image")]
+pub mod image;
+pub mod import;
+pub mod list;
+pub mod metrics;
 pub mod mirror;
+pub mod outbox;
+pub mod progress;
+pub mod provenance;
+pub mod report;
+pub mod resolve;
+mod rt;
+pub(crate) mod seekable;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod stats;
 pub mod sync;
 pub mod util;
+pub mod vendor;
 
 pub type HttpClient = reqwest::Client;
 
@@ -57,6 +79,7 @@ impl Krate {
         CloudId {
             inner: self,
             is_checkout,
+            is_checksum: false,
         }
     }
 
@@ -64,6 +87,20 @@ impl Krate {
     pub fn local_id(&self) -> LocalId<'_> {
         LocalId { inner: self }
     }
+
+    /// Backends address objects by [`CloudId`], so arbitrary objects are
+    /// accessed via a pseudo crate whose id is the object's key, the same as
+    /// the id of a registry crate is its checksum
+    pub(crate) fn object(id: &str) -> Self {
+        Self {
+            name: String::new(),
+            version: String::new(),
+            source: Source::Registry(RegistrySource {
+                registry: Arc::new(Registry::crates_io(RegistryProtocol::Sparse)),
+                chksum: id.to_owned(),
+            }),
+        }
+    }
 }
 
 impl fmt::Display for Krate {
@@ -92,9 +129,23 @@ impl<'a> fmt::Display for LocalId<'a> {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CloudId<'a> {
     inner: &'a Krate,
     is_checkout: bool,
+    is_checksum: bool,
+}
+
+impl<'a> CloudId<'a> {
+    /// The id of the `.sha256` sidecar object that holds the checksum of this
+    /// object, in the same format as `sha256sum`
+    #[inline]
+    pub fn checksum(self) -> Self {
+        Self {
+            is_checksum: true,
+            ..self
+        }
+    }
 }
 
 impl<'a> fmt::Display for CloudId<'a> {
@@ -108,7 +159,13 @@ impl<'a> fmt::Display for CloudId<'a> {
                 if self.is_checkout { "-checkout" } else { "" }
             ),
             Source::Registry(rs) => f.write_str(&rs.chksum),
+        }?;
+
+        if self.is_checksum {
+            f.write_str(".sha256")?;
         }
+
+        Ok(())
     }
 }
 
@@ -118,12 +175,40 @@ pub struct GcsLocation<'a> {
     pub prefix: &'a str,
 }
 
+#[allow(dead_code)]
+pub struct ArtifactRegistryLocation<'a> {
+    pub project: &'a str,
+    pub location: &'a str,
+    pub repository: &'a str,
+    /// The generic package the objects are stored as files of
+    pub package: &'a str,
+}
+
 #[allow(dead_code)]
 pub struct S3Location<'a> {
     pub bucket: &'a str,
-    pub region: &'a str,
-    pub host: &'a str,
+    pub region: String,
+    /// The url of the S3 service, without the bucket
+    pub endpoint: Url,
     pub prefix: &'a str,
+    /// Use path style rather than virtual-hosted-style urls, for S3 compatible
+    /// services that don't support the latter, and local testing
+    pub path_style: bool,
+    /// Sends the SHA-256 of each uploaded object in `x-amz-checksum-sha256`,
+    /// so that the service verifies it was received intact
+    pub checksum_sha256: bool,
+    /// Sends `x-amz-content-sha256` with uploads, which some S3 compatible
+    /// services require even though requests are presigned
+    pub content_sha256: Option<ContentSha256>,
+}
+
+/// The value of the `x-amz-content-sha256` header sent with uploads
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContentSha256 {
+    /// `UNSIGNED-PAYLOAD`, the same as the presigned url itself
+    Unsigned,
+    /// The hex encoded SHA-256 of the payload
+    Payload,
 }
 
 pub struct FilesystemLocation<'a> {
@@ -133,13 +218,41 @@ pub struct FilesystemLocation<'a> {
 pub struct BlobLocation<'a> {
     pub prefix: &'a str,
     pub container: &'a str,
+    /// Targets the local Azurite storage emulator with its well known
+    /// development account, unless a connection string is provided
+    pub emulator: bool,
+}
+
+pub struct SftpLocation<'a> {
+    pub user: Option<&'a str>,
+    pub host: &'a str,
+    pub port: Option<u16>,
+    /// The directory on the remote host, relative to the user's home directory
+    /// if it doesn't start with a `/`
+    pub path: &'a str,
 }
 
 pub enum CloudLocation<'a> {
     Gcs(GcsLocation<'a>),
+    ArtifactRegistry(ArtifactRegistryLocation<'a>),
     S3(S3Location<'a>),
     Fs(FilesystemLocation<'a>),
     Blob(BlobLocation<'a>),
+    Sftp(SftpLocation<'a>),
+}
+
+impl<'a> CloudLocation<'a> {
+    /// The name of the storage backend for the location
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Gcs(_) => "gcs",
+            Self::ArtifactRegistry(_) => "artifactregistry",
+            Self::S3(_) => "s3",
+            Self::Fs(_) => "fs",
+            Self::Blob(_) => "blob",
+            Self::Sftp(_) => "sftp",
+        }
+    }
 }
 
 pub type Storage = Arc<dyn Backend + Sync + Send>;
@@ -147,9 +260,80 @@ pub type Storage = Arc<dyn Backend + Sync + Send>;
 pub struct Ctx {
     pub client: HttpClient,
     pub backend: Storage,
+    /// An optional separate storage location for registry index snapshots, so
+    /// that they can have different retention policies than crates
+    pub index_backend: Option<Storage>,
     pub krates: Vec<Krate>,
     pub registries: Vec<Arc<Registry>>,
     pub root_dir: PathBuf,
+    /// Bounds the number of concurrent requests made to upstream sources and
+    /// the storage backend
+    pub request_limit: util::RequestLimit,
+    /// How requests to upstream sources are retried
+    pub retry: util::RetryPolicy,
+    /// Mirror the current head of the branch or tag followed by a git source
+    /// if the locked revision no longer exists in the remote
+    pub git_follow_fallback: bool,
+    /// The maximum time that cloning a single git source, including its
+    /// submodules, may take before it is interrupted
+    pub git_timeout: Option<std::time::Duration>,
+    /// Clone the full history of git sources, rather than only their locked
+    /// revision
+    pub git_full_history: bool,
+    /// The private key used to authenticate with git sources over ssh
+    pub git_ssh_key: Option<PathBuf>,
+    /// Upload git dbs as deltas on top of the last full db of the same
+    /// repository, until there are this many deltas. 0 disables deltas.
+    pub git_deltas: usize,
+    /// Verify that registry crates already in the storage location match the
+    /// checksum in the lockfile, and mirror the ones that don't again
+    pub refresh_mismatched: bool,
+    /// The compression used for git and registry index tarballs
+    pub compression: util::Compression,
+    /// Paths left out of git checkout tarballs
+    pub checkout_exclusions: util::PathExclusions,
+    /// A directory in which sparse index entries are persisted between runs,
+    /// so that unchanged entries can be revalidated with conditional requests
+    pub sparse_index_cache: Option<PathBuf>,
+    /// Crates patched with a local path that are mirrored as tarballs, and
+    /// restored when syncing if the path doesn't exist
+    pub path_patches: Vec<cargo::PathPatch>,
+    /// The lockfiles the crates were read from
+    pub lock_files: Vec<PathBuf>,
+    /// The lockfile(s) each crate was read from
+    pub origins: Arc<cargo::Origins>,
+    /// Upload a `.sha256` sidecar alongside each object when mirroring
+    pub checksums: bool,
+    /// Verify downloaded git tarballs, path patches, and index snapshots
+    /// against their `.sha256` sidecar when syncing
+    pub verify_checksums: bool,
+    /// Check that every object reachable from the locked revision is present
+    /// in git dbs when syncing, not just the revision itself
+    pub check_git_connectivity: bool,
+    /// Give files in git checkouts the mtime in the checkout tarball, ie. the
+    /// time of the commit, rather than the time they were unpacked
+    pub preserve_mtime: bool,
+    /// Read the `.cache` entries of sparse registries from the index snapshot,
+    /// if it is seekable, rather than requesting all of them from the registry
+    pub index_entries_from_snapshot: bool,
+    /// There is no storage location (`--url none`), so crates are synced
+    /// directly from their upstream sources
+    pub upstream_only: bool,
+    /// The scope of registry index snapshots, if they only contain the entries
+    /// for the crates in the lockfiles rather than being shared by everything
+    /// mirrored to the storage location, see [`Registry::scoped_index_krate`]
+    pub index_scope: Option<String>,
+    /// Report the progress of syncing crates
+    pub progress: bool,
+    /// Collects metrics for the run, if enabled
+    pub metrics: Option<Arc<metrics::Metrics>>,
+    /// Records the outcome of every crate processed for the run, if enabled
+    pub report: Option<Arc<report::Report>>,
+    /// Uploads that fail while mirroring are queued here, and retried by the
+    /// next run
+    pub outbox: Option<outbox::Outbox>,
+    /// The configuration used for all HTTP clients
+    pub http: util::HttpConfig,
 }
 
 impl Ctx {
@@ -160,14 +344,71 @@ impl Ctx {
         registries: Vec<Arc<Registry>>,
     ) -> Result<Self, Error> {
         Ok(Self {
-            client: HttpClient::builder().build()?,
+            client: util::HttpConfig::default()
+                .upstream_client_builder()
+                .build()?,
             backend,
+            index_backend: None,
             krates,
             registries,
             root_dir: root_dir.unwrap_or_else(|| PathBuf::from(".")),
+            request_limit: util::RequestLimit::default(),
+            retry: util::RetryPolicy::default(),
+            git_follow_fallback: false,
+            git_timeout: None,
+            git_full_history: false,
+            git_ssh_key: None,
+            git_deltas: 0,
+            refresh_mismatched: false,
+            compression: util::Compression::default(),
+            checkout_exclusions: util::PathExclusions::default(),
+            sparse_index_cache: None,
+            path_patches: Vec::new(),
+            lock_files: Vec::new(),
+            origins: Arc::default(),
+            checksums: false,
+            verify_checksums: false,
+            check_git_connectivity: false,
+            preserve_mtime: false,
+            index_entries_from_snapshot: false,
+            upstream_only: false,
+            index_scope: None,
+            progress: false,
+            metrics: None,
+            report: None,
+            outbox: None,
+            http: util::HttpConfig::default(),
         })
     }
 
+    /// Sets the HTTP configuration, recreating the client used for upstream
+    /// requests to apply it
+    pub fn set_http_config(&mut self, http: util::HttpConfig) -> Result<(), Error> {
+        self.client = http.upstream_client_builder().build()?;
+        self.http = http;
+        Ok(())
+    }
+
+    /// How git sources are cloned from their remotes
+    pub(crate) fn git_clone_options(&self) -> git::CloneOptions {
+        git::CloneOptions {
+            follow_fallback: self.git_follow_fallback,
+            timeout: self.git_timeout,
+            full_history: self.git_full_history,
+            ssh_key: self.git_ssh_key.clone(),
+            proxy: self.http.proxy.clone(),
+            ca_certificates: self.http.ca_certificates.as_ref().map(|ca| ca.path.clone()),
+            base: None,
+        }
+    }
+
+    /// The storage location for registry index snapshots, which is the same
+    /// as the crate storage location unless one was explicitly provided
+    #[inline]
+    pub fn index_backend(&self) -> &Storage {
+        self.index_backend.as_ref().unwrap_or(&self.backend)
+    }
+
     /// Create the registry and git directories as they are the root of multiple other ones
     pub fn prep_sync_dirs(&self) -> Result<(), Error> {
         std::fs::create_dir_all(self.root_dir.join("registry"))?;
@@ -177,14 +418,25 @@ impl Ctx {
     }
 
     pub fn registry_sets(&self) -> Vec<mirror::RegistrySet> {
+        self.registry_sets_for(&self.krates)
+    }
+
+    /// Groups the names of the crates by the registry they are sourced from,
+    /// the same as [`Self::registry_sets`], for crates that needn't be in any
+    /// lockfile. Every registry has a set, even if none of the crates are
+    /// sourced from it, and git crates are ignored.
+    pub fn registry_sets_for<'k>(
+        &self,
+        krates: impl IntoIterator<Item = &'k Krate> + Clone,
+    ) -> Vec<mirror::RegistrySet> {
         self.registries
             .iter()
             .map(|registry| {
                 // Gather the names of all of the crates sourced in the registry so we
                 // can add .cache entries
-                let krates = self
-                    .krates
-                    .iter()
+                let krates = krates
+                    .clone()
+                    .into_iter()
                     .filter_map(|krate| {
                         if krate == registry.as_ref() {
                             Some(krate.name.clone())
@@ -211,10 +463,122 @@ impl fmt::Debug for Ctx {
 
 pub type Timestamp = time::OffsetDateTime;
 
+/// Information about how this build of cargo-fetcher was built, useful when
+/// debugging issues
+#[derive(Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// The short sha of the commit that was built, if built from a git checkout
+    pub git_sha: Option<&'static str>,
+    /// The storage backends that were enabled
+    pub features: Vec<&'static str>,
+    /// The locked versions of key dependencies
+    pub dependencies: Vec<(&'static str, &'static str)>,
+    pub rustc: &'static str,
+    pub target: &'static str,
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cargo-fetcher {}", self.version)?;
+        writeln!(f, "commit: {}", self.git_sha.unwrap_or("unknown"))?;
+        writeln!(f, "features: {}", self.features.join(", "))?;
+        writeln!(f, "target: {}", self.target)?;
+        writeln!(f, "rustc: {}", self.rustc)?;
+        writeln!(f, "dependencies:")?;
+        for (name, version) in &self.dependencies {
+            writeln!(f, "  {name} {version}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Retrieves information about how this build of cargo-fetcher was built
+pub fn build_info() -> BuildInfo {
+    let features = [
+        ("fs", true),
+        ("gcs", cfg!(feature = "gcs")),
+        ("s3", cfg!(feature = "s3")),
+        ("blob", cfg!(feature = "blob")),
+        ("sftp", cfg!(feature = "sftp")),
+        ("image", cfg!(feature = "image")),
+        ("serve", cfg!(feature = "serve")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: Some(env!("CF_GIT_SHA")).filter(|sha| !sha.is_empty()),
+        features,
+        dependencies: env!("CF_DEPENDENCIES")
+            .split(',')
+            .filter_map(|dep| dep.split_once('='))
+            .collect(),
+        rustc: env!("CF_RUSTC_VERSION"),
+        target: env!("CF_TARGET"),
+    }
+}
+
+/// Metadata for a single object in a storage backend
+#[derive(Clone, Debug)]
+pub struct ObjectStat {
+    /// The size of the object, in bytes
+    pub size: u64,
+    /// The last time the object was modified, if the backend reports it
+    pub updated: Option<Timestamp>,
+}
+
 #[async_trait::async_trait]
 pub trait Backend: fmt::Debug {
     async fn fetch(&self, id: CloudId<'_>) -> Result<bytes::Bytes, Error>;
+    /// Fetches the object, writing it to the file as it is downloaded rather
+    /// than buffering it in memory, returning the size of the object
+    async fn fetch_to(&self, id: CloudId<'_>, file: &mut std::fs::File) -> Result<u64, Error> {
+        use std::io::Write;
+
+        let data = self.fetch(id).await?;
+        file.write_all(&data)?;
+        Ok(data.len() as u64)
+    }
+    /// Fetches the object to the file, the same as [`Self::fetch_to`], but only
+    /// if it has been modified since the specified time, returning `None` if
+    /// it hasn't
+    async fn fetch_if_newer(
+        &self,
+        id: CloudId<'_>,
+        since: Timestamp,
+        file: &mut std::fs::File,
+    ) -> Result<Option<u64>, Error> {
+        if matches!(self.updated(id).await?, Some(updated) if updated <= since) {
+            return Ok(None);
+        }
+
+        self.fetch_to(id, file).await.map(Some)
+    }
     async fn upload(&self, source: bytes::Bytes, id: CloudId<'_>) -> Result<usize, Error>;
-    async fn list(&self) -> Result<Vec<String>, Error>;
+    /// Lists the ids of all of the objects in the storage location
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .list_stats()
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+    /// Lists the ids of all of the objects in the storage location, along with
+    /// their size and modification time
+    async fn list_stats(&self) -> Result<Vec<(String, ObjectStat)>, Error>;
     async fn updated(&self, id: CloudId<'_>) -> Result<Option<Timestamp>, Error>;
+    /// Removes the object, deleting an object that doesn't exist is not an error
+    async fn delete(&self, id: CloudId<'_>) -> Result<(), Error>;
+    /// Retrieves the size and modification time of the object, or `None` if
+    /// it doesn't exist
+    async fn stat(&self, id: CloudId<'_>) -> Result<Option<ObjectStat>, Error>;
+    /// The path of the object if the backend stores it on the local
+    /// filesystem, so that it can be linked rather than copied
+    fn local_path(&self, _id: CloudId<'_>) -> Option<PathBuf> {
+        None
+    }
 }
@@ -0,0 +1,274 @@
+use crate::{cargo, Ctx, Source};
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The kind of object in a storage location
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// A `.crate` tarball from a registry
+    Registry,
+    /// A bare clone of a git repository
+    GitDb,
+    /// A checkout of a specific revision of a git repository
+    GitCheckout,
+    /// A snapshot of a registry index
+    Index,
+    /// A tarball of a crate patched with a local path
+    PathPatch,
+    /// A `.sha256` sidecar of another object
+    Checksum,
+    /// Something that wasn't put there by cargo-fetcher
+    Unknown,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Registry => "registry",
+            Self::GitDb => "git-db",
+            Self::GitCheckout => "git-checkout",
+            Self::Index => "index",
+            Self::PathPatch => "path-patch",
+            Self::Checksum => "checksum",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// An object in a storage location
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    /// The id of the object in the storage location
+    pub id: String,
+    pub kind: Kind,
+    /// The name of the crate, git repository, or registry the object is for,
+    /// registry crates are only identified by their checksum, so this is only
+    /// known if they are in one of the lockfiles
+    pub name: Option<String>,
+    /// The version of the crate, only known if the crate is in one of the lockfiles
+    pub version: Option<String>,
+    /// The short revision for git objects
+    pub rev: Option<String>,
+    /// The size of the object, in bytes
+    pub size: u64,
+    /// The last time the object was modified, in RFC 3339 format
+    pub updated: Option<String>,
+}
+
+/// Lists all of the objects in the storage location(s), using the crates and
+/// registries from the lockfiles to identify them where possible
+pub async fn objects(ctx: &Ctx) -> Result<Vec<Entry>, Error> {
+    let mut objects = ctx.backend.list_stats().await?;
+
+    if let Some(index_backend) = &ctx.index_backend {
+        objects.extend(index_backend.list_stats().await?);
+    }
+
+    // Object ids can't be mapped back to the crates they are for in all cases,
+    // so use the lockfile as a lookup table
+    let mut known = HashMap::new();
+    for krate in &ctx.krates {
+        known.insert(krate.cloud_id(false).to_string(), krate.clone());
+        if matches!(krate.source, Source::Git(_)) {
+            known.insert(krate.cloud_id(true).to_string(), krate.clone());
+        }
+    }
+
+    for registry in &ctx.registries {
+        let krate = registry.index_krate();
+        known.insert(krate.cloud_id(false).to_string(), krate);
+
+        if ctx.index_scope.is_some() {
+            let krate = registry.scoped_index_krate(ctx.index_scope.as_deref());
+            known.insert(krate.cloud_id(false).to_string(), krate);
+        }
+    }
+
+    for patch in &ctx.path_patches {
+        let krate = patch.krate();
+        known.insert(krate.cloud_id(false).to_string(), krate);
+    }
+
+    let mut entries: Vec<_> = objects
+        .into_iter()
+        .map(|(id, stat)| {
+            let (kind, name, version, rev) = if let Some(krate) = known.get(&id) {
+                identify_known(&id, krate)
+            } else {
+                identify(&id)
+            };
+
+            Entry {
+                updated: stat.updated.and_then(|ts| {
+                    ts.format(&time::format_description::well_known::Rfc3339)
+                        .ok()
+                }),
+                id,
+                kind,
+                name,
+                version,
+                rev,
+                size: stat.size,
+            }
+        })
+        .collect();
+
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(entries)
+}
+
+type Identity = (Kind, Option<String>, Option<String>, Option<String>);
+
+fn identify_known(id: &str, krate: &crate::Krate) -> Identity {
+    match &krate.source {
+        Source::Registry(_) => (
+            Kind::Registry,
+            Some(krate.name.clone()),
+            Some(krate.version.clone()),
+            None,
+        ),
+        Source::Git(_) if cargo::is_index_snapshot(id) => {
+            (Kind::Index, Some(krate.name.clone()), None, None)
+        }
+        Source::Git(gs) => {
+            let rev = gs.rev.short();
+            let kind = if rev == &cargo::PATH_PATCH_REV[..7] {
+                Kind::PathPatch
+            } else if id.ends_with("-checkout") {
+                Kind::GitCheckout
+            } else {
+                Kind::GitDb
+            };
+
+            (
+                kind,
+                Some(krate.name.clone()),
+                Some(krate.version.clone()),
+                (kind != Kind::PathPatch).then(|| rev.to_owned()),
+            )
+        }
+    }
+}
+
+/// Identifies an object that isn't referenced by any of the lockfiles from its
+/// id alone
+fn identify(id: &str) -> Identity {
+    let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+
+    if id.ends_with(".sha256") {
+        return (Kind::Checksum, None, None, None);
+    }
+
+    if id.len() == 64 && is_hex(id) {
+        return (Kind::Registry, None, None, None);
+    }
+
+    // The delta state and deltas of an index snapshot or git db are part of
+    // the snapshot or db they apply to
+    let (id, is_delta) = match crate::delta::base_id(id) {
+        Some(base) => (base, true),
+        None => (id, false),
+    };
+
+    if cargo::is_index_snapshot(id) {
+        let name = id
+            .strip_suffix(&cargo::INDEX_SNAPSHOT_REV[..7])
+            .and_then(|name| name.strip_suffix('-'));
+        return (Kind::Index, name.map(String::from), None, None);
+    }
+
+    let (kind, rest) = match id.strip_suffix("-checkout") {
+        Some(rest) => (Kind::GitCheckout, rest),
+        None => (Kind::GitDb, id),
+    };
+
+    // Git objects are `<name>-<hash of url>-<short rev>`, and path patch
+    // tarballs are `<name>-<version>-<hash of tarball>-<short rev>`
+    if let Some((ident, rev)) = rest.rsplit_once('-') {
+        if let Some((name, hash)) = ident.rsplit_once('-') {
+            if rev == &cargo::PATH_PATCH_REV[..7] && hash.len() == 16 && is_hex(hash) {
+                // Crate names can contain `-`, but never a `-` followed by a digit
+                let (name, version) = match name
+                    .match_indices('-')
+                    .find(|(i, _)| name[i + 1..].starts_with(|c: char| c.is_ascii_digit()))
+                {
+                    Some((i, _)) => (&name[..i], Some(name[i + 1..].to_owned())),
+                    None => (name, None),
+                };
+                return (Kind::PathPatch, Some(name.to_owned()), version, None);
+            }
+
+            if rev.len() == 7 && is_hex(rev) && hash.len() == 16 && is_hex(hash) {
+                return (kind, Some(name.to_owned()), None, Some(rev.to_owned()));
+            }
+        }
+
+        // The delta state of a git repository is `<name>-<hash of url>`
+        if is_delta && rev.len() == 16 && is_hex(rev) {
+            return (Kind::GitDb, Some(ident.to_owned()), None, None);
+        }
+    }
+
+    (Kind::Unknown, None, None, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identifies_objects() {
+        assert_eq!(
+            identify("a2bb4b1d6b0b2e1e3e3ce4a1f5e2b5dfb8b0e5a8b5d2f47e5cd4f8a8a8c3e7d1").0,
+            Kind::Registry
+        );
+
+        let (kind, name, _, rev) = identify("cpal-a7ffa0a7bdf9aa3e-b8ed1bc");
+        assert_eq!(kind, Kind::GitDb);
+        assert_eq!(name.as_deref(), Some("cpal"));
+        assert_eq!(rev.as_deref(), Some("b8ed1bc"));
+
+        let (kind, name, ..) = identify("gilrs-core-3e1dc8e2b5c0a1f6-2f4a6c8-checkout");
+        assert_eq!(kind, Kind::GitCheckout);
+        assert_eq!(name.as_deref(), Some("gilrs-core"));
+
+        let registry = crate::Registry::crates_io(crate::RegistryProtocol::Sparse);
+        let id = registry.index_krate().cloud_id(false).to_string();
+        let (kind, name, ..) = identify(&id);
+        assert_eq!(kind, Kind::Index);
+        assert_eq!(name.as_deref(), Some(registry.short_name()));
+
+        let (kind, name, ..) = identify(&crate::delta::delta_id(&id, 2));
+        assert_eq!(kind, Kind::Index);
+        assert_eq!(name.as_deref(), Some(registry.short_name()));
+
+        let (kind, name, _, rev) =
+            identify(&crate::delta::delta_id("cpal-a7ffa0a7bdf9aa3e-b8ed1bc", 1));
+        assert_eq!(kind, Kind::GitDb);
+        assert_eq!(name.as_deref(), Some("cpal"));
+        assert_eq!(rev.as_deref(), Some("b8ed1bc"));
+
+        let (kind, name, _, rev) = identify(&crate::delta::state_id("cpal-a7ffa0a7bdf9aa3e"));
+        assert_eq!(kind, Kind::GitDb);
+        assert_eq!(name.as_deref(), Some("cpal"));
+        assert_eq!(rev, None);
+
+        let patch = crate::cargo::PathPatch {
+            name: "gilrs-core".to_owned(),
+            version: "0.5.0".to_owned(),
+            path: "../gilrs-core".into(),
+        };
+        let hash = crate::util::sha256_hex(b"tarball");
+        let (kind, name, version, rev) =
+            identify(&patch.tarball_krate(&hash).cloud_id(false).to_string());
+        assert_eq!(kind, Kind::PathPatch);
+        assert_eq!(name.as_deref(), Some("gilrs-core"));
+        assert_eq!(version.as_deref(), Some("0.5.0"));
+        assert_eq!(rev, None);
+
+        assert_eq!(identify(&format!("{id}.sha256")).0, Kind::Checksum);
+        assert_eq!(identify("README.md").0, Kind::Unknown);
+    }
+}
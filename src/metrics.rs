@@ -0,0 +1,334 @@
+//! Counters and histograms that are written in the Prometheus text format at
+//! exit, so that the health of mirrors and syncs can be graphed across runs
+
+use crate::{Path, Timestamp};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The upper bounds of the request duration histogram buckets, in seconds
+const BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// The count of observations in each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Crates mirrored or synced, by operation and result
+    crates: BTreeMap<(&'static str, &'static str), u64>,
+    /// Bytes transferred to and from the storage backend, by backend and direction
+    bytes: BTreeMap<(&'static str, &'static str), u64>,
+    /// Failed backend requests, by backend and operation
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    /// The duration of backend requests, by backend and operation
+    durations: BTreeMap<(&'static str, &'static str), Histogram>,
+}
+
+/// Collects metrics for a single run of cargo-fetcher
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    /// Records the result of mirroring or syncing a single crate
+    pub fn krate(&self, op: &'static str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .crates
+            .entry((op, result))
+            .or_default() += 1;
+    }
+
+    /// Records a request to a storage backend
+    pub fn request(
+        &self,
+        backend: &'static str,
+        op: &'static str,
+        elapsed: Duration,
+        success: bool,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .durations
+            .entry((backend, op))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+
+        if !success {
+            *inner.errors.entry((backend, op)).or_default() += 1;
+        }
+    }
+
+    /// Records bytes uploaded to or downloaded from a storage backend
+    pub fn bytes(&self, backend: &'static str, direction: &'static str, bytes: u64) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .bytes
+            .entry((backend, direction))
+            .or_default() += bytes;
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP cargo_fetcher_crates_total Crates mirrored or synced\n# TYPE cargo_fetcher_crates_total counter"
+        );
+        for ((op, result), count) in &inner.crates {
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_crates_total{{op=\"{op}\",result=\"{result}\"}} {count}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP cargo_fetcher_backend_bytes_total Bytes transferred to or from the storage backend\n# TYPE cargo_fetcher_backend_bytes_total counter"
+        );
+        for ((backend, direction), bytes) in &inner.bytes {
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_backend_bytes_total{{backend=\"{backend}\",direction=\"{direction}\"}} {bytes}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP cargo_fetcher_backend_errors_total Failed storage backend requests\n# TYPE cargo_fetcher_backend_errors_total counter"
+        );
+        for ((backend, op), count) in &inner.errors {
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_backend_errors_total{{backend=\"{backend}\",op=\"{op}\"}} {count}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP cargo_fetcher_backend_request_duration_seconds Duration of storage backend requests\n# TYPE cargo_fetcher_backend_request_duration_seconds histogram"
+        );
+        for ((backend, op), hist) in &inner.durations {
+            let labels = format!("backend=\"{backend}\",op=\"{op}\"");
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(hist.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "cargo_fetcher_backend_request_duration_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_backend_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                hist.count
+            );
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_backend_request_duration_seconds_sum{{{labels}}} {}",
+                hist.sum
+            );
+            let _ = writeln!(
+                out,
+                "cargo_fetcher_backend_request_duration_seconds_count{{{labels}}} {}",
+                hist.count
+            );
+        }
+
+        out
+    }
+
+    /// Writes the metrics to the specified path, replacing it atomically so
+    /// that a collector never reads a partially written file
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        use anyhow::Context as _;
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.render())
+            .with_context(|| format!("failed to write metrics to '{tmp}'"))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to move metrics to '{path}'"))?;
+        Ok(())
+    }
+}
+
+/// Wraps a storage backend, recording the duration, outcome, and size of each
+/// request
+pub struct MeteredBackend {
+    inner: crate::Storage,
+    name: &'static str,
+    metrics: std::sync::Arc<Metrics>,
+}
+
+impl MeteredBackend {
+    pub fn new(
+        inner: crate::Storage,
+        name: &'static str,
+        metrics: std::sync::Arc<Metrics>,
+    ) -> Self {
+        Self {
+            inner,
+            name,
+            metrics,
+        }
+    }
+
+    #[inline]
+    fn record<T>(&self, op: &'static str, start: Instant, res: &anyhow::Result<T>) {
+        self.metrics
+            .request(self.name, op, start.elapsed(), res.is_ok());
+    }
+}
+
+impl std::fmt::Debug for MeteredBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Backend for MeteredBackend {
+    async fn fetch(&self, id: crate::CloudId<'_>) -> anyhow::Result<bytes::Bytes> {
+        let start = Instant::now();
+        let res = self.inner.fetch(id).await;
+        self.record("fetch", start, &res);
+        if let Ok(data) = &res {
+            self.metrics.bytes(self.name, "download", data.len() as u64);
+        }
+        res
+    }
+
+    async fn fetch_to(
+        &self,
+        id: crate::CloudId<'_>,
+        file: &mut std::fs::File,
+    ) -> anyhow::Result<u64> {
+        let start = Instant::now();
+        let res = self.inner.fetch_to(id, file).await;
+        self.record("fetch", start, &res);
+        if let Ok(len) = &res {
+            self.metrics.bytes(self.name, "download", *len);
+        }
+        res
+    }
+
+    async fn fetch_if_newer(
+        &self,
+        id: crate::CloudId<'_>,
+        since: crate::Timestamp,
+        file: &mut std::fs::File,
+    ) -> anyhow::Result<Option<u64>> {
+        let start = Instant::now();
+        let res = self.inner.fetch_if_newer(id, since, file).await;
+        self.record("fetch", start, &res);
+        if let Ok(Some(len)) = &res {
+            self.metrics.bytes(self.name, "download", *len);
+        }
+        res
+    }
+
+    async fn upload(&self, source: bytes::Bytes, id: crate::CloudId<'_>) -> anyhow::Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.upload(source, id).await;
+        self.record("upload", start, &res);
+        if let Ok(len) = &res {
+            self.metrics.bytes(self.name, "upload", *len as u64);
+        }
+        res
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<String>> {
+        let start = Instant::now();
+        let res = self.inner.list().await;
+        self.record("list", start, &res);
+        res
+    }
+
+    async fn list_stats(&self) -> anyhow::Result<Vec<(String, crate::ObjectStat)>> {
+        let start = Instant::now();
+        let res = self.inner.list_stats().await;
+        self.record("list", start, &res);
+        res
+    }
+
+    async fn updated(&self, id: crate::CloudId<'_>) -> anyhow::Result<Option<Timestamp>> {
+        let start = Instant::now();
+        let res = self.inner.updated(id).await;
+        self.record("updated", start, &res);
+        res
+    }
+
+    async fn delete(&self, id: crate::CloudId<'_>) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let res = self.inner.delete(id).await;
+        self.record("delete", start, &res);
+        res
+    }
+
+    async fn stat(&self, id: crate::CloudId<'_>) -> anyhow::Result<Option<crate::ObjectStat>> {
+        let start = Instant::now();
+        let res = self.inner.stat(id).await;
+        self.record("stat", start, &res);
+        res
+    }
+
+    fn local_path(&self, id: crate::CloudId<'_>) -> Option<crate::PathBuf> {
+        self.inner.local_path(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.krate("mirror", true);
+        metrics.krate("mirror", true);
+        metrics.krate("mirror", false);
+        metrics.bytes("gcs", "upload", 1024);
+        metrics.request("gcs", "upload", Duration::from_millis(30), true);
+        metrics.request("gcs", "upload", Duration::from_secs(2), false);
+
+        let text = metrics.render();
+
+        assert!(text.contains("cargo_fetcher_crates_total{op=\"mirror\",result=\"success\"} 2\n"));
+        assert!(text.contains("cargo_fetcher_crates_total{op=\"mirror\",result=\"failure\"} 1\n"));
+        assert!(text.contains(
+            "cargo_fetcher_backend_bytes_total{backend=\"gcs\",direction=\"upload\"} 1024\n"
+        ));
+        assert!(
+            text.contains("cargo_fetcher_backend_errors_total{backend=\"gcs\",op=\"upload\"} 1\n")
+        );
+        assert!(text.contains("cargo_fetcher_backend_request_duration_seconds_bucket{backend=\"gcs\",op=\"upload\",le=\"0.05\"} 1\n"));
+        assert!(text.contains("cargo_fetcher_backend_request_duration_seconds_bucket{backend=\"gcs\",op=\"upload\",le=\"2.5\"} 2\n"));
+        assert!(text.contains("cargo_fetcher_backend_request_duration_seconds_count{backend=\"gcs\",op=\"upload\"} 2\n"));
+    }
+}
@@ -1,7 +1,131 @@
-use crate::{fetch, Ctx, Krate, Registry, Source};
-use anyhow::Error;
+use crate::{delta, fetch, outbox::Outbox, util, Ctx, Krate, Registry};
+use anyhow::{Context as _, Error};
 use std::time::Duration;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn, Instrument as _};
+
+/// Uploads an object, followed by its `.sha256` sidecar if enabled, so that
+/// the contents of the storage location can be verified with `sha256sum -c`
+///
+/// If an outbox is provided, objects that fail to upload are queued in it so
+/// that they are retried by the next run.
+#[tracing::instrument(level = "debug", skip_all, fields(key = %krate.cloud_id(is_checkout)))]
+pub(crate) async fn upload(
+    backend: &crate::Storage,
+    data: bytes::Bytes,
+    krate: &Krate,
+    is_checkout: bool,
+    checksums: bool,
+    outbox: Option<&Outbox>,
+) -> Result<usize, Error> {
+    let sidecar = checksums.then(|| sidecar(&util::sha256_hex(&data), krate.cloud_id(is_checkout)));
+
+    let queue = |id: crate::CloudId<'_>, data: &[u8]| {
+        if let Some(outbox) = outbox {
+            let id = id.to_string();
+            match outbox.push(&id, data) {
+                Ok(()) => info!("queued '{id}' in the outbox"),
+                Err(err) => error!("failed to queue '{id}' in the outbox: {err:#}"),
+            }
+        }
+    };
+
+    let mut len = match backend
+        .upload(data.clone(), krate.cloud_id(is_checkout))
+        .await
+    {
+        Ok(len) => len,
+        Err(err) => {
+            queue(krate.cloud_id(is_checkout), &data);
+            if let Some(sidecar) = sidecar {
+                queue(krate.cloud_id(is_checkout).checksum(), sidecar.as_bytes());
+            }
+            return Err(err);
+        }
+    };
+
+    if let Some(sidecar) = sidecar {
+        match backend
+            .upload(
+                sidecar.clone().into(),
+                krate.cloud_id(is_checkout).checksum(),
+            )
+            .await
+        {
+            Ok(sidecar_len) => len += sidecar_len,
+            Err(err) => {
+                queue(krate.cloud_id(is_checkout).checksum(), sidecar.as_bytes());
+                return Err(err.context("failed to upload checksum"));
+            }
+        }
+    }
+
+    Ok(len)
+}
+
+/// The contents of the `.sha256` sidecar of an object, in the same format as
+/// `sha256sum` so that it can be verified with `sha256sum -c`
+fn sidecar(digest: &str, id: crate::CloudId<'_>) -> String {
+    format!("{digest}  {id}\n")
+}
+
+/// Uploads the object the same as [`upload`], unless the object already in
+/// storage has the same contents. Tarballs are packed deterministically, so
+/// this makes uploading objects that are replaced every run, eg. index
+/// snapshots, idempotent.
+async fn upload_if_changed(
+    backend: &crate::Storage,
+    data: bytes::Bytes,
+    krate: &Krate,
+    checksums: bool,
+    outbox: Option<&Outbox>,
+) -> Result<usize, Error> {
+    match is_unchanged(backend, &data, krate, checksums).await {
+        Ok(true) => {
+            info!("'{}' is unchanged, skipping upload", krate.cloud_id(false));
+            return Ok(0);
+        }
+        Ok(false) => {}
+        Err(err) => debug!("unable to compare with the existing object: {err:#}"),
+    }
+
+    upload(backend, data, krate, false, checksums, outbox).await
+}
+
+/// Checks if the object in storage has the same contents as the data, using
+/// its `.sha256` sidecar if checksums are enabled, or by downloading it if
+/// they're not. Objects with a different size are never downloaded.
+async fn is_unchanged(
+    backend: &crate::Storage,
+    data: &[u8],
+    krate: &Krate,
+    checksums: bool,
+) -> Result<bool, Error> {
+    let id = krate.cloud_id(false);
+    let Some(stat) = backend.stat(id).await? else {
+        return Ok(false);
+    };
+
+    if stat.size != data.len() as u64 {
+        return Ok(false);
+    }
+
+    let expected = util::sha256_hex(data);
+    let actual = if checksums {
+        let sidecar = backend
+            .fetch(id.checksum())
+            .await
+            .context("failed to fetch checksum")?;
+        String::from_utf8_lossy(&sidecar)
+            .split_whitespace()
+            .next()
+            .context("checksum is empty")?
+            .to_owned()
+    } else {
+        util::sha256_hex(&backend.fetch(id).await?)
+    };
+
+    Ok(actual.eq_ignore_ascii_case(&expected))
+}
 
 pub struct RegistrySet {
     pub registry: std::sync::Arc<Registry>,
@@ -12,22 +136,26 @@ pub struct RegistrySet {
 pub async fn registry_indices(
     ctx: &crate::Ctx,
     max_stale: Duration,
+    max_deltas: usize,
     registries: Vec<RegistrySet>,
 ) -> usize {
     #[allow(unsafe_code)]
     // SAFETY: we don't forget the future :p
     unsafe {
-        async_scoped::TokioScope::scope_and_collect(|s| {
+        crate::rt::Scope::scope_and_collect(|s| {
             for rset in registries {
-                s.spawn(async {
-                    match registry_index(ctx, max_stale, rset).await {
-                        Ok(size) => size,
-                        Err(err) => {
-                            error!("{err:#}");
-                            0
+                s.spawn(
+                    async {
+                        match registry_index(ctx, max_stale, max_deltas, rset).await {
+                            Ok(size) => size,
+                            Err(err) => {
+                                error!("{err:#}");
+                                0
+                            }
                         }
                     }
-                });
+                    .in_current_span(),
+                );
             }
         })
         .await
@@ -38,30 +166,36 @@ pub async fn registry_indices(
     }
 }
 
-#[tracing::instrument(level = "debug", skip_all)]
+/// Snapshots the registry index and uploads it if the snapshot in storage is
+/// stale. If `max_deltas` is not 0, only the files that changed since the
+/// previous snapshot are uploaded, as a delta on top of the last full snapshot,
+/// until there are `max_deltas` deltas, at which point a full snapshot is
+/// uploaded again.
+#[tracing::instrument(level = "debug", skip_all, fields(registry = %rset.registry.index))]
 pub async fn registry_index(
     ctx: &crate::Ctx,
     max_stale: Duration,
+    max_deltas: usize,
     rset: RegistrySet,
 ) -> Result<usize, Error> {
-    let ident = rset.registry.short_name().to_owned();
-
-    // Create a fake krate for the index, we don't have to worry about clashing
-    // since we use a `.` which is not an allowed character in crate names
-    let krate = Krate {
-        name: ident.clone(),
-        version: "2.0.0".to_owned(),
-        source: Source::Git(crate::cargo::GitSource {
-            url: rset.registry.index.clone(),
-            ident,
-            rev: crate::cargo::GitRev::parse("feedc0de00000000000000000000000000000000").unwrap(),
-            follow: None,
-        }),
-    };
+    let krate = rset.registry.scoped_index_krate(ctx.index_scope.as_deref());
+    let base_id = krate.cloud_id(false).to_string();
+    let state_krate = Krate::object(&delta::state_id(&base_id));
+    let backend = ctx.index_backend();
 
     // Retrieve the metadata for the last updated registry entry, and update
-    // only it if it's stale
-    if let Ok(Some(last_updated)) = ctx.backend.updated(krate.cloud_id(false)).await {
+    // only it if it's stale. When using deltas, the state is updated every
+    // time the index is, unlike the base snapshot.
+    let last_updated = if max_deltas > 0 {
+        match backend.updated(state_krate.cloud_id(false)).await {
+            Ok(Some(updated)) => Ok(Some(updated)),
+            _ => backend.updated(krate.cloud_id(false)).await,
+        }
+    } else {
+        backend.updated(krate.cloud_id(false)).await
+    };
+
+    if let Ok(Some(last_updated)) = last_updated {
         let now = time::OffsetDateTime::now_utc();
 
         if now - last_updated < max_stale {
@@ -77,6 +211,13 @@ pub async fn registry_index(
         &ctx.client,
         &rset.registry,
         rset.krates.into_iter().collect(),
+        &ctx.request_limit,
+        &ctx.retry,
+        &ctx.http,
+        fetch::SnapshotOptions {
+            compression: ctx.compression,
+            sparse_cache: ctx.sparse_index_cache.as_deref(),
+        },
     )
     .await?;
 
@@ -87,7 +228,347 @@ pub async fn registry_index(
 
     let span = tracing::debug_span!("upload");
     let _us = span.enter();
-    ctx.backend.upload(index, krate.cloud_id(false)).await
+
+    if max_deltas == 0 {
+        // Index snapshots are retaken by the next run anyway, so they aren't queued
+        let len = upload_if_changed(backend, index, &krate, ctx.checksums, None).await?;
+
+        // Remove the delta state from a previous run that used deltas, so that
+        // a later run that does doesn't compute deltas against the wrong base
+        if let Err(err) = backend.delete(state_krate.cloud_id(false)).await {
+            debug!("unable to delete index delta state: {err:#}");
+        }
+
+        return Ok(len);
+    }
+
+    let state = match delta::read_state(backend, &base_id).await {
+        Ok(state) => state.filter(|state| state.deltas.len() < max_deltas),
+        Err(err) => {
+            warn!("unable to read index delta state, uploading full snapshot: {err:#}");
+            None
+        }
+    };
+
+    let (state, len) = if let Some(mut state) = state {
+        let (files, delta) = {
+            let index = index.clone();
+            let previous = std::mem::take(&mut state.files);
+            let compression = ctx.compression;
+            crate::rt::spawn_blocking(move || delta::diff(&index, Some(&previous), compression))
+                .await?
+        };
+        state.files = files;
+
+        let mut len = 0;
+        if let Some(delta) = delta {
+            let delta_krate = Krate::object(&delta::delta_id(&base_id, state.deltas.len() + 1));
+            len = upload(
+                backend,
+                delta.data,
+                &delta_krate,
+                false,
+                ctx.checksums,
+                None,
+            )
+            .await?;
+            state.deltas.push(delta.removed);
+
+            info!(
+                "uploaded index delta {} of {max_deltas}",
+                state.deltas.len()
+            );
+        } else {
+            info!("index is unchanged");
+        }
+
+        (state, len)
+    } else {
+        let (files, _) = {
+            let index = index.clone();
+            let compression = ctx.compression;
+            crate::rt::spawn_blocking(move || delta::diff(&index, None, compression)).await?
+        };
+
+        let state = delta::State {
+            base: util::sha256_hex(&index),
+            files,
+            deltas: Vec::new(),
+            base_id: None,
+        };
+
+        // The base is uploaded before the state, so that a sync in between
+        // sees the state is for a different base and ignores the old deltas
+        let len = upload_if_changed(backend, index, &krate, ctx.checksums, None).await?;
+        (state, len)
+    };
+
+    // The state is always uploaded, even if nothing changed, as its update
+    // time determines when the index is next considered stale
+    let state = serde_json::to_vec(&state)?;
+    Ok(len + upload(backend, state.into(), &state_krate, false, false, None).await?)
+}
+
+/// Uploads tarballs of the crates patched with local paths. These are always
+/// packed as the contents of a path can change without its version changing.
+///
+/// Each tarball is stored under an id that includes its SHA-256, and the
+/// object under the crate's name and version is updated to hold that hash,
+/// so that `sync` can find the latest tarball without having the path.
+pub async fn path_patches(ctx: &Ctx) -> Result<usize, Error> {
+    let mut total_bytes = 0;
+
+    for patch in &ctx.path_patches {
+        let span = tracing::info_span!("mirror", krate = %patch.name, version = %patch.version);
+        let _ms = span.enter();
+
+        let tarball = {
+            let patch = patch.clone();
+            let compression = ctx.compression;
+            crate::rt::spawn_blocking(move || fetch::path_patch(&patch, compression)).await
+        };
+
+        let tarball = match tarball {
+            Ok(tb) => tb,
+            Err(err) => {
+                error!("failed to pack '{}': {err:#}", patch.path);
+                continue;
+            }
+        };
+
+        debug!(size = tarball.len(), "packed");
+
+        let hash = util::sha256_hex(&tarball);
+        let tarball_krate = patch.tarball_krate(&hash);
+        match upload_if_changed(
+            &ctx.backend,
+            tarball,
+            &tarball_krate,
+            ctx.checksums,
+            ctx.outbox.as_ref(),
+        )
+        .await
+        {
+            Ok(len) => total_bytes += len,
+            Err(err) => {
+                error!("failed to upload path patch tarball: {err:#}");
+                continue;
+            }
+        }
+
+        // The hash is only recorded once the tarball it refers to is uploaded
+        match upload_if_changed(
+            &ctx.backend,
+            hash.into(),
+            &patch.krate(),
+            false,
+            ctx.outbox.as_ref(),
+        )
+        .await
+        {
+            Ok(len) => total_bytes += len,
+            Err(err) => error!("failed to upload path patch hash: {err:#}"),
+        }
+    }
+
+    Ok(total_bytes)
+}
+
+/// Looks up the crates in the lockfile(s) in their registry's index, and
+/// returns any versions newer than the locked ones that have not already been
+/// mirrored, so that dependency bumps find them already present.
+///
+/// The newest semver compatible version (what `cargo update` would lock) and
+/// the newest version overall are selected for each crate, pre-releases and
+/// yanked versions are ignored.
+pub async fn prewarm(ctx: &Ctx) -> Result<Vec<Krate>, Error> {
+    let mut names = ctx.backend.list().await?;
+    names.sort();
+
+    let mut prewarm = Vec::new();
+
+    for registry in &ctx.registries {
+        let locked: Vec<_> = ctx
+            .krates
+            .iter()
+            .filter(|krate| *krate == registry.as_ref())
+            .collect();
+
+        if locked.is_empty() {
+            continue;
+        }
+
+        let entries = fetch::index_entries(
+            &ctx.client,
+            registry,
+            locked.iter().map(|krate| krate.name.clone()).collect(),
+            &ctx.request_limit,
+            &ctx.http,
+            ctx.sparse_index_cache.as_deref(),
+        )
+        .await
+        .with_context(|| format!("failed to read index for '{}'", registry.index))?;
+
+        for krate in locked {
+            let Some(ik) = entries.get(&krate.name) else {
+                continue;
+            };
+
+            for iv in newer_versions(&krate.version, ik) {
+                let candidate = Krate {
+                    name: krate.name.clone(),
+                    version: iv.version.to_string(),
+                    source: crate::Source::Registry(crate::RegistrySource {
+                        registry: registry.clone(),
+                        chksum: iv.checksum.to_string(),
+                    }),
+                };
+
+                let id = candidate.cloud_id(false).to_string();
+                if names.binary_search(&id).is_err() && !prewarm.contains(&candidate) {
+                    prewarm.push(candidate);
+                }
+            }
+        }
+    }
+
+    Ok(prewarm)
+}
+
+/// Selects the newest semver compatible version, and the newest version
+/// overall, that are newer than the locked version
+fn newer_versions<'ik>(
+    locked: &str,
+    ik: &'ik tame_index::IndexKrate,
+) -> Vec<&'ik tame_index::IndexVersion> {
+    let Ok(locked) = semver::Version::parse(locked) else {
+        return Vec::new();
+    };
+    let compatible = semver::VersionReq::parse(&format!("^{locked}")).ok();
+
+    let mut newest_compatible = None;
+    let mut newest = None;
+
+    for iv in &ik.versions {
+        if iv.is_yanked() {
+            continue;
+        }
+
+        let Ok(version) = semver::Version::parse(&iv.version) else {
+            continue;
+        };
+
+        if !version.pre.is_empty() || version <= locked {
+            continue;
+        }
+
+        if compatible.as_ref().is_some_and(|req| req.matches(&version))
+            && newest_compatible.as_ref().is_none_or(|(v, _)| version > *v)
+        {
+            newest_compatible = Some((version.clone(), iv));
+        }
+
+        if newest.as_ref().is_none_or(|(v, _)| version > *v) {
+            newest = Some((version, iv));
+        }
+    }
+
+    let mut versions: Vec<_> = newest_compatible.into_iter().map(|(_, iv)| iv).collect();
+    if let Some((_, iv)) = newest {
+        if !versions.iter().any(|v| std::ptr::eq(*v, iv)) {
+            versions.push(iv);
+        }
+    }
+
+    versions
+}
+
+/// The last db mirrored for a git repository, unpacked so that the next
+/// revision can be fetched into it
+struct GitBase {
+    state: delta::State,
+    dir: tempfile::TempDir,
+}
+
+/// Unpacks the last db mirrored for the repository, if it was uploaded with
+/// deltas enabled and fewer than `max_deltas` deltas have been uploaded since
+/// the last full db
+async fn unpack_git_base(
+    backend: &crate::Storage,
+    ident: &str,
+    max_deltas: usize,
+) -> Result<Option<GitBase>, Error> {
+    let Some(state) = delta::read_state(backend, ident)
+        .await?
+        .filter(|state| state.deltas.len() < max_deltas)
+    else {
+        return Ok(None);
+    };
+    let Some(pointer) = state.pointer() else {
+        return Ok(None);
+    };
+
+    let dir = tempfile::tempdir()?;
+    pointer.unpack(backend, util::path(dir.path())?).await?;
+
+    Ok(Some(GitBase { state, dir }))
+}
+
+/// Uploads the git db, as a delta on top of the base if it was fetched into
+/// it, or as a new full db that later revisions are uploaded as deltas of
+async fn upload_git_db(
+    backend: &crate::Storage,
+    db: bytes::Bytes,
+    krate: &Krate,
+    ident: &str,
+    base: Option<delta::State>,
+    compression: util::Compression,
+    checksums: bool,
+) -> Result<usize, Error> {
+    let (state, len) = if let Some(mut state) = base {
+        let (files, delta) = {
+            let previous = std::mem::take(&mut state.files);
+            crate::rt::spawn_blocking(move || delta::diff(&db, Some(&previous), compression))
+                .await?
+        };
+        state.files = files;
+
+        let mut len = 0;
+        if let Some(delta) = delta {
+            let base_id = state
+                .base_id
+                .clone()
+                .context("git delta state has no base")?;
+            let delta_krate = Krate::object(&delta::delta_id(&base_id, state.deltas.len() + 1));
+            len = upload(backend, delta.data, &delta_krate, false, checksums, None).await?;
+            state.deltas.push(delta.removed);
+
+            info!("uploaded git db delta {}", state.deltas.len());
+        }
+
+        let pointer = serde_json::to_vec(&state.pointer().context("git delta state has no base")?)?;
+        len += upload(backend, pointer.into(), krate, false, checksums, None).await?;
+        (state, len)
+    } else {
+        let (files, _) = {
+            let db = db.clone();
+            crate::rt::spawn_blocking(move || delta::diff(&db, None, compression)).await?
+        };
+
+        let state = delta::State {
+            base: util::sha256_hex(&db),
+            files,
+            deltas: Vec::new(),
+            base_id: Some(krate.cloud_id(false).to_string()),
+        };
+
+        let len = upload(backend, db, krate, false, checksums, None).await?;
+        (state, len)
+    };
+
+    let state = serde_json::to_vec(&state)?;
+    let state_krate = Krate::object(&delta::state_id(ident));
+    Ok(len + upload(backend, state.into(), &state_krate, false, false, None).await?)
 }
 
 pub async fn crates(ctx: &Ctx) -> Result<usize, Error> {
@@ -97,23 +578,60 @@ pub async fn crates(ctx: &Ctx) -> Result<usize, Error> {
     names.sort();
 
     let mut to_mirror = Vec::with_capacity(names.len());
-    for krate in &ctx.krates {
-        let cid = krate.cloud_id(false).to_string();
-        if names
-            .binary_search_by(|name| name.as_str().cmp(&cid))
+    let is_missing = |id: String| {
+        names
+            .binary_search_by(|name| name.as_str().cmp(&id))
             .is_err()
-        {
+    };
+
+    let mut present = Vec::new();
+    let mut unsummed = Vec::new();
+    for krate in &ctx.krates {
+        if is_missing(krate.cloud_id(false).to_string()) {
             to_mirror.push(krate.clone());
+            continue;
         }
+
+        // Crates mirrored before checksums were enabled need a sidecar. Git
+        // crates have no checksum to write it from, so are mirrored again.
+        if ctx.checksums && is_missing(krate.cloud_id(false).checksum().to_string()) {
+            if matches!(krate.source, crate::Source::Git(_)) {
+                to_mirror.push(krate.clone());
+                continue;
+            }
+            unsummed.push(krate);
+        }
+        present.push(krate);
     }
 
+    let refreshed = if ctx.refresh_mismatched {
+        let mut refreshed = mismatched(ctx, &present, &names).await;
+        refreshed.sort();
+        to_mirror.extend(refreshed.iter().cloned());
+        refreshed
+    } else {
+        Vec::new()
+    };
+
+    if let Some(report) = &ctx.report {
+        for krate in present {
+            if refreshed.binary_search(krate).is_err() {
+                report.skipped(krate);
+            }
+        }
+    }
+
+    // Crates that are mirrored again get their sidecar when they're uploaded
+    unsummed.retain(|krate| refreshed.binary_search(*krate).is_err());
+    let sidecar_bytes = upload_sidecars(ctx, &unsummed).await;
+
     // Remove duplicates, eg. when 2 crates are sourced from the same git repository
     to_mirror.sort();
     to_mirror.dedup();
 
     if to_mirror.is_empty() {
         info!("all crates already uploaded");
-        return Ok(0);
+        return Ok(sidecar_bytes);
     }
 
     info!(
@@ -124,82 +642,205 @@ pub async fn crates(ctx: &Ctx) -> Result<usize, Error> {
 
     let client = &ctx.client;
     let backend = &ctx.backend;
+    let limit = &ctx.request_limit;
+    let retry = &ctx.retry;
+    let git_options = &ctx.git_clone_options();
+    let compression = ctx.compression;
+    let checkout_exclusions = &ctx.checkout_exclusions;
+    let checksums = ctx.checksums;
+    let metrics = ctx.metrics.as_deref();
+    let report = ctx.report.as_deref();
+    let outbox = ctx.outbox.as_ref();
+    let git_deltas = ctx.git_deltas;
+    let refreshed = &refreshed;
+
+    // Revisions of the same repository are mirrored one at a time when using
+    // deltas, as each is fetched into, and updates the state of, the last one
+    let git_locks: std::collections::BTreeMap<_, _> = to_mirror
+        .iter()
+        .filter_map(|krate| match &krate.source {
+            crate::Source::Git(gs) if git_deltas > 0 => Some(gs.ident.clone()),
+            _ => None,
+        })
+        .map(|ident| (ident, crate::rt::Semaphore::new(1)))
+        .collect();
+    let git_locks = &git_locks;
 
     #[allow(unsafe_code)]
     // SAFETY: we don't forget the future :p
     let total_bytes = unsafe {
-        async_scoped::TokioScope::scope_and_collect(|s| {
+        crate::rt::Scope::scope_and_collect(|s| {
             for krate in to_mirror {
+                // Created outside of the task so that it's a child of the
+                // current span
+                let span = tracing::info_span!("mirror", %krate);
+
                 s.spawn(async move {
-                    let span = tracing::info_span!("mirror", %krate);
+                    // Hold the permit for both the download and upload(s)
+                    let _permit = limit.acquire().await;
+
                     let _ms = span.enter();
 
-                    let fetch_res = {
-                        let span = tracing::debug_span!("fetch");
-                        let _ms = span.enter();
-                        fetch::from_registry(client, &krate).await
-                    };
+                    let start = std::time::Instant::now();
+
+                    let res = async {
+                        let git_ident = match &krate.source {
+                            crate::Source::Git(gs) => git_locks.get_key_value(&gs.ident),
+                            crate::Source::Registry(_) => None,
+                        };
+                        let _git_lock = match git_ident {
+                            Some((_, lock)) => {
+                                Some(lock.acquire().await.expect("the semaphore is never closed"))
+                            }
+                            None => None,
+                        };
+                        let git_base = match git_ident {
+                            Some((ident, _)) => unpack_git_base(backend, ident, git_deltas)
+                                .await
+                                .unwrap_or_else(|err| {
+                                    warn!("unable to unpack previous git db, uploading full db: {err:#}");
+                                    None
+                                }),
+                            None => None,
+                        };
+                        let base_options;
+                        let git_options = match &git_base {
+                            Some(base) => {
+                                base_options = crate::git::CloneOptions {
+                                    base: Some(util::path(base.dir.path())?.to_owned()),
+                                    ..git_options.clone()
+                                };
+                                &base_options
+                            }
+                            None => git_options,
+                        };
+
+                        let krate_data = {
+                            let span = tracing::debug_span!("fetch");
+                            let _ms = span.enter();
+                            fetch::from_registry(
+                                client,
+                                &krate,
+                                retry,
+                                git_options,
+                                compression,
+                                checkout_exclusions,
+                            )
+                            .await
+                            .context("failed to retrieve")?
+                        };
+
+                        debug!(size = krate_data.len(), "fetched");
 
-                    match fetch_res {
-                        Ok(krate_data) => {
-                            debug!(size = krate_data.len(), "fetched");
-
-                            {
-                                let span = tracing::debug_span!("upload");
-                                let _us = span.enter();
-
-                                match krate_data {
-                                    fetch::KratePackage::Registry(buffer) => {
-                                        match backend.upload(buffer, krate.cloud_id(false)).await {
-                                            Ok(len) => len,
-                                            Err(err) => {
-                                                error!("failed to upload crate tarball: {err:#}");
-                                                0
+                        let span = tracing::debug_span!("upload");
+                        let _us = span.enter();
+
+                        match krate_data {
+                            fetch::KratePackage::Registry(buffer) => {
+                                upload(backend, buffer, &krate, false, checksums, outbox)
+                                    .await
+                                    .context("failed to upload crate tarball")
+                            }
+                            fetch::KratePackage::Git(gs) => {
+                                let db = gs.db;
+                                let dbk = krate.clone();
+                                let co = krate.clone();
+                                let checkout = gs.checkout;
+                                let db_backend = backend.clone();
+                                let db_outbox = outbox.cloned();
+                                let git_ident = git_ident.map(|(ident, _)| ident.clone());
+                                // The base is only used if the db was actually
+                                // fetched into it
+                                let base = git_base
+                                    .filter(|_| gs.from_base)
+                                    .map(|base| base.state);
+
+                                let db_fut = crate::rt::spawn(
+                                    async move {
+                                        match git_ident {
+                                            Some(ident) => upload_git_db(
+                                                &db_backend,
+                                                db,
+                                                &dbk,
+                                                &ident,
+                                                base,
+                                                compression,
+                                                checksums,
+                                            )
+                                            .await,
+                                            None => {
+                                                upload(
+                                                    &db_backend,
+                                                    db,
+                                                    &dbk,
+                                                    false,
+                                                    checksums,
+                                                    db_outbox.as_ref(),
+                                                )
+                                                .await
                                             }
                                         }
+                                        .context("failed to upload git db")
                                     }
-                                    fetch::KratePackage::Git(gs) => {
-                                        let db = gs.db;
-                                        let co = krate.clone();
-                                        let checkout = gs.checkout;
-                                        let db_backend = backend.clone();
-
-                                        let db_fut = tokio::task::spawn(async move {
-                                            match db_backend.upload(db, krate.cloud_id(false)).await {
-                                                Ok(l) => l,
-                                                Err(err) => {
-                                                    error!("failed to upload git db: {err:#}");
-                                                    0
-                                                }
-                                            }
-                                        });
-
-                                        let co_backend = backend.clone();
-                                        let co_fut = tokio::task::spawn(async move {
-                                            if let Some(buffer) = checkout {
-                                                match co_backend.upload(buffer, co.cloud_id(true)).await {
-                                                    Ok(l) => l,
-                                                    Err(err) => {
-                                                        error!("failed to upload git checkout: {err:#}");
-                                                        0
-                                                    }
-                                                }
-                                            } else {
-                                                0
-                                            }
-                                        });
+                                    .in_current_span(),
+                                );
 
-                                        let (db, co) = tokio::join!(db_fut, co_fut);
-                                        db.unwrap() + co.unwrap()
+                                let co_backend = backend.clone();
+                                let co_outbox = outbox.cloned();
+                                let co_fut = crate::rt::spawn(
+                                    async move {
+                                        if let Some(buffer) = checkout {
+                                            upload(
+                                                &co_backend,
+                                                buffer,
+                                                &co,
+                                                true,
+                                                checksums,
+                                                co_outbox.as_ref(),
+                                            )
+                                            .await
+                                            .context("failed to upload git checkout")
+                                        } else {
+                                            Ok(0)
+                                        }
                                     }
-                                }
+                                    .in_current_span(),
+                                );
+
+                                let (db, co) = crate::rt::join!(db_fut, co_fut);
+                                Ok(db.unwrap()? + co.unwrap()?)
                             }
                         }
+                    }
+                    .await;
+
+                    let uploaded = match &res {
+                        Ok(len) => *len,
                         Err(err) => {
-                            error!(krate = %krate, "failed to retrieve: {err:#}");
+                            error!(krate = %krate, "{err:#}");
                             0
                         }
+                    };
+
+                    if let Some(metrics) = metrics {
+                        metrics.krate("mirror", res.is_ok());
                     }
+
+                    if let Some(report) = report {
+                        report.record(
+                            &krate,
+                            if refreshed.binary_search(&krate).is_ok() {
+                                crate::report::Action::Refreshed
+                            } else {
+                                crate::report::Action::Uploaded
+                            },
+                            uploaded as u64,
+                            start.elapsed(),
+                            res.as_ref().err(),
+                        );
+                    }
+
+                    uploaded
                 });
             }
         })
@@ -207,8 +848,177 @@ pub async fn crates(ctx: &Ctx) -> Result<usize, Error> {
         .1
         .into_iter()
         .map(|res| res.unwrap())
-        .sum()
+        .sum::<usize>()
     };
 
-    Ok(total_bytes)
+    Ok(total_bytes + sidecar_bytes)
+}
+
+/// Uploads the `.sha256` sidecars of registry crates that were mirrored
+/// before checksums were enabled. The stored crate is downloaded and hashed,
+/// rather than uploading it again, as it may have been mirrored by an older
+/// version or from another lockfile, so the sidecar always reflects what is
+/// actually stored, and `--refresh-mismatched` can still detect a crate whose
+/// contents don't match the lockfile.
+async fn upload_sidecars(ctx: &Ctx, krates: &[&Krate]) -> usize {
+    if krates.is_empty() {
+        return 0;
+    }
+
+    let mut tasks = crate::rt::JoinSet::new();
+    for krate in krates {
+        let crate::Source::Registry(rs) = &krate.source else {
+            continue;
+        };
+
+        let krate = (*krate).clone();
+        let chksum = rs.chksum.clone();
+        let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+
+        tasks.spawn(
+            async move {
+                let _permit = limit.acquire().await;
+
+                let digest = util::sha256_hex(
+                    &backend
+                        .fetch(krate.cloud_id(false))
+                        .await
+                        .with_context(|| format!("failed to download {krate}"))?,
+                );
+                if digest != chksum {
+                    warn!("the contents of {krate} don't match the checksum in the lockfile, use --refresh-mismatched to mirror it again");
+                }
+
+                let sidecar = sidecar(&digest, krate.cloud_id(false));
+                backend
+                    .upload(sidecar.into(), krate.cloud_id(false).checksum())
+                    .await
+                    .with_context(|| format!("failed to upload checksum for {krate}"))
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut uploaded = 0;
+    let mut total_bytes = 0;
+    while let Some(res) = tasks.join_next().await {
+        match res.expect("sidecar task panicked") {
+            Ok(len) => {
+                uploaded += 1;
+                total_bytes += len;
+            }
+            Err(err) => error!("{err:#}"),
+        }
+    }
+
+    info!("uploaded checksums for {uploaded} already mirrored crate(s)");
+    total_bytes
+}
+
+/// Finds the registry crates already present in the storage location whose
+/// contents don't match the checksum in the lockfile, eg. because the crate
+/// was yanked and then republished with different contents. The digest in the
+/// `.sha256` sidecar is used if there is one, otherwise the crate is
+/// downloaded and hashed.
+async fn mismatched(ctx: &Ctx, present: &[&Krate], names: &[String]) -> Vec<Krate> {
+    let mut tasks = crate::rt::JoinSet::new();
+    for krate in present {
+        let crate::Source::Registry(rs) = &krate.source else {
+            continue;
+        };
+
+        let krate = (*krate).clone();
+        let chksum = rs.chksum.clone();
+        let sidecar = krate.cloud_id(false).checksum().to_string();
+        let has_sidecar = names.binary_search(&sidecar).is_ok();
+        let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+
+        tasks.spawn(
+            async move {
+                let _permit = limit.acquire().await;
+
+                let digest = if has_sidecar {
+                    let sidecar = backend
+                        .fetch(krate.cloud_id(false).checksum())
+                        .await
+                        .context("failed to download checksum")?;
+                    String::from_utf8_lossy(&sidecar)
+                        .split_whitespace()
+                        .next()
+                        .context("checksum is empty")?
+                        .to_owned()
+                } else {
+                    let data = backend
+                        .fetch(krate.cloud_id(false))
+                        .await
+                        .context("failed to download")?;
+                    util::sha256_hex(&data)
+                };
+
+                Ok::<_, Error>((!digest.eq_ignore_ascii_case(&chksum)).then_some(krate))
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut mismatched = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        match res.expect("checksum task panicked") {
+            Ok(Some(krate)) => {
+                warn!(
+                    code = crate::error::Code::ChecksumConflict.as_str(),
+                    "{krate} doesn't match the checksum in the lockfile, mirroring it again"
+                );
+                mismatched.push(krate);
+            }
+            Ok(None) => {}
+            Err(err) => warn!("unable to verify the checksum of a mirrored crate: {err:#}"),
+        }
+    }
+
+    if !mismatched.is_empty() {
+        info!(
+            "{} mirrored crate(s) don't match the lockfile",
+            mismatched.len()
+        );
+    }
+
+    mismatched
+}
+
+#[cfg(test)]
+mod test {
+    use super::newer_versions;
+    use tame_index::{IndexKrate, IndexVersion};
+
+    #[test]
+    fn selects_newer_versions() {
+        let mut yanked = IndexVersion::fake("foo", "1.4.0");
+        yanked.yanked = true;
+
+        let ik = IndexKrate {
+            versions: vec![
+                IndexVersion::fake("foo", "1.0.0"),
+                IndexVersion::fake("foo", "1.2.0"),
+                IndexVersion::fake("foo", "1.3.0"),
+                yanked,
+                IndexVersion::fake("foo", "2.0.0"),
+                IndexVersion::fake("foo", "2.1.0-rc.1"),
+            ],
+        };
+
+        let versions = |locked| -> Vec<_> {
+            newer_versions(locked, &ik)
+                .into_iter()
+                .map(|iv| iv.version.as_str())
+                .collect()
+        };
+
+        assert_eq!(versions("1.2.0"), ["1.3.0", "2.0.0"]);
+        assert_eq!(versions("1.3.0"), ["2.0.0"]);
+        assert!(versions("2.0.0").is_empty());
+        assert_eq!(versions("0.9.0"), ["2.0.0"]);
+    }
 }
@@ -0,0 +1,183 @@
+//! An on-disk queue of uploads that failed, eg. due to intermittent
+//! connectivity, so that they are retried by a later run of `mirror` rather
+//! than being lost
+
+use crate::{util, Krate, Path, PathBuf, Storage};
+use anyhow::{Context as _, Error};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// The manifest for a pending upload, the object itself is stored alongside
+/// it in `<id>.data`
+#[derive(Serialize, Deserialize, Debug)]
+struct Pending {
+    /// The id of the object in the storage location
+    id: String,
+    /// The sha-256 of the object, so that a corrupted entry is never uploaded
+    sha256: String,
+    size: u64,
+}
+
+/// The result of draining the outbox
+#[derive(Debug, Default)]
+pub struct Drained {
+    /// The number of pending uploads that were uploaded
+    pub uploaded: usize,
+    /// The number of pending uploads that failed again, and remain queued
+    pub failed: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("unable to create outbox '{dir}'"))?;
+        Ok(Self { dir })
+    }
+
+    #[inline]
+    fn paths(&self, id: &str) -> (PathBuf, PathBuf) {
+        (
+            self.dir.join(format!("{id}.json")),
+            self.dir.join(format!("{id}.data")),
+        )
+    }
+
+    /// Queues an object to be uploaded by a later run.
+    ///
+    /// The object is written before its manifest, and both are written to a
+    /// temporary file first, so that an interrupted write never results in an
+    /// entry that appears to be complete.
+    pub fn push(&self, id: &str, data: &[u8]) -> Result<(), Error> {
+        let (manifest_path, data_path) = self.paths(id);
+
+        let manifest = serde_json::to_vec(&Pending {
+            id: id.to_owned(),
+            sha256: util::sha256_hex(data),
+            size: data.len() as u64,
+        })?;
+
+        write_atomic(&data_path, data)?;
+        write_atomic(&manifest_path, &manifest)?;
+
+        Ok(())
+    }
+
+    /// Reads the manifests of every pending upload
+    fn pending(&self) -> Result<Vec<Pending>, Error> {
+        let mut pending = Vec::new();
+
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("unable to read outbox '{}'", self.dir))?
+        {
+            let path = util::path(&entry?.path())?.to_owned();
+            if path.extension() != Some("json") {
+                continue;
+            }
+
+            match std::fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|manifest| Ok(serde_json::from_slice(&manifest)?))
+            {
+                Ok(p) => pending.push(p),
+                Err(err) => warn!("ignoring invalid outbox entry '{path}': {err:#}"),
+            }
+        }
+
+        pending.sort_by(|a: &Pending, b| a.id.cmp(&b.id));
+        Ok(pending)
+    }
+
+    /// Uploads every pending object, removing the ones that succeed. Objects
+    /// whose contents no longer match their checksum are discarded.
+    pub async fn drain(&self, backend: &Storage) -> Result<Drained, Error> {
+        let mut drained = Drained::default();
+
+        for pending in self.pending()? {
+            let (manifest_path, data_path) = self.paths(&pending.id);
+
+            let data = match std::fs::read(&data_path) {
+                Ok(data) if util::sha256_hex(&data) == pending.sha256 => data,
+                Ok(_) | Err(_) => {
+                    warn!("discarding corrupted outbox entry '{}'", pending.id);
+                    remove(&manifest_path, &data_path);
+                    continue;
+                }
+            };
+
+            let krate = Krate::object(&pending.id);
+            match backend.upload(data.into(), krate.cloud_id(false)).await {
+                Ok(_) => {
+                    info!(size = pending.size, "uploaded queued '{}'", pending.id);
+                    remove(&manifest_path, &data_path);
+                    drained.uploaded += 1;
+                }
+                Err(err) => {
+                    warn!("failed to upload queued '{}': {err:#}", pending.id);
+                    drained.failed += 1;
+                }
+            }
+        }
+
+        Ok(drained)
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = PathBuf::from(format!("{path}.tmp"));
+    std::fs::write(&tmp, data).with_context(|| format!("unable to write '{tmp}'"))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("unable to move '{tmp}' to '{path}'"))
+}
+
+/// Removes an entry, the manifest first so that a partially removed entry is
+/// never drained
+fn remove(manifest_path: &Path, data_path: &Path) {
+    if let Err(err) = std::fs::remove_file(manifest_path) {
+        warn!("unable to remove '{manifest_path}': {err}");
+    }
+    let _ = std::fs::remove_file(data_path);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn drains_pending_uploads() {
+        let outbox_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+
+        let outbox = Outbox::new(util::path(outbox_dir.path()).unwrap().to_owned()).unwrap();
+        let storage_path = util::path(storage_dir.path()).unwrap();
+        let backend: Storage = std::sync::Arc::new(
+            crate::backends::fs::FsBackend::new(crate::FilesystemLocation { path: storage_path })
+                .unwrap(),
+        );
+
+        outbox.push("good", b"contents").unwrap();
+        outbox.push("good.sha256", b"abcd  good\n").unwrap();
+        outbox.push("corrupt", b"contents").unwrap();
+        std::fs::write(outbox_dir.path().join("corrupt.data"), b"tampered").unwrap();
+
+        let drained = outbox.drain(&backend).await.unwrap();
+        assert_eq!(drained.uploaded, 2);
+        assert_eq!(drained.failed, 0);
+
+        assert_eq!(
+            std::fs::read(storage_path.join("good")).unwrap(),
+            b"contents"
+        );
+        assert_eq!(
+            std::fs::read(storage_path.join("good.sha256")).unwrap(),
+            b"abcd  good\n"
+        );
+        assert!(!storage_path.join("corrupt").exists());
+
+        // Every entry, including the corrupted one, is removed
+        assert_eq!(std::fs::read_dir(outbox_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often progress is redrawn when attached to a terminal
+const TTY_INTERVAL: Duration = Duration::from_millis(200);
+/// How often progress is logged when not attached to a terminal, eg. in CI
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks the number of crates that have been downloaded and unpacked during
+/// a sync
+pub struct Progress {
+    total: u64,
+    downloaded: AtomicU64,
+    unpacked: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Arc<Self> {
+        Arc::new(Self {
+            total: total as u64,
+            downloaded: AtomicU64::new(0),
+            unpacked: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records that a crate was downloaded
+    #[inline]
+    pub fn downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records that a crate was unpacked to disk
+    #[inline]
+    pub fn unpacked(&self) {
+        self.unpacked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a crate failed to download or unpack
+    #[inline]
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The estimated time remaining, based on the rate crates have been
+    /// completed so far
+    fn eta(&self) -> Option<Duration> {
+        let done = self.unpacked.load(Ordering::Relaxed) + self.failed.load(Ordering::Relaxed);
+        if done == 0 {
+            return None;
+        }
+
+        let remaining = self.total.saturating_sub(done);
+        Some(self.start.elapsed().mul_f64(remaining as f64 / done as f64))
+    }
+
+    /// Starts periodically reporting progress until the returned [`Reporter`]
+    /// is finished. Progress is drawn on a single line if stderr is a terminal,
+    /// otherwise it is logged periodically.
+    pub fn report(self: &Arc<Self>) -> Reporter {
+        use std::io::IsTerminal as _;
+
+        let is_tty = std::io::stderr().is_terminal();
+        let progress = self.clone();
+
+        let task = crate::rt::spawn(async move {
+            let interval = if is_tty { TTY_INTERVAL } else { LOG_INTERVAL };
+
+            loop {
+                crate::rt::sleep(interval).await;
+
+                if is_tty {
+                    eprint!("\r{progress}\x1b[K");
+                } else {
+                    tracing::info!("{progress}");
+                }
+            }
+        });
+
+        Reporter {
+            progress: self.clone(),
+            task,
+            is_tty,
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "downloaded {}/{total} ({}), unpacked {}/{total}",
+            self.downloaded.load(Ordering::Relaxed),
+            crate::util::human_size(self.bytes.load(Ordering::Relaxed)),
+            self.unpacked.load(Ordering::Relaxed),
+            total = self.total,
+        )?;
+
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            write!(f, ", {failed} failed")?;
+        }
+
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {}s", eta.as_secs())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Periodically reports progress until finished
+pub struct Reporter {
+    progress: Arc<Progress>,
+    task: crate::rt::JoinHandle<()>,
+    is_tty: bool,
+}
+
+impl Reporter {
+    /// Stops reporting, emitting the final progress
+    pub fn finish(self) {
+        self.task.abort();
+
+        if self.is_tty {
+            eprintln!("\r{}\x1b[K", self.progress);
+        } else {
+            tracing::info!("{}", self.progress);
+        }
+    }
+}
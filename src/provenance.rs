@@ -0,0 +1,187 @@
+//! Maps every crate in the lockfile(s) to where it is sourced from and the
+//! object it is stored as, so that the provenance of each crate can be
+//! reviewed without access to the storage location
+
+use crate::{
+    cargo::{GitFollow, Origins, PathPatch},
+    Ctx, Krate, Source,
+};
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Registry,
+    Git,
+    /// A crate patched via `[patch]` with a local path
+    Path,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Registry => "registry",
+            Self::Git => "git",
+            Self::Path => "path",
+        }
+    }
+}
+
+/// The source of a single crate
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub version: String,
+    pub kind: Kind,
+    /// The registry index url, or git url, the crate is sourced from
+    pub source: String,
+    /// The git revision, as locked in the lockfile
+    pub rev: Option<String>,
+    /// The branch or tag followed by a git source, eg. `branch=main`
+    pub follow: Option<String>,
+    /// The key of the object the crate is stored as in the storage location
+    pub key: String,
+    /// The key of the object the checkout of a git source is stored as
+    pub checkout_key: Option<String>,
+    /// The SHA-256 checksum of registry crates, from the lockfile
+    pub checksum: Option<String>,
+    /// The lockfiles the crate is locked in
+    pub lock_files: Vec<String>,
+}
+
+/// Gets the source of every crate in the lockfile(s), including those patched
+/// with a local path
+#[inline]
+pub fn crates(ctx: &Ctx) -> Vec<Entry> {
+    entries(&ctx.krates, &ctx.path_patches, &ctx.origins)
+}
+
+/// Gets the source of the crates and path patches, which doesn't require a
+/// storage location
+pub fn entries(krates: &[Krate], path_patches: &[PathPatch], origins: &Origins) -> Vec<Entry> {
+    let mut entries: Vec<_> = krates
+        .iter()
+        .map(|krate| {
+            let (kind, source, rev, follow, checkout_key, checksum) = match &krate.source {
+                Source::Registry(rs) => (
+                    Kind::Registry,
+                    rs.registry.index.to_string(),
+                    None,
+                    None,
+                    None,
+                    Some(rs.chksum.clone()),
+                ),
+                Source::Git(gs) => (
+                    Kind::Git,
+                    gs.url.to_string(),
+                    Some(gs.rev.to_string()),
+                    gs.follow.as_ref().map(|follow| match follow {
+                        GitFollow::Branch(branch) => format!("branch={branch}"),
+                        GitFollow::Tag(tag) => format!("tag={tag}"),
+                    }),
+                    Some(krate.cloud_id(true).to_string()),
+                    None,
+                ),
+            };
+
+            Entry {
+                name: krate.name.clone(),
+                version: krate.version.clone(),
+                kind,
+                source,
+                rev,
+                follow,
+                key: krate.cloud_id(false).to_string(),
+                checkout_key,
+                checksum,
+                lock_files: origins.lock_files(krate).map(ToString::to_string).collect(),
+            }
+        })
+        .collect();
+
+    entries.extend(path_patches.iter().map(|patch| Entry {
+        name: patch.name.clone(),
+        version: patch.version.clone(),
+        kind: Kind::Path,
+        source: patch.path.to_string(),
+        rev: None,
+        follow: None,
+        key: patch.krate().cloud_id(false).to_string(),
+        checkout_key: None,
+        checksum: None,
+        lock_files: Vec::new(),
+    }));
+
+    entries.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    entries
+}
+
+/// Writes the entries as CSV, with a header row
+pub fn write_csv(entries: &[Entry], mut w: impl Write) -> std::io::Result<()> {
+    // Quotes fields that contain a delimiter, quote, or newline, as per RFC 4180
+    fn field(s: &str) -> std::borrow::Cow<'_, str> {
+        if s.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", s.replace('"', "\"\"")).into()
+        } else {
+            s.into()
+        }
+    }
+
+    writeln!(
+        w,
+        "name,version,kind,source,rev,follow,key,checkout_key,checksum,lock_files"
+    )?;
+
+    let opt = |s: &Option<String>| field(s.as_deref().unwrap_or_default()).into_owned();
+
+    for entry in entries {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{},{},{}",
+            field(&entry.name),
+            field(&entry.version),
+            entry.kind.as_str(),
+            field(&entry.source),
+            opt(&entry.rev),
+            opt(&entry.follow),
+            field(&entry.key),
+            opt(&entry.checkout_key),
+            opt(&entry.checksum),
+            // Multiple lockfiles are separated by semicolons
+            field(&entry.lock_files.join(";")),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_csv() {
+        let entries = [Entry {
+            name: "patched".to_owned(),
+            version: "0.1.0".to_owned(),
+            kind: Kind::Path,
+            source: "/src/a \"quoted\", path".to_owned(),
+            rev: None,
+            follow: None,
+            key: "patched-0.1.0-pathpat".to_owned(),
+            checkout_key: None,
+            checksum: None,
+            lock_files: vec!["a/Cargo.lock".to_owned(), "b/Cargo.lock".to_owned()],
+        }];
+
+        let mut csv = Vec::new();
+        write_csv(&entries, &mut csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "name,version,kind,source,rev,follow,key,checkout_key,checksum,lock_files\n\
+            patched,0.1.0,path,\"/src/a \"\"quoted\"\", path\",,,patched-0.1.0-pathpat,,,a/Cargo.lock;b/Cargo.lock\n"
+        );
+    }
+}
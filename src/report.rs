@@ -0,0 +1,189 @@
+//! A machine readable summary of every crate processed during a run, so that
+//! CI can fail or alert on partial failures without scraping logs
+
+use crate::{cargo::Origins, error::Code, Krate, Path, Source};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The crate was already present in the storage location or on disk
+    Skipped,
+    /// The crate was uploaded to the storage location by `mirror`
+    Uploaded,
+    /// The crate was downloaded from the storage location by `sync`
+    Downloaded,
+    /// The crate was already present in the storage location, but didn't match
+    /// the checksum in the lockfile, so was uploaded again by
+    /// `mirror --refresh-mismatched`
+    Refreshed,
+}
+
+/// The outcome of processing a single crate
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub version: String,
+    /// The registry index url, or git url, the crate is sourced from
+    pub source: String,
+    /// The action taken, or attempted if `error` is set
+    pub action: Action,
+    pub bytes: u64,
+    pub duration_secs: f64,
+    /// The error that occurred, if the action failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The stable code of the error, see [`crate::error::Code`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Code>,
+    /// The lockfiles the crate is locked in
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lock_files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Document<'e> {
+    succeeded: usize,
+    failed: usize,
+    skipped: usize,
+    crates: &'e [Entry],
+}
+
+/// Collects the outcome of every crate processed during a run
+#[derive(Default)]
+pub struct Report {
+    entries: Mutex<Vec<Entry>>,
+    origins: Arc<Origins>,
+}
+
+impl Report {
+    /// Creates a report that attributes each crate to its lockfile(s)
+    pub fn new(origins: Arc<Origins>) -> Self {
+        Self {
+            entries: Mutex::default(),
+            origins,
+        }
+    }
+
+    /// Records the outcome of processing a crate
+    pub fn record(
+        &self,
+        krate: &Krate,
+        action: Action,
+        bytes: u64,
+        duration: Duration,
+        error: Option<&anyhow::Error>,
+    ) {
+        let source = match &krate.source {
+            Source::Registry(rs) => rs.registry.index.to_string(),
+            Source::Git(gs) => gs.url.to_string(),
+        };
+
+        self.entries.lock().unwrap().push(Entry {
+            name: krate.name.clone(),
+            version: krate.version.clone(),
+            source,
+            action,
+            bytes,
+            duration_secs: duration.as_secs_f64(),
+            error: error.map(|err| format!("{err:#}")),
+            code: error.map(Code::of),
+            lock_files: self
+                .origins
+                .lock_files(krate)
+                .map(ToString::to_string)
+                .collect(),
+        });
+    }
+
+    /// Records a crate that didn't need to be processed
+    #[inline]
+    pub fn skipped(&self, krate: &Krate) {
+        self.record(krate, Action::Skipped, 0, Duration::ZERO, None);
+    }
+
+    /// Renders the report as a JSON document, with the crates sorted so that
+    /// the output is deterministic regardless of the order they completed in
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.sort_by(|a, b| {
+            (&a.name, &a.version, &a.source).cmp(&(&b.name, &b.version, &b.source))
+        });
+
+        let failed = entries.iter().filter(|e| e.error.is_some()).count();
+        let skipped = entries
+            .iter()
+            .filter(|e| e.action == Action::Skipped)
+            .count();
+
+        Ok(serde_json::to_string_pretty(&Document {
+            succeeded: entries.len() - failed - skipped,
+            failed,
+            skipped,
+            crates: &entries,
+        })?)
+    }
+
+    /// Writes the report to the specified path
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        use anyhow::Context as _;
+
+        std::fs::write(path, self.render()?)
+            .with_context(|| format!("failed to write summary to '{path}'"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Registry, RegistrySource};
+
+    #[test]
+    fn renders_sorted_summary() {
+        let registry = std::sync::Arc::new(Registry::crates_io(crate::RegistryProtocol::Sparse));
+        let krate = |name: &str| Krate {
+            name: name.to_owned(),
+            version: "1.0.0".to_owned(),
+            source: Source::Registry(RegistrySource {
+                registry: registry.clone(),
+                chksum: String::new(),
+            }),
+        };
+
+        let report = Report::default();
+        report.record(
+            &krate("b"),
+            Action::Uploaded,
+            10,
+            Duration::from_secs(1),
+            Some(&anyhow::anyhow!("failed to upload")),
+        );
+        report.skipped(&krate("c"));
+        report.record(
+            &krate("a"),
+            Action::Uploaded,
+            20,
+            Duration::from_secs(2),
+            None,
+        );
+
+        let doc: serde_json::Value = serde_json::from_str(&report.render().unwrap()).unwrap();
+
+        assert_eq!(doc["succeeded"], 1);
+        assert_eq!(doc["failed"], 1);
+        assert_eq!(doc["skipped"], 1);
+
+        let crates = doc["crates"].as_array().unwrap();
+        assert_eq!(crates[0]["name"], "a");
+        assert_eq!(crates[0]["bytes"], 20);
+        assert!(crates[0].get("error").is_none());
+        assert_eq!(crates[1]["name"], "b");
+        assert_eq!(crates[1]["error"], "failed to upload");
+        assert_eq!(crates[1]["code"], "CF-0001");
+        assert_eq!(crates[2]["action"], "skipped");
+    }
+}
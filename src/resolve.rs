@@ -0,0 +1,219 @@
+//! Resolves the dependency closure of crates that aren't in any lockfile, using
+//! the metadata in the registry index
+
+use crate::{fetch, Ctx, Krate, Registry, RegistrySource, Source};
+use anyhow::{Context as _, Error};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+};
+use tracing::{debug, warn};
+
+/// A crate to resolve, in the form `<name>[@<version>]`
+///
+/// A full version, eg. `foo@1.2.3`, selects exactly that version, while a
+/// requirement, eg. `foo@1.2`, selects the newest version that matches it the
+/// same as it would in a `Cargo.toml`. If no version is specified, the newest
+/// version is selected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrateSpec {
+    pub name: String,
+    pub req: semver::VersionReq,
+}
+
+impl std::str::FromStr for CrateSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, req) = match s.split_once('@') {
+            Some((name, version)) => {
+                let req = if let Ok(version) = semver::Version::parse(version) {
+                    semver::VersionReq {
+                        comparators: vec![semver::Comparator {
+                            op: semver::Op::Exact,
+                            major: version.major,
+                            minor: Some(version.minor),
+                            patch: Some(version.patch),
+                            pre: version.pre,
+                        }],
+                    }
+                } else {
+                    version
+                        .parse()
+                        .with_context(|| format!("'{version}' is not a valid version"))?
+                };
+
+                (name, req)
+            }
+            None => (s, semver::VersionReq::STAR),
+        };
+
+        anyhow::ensure!(
+            tame_index::KrateName::try_from(name).is_ok(),
+            "'{name}' is not a valid crate name"
+        );
+
+        Ok(Self {
+            name: name.to_owned(),
+            req,
+        })
+    }
+}
+
+impl fmt::Display for CrateSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.req)
+    }
+}
+
+/// Selects the newest version that matches the requirement, ignoring yanked
+/// versions, the same as cargo does when there is no lockfile
+fn select<'ik>(
+    ik: &'ik tame_index::IndexKrate,
+    req: &semver::VersionReq,
+) -> Option<&'ik tame_index::IndexVersion> {
+    ik.versions
+        .iter()
+        .filter(|iv| !iv.is_yanked())
+        .filter_map(|iv| {
+            semver::Version::parse(&iv.version)
+                .ok()
+                .filter(|version| req.matches(version))
+                .map(|version| (version, iv))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, iv)| iv)
+}
+
+/// Resolves the dependency closure of the specified crates from the registry.
+///
+/// Since there is no lockfile, and we don't know which features or targets
+/// will be used, every normal and build dependency is included, even optional
+/// and platform specific ones, so that the mirror covers any combination. Dev
+/// dependencies are not needed to build a crate as a dependency, so they are
+/// ignored.
+pub async fn closure(
+    ctx: &Ctx,
+    registry: &Arc<Registry>,
+    roots: &[CrateSpec],
+) -> Result<Vec<Krate>, Error> {
+    let mut entries = BTreeMap::<String, tame_index::IndexKrate>::new();
+    let mut resolved = BTreeSet::<(String, String)>::new();
+    let mut krates = Vec::new();
+
+    let mut pending: Vec<_> = roots
+        .iter()
+        .map(|spec| (spec.name.clone(), spec.req.clone()))
+        .collect();
+
+    while !pending.is_empty() {
+        // Retrieve the entries for every crate at this depth in one batch
+        let missing: BTreeSet<_> = pending
+            .iter()
+            .filter(|(name, _)| !entries.contains_key(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        if !missing.is_empty() {
+            debug!("retrieving {} index entries", missing.len());
+            entries.extend(
+                fetch::index_entries(
+                    &ctx.client,
+                    registry,
+                    missing,
+                    &ctx.request_limit,
+                    &ctx.http,
+                    ctx.sparse_index_cache.as_deref(),
+                )
+                .await
+                .with_context(|| format!("failed to read index for '{}'", registry.index))?,
+            );
+        }
+
+        let mut next = Vec::new();
+
+        for (name, req) in pending {
+            let Some(ik) = entries.get(&name) else {
+                warn!("unable to resolve '{name}@{req}': crate not found");
+                continue;
+            };
+
+            let Some(iv) = select(ik, &req) else {
+                warn!("unable to resolve '{name}@{req}': no matching version");
+                continue;
+            };
+
+            if !resolved.insert((name.clone(), iv.version.to_string())) {
+                continue;
+            }
+
+            for dep in iv.dependencies() {
+                if dep.kind() == tame_index::krate::DependencyKind::Dev {
+                    continue;
+                }
+
+                next.push((dep.crate_name().to_owned(), dep.version_requirement()));
+            }
+
+            krates.push(Krate {
+                name,
+                version: iv.version.to_string(),
+                source: Source::Registry(RegistrySource {
+                    registry: registry.clone(),
+                    chksum: iv.checksum.to_string(),
+                }),
+            });
+        }
+
+        pending = next;
+    }
+
+    Ok(krates)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tame_index::{IndexKrate, IndexVersion};
+
+    #[test]
+    fn parses_crate_specs() {
+        let spec: CrateSpec = "foo@1.2.3".parse().unwrap();
+        assert_eq!(spec.name, "foo");
+        assert_eq!(spec.req.to_string(), "=1.2.3");
+
+        let spec: CrateSpec = "foo@1.2".parse().unwrap();
+        assert_eq!(spec.req.to_string(), "^1.2");
+
+        let spec: CrateSpec = "foo-bar".parse().unwrap();
+        assert_eq!(spec.name, "foo-bar");
+        assert_eq!(spec.req, semver::VersionReq::STAR);
+
+        assert!("foo@nope".parse::<CrateSpec>().is_err());
+        assert!("@1.2.3".parse::<CrateSpec>().is_err());
+    }
+
+    #[test]
+    fn selects_newest_matching() {
+        let mut yanked = IndexVersion::fake("foo", "1.3.0");
+        yanked.yanked = true;
+
+        let ik = IndexKrate {
+            versions: vec![
+                IndexVersion::fake("foo", "1.1.0"),
+                IndexVersion::fake("foo", "1.2.0"),
+                yanked,
+                IndexVersion::fake("foo", "2.0.0"),
+            ],
+        };
+
+        let newest = |req: &str| select(&ik, &req.parse().unwrap()).map(|iv| iv.version.as_str());
+
+        assert_eq!(newest("1"), Some("1.2.0"));
+        assert_eq!(newest("=1.1.0"), Some("1.1.0"));
+        assert_eq!(newest("*"), Some("2.0.0"));
+        assert_eq!(newest("=1.3.0"), None);
+        assert_eq!(newest("3"), None);
+    }
+}
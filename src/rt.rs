@@ -0,0 +1,60 @@
+//! The library's use of the tokio runtime.
+//!
+//! Tasks, blocking work, timers, synchronization primitives, and the macros
+//! for awaiting several futures at once are used via this module rather than
+//! from tokio directly, so that the library's tokio usage is in one place.
+//! This is not a runtime abstraction, the library requires tokio, as
+//! [`JoinSet`], [`Scope`], and the HTTP client all depend on it, so its
+//! futures must be run within a tokio runtime, eg. via `async-compat` when
+//! embedded in an application that uses another executor.
+
+use std::{future::Future, time::Duration};
+
+/// Blocks on futures from threads outside of the runtime, eg. the thread pool
+/// that unpacks tarballs
+pub(crate) use tokio::runtime::Handle;
+#[cfg(feature = "image")]
+pub(crate) use tokio::sync::mpsc;
+#[cfg(feature = "serve")]
+pub(crate) use tokio::sync::RwLock;
+pub(crate) use tokio::{
+    join, select,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    try_join,
+};
+
+/// Runs scoped tasks, which may borrow from the enclosing function
+pub(crate) type Scope<'a, T> = async_scoped::TokioScope<'a, T>;
+
+/// Spawns a task that runs concurrently with the current one
+#[inline]
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::spawn(future)
+}
+
+/// Runs blocking work, eg. file I/O or git operations, on a thread where it
+/// won't stall other tasks, resuming any panic in the caller
+pub(crate) async fn spawn_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(err) => match err.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(err) => panic!("blocking task failed: {err}"),
+        },
+    }
+}
+
+/// Waits until the duration has elapsed
+#[inline]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
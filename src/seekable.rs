@@ -0,0 +1,375 @@
+//! Index snapshots compressed with zstd are written in the [seekable format],
+//! which splits the tarball into independently compressed frames followed by
+//! a seek table in a skippable frame, so that individual files can be read
+//! by only decompressing the frames that contain them. Decoders that don't
+//! know about the format ignore skippable frames, so the snapshots are still
+//! unpacked as normal by older versions.
+//!
+//! The file index, which maps the files in the tarball to where their
+//! contents are located in the decompressed stream, is written in its own
+//! skippable frame between the last compressed frame and the seek table. It
+//! isn't an entry in the seek table, so readers that only know the format
+//! still see the same frames.
+//!
+//! [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use crate::{util, Path};
+use anyhow::Context as _;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Seek, SeekFrom},
+};
+
+/// The amount of the tarball compressed in each frame, smaller frames mean
+/// less needs to be decompressed to read a file, at the cost of a worse ratio
+const FRAME_SIZE: usize = 1024 * 1024;
+/// The magic of the skippable frame holding the seek table
+const SEEK_TABLE_MAGIC: u32 = 0x184d_2a5e;
+/// The magic of the skippable frame holding the file index
+const FILE_INDEX_MAGIC: u32 = 0x184d_2a5f;
+/// The magic at the very end of the seek table footer
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+const FOOTER_SIZE: u64 = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+
+/// Where a file's contents are located in the decompressed tarball
+#[derive(Serialize, Deserialize, Debug)]
+struct IndexEntry {
+    path: String,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+struct Frame {
+    compressed_offset: u64,
+    decompressed_offset: u64,
+    compressed_size: u32,
+    decompressed_size: u32,
+}
+
+/// Packs the directory into a tarball in the seekable format, compressing
+/// each frame with the zstd level. The uncompressed tarball is spooled to a
+/// temporary file rather than held in memory, and with `threads` > 0 that
+/// many frames are compressed in parallel.
+pub(crate) fn pack_tar(path: &Path, level: i32, threads: u32) -> anyhow::Result<Bytes> {
+    let mut tar = {
+        let file = tempfile::tempfile().context("failed to create temporary file")?;
+        let mut archiver = tar::Builder::new(std::io::BufWriter::new(file));
+        util::append_dir_excluding(&mut archiver, path, &util::PathExclusions::default(), 0)?;
+        archiver.finish()?;
+        archiver
+            .into_inner()?
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("failed to write tarball")?
+    };
+    tar.rewind()?;
+
+    let mut index = Vec::new();
+    {
+        let mut archive = tar::Archive::new(std::io::BufReader::new(&mut tar));
+        for entry in archive.entries().context("failed to read tarball")? {
+            let entry = entry.context("failed to read tarball entry")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let path = entry.path().context("invalid path in tarball")?;
+            let path = path.to_string_lossy();
+            index.push(IndexEntry {
+                path: path.strip_prefix("./").unwrap_or(&path).to_owned(),
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+            });
+        }
+    }
+    let len = tar.seek(SeekFrom::End(0))?;
+    tar.rewind()?;
+
+    let pool = (threads > 0)
+        .then(|| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads as usize)
+                .build()
+        })
+        .transpose()
+        .context("failed to create compression thread pool")?;
+    let batch = (threads as usize).max(1);
+
+    let mut out = Vec::with_capacity(len as usize / 4);
+    let mut frames = Vec::new();
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let mut chunks = Vec::with_capacity(batch);
+        while chunks.len() < batch && remaining > 0 {
+            let mut chunk = vec![0u8; remaining.min(FRAME_SIZE as u64) as usize];
+            tar.read_exact(&mut chunk)
+                .context("failed to read tarball")?;
+            remaining -= chunk.len() as u64;
+            chunks.push(chunk);
+        }
+
+        let compressed: Vec<_> = match &pool {
+            Some(pool) => pool.install(|| {
+                use rayon::prelude::*;
+                chunks
+                    .par_iter()
+                    .map(|chunk| zstd::bulk::compress(chunk, level))
+                    .collect()
+            }),
+            None => chunks
+                .iter()
+                .map(|chunk| zstd::bulk::compress(chunk, level))
+                .collect(),
+        };
+
+        for (chunk, compressed) in chunks.iter().zip(compressed) {
+            let compressed = compressed?;
+            out.extend_from_slice(&compressed);
+            frames.push((compressed.len() as u32, chunk.len() as u32));
+        }
+    }
+
+    let index = serde_json::to_vec(&index)?;
+    out.extend_from_slice(&FILE_INDEX_MAGIC.to_le_bytes());
+    out.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    out.extend_from_slice(&index);
+
+    out.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&((frames.len() * 8) as u32 + FOOTER_SIZE as u32).to_le_bytes());
+    for (compressed, decompressed) in &frames {
+        out.extend_from_slice(&compressed.to_le_bytes());
+        out.extend_from_slice(&decompressed.to_le_bytes());
+    }
+    out.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    // No checksums, zstd frames already have their own
+    out.push(0);
+    out.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+
+    Ok(out.into())
+}
+
+/// A tarball in the seekable format, whose files can be read individually
+pub(crate) struct Snapshot<R> {
+    reader: R,
+    frames: Vec<Frame>,
+    files: BTreeMap<String, (u64, u64)>,
+    /// The most recently decompressed frame, as small files are often read
+    /// from the same frame
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().unwrap())
+}
+
+impl<R: Read + Seek> Snapshot<R> {
+    /// Opens the tarball, returning `None` if it isn't in the seekable format,
+    /// eg. it was written by an older version or isn't compressed with zstd
+    pub(crate) fn open(mut reader: R) -> anyhow::Result<Option<Self>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        reader.read_exact(&mut footer)?;
+
+        if read_u32(&footer[5..]) != SEEKABLE_MAGIC {
+            return Ok(None);
+        }
+
+        let count = read_u32(&footer) as u64;
+        let descriptor = footer[4];
+        anyhow::ensure!(
+            descriptor & !CHECKSUM_FLAG == 0,
+            "unsupported seek table descriptor {descriptor:#x}"
+        );
+        let entry_size = if descriptor & CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+
+        let table_size = count * entry_size + FOOTER_SIZE;
+        anyhow::ensure!(
+            table_size + SKIPPABLE_HEADER_SIZE <= len,
+            "seek table is larger than the tarball"
+        );
+
+        let mut table = vec![0u8; (SKIPPABLE_HEADER_SIZE + table_size - FOOTER_SIZE) as usize];
+        reader.seek(SeekFrom::End(
+            -((SKIPPABLE_HEADER_SIZE + table_size) as i64),
+        ))?;
+        reader.read_exact(&mut table)?;
+
+        anyhow::ensure!(
+            read_u32(&table) == SEEK_TABLE_MAGIC && read_u32(&table[4..]) as u64 == table_size,
+            "invalid seek table header"
+        );
+
+        let mut frames = Vec::with_capacity(count as usize);
+        let (mut compressed_offset, mut decompressed_offset) = (0, 0);
+        for entry in table[SKIPPABLE_HEADER_SIZE as usize..].chunks(entry_size as usize) {
+            let frame = Frame {
+                compressed_offset,
+                decompressed_offset,
+                compressed_size: read_u32(entry),
+                decompressed_size: read_u32(&entry[4..]),
+            };
+            compressed_offset += frame.compressed_size as u64;
+            decompressed_offset += frame.decompressed_size as u64;
+            frames.push(frame);
+        }
+
+        // The file index sits between the last frame and the seek table,
+        // tarballs in the seekable format written by other tools don't have
+        // one, and are treated the same as regular tarballs
+        let index_start = compressed_offset;
+        let index_end = len - SKIPPABLE_HEADER_SIZE - table_size;
+        if index_end < index_start + SKIPPABLE_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut index = vec![0u8; (index_end - index_start) as usize];
+        reader.seek(SeekFrom::Start(index_start))?;
+        reader.read_exact(&mut index)?;
+
+        if read_u32(&index) != FILE_INDEX_MAGIC
+            || read_u32(&index[4..]) as u64 != index_end - index_start - SKIPPABLE_HEADER_SIZE
+        {
+            return Ok(None);
+        }
+
+        let index: Vec<IndexEntry> =
+            serde_json::from_slice(&index[SKIPPABLE_HEADER_SIZE as usize..])
+                .context("failed to deserialize file index")?;
+
+        Ok(Some(Self {
+            reader,
+            frames,
+            files: index
+                .into_iter()
+                .map(|entry| (entry.path, (entry.offset, entry.size)))
+                .collect(),
+            cached: None,
+        }))
+    }
+
+    fn frame(&mut self, i: usize) -> anyhow::Result<&[u8]> {
+        if self.cached.as_ref().is_none_or(|(cached, _)| *cached != i) {
+            let frame = &self.frames[i];
+            let mut compressed = vec![0u8; frame.compressed_size as usize];
+            self.reader
+                .seek(SeekFrom::Start(frame.compressed_offset))
+                .and_then(|_| self.reader.read_exact(&mut compressed))
+                .context("failed to read frame")?;
+
+            let decompressed =
+                zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)
+                    .context("failed to decompress frame")?;
+            anyhow::ensure!(
+                decompressed.len() == frame.decompressed_size as usize,
+                "frame decompressed to {} bytes instead of {}",
+                decompressed.len(),
+                frame.decompressed_size
+            );
+
+            self.cached = Some((i, decompressed));
+        }
+
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+
+    /// Reads the contents of the file at the path relative to the root of the
+    /// tarball, only decompressing the frames that contain it
+    pub(crate) fn read(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(&(offset, size)) = self.files.get(path) else {
+            return Ok(None);
+        };
+
+        let end = offset + size;
+        let mut contents = Vec::with_capacity(size as usize);
+        let first = self.frames.partition_point(|frame| {
+            frame.decompressed_offset + frame.decompressed_size as u64 <= offset
+        });
+
+        for i in first..self.frames.len() {
+            let start = self.frames[i].decompressed_offset;
+            if start >= end {
+                break;
+            }
+
+            let frame = self.frame(i)?;
+            let from = offset.saturating_sub(start) as usize;
+            let to = ((end - start) as usize).min(frame.len());
+            contents.extend_from_slice(&frame[from..to]);
+        }
+
+        anyhow::ensure!(
+            contents.len() as u64 == size,
+            "'{path}' is truncated, expected {size} bytes but read {}",
+            contents.len()
+        );
+
+        Ok(Some(contents))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_individual_files() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+
+        std::fs::write(
+            root.join("config.json"),
+            b"{\"dl\":\"https://example.com\"}",
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.join(".cache/3/s")).unwrap();
+        std::fs::write(root.join(".cache/3/s/syn"), b"syn").unwrap();
+        // Spans several frames
+        let large: Vec<u8> = (0..FRAME_SIZE * 2 + 7).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join(".cache/3/s/big"), &large).unwrap();
+
+        let packed = pack_tar(root, 3, 0).unwrap();
+        let mut snapshot = Snapshot::open(std::io::Cursor::new(packed.clone()))
+            .unwrap()
+            .expect("snapshot is seekable");
+        // Compressing frames in parallel doesn't change the output
+        assert_eq!(pack_tar(root, 3, 2).unwrap(), packed);
+
+        assert_eq!(
+            snapshot.read("config.json").unwrap().unwrap(),
+            b"{\"dl\":\"https://example.com\"}"
+        );
+        assert_eq!(snapshot.read(".cache/3/s/big").unwrap().unwrap(), large);
+        assert_eq!(snapshot.read(".cache/3/s/syn").unwrap().unwrap(), b"syn");
+        assert!(snapshot.read(".cache/3/s/serde").unwrap().is_none());
+
+        // Regular decoders must still be able to unpack the whole tarball
+        let unpacked = tempfile::tempdir().unwrap();
+        let unpacked = util::path(unpacked.path()).unwrap();
+        util::unpack_tar(packed, util::Encoding::Detect, unpacked).unwrap();
+        assert_eq!(
+            std::fs::read(unpacked.join(".cache/3/s/big")).unwrap(),
+            large
+        );
+
+        // Regular zstd tarballs aren't seekable
+        let regular = util::pack_tar(root, util::Compression::default()).unwrap();
+        assert!(Snapshot::open(std::io::Cursor::new(regular))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,404 @@
+//! Serves the mirrored crates over HTTP as a [sparse registry](https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol),
+//! so that plain `cargo` on machines without cargo-fetcher installed can use
+//! the mirror via source replacement.
+//!
+//! Each registry is served under its directory name, eg.
+//! `/index.crates.io-6f17d22bba15001f/`. The index entries are read from the
+//! registry's index snapshot, and only contain the versions whose crate is
+//! present in the storage location, so that cargo never resolves a version
+//! it can't then download.
+
+use crate::{util, Ctx, Krate, Registry, Storage};
+use anyhow::{Context as _, Error};
+use bytes::{Buf, Bytes};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+
+/// The index entries for a single registry, keyed by the lowercased crate
+/// name, in the JSON lines format of the sparse protocol
+type Entries = HashMap<String, Bytes>;
+
+/// Reads the index entries from an index snapshot, which for both git and
+/// sparse registries contain the `.cache` entries for every crate in the
+/// lockfile(s), dropping any versions that aren't in `available`
+fn read_snapshot(snapshot: Bytes, available: &HashSet<String>) -> Result<Entries, Error> {
+    let mut archive = util::read_tar(snapshot.reader(), util::Encoding::Detect)?;
+    let mut entries = Entries::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        if !path.components().any(|comp| comp.as_os_str() == ".cache") {
+            continue;
+        }
+
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let name = name.to_owned();
+
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+
+        let mut ik = match tame_index::index::cache::ValidCacheEntry::read(&buf)
+            .map_err(Error::from)
+            .and_then(|entry| entry.to_krate(None).map_err(Error::from))
+        {
+            Ok(Some(ik)) => ik,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("unable to read index entry for '{name}': {err:#}");
+                continue;
+            }
+        };
+
+        ik.versions
+            .retain(|iv| available.contains(&iv.checksum.to_string()));
+        if ik.versions.is_empty() {
+            continue;
+        }
+
+        let mut lines = Vec::new();
+        ik.write_json_lines(&mut lines)?;
+        entries.insert(name, lines.into());
+    }
+
+    Ok(entries)
+}
+
+/// Loads the index entries for every registry from their snapshots
+async fn load(ctx: &Ctx) -> Result<HashMap<String, Entries>, Error> {
+    let available: Arc<HashSet<_>> = Arc::new(
+        ctx.backend
+            .list()
+            .await
+            .context("failed to list storage location")?
+            .into_iter()
+            .collect(),
+    );
+
+    let mut indices = HashMap::new();
+    for registry in &ctx.registries {
+        let snapshot = match crate::delta::fetch_snapshot(
+            ctx.index_backend(),
+            &registry.index_krate(),
+        )
+        .await
+        {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                error!(
+                    "failed to download index snapshot for {}: {err:#}",
+                    registry.index
+                );
+                continue;
+            }
+        };
+
+        let available = available.clone();
+        let entries = crate::rt::spawn_blocking(move || read_snapshot(snapshot, &available))
+            .await
+            .with_context(|| format!("failed to read index snapshot for {}", registry.index))?;
+
+        info!(
+            "serving {} crates from {} at /{}/",
+            entries.len(),
+            registry.index,
+            registry.short_name()
+        );
+        indices.insert(registry.short_name().to_owned(), entries);
+    }
+
+    Ok(indices)
+}
+
+/// A request for one of the resources in a served registry
+#[derive(Debug, PartialEq, Eq)]
+enum Route<'p> {
+    Config {
+        registry: &'p str,
+    },
+    Download {
+        registry: &'p str,
+        checksum: &'p str,
+    },
+    Entry {
+        registry: &'p str,
+        name: String,
+    },
+}
+
+impl<'p> Route<'p> {
+    fn parse(path: &'p str) -> Option<Self> {
+        let (registry, rest) = path.trim_start_matches('/').split_once('/')?;
+
+        if rest == "config.json" {
+            return Some(Self::Config { registry });
+        }
+
+        if let Some(checksum) = rest.strip_prefix("crates/") {
+            return (checksum.len() == 64 && checksum.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then_some(Self::Download { registry, checksum });
+        }
+
+        // The index paths are derived from the name, eg. `se/rd/serde`, so
+        // only the name itself matters
+        let name = rest.rsplit('/').next().filter(|name| !name.is_empty())?;
+        Some(Self::Entry {
+            registry,
+            name: name.to_lowercase(),
+        })
+    }
+}
+
+struct Server {
+    backend: Storage,
+    indices: crate::rt::RwLock<HashMap<String, Entries>>,
+    /// The url the server is reachable at, if not specified it is inferred
+    /// from the `Host` of each request
+    public_url: Option<url::Url>,
+}
+
+type Response = http::Response<hyper::Body>;
+
+fn status(code: http::StatusCode) -> Response {
+    let mut res = Response::new(hyper::Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+impl Server {
+    /// The root url of the registry, used for the download url in its config
+    fn registry_url(&self, req: &http::Request<hyper::Body>, registry: &str) -> String {
+        if let Some(public_url) = &self.public_url {
+            return format!("{}/{registry}", public_url.as_str().trim_end_matches('/'));
+        }
+
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("localhost");
+        format!("http://{host}/{registry}")
+    }
+
+    async fn handle(&self, req: http::Request<hyper::Body>) -> Response {
+        if req.method() != http::Method::GET && req.method() != http::Method::HEAD {
+            return status(http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let Some(route) = Route::parse(req.uri().path()) else {
+            return status(http::StatusCode::NOT_FOUND);
+        };
+        debug!(?route, "request");
+
+        let indices = self.indices.read().await;
+
+        match route {
+            Route::Config { registry } => {
+                if !indices.contains_key(registry) {
+                    return status(http::StatusCode::NOT_FOUND);
+                }
+
+                let config = serde_json::json!({
+                    "dl": format!("{}/crates/{{sha256-checksum}}", self.registry_url(&req, registry)),
+                    "api": null,
+                });
+
+                Response::new(config.to_string().into())
+            }
+            Route::Entry { registry, name } => {
+                match indices.get(registry).and_then(|entries| entries.get(&name)) {
+                    Some(lines) => Response::new(lines.clone().into()),
+                    None => status(http::StatusCode::NOT_FOUND),
+                }
+            }
+            Route::Download { registry, checksum } => {
+                if !indices.contains_key(registry) {
+                    return status(http::StatusCode::NOT_FOUND);
+                }
+                drop(indices);
+
+                // Crates are stored by their checksum, so cargo verifies that
+                // the download is the object it asked for
+                match self
+                    .backend
+                    .fetch(Krate::object(checksum).cloud_id(false))
+                    .await
+                {
+                    Ok(data) => Response::new(data.into()),
+                    Err(err) => {
+                        warn!("failed to download '{checksum}': {err:#}");
+                        status(http::StatusCode::NOT_FOUND)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Serves the mirrored registries on the address until the process is killed,
+/// reloading the index snapshots at the interval, if specified
+pub async fn registries(
+    ctx: Ctx,
+    addr: SocketAddr,
+    public_url: Option<url::Url>,
+    refresh: Option<Duration>,
+) -> Result<(), Error> {
+    let indices = load(&ctx).await?;
+    anyhow::ensure!(!indices.is_empty(), "no registry index snapshots to serve");
+
+    let ctx = Arc::new(ctx);
+    let server = Arc::new(Server {
+        backend: ctx.backend.clone(),
+        indices: crate::rt::RwLock::new(indices),
+        public_url,
+    });
+
+    if let Some(refresh) = refresh {
+        let ctx = ctx.clone();
+        let server = server.clone();
+        crate::rt::spawn(async move {
+            // The snapshots have just been loaded, so wait before the first reload
+            loop {
+                crate::rt::sleep(refresh).await;
+
+                // Keep serving the previous snapshots if they can't be reloaded
+                match load(&ctx).await {
+                    Ok(indices) if !indices.is_empty() => {
+                        *server.indices.write().await = indices;
+                    }
+                    Ok(_) => error!("no registry index snapshots to reload"),
+                    Err(err) => error!("failed to reload index snapshots: {err:#}"),
+                }
+            }
+        });
+    }
+
+    let make_service = hyper::service::make_service_fn(move |_conn| {
+        let server = server.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                let server = server.clone();
+                async move { Ok::<_, std::convert::Infallible>(server.handle(req).await) }
+            }))
+        }
+    });
+
+    let http_server = hyper::Server::try_bind(&addr)
+        .with_context(|| format!("unable to bind to {addr}"))?
+        .serve(make_service);
+
+    info!("listening on {}", http_server.local_addr());
+    http_server.await.context("server failed")
+}
+
+/// Renders the `[source]` configuration that replaces each registry with its
+/// served mirror, for use in a `.cargo/config.toml`
+pub fn source_config(registries: &[Arc<Registry>], url: &str) -> String {
+    use std::fmt::Write;
+
+    let url = url.trim_end_matches('/');
+    let mut config = String::new();
+
+    for registry in registries {
+        let short_name = registry.short_name();
+        let mirror = format!("cargo-fetcher-{short_name}");
+
+        if registry.serves_crates_io() {
+            let _ = writeln!(config, "[source.crates-io]");
+        } else {
+            let _ = writeln!(config, "[source.\"{short_name}\"]");
+            let _ = writeln!(config, "registry = \"{}\"", registry.index);
+        }
+        let _ = writeln!(config, "replace-with = \"{mirror}\"\n");
+
+        let _ = writeln!(config, "[source.\"{mirror}\"]");
+        let _ = writeln!(config, "registry = \"sparse+{url}/{short_name}/\"\n");
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tame_index::{IndexKrate, IndexVersion};
+
+    #[test]
+    fn reads_snapshot_entries() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+
+        let chksum = |c: char| c.to_string().repeat(64);
+        let version = |version: &str, c: char| {
+            let mut iv = IndexVersion::fake("Foo", version);
+            iv.checksum = chksum(c).parse().unwrap();
+            iv
+        };
+
+        let cache_dir = root.join(".cache/3/f");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+
+        let ik = IndexKrate {
+            versions: vec![version("1.0.0", 'a'), version("1.1.0", 'b')],
+        };
+        let mut entry = Vec::new();
+        ik.write_cache_entry(&mut entry, "etag: \"1\"").unwrap();
+        std::fs::write(cache_dir.join("foo"), entry).unwrap();
+
+        // Crates without any mirrored versions aren't served
+        let ik = IndexKrate {
+            versions: vec![version("0.1.0", 'c')],
+        };
+        let mut entry = Vec::new();
+        ik.write_cache_entry(&mut entry, "etag: \"2\"").unwrap();
+        std::fs::write(cache_dir.join("fob"), entry).unwrap();
+
+        let snapshot = util::pack_tar(root, util::Compression::default()).unwrap();
+        let available = [chksum('a')].into_iter().collect();
+        let entries = read_snapshot(snapshot, &available).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        let lines = std::str::from_utf8(&entries["foo"]).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.contains("\"vers\":\"1.0.0\""));
+    }
+
+    #[test]
+    fn parses_routes() {
+        let registry = "index.crates.io-6f17d22bba15001f";
+
+        assert_eq!(
+            Route::parse(&format!("/{registry}/config.json")),
+            Some(Route::Config { registry })
+        );
+        assert_eq!(
+            Route::parse(&format!("/{registry}/se/rd/Serde")),
+            Some(Route::Entry {
+                registry,
+                name: "serde".to_owned()
+            })
+        );
+        let checksum = "a".repeat(64);
+        assert_eq!(
+            Route::parse(&format!("/{registry}/crates/{checksum}")),
+            Some(Route::Download {
+                registry,
+                checksum: &checksum
+            })
+        );
+        assert_eq!(Route::parse(&format!("/{registry}/crates/nope")), None);
+        assert_eq!(Route::parse("/"), None);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{Ctx, Source};
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Mirror coverage for a single source of crates
+#[derive(Serialize, Debug, Default)]
+pub struct SourceStats {
+    /// The number of crates in the lockfile(s) from the source
+    pub crates: usize,
+    /// The number of those crates that are present in the storage location
+    pub mirrored: usize,
+    /// The total size of the objects for the mirrored crates
+    pub bytes: u64,
+}
+
+/// A crate in the lockfile(s) that is not present in the storage location
+#[derive(Serialize, Debug)]
+pub struct Missing {
+    pub name: String,
+    pub version: String,
+    pub source: String,
+    /// The id the object would have in the storage location
+    pub id: String,
+}
+
+/// A report of how much of the lockfile(s) is mirrored in the storage location
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    /// Totals across all sources
+    #[serde(flatten)]
+    pub total: SourceStats,
+    /// Breakdown by source, keyed by registry index url, or `git`
+    pub sources: BTreeMap<String, SourceStats>,
+    /// The crates that would be uploaded by `mirror`
+    pub missing: Vec<Missing>,
+}
+
+/// Cross-references the crates in the lockfile(s) with the contents of the
+/// storage location, without uploading anything
+pub async fn coverage(ctx: &Ctx) -> Result<Stats, Error> {
+    let objects: HashMap<_, _> = ctx
+        .backend
+        .list_stats()
+        .await?
+        .into_iter()
+        .map(|(id, stat)| (id, stat.size))
+        .collect();
+
+    let mut total = SourceStats::default();
+    let mut sources = BTreeMap::<String, SourceStats>::new();
+    let mut missing = Vec::new();
+    // Multiple crates can be sourced from the same git repository, so their
+    // objects are only counted once
+    let mut counted = HashSet::new();
+
+    for krate in &ctx.krates {
+        let source = match &krate.source {
+            Source::Registry(rs) => rs.registry.index.to_string(),
+            Source::Git(_) => "git".to_owned(),
+        };
+
+        let stats = sources.entry(source.clone()).or_default();
+        stats.crates += 1;
+        total.crates += 1;
+
+        let id = krate.cloud_id(false).to_string();
+        let Some(size) = objects.get(&id) else {
+            missing.push(Missing {
+                name: krate.name.clone(),
+                version: krate.version.clone(),
+                source,
+                id,
+            });
+            continue;
+        };
+
+        stats.mirrored += 1;
+        total.mirrored += 1;
+
+        if !counted.insert(id) {
+            continue;
+        }
+
+        let mut bytes = *size;
+        if matches!(krate.source, Source::Git(_)) {
+            bytes += objects
+                .get(&krate.cloud_id(true).to_string())
+                .copied()
+                .unwrap_or_default();
+        }
+
+        stats.bytes += bytes;
+        total.bytes += bytes;
+    }
+
+    Ok(Stats {
+        total,
+        sources,
+        missing,
+    })
+}
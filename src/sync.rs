@@ -1,29 +1,205 @@
 use crate::{util, Krate, Path, PathBuf, Registry, RegistryProtocol, Source};
 use anyhow::Context as _;
-use std::io::Write;
-use tracing::{debug, error, info, warn};
+use std::{fmt, io::Write};
+use tracing::{debug, error, info, warn, Instrument as _};
 
 pub const INDEX_DIR: &str = "registry/index";
 pub const CACHE_DIR: &str = "registry/cache";
 pub const SRC_DIR: &str = "registry/src";
+/// Crate tarballs that fail validation are moved here rather than being
+/// placed in the cache
+pub const QUARANTINE_DIR: &str = "registry/quarantine";
 pub const GIT_DB_DIR: &str = "git/db";
 pub const GIT_CO_DIR: &str = "git/checkouts";
+/// Records when the registry index snapshot in the index directory was last
+/// updated in the storage location
+const SNAPSHOT_UPDATED: &str = ".cargo-fetcher-updated";
+/// The version of the layout crates are written in to the cargo home, which
+/// is part of the [`cache_key`] so that it changes if the layout does
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// An object downloaded to an anonymous temporary file, so that large tarballs
+/// don't need to be held in memory
+struct Download {
+    file: std::fs::File,
+    len: u64,
+}
+
+impl Download {
+    /// Writes the data to a temporary file, eg. a tarball that was fetched
+    /// directly from upstream rather than downloaded
+    fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        use std::io::Seek;
+
+        let mut file = tempfile::tempfile().context("failed to create temporary file")?;
+        file.write_all(data)
+            .and_then(|_| file.rewind())
+            .context("failed to write temporary file")?;
+
+        Ok(Self {
+            file,
+            len: data.len() as u64,
+        })
+    }
+
+    async fn fetch(backend: &crate::Storage, id: crate::CloudId<'_>) -> anyhow::Result<Self> {
+        use std::io::Seek;
+
+        let mut file = tempfile::tempfile().context("failed to create temporary file")?;
+        let len = backend.fetch_to(id, &mut file).await?;
+        file.rewind().context("failed to rewind downloaded file")?;
+
+        Ok(Self { file, len })
+    }
+
+    /// Fetches the object, verifying it against its `.sha256` sidecar if
+    /// `verify` is set
+    async fn fetch_verified(
+        backend: &crate::Storage,
+        id: crate::CloudId<'_>,
+        verify: bool,
+    ) -> anyhow::Result<Self> {
+        let mut dl = Self::fetch(backend, id).await?;
+        if verify {
+            dl.verify(backend, id)
+                .await
+                .with_context(|| format!("failed to verify '{id}'"))?;
+        }
+        Ok(dl)
+    }
+
+    /// Verifies the downloaded object against the checksum in its `.sha256`
+    /// sidecar, so that an object corrupted in the storage location or in
+    /// transit is never unpacked
+    async fn verify(
+        &mut self,
+        backend: &crate::Storage,
+        id: crate::CloudId<'_>,
+    ) -> anyhow::Result<()> {
+        use std::io::Seek;
+
+        let sidecar = backend
+            .fetch(id.checksum())
+            .await
+            .context("failed to fetch checksum, was the object mirrored with --checksums?")?;
+        let expected = std::str::from_utf8(&sidecar)
+            .ok()
+            .and_then(|sidecar| sidecar.split_whitespace().next())
+            .context("checksum sidecar is malformed")?
+            .to_owned();
+
+        let actual = util::sha256_hex_from(&mut self.file).context("failed to hash object")?;
+        self.file
+            .rewind()
+            .context("failed to rewind downloaded file")?;
+
+        if !actual.eq_ignore_ascii_case(&expected) {
+            return Err(crate::error::coded(
+                crate::error::Code::Corrupt,
+                format!("checksum mismatch, expected {expected} but object has {actual}"),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Replaces a git db that was uploaded as a delta with the db it points
+    /// to, which is the base db with the deltas applied
+    async fn resolve_git_db(mut self, backend: &crate::Storage) -> anyhow::Result<Self> {
+        use std::io::{Read, Seek};
+
+        // Pointers are tiny compared to even the smallest db
+        if self.len > 64 * 1024 {
+            return Ok(self);
+        }
+
+        let mut data = Vec::with_capacity(self.len as usize);
+        self.file
+            .read_to_end(&mut data)
+            .and_then(|_| self.file.rewind())
+            .context("failed to read downloaded file")?;
+
+        let Some(pointer) = crate::delta::Pointer::parse(&data) else {
+            return Ok(self);
+        };
+
+        let db = pointer
+            .resolve(backend)
+            .await
+            .context("failed to resolve git db delta")?;
+        Self::from_bytes(&db)
+    }
+}
+
+/// Fetches crates directly from their upstream sources, rather than from the
+/// storage location, when syncing with `--url none`
+#[derive(Clone)]
+struct Upstream {
+    client: crate::HttpClient,
+    retry: util::RetryPolicy,
+    git_options: crate::git::CloneOptions,
+}
+
+impl Upstream {
+    fn new(ctx: &crate::Ctx) -> Option<Self> {
+        ctx.upstream_only.then(|| Self {
+            client: ctx.client.clone(),
+            retry: ctx.retry,
+            git_options: ctx.git_clone_options(),
+        })
+    }
+
+    async fn fetch(&self, krate: &Krate) -> anyhow::Result<Pkg> {
+        // The tarballs are unpacked immediately, so there's no point
+        // compressing them
+        let pkg = crate::fetch::from_registry(
+            &self.client,
+            krate,
+            &self.retry,
+            &self.git_options,
+            util::Compression::None,
+            &util::PathExclusions::default(),
+        )
+        .await?;
+
+        Ok(match pkg {
+            crate::fetch::KratePackage::Registry(data) => Pkg::Registry { data, local: None },
+            crate::fetch::KratePackage::Git(gs) => Pkg::Git {
+                db: Download::from_bytes(&gs.db)?,
+                checkout: gs
+                    .checkout
+                    .as_deref()
+                    .map(Download::from_bytes)
+                    .transpose()?,
+            },
+        })
+    }
+}
 
 pub async fn registry_indices(
     root_dir: PathBuf,
     backend: crate::Storage,
     registries: Vec<std::sync::Arc<Registry>>,
+    verify: bool,
+    scope: Option<&str>,
 ) {
     #[allow(unsafe_code)]
     // SAFETY: we don't forget the future :p
     unsafe {
-        async_scoped::TokioScope::scope_and_collect(|s| {
+        crate::rt::Scope::scope_and_collect(|s| {
             for registry in registries {
-                s.spawn(async {
-                    if let Err(err) = registry_index(&root_dir, backend.clone(), registry).await {
-                        error!("{err:#}");
+                s.spawn(
+                    async {
+                        if let Err(err) =
+                            registry_index(&root_dir, backend.clone(), registry, verify, scope)
+                                .await
+                        {
+                            error!("{err:#}");
+                        }
                     }
-                });
+                    .in_current_span(),
+                );
             }
         })
         .await;
@@ -38,7 +214,7 @@ async fn maybe_fetch_index(index_path: &Path, registry: &Registry) -> anyhow::Re
 
     let index_path = index_path.to_owned();
     let index_url = registry.index.to_string();
-    tokio::task::spawn_blocking(move || {
+    crate::rt::spawn_blocking(move || {
         let last_updated = index_path.join(".last-updated");
 
         let gi = tame_index::GitIndex::new(tame_index::IndexLocation {
@@ -47,7 +223,7 @@ async fn maybe_fetch_index(index_path: &Path, registry: &Registry) -> anyhow::Re
         })?;
 
         {
-            let span = tracing::debug_span!("fetch", index = index_url.clone());
+            let span = tracing::debug_span!("fetch", registry = %index_url);
             let _sf = span.enter();
             let unlocked = &tame_index::index::FileLock::unlocked();
             let mut rgi = tame_index::index::RemoteGitIndex::new(gi, unlocked)?;
@@ -58,20 +234,19 @@ async fn maybe_fetch_index(index_path: &Path, registry: &Registry) -> anyhow::Re
         Ok(())
     })
     .await
-    .unwrap()
 }
 
-#[tracing::instrument(skip(backend))]
+#[tracing::instrument(skip_all, fields(registry = %registry.index))]
 pub async fn registry_index(
     root_dir: &Path,
     backend: crate::Storage,
     registry: std::sync::Arc<Registry>,
+    verify: bool,
+    scope: Option<&str>,
 ) -> anyhow::Result<()> {
-    let ident = registry.short_name().to_owned();
-
     let index_path = {
         let mut ip = root_dir.join(INDEX_DIR);
-        ip.push(&ident);
+        ip.push(registry.short_name());
         ip
     };
     std::fs::create_dir_all(&index_path).context("failed to create index dir")?;
@@ -88,33 +263,318 @@ pub async fn registry_index(
         }
     }
 
-    let krate = Krate {
-        name: ident.clone(),
-        version: "2.0.0".to_owned(),
-        source: Source::Git(crate::cargo::GitSource {
-            url: registry.index.clone(),
-            ident,
-            rev: crate::cargo::GitRev::parse("feedc0de00000000000000000000000000000000").unwrap(),
-            follow: None,
-        }),
+    let krate = registry.scoped_index_krate(scope);
+    let state = Krate::object(&crate::delta::state_id(&krate.cloud_id(false).to_string()));
+    let marker = index_path.join(SNAPSHOT_UPDATED);
+
+    // The snapshot is only downloaded again if it, or its deltas, have been
+    // updated since it was last synced
+    let since = std::fs::read_to_string(&marker).ok().and_then(|ts| {
+        crate::Timestamp::parse(ts.trim(), &time::format_description::well_known::Rfc3339).ok()
+    });
+    let deltas_updated = backend.updated(state.cloud_id(false)).await.ok().flatten();
+
+    let mut index_data = match since {
+        Some(since) if deltas_updated.is_none_or(|du| du <= since) => {
+            use std::io::Seek;
+
+            let mut file = tempfile::tempfile().context("failed to create temporary file")?;
+            let Some(len) = backend
+                .fetch_if_newer(krate.cloud_id(false), since, &mut file)
+                .await?
+            else {
+                info!("index snapshot is unchanged since it was last synced");
+                return Ok(());
+            };
+            file.rewind().context("failed to rewind downloaded file")?;
+
+            let mut dl = Download { file, len };
+            if verify {
+                dl.verify(&backend, krate.cloud_id(false))
+                    .await
+                    .context("failed to verify index snapshot")?;
+            }
+            dl
+        }
+        _ => Download::fetch_verified(&backend, krate.cloud_id(false), verify).await?,
+    };
+
+    // Recorded once the snapshot is unpacked, so an interrupted sync doesn't
+    // leave a partial snapshot that is considered up to date
+    let _ = std::fs::remove_file(&marker);
+    // Note the snapshot could be replaced between being downloaded and this,
+    // but mirrors only replace it when stale, so the window is negligible
+    let updated = backend
+        .updated(krate.cloud_id(false))
+        .await
+        .ok()
+        .flatten()
+        .map(|updated| deltas_updated.map_or(updated, |du| du.max(updated)));
+
+    // Deltas are only applied to the base they were computed against
+    let base_hash = {
+        use std::io::Seek;
+        let hash =
+            util::sha256_hex_from(&mut index_data.file).context("failed to hash index snapshot")?;
+        index_data
+            .file
+            .rewind()
+            .context("failed to rewind index snapshot")?;
+        hash
     };
 
-    let index_data = backend.fetch(krate.cloud_id(false)).await?;
+    let deltas = crate::delta::fetch(&backend, &krate.cloud_id(false).to_string(), &base_hash)
+        .await
+        .context("failed to fetch index deltas")?;
 
-    if let Err(e) = util::unpack_tar(index_data, util::Encoding::Zstd, &index_path) {
+    // Tarballs are packed with zeroed mtimes, so the entries are given the
+    // time they are unpacked instead
+    if let Err(e) =
+        util::unpack_tar_from(index_data.file, util::Encoding::Detect, &index_path, false)
+    {
         error!(err = ?e, "failed to unpack crates.io-index");
+        return Ok(());
+    }
+
+    for (i, delta) in deltas.iter().enumerate() {
+        crate::delta::apply(delta, &index_path)
+            .with_context(|| format!("failed to apply index delta {}", i + 1))?;
+    }
+
+    if !deltas.is_empty() {
+        debug!("applied {} index deltas", deltas.len());
+    }
+
+    if let Some(updated) = updated {
+        let updated = updated.format(&time::format_description::well_known::Rfc3339)?;
+        if let Err(err) = std::fs::write(&marker, updated) {
+            debug!(?err, "failed to write {marker}");
+        }
     }
 
     Ok(())
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(name = krate.name, version = krate.version, rev = %rev.id))]
+/// Writes the `.cache` entries for every crate from a sparse registry directly
+/// into the registry's index directory, so that cargo doesn't need to request
+/// the metadata for any of the locked crates when the index snapshot isn't
+/// synced
+pub async fn sparse_index_entries(ctx: &crate::Ctx) {
+    #[allow(unsafe_code)]
+    // SAFETY: we don't forget the future :p
+    unsafe {
+        crate::rt::Scope::scope_and_collect(|s| {
+            for rset in ctx.registry_sets() {
+                if rset.registry.protocol != RegistryProtocol::Sparse {
+                    continue;
+                }
+
+                s.spawn(
+                    async move {
+                        if let Err(err) = sparse_registry_entries(ctx, rset).await {
+                            warn!("unable to write index entries: {err:#}");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+        })
+        .await;
+    }
+}
+
+#[tracing::instrument(skip_all, fields(registry = %rset.registry.index))]
+async fn sparse_registry_entries(
+    ctx: &crate::Ctx,
+    rset: crate::mirror::RegistrySet,
+) -> anyhow::Result<()> {
+    let index_path = {
+        let mut ip = ctx.root_dir.join(INDEX_DIR);
+        ip.push(rset.registry.short_name());
+        ip
+    };
+    std::fs::create_dir_all(&index_path).context("failed to create index dir")?;
+
+    // Entries that contain the locked versions are taken from the index
+    // snapshot if it is seekable, so only the rest are requested. This
+    // downloads the whole snapshot, so is opt-in
+    let mut krates = rset.krates;
+    if ctx.index_entries_from_snapshot {
+        match snapshot_entries(ctx, &rset.registry, &index_path).await {
+            Ok(Some(extracted)) => {
+                krates.retain(|name| !extracted.contains(name));
+                debug!(
+                    "extracted {} index entries from the snapshot",
+                    extracted.len()
+                );
+
+                if krates.is_empty() {
+                    return Ok(());
+                }
+            }
+            Ok(None) => debug!("index snapshot is not seekable"),
+            Err(err) => debug!("unable to read index entries from the snapshot: {err:#}"),
+        }
+    }
+
+    let auth_client = crate::fetch::authenticated_client(&rset.registry, &ctx.http)?;
+    let client = auth_client.as_ref().unwrap_or(&ctx.client);
+
+    crate::fetch::write_cache_entries(
+        client,
+        &rset.registry,
+        &index_path,
+        &index_path,
+        &krates,
+        &ctx.request_limit,
+        &ctx.retry,
+    )
+    .await?;
+
+    debug!("wrote {} index entries", krates.len());
+    Ok(())
+}
+
+/// Writes the `.cache` entries for the crates, which needn't be in any
+/// lockfile, eg. the crates of a dependency closure that isn't locked yet, to
+/// the index directory of the registry at `out_dir`, as well as the registry's
+/// `config.json` for sparse registries. Unlike [`sparse_index_entries`], the
+/// entries are always requested from the registry itself.
+///
+/// As with cargo, failures for individual crates are only logged.
+pub async fn cache_entries<I, S>(
+    ctx: &crate::Ctx,
+    registry: &Registry,
+    krates: I,
+    out_dir: &Path,
+) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let krates: Vec<String> = krates.into_iter().map(Into::into).collect();
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("failed to create index dir '{out_dir}'"))?;
+
+    let auth_client = crate::fetch::authenticated_client(registry, &ctx.http)?;
+    let client = auth_client.as_ref().unwrap_or(&ctx.client);
+
+    crate::fetch::write_cache_entries(
+        client,
+        registry,
+        out_dir,
+        out_dir,
+        &krates,
+        &ctx.request_limit,
+        &ctx.retry,
+    )
+    .await
+}
+
+/// Writes the `config.json` and the `.cache` entries that contain the locked
+/// versions of the registry's crates from the index snapshot, returning the
+/// names of the crates whose entries were written, or `None` if the snapshot
+/// isn't in the seekable format. Only the frames of the snapshot that contain
+/// the entries are decompressed.
+async fn snapshot_entries(
+    ctx: &crate::Ctx,
+    registry: &Registry,
+    index_path: &Path,
+) -> anyhow::Result<Option<std::collections::BTreeSet<String>>> {
+    let mut locked = std::collections::BTreeMap::<String, Vec<String>>::new();
+    for krate in ctx.krates.iter().filter(|krate| *krate == registry) {
+        locked
+            .entry(krate.name.clone())
+            .or_default()
+            .push(krate.version.clone());
+    }
+
+    let snapshot = Download::fetch(
+        ctx.index_backend(),
+        registry
+            .scoped_index_krate(ctx.index_scope.as_deref())
+            .cloud_id(false),
+    )
+    .await
+    .context("failed to download index snapshot")?;
+    let index_path = index_path.to_owned();
+
+    crate::rt::spawn_blocking(move || -> anyhow::Result<_> {
+        let Some(mut snapshot) = crate::seekable::Snapshot::open(snapshot.file)? else {
+            return Ok(None);
+        };
+        let Some(config) = snapshot.read("config.json")? else {
+            return Ok(None);
+        };
+        std::fs::write(index_path.join("config.json"), config)
+            .context("failed to write config.json")?;
+
+        let mut extracted = std::collections::BTreeSet::new();
+        for (name, versions) in locked {
+            let Ok(krate_name) = tame_index::KrateName::try_from(name.as_str()) else {
+                continue;
+            };
+            let rel_path = format!(".cache/{}", krate_name.relative_path(Some('/')));
+            let Some(entry) = snapshot.read(&rel_path)? else {
+                continue;
+            };
+
+            // The snapshot may have been taken before a version was locked
+            let Some(index_krate) = tame_index::index::cache::ValidCacheEntry::read(&entry)
+                .ok()
+                .and_then(|entry| entry.to_krate(None).ok().flatten())
+            else {
+                continue;
+            };
+            if !versions.iter().all(|version| {
+                index_krate
+                    .versions
+                    .iter()
+                    .any(|iv| iv.version == version.as_str())
+            }) {
+                continue;
+            }
+
+            let path = index_path.join(&rel_path);
+            std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| std::fs::write(&path, entry))
+                .with_context(|| format!("failed to write '{path}'"))?;
+            extracted.insert(name);
+        }
+
+        Ok(Some(extracted))
+    })
+    .await
+}
+
+/// How git dbs and checkouts are written to disk
+#[derive(Clone, Copy)]
+struct GitOptions {
+    /// Check that every object reachable from the locked revision is present
+    /// in the db, see [`crate::Ctx::check_git_connectivity`]
+    connectivity: bool,
+    /// Give files in checkouts the mtime from the checkout tarball
+    preserve_mtime: bool,
+}
+
+impl GitOptions {
+    fn new(ctx: &crate::Ctx) -> Self {
+        Self {
+            connectivity: ctx.check_git_connectivity,
+            preserve_mtime: ctx.preserve_mtime,
+        }
+    }
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(krate = %krate, rev = %rev))]
 fn sync_git(
     db_dir: &Path,
     co_dir: &Path,
     krate: &Krate,
-    pkg: crate::git::GitPackage,
+    db: Download,
+    checkout: Option<Download>,
     rev: &crate::cargo::GitRev,
+    options: GitOptions,
 ) -> anyhow::Result<()> {
     let db_path = db_dir.join(krate.local_id().to_string());
 
@@ -123,17 +583,49 @@ fn sync_git(
         remove_dir_all::remove_dir_all(&db_path).context("failed to remove existing DB path")?;
     }
 
-    let crate::git::GitPackage { db, checkout } = pkg;
-
     let unpack_path = db_path.clone();
-    let compressed = db.len();
-    let uncompressed = util::unpack_tar(db, util::Encoding::Zstd, &unpack_path)?;
+    let compressed = db.len;
+    let uncompressed = util::unpack_tar_from(db.file, util::Encoding::Detect, &unpack_path, false)?;
     debug!(
         compressed = compressed,
         uncompressed = uncompressed,
         "unpacked db dir"
     );
 
+    let stale = |err: anyhow::Error| {
+        if let Err(rm_err) = remove_dir_all::remove_dir_all(&db_path) {
+            warn!("failed to remove invalid db {db_path}: {rm_err}");
+        }
+        err.context(crate::error::coded(
+            crate::error::Code::Corrupt,
+            "git db is stale or truncated",
+        ))
+    };
+
+    let repo = gix::open(db_path.as_std_path())
+        .context("failed to open git db")
+        .map_err(stale)?;
+
+    // Abbreviated revisions need to be resolved so that the checkout has the
+    // same identity that cargo would give it
+    let rev = if rev.prefix().is_some() {
+        rev.resolved(crate::git::resolve(&repo, rev).map_err(stale)?)
+    } else {
+        rev.clone()
+    };
+
+    if let Err(err) = crate::git::validate(&repo, rev.id, options.connectivity) {
+        // The db isn't needed to use the checkout, eg. if the current head of
+        // the branch was mirrored with --git-follow-fallback, in which case
+        // the db doesn't contain the locked revision
+        if checkout.is_some() {
+            warn!("{err:#}, using the checkout tarball");
+        } else {
+            return Err(stale(err));
+        }
+    }
+    drop(repo);
+
     let co_path = co_dir.join(format!("{}/{}", krate.local_id(), rev.short()));
 
     // If we get here, it means there wasn't a .cargo-ok in the dir, even if the
@@ -149,8 +641,13 @@ fn sync_git(
     // otherwise do a checkout
     match checkout {
         Some(checkout) => {
-            let compressed = checkout.len();
-            let uncompressed = util::unpack_tar(checkout, util::Encoding::Zstd, &co_path)?;
+            let compressed = checkout.len;
+            let uncompressed = util::unpack_tar_from(
+                checkout.file,
+                util::Encoding::Detect,
+                &co_path,
+                options.preserve_mtime,
+            )?;
             debug!(
                 compressed = compressed,
                 uncompressed = uncompressed,
@@ -170,15 +667,33 @@ fn sync_git(
     Ok(())
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(name = krate.name, version = krate.version))]
+#[tracing::instrument(level = "debug", skip_all, fields(krate = %krate))]
 fn sync_package(
     cache_dir: &Path,
     src_dir: &Path,
+    quarantine_dir: &Path,
     krate: &Krate,
     data: bytes::Bytes,
+    local: Option<&Path>,
     chksum: &str,
 ) -> anyhow::Result<()> {
-    util::validate_checksum(&data, chksum)?;
+    util::validate_mirrored_checksum(&data, chksum)?;
+
+    if let Err(err) =
+        util::validate_crate_tarball(&data, &format!("{}-{}", krate.name, krate.version))
+    {
+        let quarantined = quarantine_dir.join(krate.local_id().to_string());
+
+        if let Err(qerr) = std::fs::create_dir_all(quarantine_dir)
+            .and_then(|()| std::fs::write(&quarantined, &data))
+        {
+            warn!(err = ?qerr, "failed to quarantine {quarantined}");
+        }
+
+        return Err(err.context(format!(
+            "malformed crate tarball, quarantined to {quarantined}"
+        )));
+    }
 
     let packed_krate_path = cache_dir.join(format!("{}", krate.local_id()));
 
@@ -191,11 +706,28 @@ fn sync_package(
         || -> anyhow::Result<()> {
             let s = tracing::debug_span!("pack_write");
             let _ = s.enter();
-            let mut f = std::fs::File::create(&packed_path)?;
 
-            let _ = f.set_len(pack_data.len() as u64);
+            // If the storage location is on the same filesystem, just link
+            // the object rather than writing another copy of it
+            if let Some(local) = local {
+                match link(local, &packed_path) {
+                    Ok(()) => {
+                        debug!("linked pack file from {local}");
+                        return Ok(());
+                    }
+                    Err(err) => debug!(?err, "unable to link pack file, writing it instead"),
+                }
+            }
+
+            // Written to a temporary file first, as the crate is considered
+            // synced once the pack file exists, so an interrupted write must
+            // never leave a truncated one behind
+            let mut f = tempfile::NamedTempFile::new_in(packed_path.parent().unwrap())?;
+
+            let _ = f.as_file().set_len(pack_data.len() as u64);
             f.write_all(&pack_data)?;
-            f.sync_all()?;
+            f.as_file().sync_all()?;
+            f.persist(&packed_path)?;
 
             debug!(bytes = pack_data.len(), "wrote pack file to disk");
             Ok(())
@@ -247,23 +779,89 @@ fn sync_package(
     Ok(())
 }
 
+/// Takes cargo's package cache lock in the cargo home, which cargo holds while
+/// it downloads and unpacks crates, so that concurrent syncs, or cargo, don't
+/// corrupt the crates the other is writing. Waits at most `wait` for the lock
+/// to be released, or indefinitely if `None`.
+///
+/// As with cargo, the cargo home isn't locked if it is on a filesystem that
+/// doesn't support locking, eg. NFS.
+pub async fn lock_cargo_home(
+    root_dir: &Path,
+    wait: Option<std::time::Duration>,
+) -> anyhow::Result<tame_index::index::FileLock> {
+    use tame_index::utils::flock::{LockError, LockOptions};
+
+    let root_dir = root_dir.to_owned();
+
+    crate::rt::spawn_blocking(move || {
+        let res = LockOptions::cargo_package_lock(Some(root_dir))?
+            .exclusive(false)
+            .lock(|path| {
+                info!(
+                    "waiting for the package cache lock '{path}', which is held by another process"
+                );
+                wait
+            });
+
+        match res {
+            Ok(lock) => Ok(lock),
+            Err(tame_index::Error::Lock(err))
+                if matches!(err.source, LockError::Nfs | LockError::NotSupported) =>
+            {
+                warn!("{err}, the cargo home is not locked: {}", err.source);
+                Ok(tame_index::index::FileLock::unlocked())
+            }
+            Err(err) => Err(anyhow::Error::from(err).context("failed to lock the cargo home")),
+        }
+    })
+    .await
+}
+
+/// Hard links the object into the cache, which fails if they are on different
+/// filesystems
+fn link(src: &Path, dst: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(dst) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    std::fs::hard_link(src, dst)
+}
+
 fn get_missing_git_sources<'krate>(
     ctx: &'krate crate::Ctx,
     git_co_dir: &Path,
     to_sync: &mut Vec<&'krate Krate>,
 ) {
     for (rev, ident, krate) in ctx.krates.iter().filter_map(|k| match &k.source {
-        Source::Git(gs) => Some((gs.rev.short(), &gs.ident, k)),
+        Source::Git(gs) => Some((&gs.rev, &gs.ident, k)),
         Source::Registry { .. } => None,
     }) {
-        let path = git_co_dir.join(format!("{ident}/{rev}/.cargo-ok"));
-
-        if !path.exists() {
+        if !is_checked_out(git_co_dir, ident, rev) {
             to_sync.push(krate);
         }
     }
 }
 
+/// Whether the revision of a git source has already been checked out
+fn is_checked_out(git_co_dir: &Path, ident: &str, rev: &crate::cargo::GitRev) -> bool {
+    let path = git_co_dir.join(format!("{ident}/{}/.cargo-ok", rev.short()));
+
+    // An abbreviated revision can be shorter than the short id of its
+    // checkout, so look for a checkout that it is a prefix of
+    path.exists()
+        || (rev.prefix().is_some()
+            && std::fs::read_dir(git_co_dir.join(ident)).is_ok_and(|entries| {
+                entries.filter_map(|entry| entry.ok()).any(|entry| {
+                    entry.file_name().to_str().is_some_and(|name| {
+                        name.starts_with(rev.short()) && entry.path().join(".cargo-ok").exists()
+                    })
+                })
+            }))
+}
+
 fn get_missing_registry_sources<'krate>(
     ctx: &'krate crate::Ctx,
     registry: &Registry,
@@ -298,14 +896,431 @@ fn get_missing_registry_sources<'krate>(
     Ok(())
 }
 
+/// Restores crates patched with local paths from the tarballs uploaded during
+/// mirror, paths that already contain a crate are left untouched
+pub async fn path_patches(ctx: &crate::Ctx) -> anyhow::Result<usize> {
+    let mut restored = 0;
+
+    for patch in &ctx.path_patches {
+        if patch.path.join("Cargo.toml").exists() {
+            debug!("'{}' already exists", patch.path);
+            continue;
+        }
+
+        let krate = patch.krate();
+        let tarball = match path_patch_tarball(ctx, patch).await {
+            Ok(dl) => dl,
+            Err(err) => {
+                error!("failed to download path patch '{krate}': {err:#}");
+                continue;
+            }
+        };
+
+        std::fs::create_dir_all(&patch.path)
+            .with_context(|| format!("unable to create '{}'", patch.path))?;
+
+        // Patches are packed with zeroed mtimes, which would make cargo
+        // consider them older than any build artifacts
+        match util::unpack_tar_from(tarball.file, util::Encoding::Detect, &patch.path, false) {
+            Ok(_) => {
+                info!("restored '{krate}' to '{}'", patch.path);
+                restored += 1;
+            }
+            Err(err) => error!("failed to unpack path patch '{krate}': {err:#}"),
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Downloads the latest tarball of a crate patched with a local path, via the
+/// SHA-256 recorded under its name and version by `mirror`
+async fn path_patch_tarball(
+    ctx: &crate::Ctx,
+    patch: &crate::cargo::PathPatch,
+) -> anyhow::Result<Download> {
+    let krate = patch.krate();
+    let hash = ctx.backend.fetch(krate.cloud_id(false)).await?;
+
+    // Tarballs were stored directly under the name and version before they
+    // were keyed by their hash
+    let tarball_krate = match std::str::from_utf8(&hash) {
+        Ok(hash) if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            patch.tarball_krate(hash)
+        }
+        _ => krate,
+    };
+
+    Download::fetch_verified(
+        &ctx.backend,
+        tarball_krate.cloud_id(false),
+        ctx.verify_checksums,
+    )
+    .await
+}
+
+/// The downloaded object(s) of a crate
+enum Pkg {
+    Registry {
+        data: bytes::Bytes,
+        /// The path of the object, if the storage location is local
+        local: Option<PathBuf>,
+    },
+    Git {
+        db: Download,
+        checkout: Option<Download>,
+    },
+}
+
+impl Pkg {
+    /// The number of bytes downloaded
+    fn len(&self) -> u64 {
+        match self {
+            Self::Registry { data, .. } => data.len() as u64,
+            Self::Git { db, checkout } => db.len + checkout.as_ref().map_or(0, |co| co.len),
+        }
+    }
+}
+
+/// Downloads the object(s) of a crate, the checkout of a git source is
+/// optional, as it can be recreated from the db. Registry crates are always
+/// verified against the checksum in the lockfile, git tarballs are only
+/// verified against their `.sha256` sidecar if `verify` is set. Crates are
+/// fetched directly from `upstream` instead, if there is no storage location.
+async fn download(
+    backend: &crate::Storage,
+    upstream: Option<&Upstream>,
+    krate: &Krate,
+    verify: bool,
+) -> anyhow::Result<Pkg> {
+    if let Some(upstream) = upstream {
+        return upstream.fetch(krate).await;
+    }
+
+    match &krate.source {
+        Source::Registry(_) => {
+            let span = tracing::debug_span!("download", key = %krate.cloud_id(false));
+            let data = backend
+                .fetch(krate.cloud_id(false))
+                .instrument(span)
+                .await?;
+            let local = backend.local_path(krate.cloud_id(false));
+            Ok(Pkg::Registry { data, local })
+        }
+        Source::Git(_) => {
+            let kd = krate.clone();
+            let kdb = backend.clone();
+            let co = krate.clone();
+            let cob = backend.clone();
+            let db_span = tracing::debug_span!("download", key = %krate.cloud_id(false));
+            let co_span = tracing::debug_span!("download_checkout", key = %krate.cloud_id(true));
+            let (db, checkout) = crate::rt::join!(
+                crate::rt::spawn(
+                    async move {
+                        Download::fetch_verified(&kdb, kd.cloud_id(false), verify)
+                            .await?
+                            .resolve_git_db(&kdb)
+                            .await
+                    }
+                    .instrument(db_span)
+                ),
+                crate::rt::spawn(
+                    async move {
+                        // A checkout that fails verification is recreated from the db
+                        match Download::fetch(&cob, co.cloud_id(true)).await {
+                            Ok(mut dl) if verify => {
+                                match dl.verify(&cob, co.cloud_id(true)).await {
+                                    Ok(()) => Some(dl),
+                                    Err(err) => {
+                                        warn!("ignoring checkout of {co}: {err:#}");
+                                        None
+                                    }
+                                }
+                            }
+                            res => res.ok(),
+                        }
+                    }
+                    .instrument(co_span)
+                ),
+            );
+
+            Ok(Pkg::Git {
+                db: db.unwrap()?,
+                checkout: checkout.unwrap(),
+            })
+        }
+    }
+}
+
+/// Writes the downloaded crate to disk in the same layout as cargo, returning
+/// the number of bytes that were downloaded for it
+fn splat(root_dir: &Path, krate: &Krate, pkg: Pkg, git: GitOptions) -> anyhow::Result<usize> {
+    let len = pkg.len() as usize;
+
+    match (&krate.source, pkg) {
+        (Source::Registry(rs), Pkg::Registry { data, local }) => {
+            let (cache_dir, src_dir) = rs.registry.sync_dirs(root_dir);
+            let quarantine_dir = rs.registry.quarantine_dir(root_dir);
+            sync_package(
+                &cache_dir,
+                &src_dir,
+                &quarantine_dir,
+                krate,
+                data,
+                local.as_deref(),
+                &rs.chksum,
+            )
+            .context("failed to splat package")?;
+        }
+        (Source::Git(gs), Pkg::Git { db, checkout }) => {
+            sync_git(
+                &root_dir.join(GIT_DB_DIR),
+                &root_dir.join(GIT_CO_DIR),
+                krate,
+                db,
+                checkout,
+                &gs.rev,
+                git,
+            )
+            .context("failed to splat git repo")?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(len)
+}
+
+/// Whether the crate failed to sync because its git db was stale or
+/// truncated, which may be transient, eg. if it was replaced while it was
+/// being downloaded, so is worth downloading again
+fn is_invalid_db(krate: &Krate, err: &anyhow::Error) -> bool {
+    matches!(krate.source, Source::Git(_))
+        && crate::error::Code::of(err) == crate::error::Code::Corrupt
+}
+
+/// Syncs a single crate, eg. on demand, if it isn't already present on disk,
+/// returning the number of bytes that were downloaded for it, which is 0 if it
+/// was already present
+pub async fn single(ctx: &crate::Ctx, krate: &Krate) -> anyhow::Result<usize> {
+    let root_dir = &ctx.root_dir;
+
+    let present = match &krate.source {
+        Source::Registry(rs) => {
+            let (cache_dir, src_dir) = rs.registry.sync_dirs(root_dir);
+            std::fs::create_dir_all(&cache_dir).context("failed to create registry/cache")?;
+            std::fs::create_dir_all(src_dir).context("failed to create registry/src")?;
+            cache_dir.join(krate.local_id().to_string()).exists()
+        }
+        Source::Git(gs) => {
+            let git_co_dir = root_dir.join(GIT_CO_DIR);
+            std::fs::create_dir_all(root_dir.join(GIT_DB_DIR))
+                .context("failed to create git/db/")?;
+            std::fs::create_dir_all(&git_co_dir).context("failed to create git/checkouts/")?;
+            is_checked_out(&git_co_dir, &gs.ident, &gs.rev)
+        }
+    };
+
+    if present {
+        debug!("{krate} is already available on local disk");
+        return Ok(0);
+    }
+
+    let upstream = Upstream::new(ctx);
+    let pkg = {
+        let _permit = ctx.request_limit.acquire().await;
+        download(&ctx.backend, upstream.as_ref(), krate, ctx.verify_checksums)
+            .await
+            .with_context(|| format!("failed to download {krate}"))?
+    };
+
+    let splat_pkg = |pkg| {
+        let root_dir = root_dir.clone();
+        let to_splat = krate.clone();
+        let git = GitOptions::new(ctx);
+        crate::rt::spawn_blocking(move || splat(&root_dir, &to_splat, pkg, git))
+    };
+
+    let synced = match splat_pkg(pkg).await {
+        Err(err) if is_invalid_db(krate, &err) => {
+            warn!("{err:#}, downloading {krate} again");
+            let pkg = {
+                let _permit = ctx.request_limit.acquire().await;
+                download(&ctx.backend, upstream.as_ref(), krate, ctx.verify_checksums).await?
+            };
+            splat_pkg(pkg).await
+        }
+        res => res,
+    };
+
+    synced.with_context(|| format!("failed to sync {krate}"))
+}
+
+/// A deterministic subset of the crates, so that syncing can be split between
+/// several machines that share the same root directory, in the form
+/// `<index>/<count>`, eg. `2/8`, where the index starts at 1
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    /// Whether the crate belongs to this shard. Crates are assigned by the
+    /// object they are stored as, so that crates which share an object, eg.
+    /// from the same git repository, are always synced by the same shard.
+    pub fn contains(&self, krate: &Krate) -> bool {
+        let hash = util::sha256_hex(krate.cloud_id(false).to_string().as_bytes());
+        let bucket = u64::from_str_radix(&hash[..16], 16).expect("hash is hex");
+        bucket % self.count as u64 == (self.index - 1) as u64
+    }
+
+    /// Whether this is the first shard, which is responsible for the state
+    /// shared by all crates, eg. registry indices
+    #[inline]
+    pub fn is_first(&self) -> bool {
+        self.index == 1
+    }
+}
+
+impl std::str::FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .context("shard must be in the form <index>/<count>")?;
+        let index = index.parse().context("invalid shard index")?;
+        let count = count.parse().context("invalid shard count")?;
+
+        anyhow::ensure!(
+            (1..=count).contains(&index),
+            "shard index must be in the range 1..={count}"
+        );
+
+        Ok(Self { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Computes a digest of the set of crates that are synced, and the layout
+/// they are written in, so that external caching layers, eg. buildkit cache
+/// mounts, can key the state of the cargo home off of it. The digest doesn't
+/// depend on the order of the crates, and registry indices aren't included,
+/// as they change independently of the lockfile.
+pub fn cache_key(krates: &[Krate]) -> String {
+    let mut ids: Vec<_> = krates
+        .iter()
+        .map(|krate| krate.cloud_id(false).to_string())
+        .collect();
+    ids.sort();
+    ids.dedup();
+
+    let mut content = format!("cargo-fetcher-layout-v{LAYOUT_VERSION}\n");
+    for id in ids {
+        content.push_str(&id);
+        content.push('\n');
+    }
+
+    util::sha256_hex(content.as_bytes())
+}
+
 #[derive(Debug)]
 pub struct Summary {
     pub total_bytes: usize,
     pub bad: u32,
     pub good: u32,
+    /// The crates that failed to download or unpack
+    pub failed: Vec<Krate>,
+    /// The sync was cancelled via [`Cancel`] before every crate was synced
+    pub interrupted: bool,
+}
+
+/// The paths a crate is written to when it is synced, which are removed if
+/// the sync is interrupted while it is being written, so that the next sync
+/// doesn't consider it already synced
+fn sync_paths(root_dir: &Path, krate: &Krate) -> Vec<PathBuf> {
+    match &krate.source {
+        Source::Registry(rs) => {
+            let (cache_dir, src_dir) = rs.registry.sync_dirs(root_dir);
+            let mut src_path = src_dir.join(krate.local_id().to_string());
+            src_path.set_extension("");
+
+            vec![cache_dir.join(krate.local_id().to_string()), src_path]
+        }
+        Source::Git(gs) => vec![
+            root_dir.join(GIT_DB_DIR).join(krate.local_id().to_string()),
+            root_dir
+                .join(GIT_CO_DIR)
+                .join(format!("{}/{}", krate.local_id(), gs.rev.short())),
+        ],
+    }
+}
+
+/// Cancels a [`crates`] sync that is in progress, eg. when it is interrupted.
+///
+/// Cancelling stops the downloads that are still outstanding, and lets the
+/// crates that are being written to disk finish, the caller can instead remove
+/// those with [`cleanup_partial`] if it needs to exit immediately.
+#[derive(Clone, Default)]
+pub struct Cancel {
+    triggered: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    notify: std::sync::Arc<crate::rt::Notify>,
+    /// The crates that are currently being written to disk
+    in_progress: std::sync::Arc<std::sync::Mutex<Vec<Krate>>>,
+}
+
+impl Cancel {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the sync, crates that haven't started being written to disk
+    /// are left for the next sync
+    pub fn cancel(&self) {
+        self.triggered
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.triggered.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// The crates that are currently being written to disk
+    pub fn in_progress(&self) -> Vec<Krate> {
+        self.in_progress.lock().unwrap().clone()
+    }
 }
 
-pub async fn crates(ctx: &crate::Ctx) -> anyhow::Result<Summary> {
+/// Removes the paths of crates that were only partially written to disk, eg.
+/// the [`Cancel::in_progress`] crates of a sync that has to exit immediately,
+/// so that the next sync doesn't consider them already synced
+pub fn cleanup_partial(root_dir: &Path, in_progress: &[Krate]) {
+    for krate in in_progress {
+        for path in sync_paths(root_dir, krate) {
+            let res = if path.is_dir() {
+                remove_dir_all::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+
+            match res {
+                Ok(()) => info!("removed partially synced '{path}'"),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => error!("failed to remove partially synced '{path}': {err}"),
+            }
+        }
+    }
+}
+
+pub async fn crates(ctx: &crate::Ctx, cancel: &Cancel) -> anyhow::Result<Summary> {
     info!("synchronizing {} crates...", ctx.krates.len());
 
     let root_dir = &ctx.root_dir;
@@ -336,12 +1351,24 @@ pub async fn crates(ctx: &crate::Ctx) -> anyhow::Result<Summary> {
     registry_sync.sort();
     registry_sync.dedup();
 
+    if let Some(report) = &ctx.report {
+        for krate in &ctx.krates {
+            if git_sync.binary_search(&krate).is_err()
+                && registry_sync.binary_search(&krate).is_err()
+            {
+                report.skipped(krate);
+            }
+        }
+    }
+
     if git_sync.is_empty() && registry_sync.is_empty() {
         info!("all crates already available on local disk");
         return Ok(Summary {
             total_bytes: 0,
             good: 0,
             bad: 0,
+            failed: Vec::new(),
+            interrupted: false,
         });
     }
 
@@ -350,73 +1377,51 @@ pub async fn crates(ctx: &crate::Ctx) -> anyhow::Result<Summary> {
         git_sync.len() + registry_sync.len()
     );
 
-    enum Pkg {
-        Registry(bytes::Bytes),
-        Git(crate::git::GitPackage),
-    }
+    let progress = ctx
+        .progress
+        .then(|| crate::progress::Progress::new(git_sync.len() + registry_sync.len()));
+    let reporter = progress.as_ref().map(|progress| progress.report());
+
+    // The same failure, eg. missing permissions, typically occurs for every
+    // crate, so each is only logged in full the first time
+    let repeated = std::sync::Arc::new(util::RepeatedWarnings::default());
+
+    let upstream = Upstream::new(ctx);
 
     // Kick off all the remote I/O first
-    let mut tasks = tokio::task::JoinSet::new();
-    for krate in git_sync
-        .into_iter()
-        .chain(registry_sync.into_iter())
-        .cloned()
-    {
+    let mut tasks = crate::rt::JoinSet::new();
+    for krate in git_sync.into_iter().chain(registry_sync).cloned() {
         let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+        let progress = progress.clone();
+        let repeated = repeated.clone();
+        let verify = ctx.verify_checksums;
+        let upstream = upstream.clone();
+
+        // Created outside of the task so that it's a child of the current span
+        let span = tracing::info_span!("sync", %krate);
 
         tasks.spawn(async move {
-            let span = tracing::info_span!("sync", %krate);
+            let _permit = limit.acquire().await;
+
             let _ss = span.enter();
 
-            match &krate.source {
-                Source::Registry(_rs) => {
-                    match {
-                        let span = tracing::debug_span!("download");
-                        let _ds = span.enter();
-                        backend.fetch(krate.cloud_id(false)).await
-                    } {
-                        Ok(krate_data) => {
-                            Some((krate, Pkg::Registry(krate_data)))
-                        }
-                        Err(err) => {
-                            error!(err = ?err, krate = %krate, cloud = %krate.cloud_id(false), "failed to download");
-                            None
-                        }
+            let start = std::time::Instant::now();
+
+            match download(&backend, upstream.as_ref(), &krate, verify).await {
+                Ok(pkg) => {
+                    if let Some(progress) = &progress {
+                        progress.downloaded(pkg.len());
                     }
+                    (krate, start, Ok(pkg))
                 }
-                Source::Git(_gs) => {
-                    let kd = krate.clone();
-                    let kdb = backend.clone();
-                    let co = krate.clone();
-                    let (krate_data, checkout) = tokio::join!(
-                        tokio::task::spawn(async move {
-                            let span = tracing::debug_span!("download");
-                            let _ds = span.enter();
-                            kdb.fetch(kd.cloud_id(false)).await
-                        }),
-                        tokio::task::spawn(async move {
-                            let span = tracing::debug_span!("download_checkout");
-                            let _ds = span.enter();
-                            backend.fetch(co.cloud_id(true)).await.ok()
-                        }),
-                    );
-
-                    let krate_data = match krate_data.unwrap() {
-                        Ok(krate_data) => {
-                            krate_data
-                        }
-                        Err(err) => {
-                            error!(err = ?err, krate = %krate, cloud = %krate.cloud_id(false), "failed to download");
-                            return None;
-                        }
-                    };
-
-                    let git_pkg = crate::git::GitPackage {
-                        db: krate_data,
-                        checkout: checkout.unwrap(),
-                    };
-
-                    Some((krate, Pkg::Git(git_pkg)))
+                Err(err) => {
+                    if repeated.first(&format!("failed to download: {}", err.root_cause())) {
+                        error!(err = ?err, krate = %krate, key = %krate.cloud_id(false), "failed to download");
+                    } else {
+                        debug!(krate = %krate, "failed to download");
+                    }
+                    (krate, start, Err(err))
                 }
             }
         });
@@ -426,58 +1431,102 @@ pub async fn crates(ctx: &crate::Ctx) -> anyhow::Result<Summary> {
         total_bytes: 0,
         bad: 0,
         good: 0,
+        failed: Vec::new(),
+        interrupted: false,
     }));
 
-    let (tx, rx) = crossbeam_channel::unbounded::<(Krate, Pkg)>();
+    let (tx, rx) = crossbeam_channel::unbounded::<(Krate, std::time::Instant, Pkg)>();
     let fs_thread = {
         let summary = summary.clone();
         let root_dir = root_dir.clone();
+        let progress = progress.clone();
+        let metrics = ctx.metrics.clone();
+        let report = ctx.report.clone();
+        let repeated = repeated.clone();
+        let backend = ctx.backend.clone();
+        let verify = ctx.verify_checksums;
+        let git = GitOptions::new(ctx);
+        let handle = crate::rt::Handle::current();
+        let upstream = upstream.clone();
+        let triggered = cancel.triggered.clone();
+        let in_progress = cancel.in_progress.clone();
 
         std::thread::spawn(move || {
-            let db_dir = &git_db_dir;
-            let co_dir = &git_co_dir;
             let root_dir = &root_dir;
             let summary = &summary;
+            let progress = &progress;
+            let metrics = &metrics;
+            let report = &report;
+            let repeated = &repeated;
+            let backend = &backend;
+            let handle = &handle;
+            let upstream = upstream.as_ref();
+            let triggered = &triggered;
+            let in_progress = &in_progress;
             rayon::scope(|s| {
-                while let Ok((krate, pkg)) = rx.recv() {
+                while let Ok((krate, start, pkg)) = rx.recv() {
                     s.spawn(move |_s| {
-                        let synced = match (&krate.source, pkg) {
-                            (Source::Registry(rs), Pkg::Registry(krate_data)) => {
-                                let len = krate_data.len();
-                                let (cache_dir, src_dir) = rs.registry.sync_dirs(root_dir);
-                                if let Err(err) = sync_package(
-                                    &cache_dir, &src_dir, &krate, krate_data, &rs.chksum,
-                                ) {
-                                    error!(krate = %krate, "failed to splat package: {err:#}");
-                                    None
-                                } else {
-                                    Some(len)
-                                }
+                        // Crates that haven't started being written when the
+                        // sync is cancelled are left for the next sync
+                        if triggered.load(std::sync::atomic::Ordering::Relaxed) {
+                            let mut sum = summary.lock().unwrap();
+                            sum.bad += 1;
+                            sum.failed.push(krate);
+                            return;
+                        }
+
+                        in_progress.lock().unwrap().push(krate.clone());
+                        let mut synced = splat(root_dir, &krate, pkg, git);
+
+                        // Invalid dbs are downloaded again, once
+                        if let Err(err) = &synced {
+                            if is_invalid_db(&krate, err) {
+                                warn!(krate = %krate, "{err:#}, downloading again");
+                                synced = handle
+                                    .block_on(download(backend, upstream, &krate, verify))
+                                    .and_then(|pkg| splat(root_dir, &krate, pkg, git));
                             }
-                            (Source::Git(gs), Pkg::Git(pkg)) => {
-                                let mut len = pkg.db.len();
+                        }
 
-                                if let Some(co) = &pkg.checkout {
-                                    len += co.len();
-                                }
+                        in_progress.lock().unwrap().retain(|ip| ip != &krate);
 
-                                match sync_git(db_dir, co_dir, &krate, pkg, &gs.rev) {
-                                    Ok(_) => Some(len),
-                                    Err(err) => {
-                                        error!(krate = %krate, "failed to splat git repo: {err:#}");
-                                        None
-                                    }
-                                }
+                        if let Err(err) = &synced {
+                            if repeated.first(&format!("failed to sync: {}", err.root_cause())) {
+                                error!(krate = %krate, "{err:#}");
+                            } else {
+                                debug!(krate = %krate, "{err:#}");
                             }
-                            _ => unreachable!(),
-                        };
+                        }
+
+                        if let Some(metrics) = metrics {
+                            metrics.krate("sync", synced.is_ok());
+                        }
+
+                        if let Some(progress) = progress {
+                            if synced.is_ok() {
+                                progress.unpacked();
+                            } else {
+                                progress.failed();
+                            }
+                        }
+
+                        if let Some(report) = report {
+                            report.record(
+                                &krate,
+                                crate::report::Action::Downloaded,
+                                synced.as_ref().map_or(0, |len| *len as u64),
+                                start.elapsed(),
+                                synced.as_ref().err(),
+                            );
+                        }
 
                         let mut sum = summary.lock().unwrap();
-                        if let Some(synced) = synced {
+                        if let Ok(synced) = synced {
                             sum.good += 1;
                             sum.total_bytes += synced;
                         } else {
                             sum.bad += 1;
+                            sum.failed.push(krate);
                         }
                     });
                 }
@@ -487,25 +1536,243 @@ pub async fn crates(ctx: &crate::Ctx) -> anyhow::Result<Summary> {
 
     // As each remote I/O op completes, pass it off to the thread pool to do
     // the more CPU intensive work of decompression, etc
-    while let Some(res) = tasks.join_next().await {
+    loop {
+        let res = crate::rt::select! {
+            res = tasks.join_next() => res,
+            () = cancel.notify.notified() => None,
+        };
+        let Some(res) = res else {
+            break;
+        };
         let Ok(res) = res else {
             continue;
         };
 
-        if let Some(pkg) = res {
-            let _ = tx.send(pkg);
-        } else {
-            summary.lock().unwrap().bad += 1;
+        match res {
+            (krate, start, Ok(pkg)) => {
+                let _ = tx.send((krate, start, pkg));
+            }
+            (krate, start, Err(err)) => {
+                {
+                    let mut sum = summary.lock().unwrap();
+                    sum.bad += 1;
+                    sum.failed.push(krate.clone());
+                }
+
+                if let Some(progress) = &progress {
+                    progress.failed();
+                }
+
+                if let Some(metrics) = &ctx.metrics {
+                    metrics.krate("sync", false);
+                }
+
+                if let Some(report) = &ctx.report {
+                    report.record(
+                        &krate,
+                        crate::report::Action::Downloaded,
+                        0,
+                        start.elapsed(),
+                        Some(&err),
+                    );
+                }
+            }
         }
     }
 
+    // Outstanding downloads are cancelled if the sync was cancelled
+    tasks.abort_all();
+
     // Drop the sender otherwise we'll deadlock
     drop(tx);
 
     fs_thread.join().expect("failed to join thread");
+    repeated.summarize();
+
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
 
-    Ok(std::sync::Arc::into_inner(summary)
+    let mut summary = std::sync::Arc::into_inner(summary)
         .unwrap()
         .into_inner()
-        .unwrap())
+        .unwrap();
+
+    if cancel.is_cancelled() {
+        warn!("sync was cancelled before every crate was synced");
+        summary.interrupted = true;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn locks_cargo_home() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+
+        let lock = lock_cargo_home(root, None).await.unwrap();
+        assert!(root.join(".package-cache").exists());
+
+        // A concurrent sync fails once the wait has elapsed
+        assert!(
+            lock_cargo_home(root, Some(std::time::Duration::from_millis(10)))
+                .await
+                .is_err()
+        );
+
+        drop(lock);
+        lock_cargo_home(root, Some(std::time::Duration::from_millis(10)))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn shards_crates() {
+        assert_eq!(
+            "2/8".parse::<Shard>().unwrap(),
+            Shard { index: 2, count: 8 }
+        );
+        assert!("0/8".parse::<Shard>().is_err());
+        assert!("9/8".parse::<Shard>().is_err());
+        assert!("2".parse::<Shard>().is_err());
+
+        let registry = std::sync::Arc::new(Registry::crates_io(RegistryProtocol::Sparse));
+        let krates: Vec<_> = (0..100)
+            .map(|i| Krate {
+                name: format!("krate-{i}"),
+                version: "0.1.0".to_owned(),
+                source: Source::Registry(crate::RegistrySource {
+                    registry: registry.clone(),
+                    chksum: util::sha256_hex(format!("krate-{i}").as_bytes()),
+                }),
+            })
+            .collect();
+
+        // Every crate belongs to exactly one shard
+        let shards: Vec<_> = (1..=3).map(|index| Shard { index, count: 3 }).collect();
+        for krate in &krates {
+            assert_eq!(shards.iter().filter(|s| s.contains(krate)).count(), 1);
+        }
+        assert!(shards
+            .iter()
+            .all(|shard| krates.iter().any(|krate| shard.contains(krate))));
+    }
+
+    #[tokio::test]
+    async fn writes_cache_entries_for_any_crates() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+
+        // A local registry, so that the entries are read from disk
+        let index = root.join("registry");
+        std::fs::create_dir_all(index.join("3/f")).unwrap();
+        std::fs::write(index.join("config.json"), r#"{"dl":"file:///crates"}"#).unwrap();
+        std::fs::write(
+            index.join("3/f/foo"),
+            format!(
+                r#"{{"name":"foo","vers":"1.0.0","deps":[],"cksum":"{}","features":{{}},"yanked":false}}"#,
+                util::sha256_hex(b"foo")
+            ),
+        )
+        .unwrap();
+        let registry = Registry::new(format!("sparse+file://{index}"), None).unwrap();
+
+        let backend: crate::Storage = std::sync::Arc::new(
+            crate::backends::fs::FsBackend::new(crate::FilesystemLocation {
+                path: &root.join("storage"),
+            })
+            .unwrap(),
+        );
+        let ctx = crate::Ctx::new(None, backend, Vec::new(), Vec::new()).unwrap();
+
+        let out = root.join("out");
+        cache_entries(&ctx, &registry, ["foo", "bar"], &out)
+            .await
+            .unwrap();
+
+        assert!(out.join(".cache/3/f/foo").exists());
+        assert!(!out.join(".cache/3/b/bar").exists());
+        assert!(out.join("config.json").exists());
+    }
+
+    #[test]
+    fn computes_cache_keys() {
+        let krates: Vec<_> = ["a", "b", "c"].into_iter().map(Krate::object).collect();
+        let key = cache_key(&krates);
+
+        // Neither the order nor duplicates affect the key
+        let mut shuffled = vec![krates[2].clone(), krates[0].clone()];
+        shuffled.extend(krates.iter().cloned());
+        assert_eq!(cache_key(&shuffled), key);
+
+        assert_ne!(cache_key(&krates[..2]), key);
+        assert_eq!(key.len(), 64);
+    }
+
+    #[tokio::test]
+    async fn verifies_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = util::path(dir.path()).unwrap();
+        let backend: crate::Storage = std::sync::Arc::new(
+            crate::backends::fs::FsBackend::new(crate::FilesystemLocation { path }).unwrap(),
+        );
+
+        let verified = Krate::object("verified");
+        crate::mirror::upload(
+            &backend,
+            bytes::Bytes::from_static(b"tarball"),
+            &verified,
+            false,
+            true,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut dl = Download::fetch_verified(&backend, verified.cloud_id(false), true)
+            .await
+            .unwrap();
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut dl.file, &mut contents).unwrap();
+        assert_eq!(contents, b"tarball");
+
+        // Replaced without updating the sidecar
+        backend
+            .upload(
+                bytes::Bytes::from_static(b"corrupt"),
+                verified.cloud_id(false),
+            )
+            .await
+            .unwrap();
+        let err = Download::fetch_verified(&backend, verified.cloud_id(false), true)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(crate::error::Code::of(&err), crate::error::Code::Corrupt);
+
+        // Objects without a sidecar can only be fetched without verification
+        let unverified = Krate::object("unverified");
+        backend
+            .upload(
+                bytes::Bytes::from_static(b"tarball"),
+                unverified.cloud_id(false),
+            )
+            .await
+            .unwrap();
+        assert!(
+            Download::fetch_verified(&backend, unverified.cloud_id(false), true)
+                .await
+                .is_err()
+        );
+        assert!(
+            Download::fetch_verified(&backend, unverified.cloud_id(false), false)
+                .await
+                .is_ok()
+        );
+    }
 }
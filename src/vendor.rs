@@ -0,0 +1,458 @@
+//! Exports the mirrored crates as a directory of vendored sources, in the same
+//! layout as `cargo vendor --versioned-dirs`, so that builds which can't reach
+//! the storage location at all can still consume the mirror
+
+use crate::{cargo::GitFollow, util, Ctx, Krate, Path, PathBuf, Source, Storage};
+use anyhow::{Context as _, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::{debug, error, info, warn};
+
+/// The name of the source that the vendored crates replace other sources with
+pub const SOURCE_NAME: &str = "vendored-sources";
+
+/// Files that `cargo vendor` doesn't copy from a package
+const EXCLUDED: &[&str] = &[".gitattributes", ".gitignore", ".git", ".cargo-ok"];
+
+pub struct Summary {
+    pub total_bytes: usize,
+    pub vendored: usize,
+    pub failed: Vec<Krate>,
+}
+
+/// The `.cargo-checksum.json` cargo uses to verify the contents of a vendored
+/// crate haven't been modified
+#[derive(Serialize)]
+struct Checksums {
+    files: BTreeMap<String, String>,
+    /// The checksum of the crate tarball, git sources don't have one
+    package: Option<String>,
+}
+
+/// Writes the `.cargo-checksum.json` for every file in the crate directory
+fn write_checksums(dir: &Path, package: Option<&str>) -> Result<(), Error> {
+    let mut files = BTreeMap::new();
+
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = util::path(entry.path())?;
+        let rel_path = path.strip_prefix(dir)?;
+
+        // Cargo always uses forward slashes, regardless of platform
+        let rel_path = rel_path
+            .components()
+            .map(|comp| comp.as_str())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let contents = std::fs::read(path).with_context(|| format!("unable to read {path}"))?;
+        files.insert(rel_path, util::sha256_hex(&contents));
+    }
+
+    let checksums = serde_json::to_vec(&Checksums {
+        files,
+        package: package.map(str::to_owned),
+    })?;
+
+    std::fs::write(dir.join(".cargo-checksum.json"), checksums)
+        .context("unable to write .cargo-checksum.json")
+}
+
+/// Copies the package, skipping the same files that `cargo vendor` does
+fn copy_package(src: &Path, dst: &Path) -> Result<(), Error> {
+    let walker = walkdir::WalkDir::new(src)
+        .into_iter()
+        .filter_entry(|entry| {
+            let Some(name) = entry.file_name().to_str() else {
+                return true;
+            };
+
+            // Submodules have their own .git
+            name != ".git"
+                && (entry.depth() != 1 || !(EXCLUDED.contains(&name) || name == "target"))
+        });
+
+    for entry in walker {
+        let entry = entry?;
+        let path = util::path(entry.path())?;
+        let target = dst.join(path.strip_prefix(src)?);
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("unable to create {target}"))?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(path, &target)
+                .with_context(|| format!("unable to copy {path} to {target}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the directory of the package in a git checkout, which may be one of
+/// many in a workspace
+fn find_package(root: &Path, name: &str) -> Result<PathBuf, Error> {
+    let walker = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name != ".git" && name != "target")
+        });
+
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_name() != "Cargo.toml" {
+            continue;
+        }
+
+        let path = util::path(entry.path())?;
+        let Ok(manifest) = std::fs::read_to_string(path) else {
+            continue;
+        };
+        let Ok(manifest) = manifest.parse::<toml::Table>() else {
+            continue;
+        };
+
+        let package_name = manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(|name| name.as_str());
+
+        if package_name == Some(name) {
+            return Ok(path.parent().unwrap().to_owned());
+        }
+    }
+
+    anyhow::bail!("unable to find package '{name}' in the git checkout")
+}
+
+/// Retrieves a checkout of the git source, either from its checkout tarball,
+/// or if there isn't one, by checking out the revision from the bare clone,
+/// returning the number of bytes downloaded
+async fn git_checkout(backend: &Storage, krate: &Krate, dir: &Path) -> Result<usize, Error> {
+    let Source::Git(gs) = &krate.source else {
+        unreachable!()
+    };
+
+    let dir = dir.to_owned();
+
+    if let Ok(checkout) = backend.fetch(krate.cloud_id(true)).await {
+        let len = checkout.len();
+        crate::rt::spawn_blocking(move || util::unpack_tar(checkout, util::Encoding::Detect, &dir))
+            .await?;
+        return Ok(len);
+    }
+
+    let db = crate::delta::fetch_git_db(backend, krate)
+        .await
+        .context("failed to download git db")?;
+    let len = db.len();
+
+    let rev = gs.rev.clone();
+    crate::rt::spawn_blocking(move || -> Result<(), Error> {
+        let db_path = dir.with_extension("db");
+        util::unpack_tar(db, util::Encoding::Detect, &db_path)?;
+
+        let repo = gix::open(db_path.as_std_path()).context("failed to open git db")?;
+        let rev = crate::git::resolve(&repo, &rev)?;
+        crate::git::checkout(db_path, dir, rev)?;
+        Ok(())
+    })
+    .await?;
+
+    Ok(len)
+}
+
+/// Writes a single crate to the vendor directory, returning the number of
+/// bytes downloaded
+async fn vendor(backend: &Storage, krate: &Krate, out: &Path) -> Result<usize, Error> {
+    let krate_dir = out.join(format!("{}-{}", krate.name, krate.version));
+
+    if krate_dir.exists() {
+        remove_dir_all::remove_dir_all(&krate_dir)
+            .with_context(|| format!("unable to remove {krate_dir}"))?;
+    }
+
+    match &krate.source {
+        Source::Registry(rs) => {
+            let data = backend
+                .fetch(krate.cloud_id(false))
+                .await
+                .context("failed to download")?;
+            let len = data.len();
+
+            util::validate_mirrored_checksum(&data, &rs.chksum)?;
+            util::validate_crate_tarball(&data, &format!("{}-{}", krate.name, krate.version))?;
+
+            let chksum = rs.chksum.clone();
+            crate::rt::spawn_blocking(move || -> Result<(), Error> {
+                // Crate tarballs already contain the `<name>-<version>` directory
+                util::unpack_tar(data, util::Encoding::Gzip, krate_dir.parent().unwrap())?;
+
+                for excluded in EXCLUDED {
+                    let _ = std::fs::remove_file(krate_dir.join(excluded));
+                }
+
+                write_checksums(&krate_dir, Some(&chksum))
+            })
+            .await?;
+
+            Ok(len)
+        }
+        Source::Git(_) => {
+            let temp_dir = tempfile::tempdir()?;
+            let checkout_dir = util::path(temp_dir.path())?.join("checkout");
+
+            let len = git_checkout(backend, krate, &checkout_dir).await?;
+
+            let name = krate.name.clone();
+            crate::rt::spawn_blocking(move || -> Result<(), Error> {
+                let package_dir = find_package(&checkout_dir, &name)?;
+                copy_package(&package_dir, &krate_dir)?;
+
+                // Unlike cargo vendor we don't normalize the manifest, so
+                // a package that inherits from its workspace can't be built
+                if let Ok(manifest) = std::fs::read_to_string(krate_dir.join("Cargo.toml")) {
+                    if manifest.contains("workspace = true") {
+                        warn!(
+                            "'{name}' inherits from its workspace, which vendored crates can't do"
+                        );
+                    }
+                }
+
+                write_checksums(&krate_dir, None)
+            })
+            .await?;
+
+            Ok(len)
+        }
+    }
+}
+
+/// Writes every crate in the lockfile(s) to the vendor directory
+pub async fn crates(ctx: &Ctx, out: &Path) -> Result<Summary, Error> {
+    std::fs::create_dir_all(out).with_context(|| format!("unable to create {out}"))?;
+
+    let mut krates = ctx.krates.clone();
+    krates.sort();
+    krates.dedup();
+
+    // The same git revision can be referred to by different specifiers
+    let object = |krate: &Krate| krate.cloud_id(false).to_string();
+    krates.dedup_by(|a, b| object(a) == object(b));
+
+    // Crates are vendored to `<name>-<version>`, so, the same as cargo vendor,
+    // the same version of a crate can't be vendored from two sources
+    for pair in krates.windows(2) {
+        let [a, b] = pair else { unreachable!() };
+        anyhow::ensure!(
+            a.name != b.name || a.version != b.version,
+            "'{a}' and '{b}' would both be vendored to '{}-{}', the same version of a crate can't be vendored from two sources",
+            a.name,
+            a.version,
+        );
+    }
+
+    info!("vendoring {} crates...", krates.len());
+
+    let mut tasks = crate::rt::JoinSet::new();
+    for krate in krates {
+        let backend = ctx.backend.clone();
+        let limit = ctx.request_limit.clone();
+        let out = out.to_owned();
+
+        // Created outside of the task so that it's a child of the current span
+        let span = tracing::info_span!("vendor", %krate);
+
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+
+            let _vs = span.enter();
+
+            let res = vendor(&backend, &krate, &out).await;
+            (krate, res)
+        });
+    }
+
+    let mut summary = Summary {
+        total_bytes: 0,
+        vendored: 0,
+        failed: Vec::new(),
+    };
+
+    while let Some(res) = tasks.join_next().await {
+        let Ok((krate, res)) = res else {
+            continue;
+        };
+
+        match res {
+            Ok(len) => {
+                debug!(bytes = len, "vendored {krate}");
+                summary.vendored += 1;
+                summary.total_bytes += len;
+            }
+            Err(err) => {
+                error!(krate = %krate, "failed to vendor: {err:#}");
+                summary.failed.push(krate);
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Renders the `[source]` configuration that replaces the sources of the
+/// crates in the lockfile(s) with the vendor directory, for use in a
+/// `.cargo/config.toml`
+pub fn source_config(ctx: &Ctx, out: &Path) -> String {
+    use std::fmt::Write;
+
+    let mut config = String::new();
+    let mut replace = |name: &str, kind: &str, url: &str, extra: Option<(&str, &str)>| {
+        let _ = writeln!(config, "[source.{name}]");
+        if !kind.is_empty() {
+            let _ = writeln!(config, "{kind} = \"{url}\"");
+        }
+        if let Some((key, value)) = extra {
+            let _ = writeln!(config, "{key} = \"{value}\"");
+        }
+        let _ = writeln!(config, "replace-with = \"{SOURCE_NAME}\"\n");
+    };
+
+    for registry in &ctx.registries {
+        if registry.serves_crates_io() {
+            replace("crates-io", "", "", None);
+        } else {
+            replace(
+                &format!("\"{}\"", registry.short_name()),
+                "registry",
+                registry.index.as_str(),
+                None,
+            );
+        }
+    }
+
+    // Sources are identified the same as in the lockfile, ie. by the
+    // specifier in the manifest rather than the locked revision
+    let mut git_sources = std::collections::BTreeSet::new();
+    for krate in &ctx.krates {
+        let Source::Git(gs) = &krate.source else {
+            continue;
+        };
+
+        let (key, value) = match (&gs.follow, &gs.rev_spec) {
+            (Some(GitFollow::Branch(branch)), _) => ("branch", branch.clone()),
+            (Some(GitFollow::Tag(tag)), _) => ("tag", tag.clone()),
+            (None, Some(rev)) => ("rev", rev.clone()),
+            (None, None) => ("rev", gs.rev.to_string()),
+        };
+
+        // The url of the source may still contain the query and fragment
+        let mut url = gs.url.clone();
+        url.set_query(None);
+        url.set_fragment(None);
+
+        git_sources.insert((url.to_string(), key, value));
+    }
+
+    for (url, key, value) in &git_sources {
+        replace(
+            &format!("\"git+{url}?{key}={value}\""),
+            "git",
+            url,
+            Some((key, value)),
+        );
+    }
+
+    let _ = writeln!(config, "[source.{SOURCE_NAME}]");
+    let _ = writeln!(config, "directory = \"{out}\"");
+
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_checksums() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = util::path(td.path()).unwrap();
+
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+
+        write_checksums(dir, Some("abcd")).unwrap();
+
+        let checksums: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join(".cargo-checksum.json")).unwrap())
+                .unwrap();
+
+        assert_eq!(checksums["package"], "abcd");
+        assert_eq!(
+            checksums["files"]["src/lib.rs"],
+            util::sha256_hex(b"").as_str()
+        );
+        assert_eq!(
+            checksums["files"]["Cargo.toml"],
+            util::sha256_hex(b"[package]\n").as_str()
+        );
+        assert_eq!(checksums["files"].as_object().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn replaces_sources() {
+        let td = tempfile::tempdir().unwrap();
+        let root = util::path(td.path()).unwrap();
+        let backend: Storage = std::sync::Arc::new(
+            crate::backends::fs::FsBackend::new(crate::FilesystemLocation { path: root }).unwrap(),
+        );
+
+        let git = |name: &str, url: &str| Krate {
+            name: name.to_owned(),
+            version: "0.1.0".to_owned(),
+            source: Source::from_git_url(&url::Url::parse(url).unwrap()).unwrap(),
+        };
+        let krates = vec![
+            git(
+                "cpal",
+                "https://github.com/EmbarkStudios/cpal?rev=71ab#71abe4d0a8f0b5fa1e7b8c2c6c0b3f1ad2c40e99",
+            ),
+            git(
+                "axum-live-view",
+                "https://github.com/EmbarkStudios/axum-live-view?branch=main#165e11655aa0094388df1905da8758d7a4f60e3c",
+            ),
+        ];
+        let registries = vec![std::sync::Arc::new(crate::Registry::crates_io(
+            crate::RegistryProtocol::Sparse,
+        ))];
+        let ctx = Ctx::new(None, backend, krates, registries).unwrap();
+
+        assert_eq!(
+            source_config(&ctx, Path::new("/vendor")),
+            r#"[source.crates-io]
+replace-with = "vendored-sources"
+
+[source."git+https://github.com/EmbarkStudios/axum-live-view?branch=main"]
+git = "https://github.com/EmbarkStudios/axum-live-view"
+branch = "main"
+replace-with = "vendored-sources"
+
+[source."git+https://github.com/EmbarkStudios/cpal?rev=71ab"]
+git = "https://github.com/EmbarkStudios/cpal"
+rev = "71ab"
+replace-with = "vendored-sources"
+
+[source.vendored-sources]
+directory = "/vendor"
+"#
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use cargo_fetcher as cf;
+use cf::{Krate, RegistrySource, Source};
+use std::io::{BufRead, Write};
+
+mod tutil;
+use tutil as util;
+
+/// Serves the same response to every request, claiming the body is gzip
+/// encoded, which is what some registries do for .crate files
+fn serve_gzip_encoded(body: Vec<u8>) -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                break;
+            };
+
+            // Drain the request headers before responding
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok() && line != "\r\n" && !line.is_empty() {
+                line.clear();
+            }
+
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/x-tar\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(&body);
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn ignores_content_encoding() {
+    // .crate files are themselves gzipped tarballs, so the body is valid gzip
+    // and would be transparently decompressed if the client allowed it
+    let body = {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"not actually a tarball").unwrap();
+        encoder.finish().unwrap()
+    };
+
+    let chksum = ring::digest::digest(&ring::digest::SHA256, &body)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    let addr = serve_gzip_encoded(body.clone());
+
+    let registry = std::sync::Arc::new(
+        cf::Registry::new(
+            format!("https://{addr}/index/"),
+            Some(format!("http://{addr}/{{crate}}/{{version}}/download")),
+        )
+        .unwrap(),
+    );
+
+    let fs_root = util::tempdir();
+    let mut fs_ctx = util::fs_ctx(fs_root.pb(), vec![registry.clone()]);
+
+    fs_ctx.krates = vec![Krate {
+        name: "gzipped".to_owned(),
+        version: "0.1.0".to_owned(),
+        source: Source::Registry(RegistrySource {
+            registry,
+            chksum: chksum.clone(),
+        }),
+    }];
+
+    let uploaded = cf::mirror::crates(&fs_ctx)
+        .await
+        .expect("failed to mirror crates");
+    assert_eq!(uploaded, body.len());
+
+    let mirrored = std::fs::read(fs_root.path().join(&chksum)).expect("crate was not mirrored");
+    assert_eq!(mirrored, body);
+}
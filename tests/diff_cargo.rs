@@ -141,6 +141,7 @@ async fn diff_cargo() {
     let (the_krates, registries) = cf::cargo::read_lock_files(
         vec!["tests/full/Cargo.lock".into()],
         vec![util::crates_io_registry()],
+        false,
     )
     .unwrap();
 
@@ -175,16 +176,25 @@ async fn diff_cargo() {
         assert_eq!(registry_sets.len(), 1);
         let the_registry = fs_ctx.registries[0].clone();
 
-        cf::mirror::registry_indices(&fs_ctx, std::time::Duration::new(10, 0), registry_sets).await;
+        cf::mirror::registry_indices(&fs_ctx, std::time::Duration::new(10, 0), 0, registry_sets)
+            .await;
         cf::mirror::crates(&fs_ctx)
             .await
             .expect("failed to mirror crates");
 
         fs_ctx.prep_sync_dirs().expect("create base dirs");
-        cf::sync::crates(&fs_ctx).await.expect("synced crates");
-        cf::sync::registry_index(&fs_ctx.root_dir, fs_ctx.backend.clone(), the_registry)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
-            .expect("failed to sync index");
+            .expect("synced crates");
+        cf::sync::registry_index(
+            &fs_ctx.root_dir,
+            fs_ctx.backend.clone(),
+            the_registry,
+            false,
+            None,
+        )
+        .await
+        .expect("failed to sync index");
     }
 
     cargo_fetch.join().unwrap();
@@ -222,6 +232,7 @@ async fn nothing_to_do() {
         let (the_krates, registries) = cf::cargo::read_lock_files(
             vec!["tests/full/Cargo.lock".into()],
             vec![util::crates_io_registry()],
+            false,
         )
         .unwrap();
 
@@ -232,16 +243,25 @@ async fn nothing_to_do() {
         let registry_sets = fs_ctx.registry_sets();
         let the_registry = fs_ctx.registries[0].clone();
 
-        cf::mirror::registry_indices(&fs_ctx, std::time::Duration::new(10, 0), registry_sets).await;
+        cf::mirror::registry_indices(&fs_ctx, std::time::Duration::new(10, 0), 0, registry_sets)
+            .await;
         cf::mirror::crates(&fs_ctx)
             .await
             .expect("failed to mirror crates");
 
         fs_ctx.prep_sync_dirs().expect("create base dirs");
-        cf::sync::crates(&fs_ctx).await.expect("synced crates");
-        cf::sync::registry_index(&fs_ctx.root_dir, fs_ctx.backend.clone(), the_registry)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
-            .expect("failed to sync index");
+            .expect("synced crates");
+        cf::sync::registry_index(
+            &fs_ctx.root_dir,
+            fs_ctx.backend.clone(),
+            the_registry,
+            false,
+            None,
+        )
+        .await
+        .expect("failed to sync index");
     }
 
     let output = std::process::Command::new("cargo")
@@ -5,6 +5,7 @@ fn parses_v2() {
     let (krates, _) = read_lock_files(
         vec!["tests/v2.lock".into()],
         vec![Registry::crates_io(RegistryProtocol::Git)],
+        false,
     )
     .unwrap();
     assert_eq!(krates.len(), 258);
@@ -15,6 +16,7 @@ fn parses_v3() {
     let (krates, _) = read_lock_files(
         vec!["tests/v3.lock".into()],
         vec![Registry::crates_io(RegistryProtocol::Sparse)],
+        false,
     )
     .unwrap();
     assert_eq!(krates.len(), 223);
@@ -0,0 +1,81 @@
+//! Runs the S3 backend against a real S3 compatible service, eg. Cloudflare R2
+//! or Backblaze B2, to catch differences in how they validate requests. The
+//! location is taken from `CARGO_FETCHER_TEST_S3_URL`, eg.
+//! `s3://bucket/cf-test/?endpoint=<account>.r2.cloudflarestorage.com&region=auto&checksum=sha256`,
+//! and the credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+//! The tests are skipped if the url isn't set.
+
+#![cfg(feature = "s3")]
+
+use cargo_fetcher as cf;
+use cf::{backends::s3::S3Backend, Backend as _, Krate, RegistrySource, Source};
+
+async fn backend() -> Option<S3Backend> {
+    let Ok(url) = std::env::var("CARGO_FETCHER_TEST_S3_URL") else {
+        eprintln!("skipping, CARGO_FETCHER_TEST_S3_URL is not set");
+        return None;
+    };
+
+    let url = url::Url::parse(&url).expect("invalid CARGO_FETCHER_TEST_S3_URL");
+    let url = cf::util::CloudLocationUrl::from_url(url).unwrap();
+    let cf::CloudLocation::S3(loc) = cf::util::parse_cloud_location(&url).unwrap() else {
+        panic!("CARGO_FETCHER_TEST_S3_URL is not an s3 url");
+    };
+
+    Some(
+        S3Backend::new(
+            loc,
+            std::time::Duration::from_secs(60),
+            cf::util::RetryPolicy::default(),
+            &cf::util::HttpConfig::default(),
+        )
+        .await
+        .expect("failed to create s3 backend"),
+    )
+}
+
+/// A pseudo crate whose object key is unique to this run, so that concurrent
+/// runs against the same bucket don't interfere
+fn object(name: &str) -> Krate {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    Krate {
+        name: name.to_owned(),
+        version: "0.0.0".to_owned(),
+        source: Source::Registry(RegistrySource {
+            registry: std::sync::Arc::new(cf::Registry::crates_io(cf::RegistryProtocol::Sparse)),
+            chksum: format!("{name}-{nanos}"),
+        }),
+    }
+}
+
+#[tokio::test]
+async fn round_trips_objects() {
+    let Some(backend) = backend().await else {
+        return;
+    };
+
+    let krate = object("round-trip");
+    let id = krate.cloud_id(false);
+    let payload = bytes::Bytes::from_static(b"cargo-fetcher s3 compatibility");
+
+    assert_eq!(
+        backend.upload(payload.clone(), id).await.unwrap(),
+        payload.len()
+    );
+
+    let stat = backend
+        .stat(id)
+        .await
+        .unwrap()
+        .expect("object was uploaded");
+    assert_eq!(stat.size, payload.len() as u64);
+    assert_eq!(backend.fetch(id).await.unwrap(), payload);
+    assert!(backend.list().await.unwrap().contains(&id.to_string()));
+
+    backend.delete(id).await.unwrap();
+    assert!(backend.stat(id).await.unwrap().is_none());
+}
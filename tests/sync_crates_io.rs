@@ -50,7 +50,7 @@ async fn all_missing() {
         .expect("failed to mirror crates");
     fs_ctx.prep_sync_dirs().expect("create base dirs");
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 3 crates")
             .good,
@@ -139,7 +139,7 @@ async fn some_missing() {
     let stored = fs_ctx.krates.clone();
     fs_ctx.krates = vec![stored[2].clone()];
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 1 crate")
             .good,
@@ -177,7 +177,7 @@ async fn some_missing() {
     // only receive the other 2
     fs_ctx.krates = stored;
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 2 crates")
             .good,
@@ -255,7 +255,7 @@ async fn none_missing() {
     fs_ctx.prep_sync_dirs().expect("create base dirs");
 
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 3 crate")
             .good,
@@ -290,7 +290,7 @@ async fn none_missing() {
     }
 
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 0 crates")
             .total_bytes,
@@ -44,7 +44,7 @@ async fn multiple_from_same_repo() {
         .expect("failed to mirror crates");
     fs_ctx.prep_sync_dirs().expect("create base dirs");
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 1 git source")
             .good,
@@ -128,7 +128,7 @@ async fn proper_head() {
         .expect("failed to mirror crates");
     fs_ctx.prep_sync_dirs().expect("create base dirs");
     assert_eq!(
-        cf::sync::crates(&fs_ctx)
+        cf::sync::crates(&fs_ctx, &cf::sync::Cancel::new())
             .await
             .expect("synced 1 git source")
             .good,